@@ -1,9 +1,10 @@
 use clap::{Parser, Subcommand};
-use file_storage_system::file::{FileManager, PieceManager, TorrentParser};
-use file_storage_system::peer::PeerManager;
+use file_storage_system::file::{CreateOptions, FileManager, MagnetLink, TorrentParser};
+use file_storage_system::network::{NetworkManager, TrackerEvent};
 use file_storage_system::prelude::*;
 use std::path::PathBuf;
-use tokio;
+use std::sync::Arc;
+use tokio::sync::RwLock;
 
 #[derive(Parser)]
 #[command(name = "file-storage-client")]
@@ -27,6 +28,19 @@ enum Commands {
         piece_size: u32,
         #[arg(short, long)]
         comment: Option<String>,
+        #[arg(long)]
+        created_by: Option<String>,
+        //=== Unix timestamp to record instead of now ===//
+        #[arg(long, conflicts_with = "no_date")]
+        creation_date: Option<u64>,
+        //=== Leave out the creation date so the info hash is reproducible ===//
+        #[arg(long)]
+        no_date: bool,
+        #[arg(long)]
+        encoding: Option<String>,
+        //=== Refuse a piece size that isn't a power of two in 16 KiB..=16 MiB ===//
+        #[arg(long)]
+        strict: bool,
     },
     //===  information about a torrent file ===//
     Info {
@@ -45,6 +59,54 @@ enum Commands {
 
         #[arg(short, long)]
         data_dir: PathBuf,
+
+        //=== Print the verification report as JSON instead of text ===//
+        #[arg(long)]
+        json: bool,
+    },
+    //=== Seed a torrent from content already on disk ===//
+    Seed {
+        torrent: PathBuf,
+
+        #[arg(short, long)]
+        content: PathBuf,
+
+        #[arg(short, long, default_value = "6881")]
+        port: u16,
+    },
+    //=== Add or remove trackers without touching the info hash ===//
+    Edit {
+        torrent: PathBuf,
+
+        #[arg(long = "add-tracker")]
+        add_tracker: Vec<String>,
+
+        #[arg(long = "remove-tracker")]
+        remove_tracker: Vec<String>,
+
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    //=== Force an announce to one specific tracker ===//
+    Reannounce {
+        torrent: PathBuf,
+
+        #[arg(short, long)]
+        tracker: String,
+
+        #[arg(short, long, default_value = "6881")]
+        port: u16,
+    },
+    //=== Print a magnet link for a torrent file ===//
+    Magnet {
+        torrent: PathBuf,
+    },
+    //=== Ask a magnet link's trackers for peers, without a torrent file ===//
+    MagnetPeers {
+        uri: String,
+
+        #[arg(short, long, default_value = "6881")]
+        port: u16,
     },
 }
 
@@ -61,8 +123,21 @@ async fn main() -> Result<()> {
             name,
             piece_size,
             comment,
+            created_by,
+            creation_date,
+            no_date,
+            encoding,
+            strict,
         } => {
-            create_torrent(files, output, name, piece_size, comment).await?;
+            let options = CreateOptions {
+                comment,
+                created_by,
+                creation_date,
+                no_date,
+                encoding,
+                strict_piece_length: strict,
+            };
+            create_torrent(files, output, name, piece_size, options).await?;
         }
         Commands::Info { torrent } => {
             show_torrent_info(torrent).await?;
@@ -73,24 +148,66 @@ async fn main() -> Result<()> {
         } => {
             download_torrent(torrent, output_dir).await?;
         }
-        Commands::Verify { torrent, data_dir } => {
-            verify_torrent(torrent, data_dir).await?;
+        Commands::Verify {
+            torrent,
+            data_dir,
+            json,
+        } => {
+            verify_torrent(torrent, data_dir, json).await?;
+        }
+        Commands::Seed {
+            torrent,
+            content,
+            port,
+        } => {
+            seed_torrent(torrent, content, port).await?;
+        }
+        Commands::Edit {
+            torrent,
+            add_tracker,
+            remove_tracker,
+            output,
+        } => {
+            edit_torrent(torrent, add_tracker, remove_tracker, output).await?;
+        }
+        Commands::Reannounce {
+            torrent,
+            tracker,
+            port,
+        } => {
+            reannounce_torrent(torrent, tracker, port).await?;
+        }
+        Commands::Magnet { torrent } => {
+            show_magnet(torrent).await?;
+        }
+        Commands::MagnetPeers { uri, port } => {
+            magnet_peers(uri, port).await?;
         }
     }
 
     Ok(())
 }
 
+//=== Config for commands that talk to trackers; tracker state is always kept between runs ===//
+fn cli_config() -> Result<Config> {
+    let mut config = Config::from_env()?;
+    if config.tracker_state_dir.is_none() {
+        config.tracker_state_dir = Some(config.download_path.join(".tracker-state"));
+    }
+    Ok(config)
+}
+
 async fn create_torrent(
     files: Vec<PathBuf>,
     output: PathBuf,
     name: String,
     piece_size: u32,
-    comment: Option<String>,
+    options: CreateOptions,
 ) -> Result<()> {
     println!("Creating torrent '{}'...", name);
 
-    let torrent_info = TorrentParser::create_torrent(files, piece_size, name, comment).await?;
+    let torrent_info =
+        TorrentParser::create_torrent_with_options(files, piece_size, name, &options).await?;
 
     TorrentParser::write_torrent_file(&torrent_info, &output).await?;
 
@@ -107,15 +224,24 @@ async fn create_torrent(
 async fn show_torrent_info(torrent: PathBuf) -> Result<()> {
     println!("Loading torrent file: {}", torrent.display());
 
-    let torrent_info = TorrentParser::parse_file(torrent).await?;
+    //=== Only the header is needed, so partial torrents can be inspected too ===//
+    let data = tokio::fs::read(&torrent).await?;
+    let torrent_info = TorrentParser::parse_metadata_only(&data)?;
 
     println!("\nTorrent Information:");
     println!("  Name: {}", torrent_info.name);
     println!("  Private: {}", torrent_info.private);
     println!("  Piece length: {} bytes", torrent_info.piece_length);
-    println!("  Number of pieces: {}", torrent_info.num_pieces());
+    match torrent_info.num_pieces {
+        Some(num_pieces) => println!("  Number of pieces: {}", num_pieces),
+        None => println!("  Number of pieces: unknown"),
+    }
     println!("  Total size: {} bytes", torrent_info.total_size());
 
+    for tracker in &torrent_info.trackers {
+        println!("  Tracker: {}", tracker);
+    }
+
     if let Some(comment) = &torrent_info.comment {
         println!("  Comment: {}", comment);
     }
@@ -128,6 +254,14 @@ async fn show_torrent_info(torrent: PathBuf) -> Result<()> {
         println!("  Creation date: {}", creation_date);
     }
 
+    if let Some(encoding) = &torrent_info.encoding {
+        println!("  Encoding: {}", encoding);
+    }
+
+    for node in &torrent_info.dht_nodes {
+        println!("  DHT node: {}", node);
+    }
+
     println!("\nFiles:");
     for (i, file) in torrent_info.files.iter().enumerate() {
         println!(
@@ -163,15 +297,17 @@ async fn download_torrent(torrent: PathBuf, output_dir: PathBuf) -> Result<()> {
     println!("Current completion: {:.2}%", completion);
 
     let file_progress = file_manager.file_progress();
-    for (file_path, progress) in file_progress {
-        println!("  {}: {:.2}%", file_path, progress);
+    for (file_info, progress) in torrent_info.files.iter().zip(file_progress) {
+        println!("  {}: {:.2}%", file_info.full_path().display(), progress);
     }
 
     Ok(())
 }
 
-async fn verify_torrent(torrent: PathBuf, data_dir: PathBuf) -> Result<()> {
-    println!("Verifying torrent data in: {}", data_dir.display());
+async fn verify_torrent(torrent: PathBuf, data_dir: PathBuf, json: bool) -> Result<()> {
+    if !json {
+        println!("Verifying torrent data in: {}", data_dir.display());
+    }
 
     let torrent_info = TorrentParser::parse_file(torrent).await?;
     let mut file_manager = FileManager::new(torrent_info.clone(), data_dir, 100);
@@ -179,16 +315,31 @@ async fn verify_torrent(torrent: PathBuf, data_dir: PathBuf) -> Result<()> {
     file_manager.initialize().await?;
     file_manager.scan_existing_files().await?;
 
-    println!("Scanning and verifying pieces...");
+    if !json {
+        println!("Scanning and verifying pieces...");
+    }
 
-    let failed_pieces = file_manager.verify_integrity().await?;
+    let report = file_manager.verify_integrity().await?;
 
-    if failed_pieces.is_empty() {
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+        return Ok(());
+    }
+
+    println!(
+        "Checked {} pieces, {} passed ({} bytes)",
+        report.checked, report.passed, report.bytes_verified
+    );
+    if report.is_clean() {
         println!("✓ All pieces verified successfully!");
     } else {
-        println!("✗ Found {} corrupted pieces:", failed_pieces.len());
-        for piece in failed_pieces {
-            println!("  Piece {}", piece);
+        println!("✗ Found {} corrupted pieces:", report.failed.len());
+        for (file_index, pieces) in &report.failed_by_file {
+            println!(
+                "  {}: pieces {:?}",
+                torrent_info.files[*file_index].full_path().display(),
+                pieces
+            );
         }
     }
 
@@ -197,3 +348,150 @@ async fn verify_torrent(torrent: PathBuf, data_dir: PathBuf) -> Result<()> {
 
     Ok(())
 }
+
+async fn seed_torrent(torrent: PathBuf, content: PathBuf, port: u16) -> Result<()> {
+    println!("Seeding from: {}", content.display());
+
+    let data = tokio::fs::read(&torrent).await?;
+    let (torrent_info, info_hash) = TorrentParser::parse_bytes_with_info_hash(&data)?;
+    let existing_paths = torrent_info
+        .files
+        .iter()
+        .map(|file| content.join(file.full_path()))
+        .collect();
+
+    let mut file_manager = FileManager::new(torrent_info.clone(), content.clone(), 100);
+    file_manager.open_for_seeding(existing_paths).await?;
+    println!(
+        "Verified {}/{} pieces ({:.2}%)",
+        file_manager.piece_manager().completed_pieces().len(),
+        torrent_info.num_pieces(),
+        file_manager.completion_percentage()
+    );
+
+    let config = Config {
+        listen_port: port,
+        ..Config::from_env()?
+    };
+    let mut network_manager = NetworkManager::new(config, generate_peer_id());
+    network_manager
+        .add_torrent_info(info_hash, torrent_info)
+        .await?;
+    network_manager
+        .add_file_manager(info_hash, Arc::new(RwLock::new(file_manager)))
+        .await?;
+    network_manager.spawn_choker();
+
+    println!("Listening for peers on port {}", port);
+    network_manager.start().await?;
+
+    //=== Serve until interrupted, then stop accepting before exiting ===//
+    tokio::signal::ctrl_c().await?;
+    println!("Stopping seeding");
+    network_manager.stop().await?;
+
+    Ok(())
+}
+
+async fn edit_torrent(
+    torrent: PathBuf,
+    add_tracker: Vec<String>,
+    remove_tracker: Vec<String>,
+    output: PathBuf,
+) -> Result<()> {
+    let data = tokio::fs::read(&torrent).await?;
+    let edited = TorrentParser::edit_trackers(&data, &add_tracker, &remove_tracker)?;
+    tokio::fs::write(&output, &edited).await?;
+
+    let metadata = TorrentParser::parse_metadata_only(&edited)?;
+    println!("Wrote {}", output.display());
+    for tracker in &metadata.trackers {
+        println!("  Tracker: {}", tracker);
+    }
+
+    Ok(())
+}
+
+async fn reannounce_torrent(torrent: PathBuf, tracker: String, port: u16) -> Result<()> {
+    println!("Re-announcing to tracker: {}", tracker);
+
+    let data = tokio::fs::read(&torrent).await?;
+    let (torrent_info, info_hash) = TorrentParser::parse_bytes_with_info_hash(&data)?;
+    let statistics = Statistics::new(torrent_info.total_size());
+
+    //=== Each run starts afresh, so the anti-hammer floor comes from the saved state ===//
+    let config = cli_config()?;
+    let state_file = config.tracker_state_file(&info_hash);
+    let mut tracker_manager = TrackerManager::new(config, vec![tracker.clone()]);
+    if let Some(path) = &state_file {
+        tracker_manager.load_state(path).await;
+    }
+    let announced = tracker_manager
+        .announce_one(
+            &tracker,
+            info_hash,
+            generate_peer_id(),
+            port,
+            &statistics,
+            TrackerEvent::None,
+        )
+        .await;
+    if let Some(path) = &state_file {
+        tracker_manager.save_state(path).await?;
+    }
+    let peers = announced?;
+
+    println!("Tracker returned {} peers", peers.len());
+    for peer in peers {
+        println!("  {}:{}", peer.ip, peer.port);
+    }
+
+    Ok(())
+}
+
+async fn show_magnet(torrent: PathBuf) -> Result<()> {
+    let data = tokio::fs::read(&torrent).await?;
+    let torrent_info = TorrentParser::parse_bytes(&data)?;
+    let trackers = TorrentParser::parse_metadata_only(&data)?.trackers;
+
+    println!("{}", torrent_info.to_magnet(&trackers));
+    Ok(())
+}
+
+async fn magnet_peers(uri: String, port: u16) -> Result<()> {
+    let magnet = MagnetLink::parse(&uri)?;
+    println!("Magnet: {}", magnet.name().unwrap_or("(no name)"));
+    println!("  Info hash: {}", hex::encode(magnet.info_hash()));
+    if magnet.trackers().is_empty() {
+        println!("  No trackers in the link");
+        return Ok(());
+    }
+
+    //=== The size is unknown until the metadata arrives; anything left keeps us a leecher ===//
+    let statistics = Statistics::new(1);
+    let config = cli_config()?;
+    let state_file = config.tracker_state_file(&magnet.info_hash());
+    let mut tracker_manager = TrackerManager::new(config, magnet.trackers());
+    if let Some(path) = &state_file {
+        tracker_manager.load_state(path).await;
+    }
+    let peers = tracker_manager
+        .announce_all(
+            magnet.info_hash(),
+            generate_peer_id(),
+            port,
+            &statistics,
+            TrackerEvent::Started,
+        )
+        .await?;
+    if let Some(path) = &state_file {
+        tracker_manager.save_state(path).await?;
+    }
+
+    println!("Trackers returned {} peers", peers.len());
+    for peer in peers {
+        println!("  {}:{}", peer.ip, peer.port);
+    }
+
+    Ok(())
+}
@@ -30,6 +30,15 @@ async fn main() -> Result<()> {
     // Test 5: Tracker Communication (simulated)
     test_tracker_communication().await?;
     
+    // Test 6: Network Integration
+    test_network_integration().await?;
+    
+    // Test 7: Message Throughput
+    test_message_throughput().await?;
+    
+    // Test 8: Error Handling
+    test_error_handling().await?;
+    
     info!("All network tests completed successfully!");
     Ok(())
 }
@@ -38,7 +47,7 @@ async fn test_network_manager_creation() -> Result<()> {
     info!("Testing network manager creation...");
     
     let config = Config::default();
-    let network_manager = NetworkManager::new(config);
+    let network_manager = NetworkManager::new(config, generate_peer_id());
     
     assert_eq!(network_manager.config().listen_port, 6881);
     assert_eq!(network_manager.config().max_connections, 50);
@@ -218,7 +227,7 @@ async fn test_network_integration() -> Result<()> {
     
     // Create network manager
     let config = Config::default();
-    let mut network_manager = NetworkManager::new(config);
+    let network_manager = NetworkManager::new(config, generate_peer_id());
     
     // Add torrent info
     let info_hash = [1u8; 20];
@@ -232,7 +241,10 @@ async fn test_network_integration() -> Result<()> {
     network_manager.add_torrent_info(info_hash, torrent_info).await?;
     
     // Create peer manager
-    let peer_manager = network_manager.peer_manager();
+    let peer_manager = network_manager
+        .peer_manager(&info_hash)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("No peer manager for torrent"))?;
     let peer_manager_guard = peer_manager.read().await;
             assert_eq!(peer_manager_guard.connected_peer_count(), 0);
     drop(peer_manager_guard);
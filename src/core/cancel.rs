@@ -0,0 +1,85 @@
+//=== Cooperative cancellation for long-running async work ===//
+
+use crate::core::{Result, TorrentError};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+
+//=== Shared flag; every clone sees a cancel made through any other ===//
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    inner: Arc<Inner>,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    cancelled: AtomicBool,
+    notify: Notify,
+}
+
+impl CancellationToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //=== Idempotent; wakes everything waiting in `cancelled` ===//
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        self.inner.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    //=== Resolves once the token is cancelled, immediately if it already is ===//
+    pub async fn cancelled(&self) {
+        loop {
+            let notified = self.inner.notify.notified();
+            if self.is_cancelled() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    //=== For checkpoints between units of work: Err(Cancelled) once cancelled ===//
+    pub fn check(&self) -> Result<()> {
+        if self.is_cancelled() {
+            Err(TorrentError::Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn test_cancel_wakes_waiters_and_sticks() {
+        let token = CancellationToken::new();
+        assert!(token.check().is_ok());
+
+        let waiter = tokio::spawn({
+            let token = token.clone();
+            async move { token.cancelled().await }
+        });
+        tokio::task::yield_now().await;
+        token.clone().cancel();
+
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .expect("waiter should wake on cancel")
+            .unwrap();
+        assert!(token.is_cancelled());
+        assert!(matches!(token.check(), Err(TorrentError::Cancelled)));
+
+        //=== Waiting after the fact returns straight away ===//
+        tokio::time::timeout(Duration::from_millis(100), token.cancelled())
+            .await
+            .unwrap();
+    }
+}
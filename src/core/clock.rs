@@ -0,0 +1,83 @@
+//=== Time source abstraction for deterministic time-based logic ===//
+
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+//=== Real monotonic time, used everywhere outside of tests ===//
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub fn system_clock() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+//=== Manually advanced clock for tests ===//
+#[derive(Debug, Clone)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    pub fn new() -> Self {
+        Self {
+            now: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+
+    pub fn advance(&self, duration: Duration) {
+        let mut now = self.now.lock().unwrap();
+        *now += duration;
+    }
+
+    pub fn set(&self, instant: Instant) {
+        *self.now.lock().unwrap() = instant;
+    }
+
+    pub fn shared(&self) -> SharedClock {
+        Arc::new(self.clone())
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mock_clock_advances() {
+        let clock = MockClock::new();
+        let start = clock.now();
+
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now() - start, Duration::from_secs(5));
+
+        //=== Clones share the same time ===//
+        let shared = clock.shared();
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(shared.now() - start, Duration::from_secs(6));
+    }
+}
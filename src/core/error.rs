@@ -24,6 +24,9 @@ pub enum TorrentError {
 
     #[error("Serialization error: {0}")]
     Serialization(#[from] serde_json::Error),
+
+    #[error("Operation cancelled")]
+    Cancelled,
 }
 
 #[derive(Error, Debug)]
@@ -45,6 +48,12 @@ pub enum NetworkError {
 
     #[error("Network bind failed")]
     BindFailed,
+
+    #[error("Tracker sent neither JSON nor bencode: {snippet:?}")]
+    UnparseableTrackerBody { snippet: String },
+
+    #[error("Tracker redirected to {location}, and redirects are disabled")]
+    TrackerRedirect { location: String },
 }
 
 #[derive(Error, Debug)]
@@ -66,6 +75,12 @@ pub enum FileError {
 
     #[error("Piece verification failed")]
     PieceVerificationFailed,
+
+    #[error("Range of {length} bytes at {offset} lies outside the torrent")]
+    OutOfRange { offset: u64, length: u64 },
+
+    #[error("Pieces not yet verified: {pieces:?}")]
+    PiecesPending { pieces: Vec<u32> },
 }
 
 #[derive(Error, Debug)]
@@ -87,6 +102,12 @@ pub enum ProtocolError {
 
     #[error("Invalid block request")]
     InvalidBlockRequest,
+
+    #[error("Frame length mismatch: header says {declared} bytes, got {actual}")]
+    FrameLengthMismatch { declared: usize, actual: usize },
+
+    #[error("Malformed payload of {length} bytes for message type {message_type}")]
+    InvalidPayload { message_type: u8, length: usize },
 }
 
 #[derive(Error, Debug)]
@@ -108,6 +129,15 @@ pub enum PeerError {
 
     #[error("Peer timeout")]
     Timeout,
+
+    #[error("Peer is banned")]
+    Banned,
+
+    #[error("Peer sent {requests} requests within a second")]
+    RequestFlood { requests: u32 },
+
+    #[error("Peer has {queued} requests waiting to be served")]
+    UploadQueueFull { queued: usize },
 }
 
 #[derive(Error, Debug)]
@@ -126,6 +156,12 @@ pub enum ValidationError {
 
     #[error("Invalid configuration: {message}")]
     InvalidConfig { message: String },
+
+    #[error("Inconsistent torrent: {reason}")]
+    InconsistentTorrent { reason: String },
+
+    #[error("Invalid magnet link: {reason}")]
+    InvalidMagnetLink { reason: String },
 }
 
 pub type Result<T> = std::result::Result<T, TorrentError>;
@@ -1,7 +1,11 @@
 //=== Core types and error handling ===//
 
+pub mod cancel;
+pub mod clock;
 pub mod error;
 pub mod types;
 
+pub use cancel::*;
+pub use clock::*;
 pub use error::*;
 pub use types::*;
@@ -1,8 +1,12 @@
 //=== All Core types and data structures ===//
 
+use crate::core::{Result, TorrentError, ValidationError};
 use bitvec::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use std::collections::HashSet;
+use std::net::{SocketAddrV4, SocketAddrV6};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::time::{Duration, Instant};
 
 pub type Hash = [u8; 20];
@@ -15,6 +19,19 @@ pub type BlockOffset = u32;
 
 pub type BlockLength = u32;
 
+//=== Azureus-style client prefix for our peer IDs ===//
+pub const PEER_ID_PREFIX: &[u8; 8] = b"-FS0001-";
+
+//=== Generate a random peer ID; our client prefix keeps it from ever being all zeros ===//
+pub fn generate_peer_id() -> PeerId {
+    use rand::Rng;
+
+    let mut peer_id = [0u8; 20];
+    peer_id[..8].copy_from_slice(PEER_ID_PREFIX);
+    rand::thread_rng().fill(&mut peer_id[8..]);
+    peer_id
+}
+
 // === Configuration for the  system ===//
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
@@ -22,19 +39,91 @@ pub struct Config {
     pub listen_port: u16,
     pub max_connections: usize,
     pub connection_timeout: Duration,
+    // Idle connections get a keep-alive once quiet for this long //
+    pub keep_alive_interval: Duration,
+    // How often the connection pool's reaper scans for idle connections //
+    pub reaper_interval: Duration,
+    // First wait before redialing a failed address; doubles with each failure //
+    pub dial_retry_backoff: Duration,
+    // Failed addresses are forgotten after this long //
+    pub dial_failure_cooldown: Duration,
+    // Drop and redial every peer when our external address changes //
+    pub reconnect_on_network_change: bool,
+    // Socket buffer sizes in bytes; None keeps the OS defaults //
+    pub socket_send_buf: Option<u32>,
+    pub socket_recv_buf: Option<u32>,
+    // Disable Nagle's algorithm so small messages like requests go out at once //
+    pub tcp_nodelay: bool,
+    // Remember peer reputation by IP across sessions; None keeps nothing on disk //
+    pub peer_reputation_file: Option<PathBuf>,
+    // Keep each torrent's tracker announce state across restarts; None keeps nothing on disk //
+    pub tracker_state_dir: Option<PathBuf>,
 
     /// File settings //
     pub download_path: PathBuf,
     pub piece_cache_size: usize,
+    // Soft cap on cached pieces plus in-flight buffers; nearing it evicts and shallows pipelines //
+    pub max_memory_bytes: Option<usize>,
 
     /// Choking settings //
     pub upload_limit: Option<u64>,
     pub download_limit: Option<u64>,
     pub unchoke_interval: Duration,
+    pub max_unchoked: usize,
+    // Derive the unchoke slot count from `upload_limit` instead of `max_unchoked` //
+    pub auto_unchoke_slots: bool,
+    // Once we have everything, connections to other seeds are wasted slots //
+    pub disconnect_seeds_when_seeding: bool,
+    // Bytes each peer may take from us before it is choked for good //
+    pub peer_upload_quota: Option<u64>,
+    // Bytes we take from one peer before asking others instead //
+    pub peer_download_quota: Option<u64>,
+    // Requests from one peer waiting to be served; a peer queueing more is flooding us //
+    pub max_queued_uploads_per_peer: usize,
+    // Requests one peer may send per second before it is disconnected //
+    pub max_peer_request_rate: u32,
 
     /// Tracker settings //
+    // Cap on one HTTP or WebSocket tracker request; UDP follows the BEP 15 schedule //
     pub tracker_timeout: Duration,
     pub announce_interval: Duration,
+    // First wait before retrying a failed tracker; doubles up to `announce_interval` //
+    pub tracker_retry_backoff: Duration,
+    // First wait for a UDP tracker reply; doubled on each of up to 8 retransmits (BEP 15) //
+    pub udp_retransmit_base: Duration,
+    // Where we are reachable per address family, announced per BEP 7 //
+    pub announce_ipv4: Option<SocketAddrV4>,
+    pub announce_ipv6: Option<SocketAddrV6>,
+    // Follow HTTP redirects from trackers; when off a redirect fails the announce //
+    pub follow_tracker_redirects: bool,
+    // Each announce interval is stretched or shrunk by up to this fraction, at random //
+    pub announce_jitter: f64,
+
+    /// Request settings //
+    // In-flight requests older than this count as timed out //
+    pub request_timeout: Duration,
+    // Endgame starts once this few pieces are missing and all are in flight //
+    pub endgame_threshold: usize,
+    // Largest share of in-flight blocks one peer may hold, so losing it costs little //
+    pub max_blocks_per_peer_fraction: f64,
+
+    /// Statistics settings //
+    // How often transfer rates are recomputed and sampled //
+    pub stats_interval: Duration,
+    // How far back the rate history reaches //
+    pub rate_history_retention: Duration,
+
+    /// Protocol settings //
+    // Replaces the computed handshake reserved bytes, for interop testing //
+    pub reserved_override: Option<[u8; 8]>,
+    // Advertise the fast extension: rejected requests and allowed-fast pieces //
+    pub fast_extension: bool,
+    // Pieces a choked fast-extension peer may still request from us //
+    pub allowed_fast_count: usize,
+    // Refuse handshakes carrying an all-zero peer id; legal, but it collides across peers //
+    pub reject_zero_peer_id: bool,
+    // UDP port of our DHT node; when set we advertise DHT and send it in Port messages //
+    pub dht_port: Option<u16>,
 }
 
 impl Default for Config {
@@ -43,19 +132,191 @@ impl Default for Config {
             listen_port: 6881,
             max_connections: 50,
             connection_timeout: Duration::from_secs(30),
+            keep_alive_interval: Duration::from_secs(20),
+            reaper_interval: Duration::from_secs(5),
+            dial_retry_backoff: Duration::from_secs(30),
+            dial_failure_cooldown: Duration::from_secs(600),
+            reconnect_on_network_change: true,
+            socket_send_buf: None,
+            socket_recv_buf: None,
+            tcp_nodelay: true,
+            peer_reputation_file: None,
+            tracker_state_dir: None,
             download_path: PathBuf::from("./downloads"),
             piece_cache_size: 100,
+            max_memory_bytes: None,
             upload_limit: None,
             download_limit: None,
             unchoke_interval: Duration::from_secs(10),
+            max_unchoked: 4,
+            auto_unchoke_slots: false,
+            disconnect_seeds_when_seeding: true,
+            peer_upload_quota: None,
+            peer_download_quota: None,
+            max_queued_uploads_per_peer: 256,
+            max_peer_request_rate: 500,
             tracker_timeout: Duration::from_secs(30),
             announce_interval: Duration::from_secs(1800),
+            tracker_retry_backoff: Duration::from_secs(15),
+            udp_retransmit_base: Duration::from_secs(15),
+            announce_ipv4: None,
+            announce_ipv6: None,
+            follow_tracker_redirects: true,
+            announce_jitter: 0.05,
+            request_timeout: Duration::from_secs(60),
+            endgame_threshold: 2,
+            max_blocks_per_peer_fraction: 1.0,
+            stats_interval: Duration::from_secs(1),
+            rate_history_retention: Duration::from_secs(300),
+            reserved_override: None,
+            fast_extension: false,
+            allowed_fast_count: 10,
+            reject_zero_peer_id: false,
+            dht_port: None,
         }
     }
 }
 
+//=== Upload bandwidth we aim to give each unchoked peer when sizing slots automatically ===//
+pub const UPLOAD_PER_UNCHOKE_SLOT: u64 = 16 * 1024;
+pub const MIN_AUTO_UNCHOKE_SLOTS: usize = 2;
+pub const MAX_AUTO_UNCHOKE_SLOTS: usize = 50;
+
+//=== Allowed-fast pieces never exceed this fraction (1/n) of the torrent ===//
+pub const MAX_ALLOWED_FAST_SHARE: usize = 4;
+
+impl Config {
+    //=== Size of the allowed-fast set for a torrent, kept small for small torrents ===//
+    pub fn allowed_fast_slots(&self, num_pieces: usize) -> usize {
+        self.allowed_fast_count
+            .min(num_pieces / MAX_ALLOWED_FAST_SHARE)
+    }
+
+    //=== Where a torrent's tracker state is kept, if the config keeps it at all ===//
+    pub fn tracker_state_file(&self, info_hash: &Hash) -> Option<PathBuf> {
+        self.tracker_state_dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{}.tracker", hex::encode(info_hash))))
+    }
+
+    //=== Number of peers to keep unchoked, including the optimistic slot ===//
+    pub fn unchoke_slots(&self) -> usize {
+        match (self.auto_unchoke_slots, self.upload_limit) {
+            (true, Some(limit)) => ((limit / UPLOAD_PER_UNCHOKE_SLOT) as usize)
+                .clamp(MIN_AUTO_UNCHOKE_SLOTS, MAX_AUTO_UNCHOKE_SLOTS),
+            _ => self.max_unchoked,
+        }
+    }
+}
+
+//=== Names a JSON config file that `Config::from_env` reads before the other variables ===//
+pub const CONFIG_FILE_ENV: &str = "FSS_CONFIG_FILE";
+
+impl Config {
+    //=== Defaults, then the file named by `FSS_CONFIG_FILE`, then `FSS_*` variables ===//
+    pub fn from_env() -> Result<Self> {
+        let config = match std::env::var_os(CONFIG_FILE_ENV) {
+            Some(path) => Self::from_file(Path::new(&path))?,
+            None => Self::default(),
+        };
+        config.with_vars(|key| std::env::var(key).ok())
+    }
+
+    //=== A JSON config file; keys it leaves out keep their defaults ===//
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let invalid =
+            |message: String| TorrentError::Validation(ValidationError::InvalidConfig { message });
+        let data = std::fs::read(path)?;
+        let overrides: serde_json::Value = serde_json::from_slice(&data)
+            .map_err(|e| invalid(format!("{}: {}", path.display(), e)))?;
+        let serde_json::Value::Object(overrides) = overrides else {
+            return Err(invalid(format!("{}: not a JSON object", path.display())));
+        };
+
+        let mut config = serde_json::to_value(Self::default())?;
+        if let serde_json::Value::Object(fields) = &mut config {
+            fields.extend(overrides);
+        }
+        serde_json::from_value(config).map_err(|e| invalid(format!("{}: {}", path.display(), e)))
+    }
+
+    //=== Layer `FSS_*` variables from `var` over this config; durations are in seconds ===//
+    pub fn with_vars(mut self, var: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        read(&var, "FSS_LISTEN_PORT", &mut self.listen_port)?;
+        read(&var, "FSS_MAX_CONNECTIONS", &mut self.max_connections)?;
+        read_seconds(&var, "FSS_CONNECTION_TIMEOUT", &mut self.connection_timeout)?;
+        read_seconds(
+            &var,
+            "FSS_KEEP_ALIVE_INTERVAL",
+            &mut self.keep_alive_interval,
+        )?;
+        read(&var, "FSS_TCP_NODELAY", &mut self.tcp_nodelay)?;
+        read_optional(
+            &var,
+            "FSS_PEER_REPUTATION_FILE",
+            &mut self.peer_reputation_file,
+        )?;
+        read_optional(&var, "FSS_TRACKER_STATE_DIR", &mut self.tracker_state_dir)?;
+        read(&var, "FSS_DOWNLOAD_PATH", &mut self.download_path)?;
+        read(&var, "FSS_PIECE_CACHE_SIZE", &mut self.piece_cache_size)?;
+        read_optional(&var, "FSS_MAX_MEMORY_BYTES", &mut self.max_memory_bytes)?;
+        read_optional(&var, "FSS_UPLOAD_LIMIT", &mut self.upload_limit)?;
+        read_optional(&var, "FSS_DOWNLOAD_LIMIT", &mut self.download_limit)?;
+        read_seconds(&var, "FSS_UNCHOKE_INTERVAL", &mut self.unchoke_interval)?;
+        read(&var, "FSS_MAX_UNCHOKED", &mut self.max_unchoked)?;
+        read_seconds(&var, "FSS_TRACKER_TIMEOUT", &mut self.tracker_timeout)?;
+        read_seconds(&var, "FSS_ANNOUNCE_INTERVAL", &mut self.announce_interval)?;
+        read_seconds(&var, "FSS_REQUEST_TIMEOUT", &mut self.request_timeout)?;
+        read(&var, "FSS_FAST_EXTENSION", &mut self.fast_extension)?;
+        read_optional(&var, "FSS_DHT_PORT", &mut self.dht_port)?;
+        Ok(self)
+    }
+}
+
+fn read<T: FromStr>(var: &impl Fn(&str) -> Option<String>, key: &str, field: &mut T) -> Result<()> {
+    if let Some(value) = var(key) {
+        *field = parse(key, &value)?;
+    }
+    Ok(())
+}
+
+fn read_seconds(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    field: &mut Duration,
+) -> Result<()> {
+    if let Some(value) = var(key) {
+        *field = Duration::from_secs(parse(key, &value)?);
+    }
+    Ok(())
+}
+
+//=== Optional settings are cleared by an empty value or "none" ===//
+fn read_optional<T: FromStr>(
+    var: &impl Fn(&str) -> Option<String>,
+    key: &str,
+    field: &mut Option<T>,
+) -> Result<()> {
+    match var(key) {
+        Some(value) if value.trim().is_empty() || value.trim().eq_ignore_ascii_case("none") => {
+            *field = None
+        }
+        Some(value) => *field = Some(parse(key, &value)?),
+        None => {}
+    }
+    Ok(())
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T> {
+    value.trim().parse().map_err(|_| {
+        TorrentError::Validation(ValidationError::InvalidConfig {
+            message: format!("{} is set to {:?}, which is not a valid value", key, value),
+        })
+    })
+}
+
 /// Information about a single file in a torrent //
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct FileInfo {
     // Path (directory structure) //
     pub path: Vec<String>,
@@ -63,6 +324,8 @@ pub struct FileInfo {
     pub length: u64,
     // Optional MD5 hash for verification //
     pub md5sum: Option<String>,
+    // Optional SHA-1 of the whole file (BEP 47), which lets identical files be found //
+    pub sha1: Option<Hash>,
 }
 
 impl FileInfo {
@@ -71,6 +334,7 @@ impl FileInfo {
             path,
             length,
             md5sum: None,
+            sha1: None,
         }
     }
 
@@ -83,8 +347,24 @@ impl FileInfo {
     }
 }
 
+//=== How a web seed expects its URLs to be built ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WebSeedStyle {
+    // BEP 19 `url-list`: plain file URLs read with Range requests //
+    UrlList,
+    // BEP 17 `httpseeds`: a script taking the info hash, a piece and byte ranges //
+    HttpSeed,
+}
+
+//=== An HTTP server holding the torrent's content ===//
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebSeed {
+    pub url: String,
+    pub style: WebSeedStyle,
+}
+
 //=== Complete torrent metadata ===//
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TorrentInfo {
     pub name: String,
 
@@ -101,6 +381,21 @@ pub struct TorrentInfo {
     pub creation_date: Option<u64>,
 
     pub created_by: Option<String>,
+
+    // Character set of the text fields, e.g. `UTF-8` //
+    #[serde(default)]
+    pub encoding: Option<String>,
+
+    #[serde(default)]
+    pub web_seeds: Vec<WebSeed>,
+
+    // Trackerless torrents bootstrap the DHT from these //
+    #[serde(default)]
+    pub dht_nodes: Vec<std::net::SocketAddr>,
+
+    // SHA-1 of the info dict exactly as parsed, unknown keys and all; None if built in memory //
+    #[serde(default)]
+    pub info_hash: Option<Hash>,
 }
 
 impl TorrentInfo {
@@ -114,6 +409,10 @@ impl TorrentInfo {
             comment: None,
             creation_date: None,
             created_by: None,
+            encoding: None,
+            web_seeds: Vec::new(),
+            dht_nodes: Vec::new(),
+            info_hash: None,
         }
     }
 
@@ -129,20 +428,68 @@ impl TorrentInfo {
 
     //=== Get the size of a specific piece ===//
     pub fn piece_size(&self, piece_index: PieceIndex) -> u32 {
-        let total_size = self.total_size();
-        let piece_index = piece_index as u64;
-        let piece_length = self.piece_length as u64;
+        Self::piece_size_of(self.total_size(), self.piece_length, piece_index)
+    }
 
-        if piece_index < (self.num_pieces() as u64 - 1) {
-            self.piece_length
-        } else {
-            (total_size - piece_index * piece_length) as u32
-        }
+    //=== The one place piece sizes are worked out: full pieces, then a short last one ===//
+    pub fn piece_size_of(total_size: u64, piece_length: u32, piece_index: PieceIndex) -> u32 {
+        let start = piece_index as u64 * piece_length as u64;
+        total_size.saturating_sub(start).min(piece_length as u64) as u32
+    }
+
+    //=== Offset of a piece's first byte within the torrent's content ===//
+    pub fn piece_offset(&self, piece_index: PieceIndex) -> u64 {
+        piece_index as u64 * self.piece_length as u64
     }
 
     pub fn is_valid_piece_index(&self, piece_index: PieceIndex) -> bool {
         (piece_index as usize) < self.num_pieces()
     }
+
+    //=== Byte offsets of the torrent's content that a piece covers ===//
+    pub fn byte_range_for_piece(&self, piece_index: PieceIndex) -> std::ops::Range<u64> {
+        let start = self.piece_offset(piece_index);
+        start..start + self.piece_size(piece_index) as u64
+    }
+
+    //=== Pieces holding any of the `length` bytes starting at `offset` ===//
+    pub fn pieces_in_range(&self, offset: u64, length: u64) -> std::ops::Range<PieceIndex> {
+        if length == 0 || self.piece_length == 0 {
+            return 0..0;
+        }
+
+        let piece_length = self.piece_length as u64;
+        let first = offset / piece_length;
+        let last = (offset + length - 1) / piece_length;
+        first as PieceIndex..(last + 1).min(self.num_pieces() as u64) as PieceIndex
+    }
+}
+
+//=== Standard request size; the last block of a piece may be shorter ===//
+pub const BLOCK_SIZE: u32 = 16 * 1024;
+
+//=== A single block we have asked a peer for ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BlockRequest {
+    pub peer_id: PeerId,
+    pub piece_index: PieceIndex,
+    pub offset: u32,
+    pub length: u32,
+}
+
+impl BlockRequest {
+    //=== Split a piece of the given size into block requests ===//
+    pub fn for_piece(peer_id: PeerId, piece_index: PieceIndex, piece_size: u32) -> Vec<Self> {
+        (0..piece_size)
+            .step_by(BLOCK_SIZE as usize)
+            .map(|offset| Self {
+                peer_id,
+                piece_index,
+                offset,
+                length: BLOCK_SIZE.min(piece_size - offset),
+            })
+            .collect()
+    }
 }
 
 //== Represents a single piece of a file ===//
@@ -195,7 +542,7 @@ impl Piece {
 }
 
 //=== Bitfield for tracking piece availability ===//
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Bitfield {
     bits: BitVec,
     num_pieces: usize,
@@ -209,6 +556,33 @@ impl Bitfield {
         }
     }
 
+    //=== Every piece set, as a seed's bitfield ===//
+    pub fn full(num_pieces: usize) -> Self {
+        Self {
+            bits: bitvec![1; num_pieces],
+            num_pieces,
+        }
+    }
+
+    //=== Only the given pieces set; indices past the end are ignored ===//
+    pub fn from_indices(num_pieces: usize, pieces: &[PieceIndex]) -> Self {
+        let mut bitfield = Self::new(num_pieces);
+        for &piece_index in pieces {
+            bitfield.set_piece(piece_index);
+        }
+        bitfield
+    }
+
+    //=== `fraction` of the pieces set, rounded, chosen at random from `rng` ===//
+    pub fn random<R: rand::Rng + ?Sized>(num_pieces: usize, fraction: f64, rng: &mut R) -> Self {
+        let count = (num_pieces as f64 * fraction.clamp(0.0, 1.0)).round() as usize;
+        let mut bitfield = Self::new(num_pieces);
+        for piece_index in rand::seq::index::sample(rng, num_pieces, count) {
+            bitfield.set_piece(piece_index as PieceIndex);
+        }
+        bitfield
+    }
+
     //=== Create a bitfield from raw bytes ===//
     pub fn from_bytes(bytes: &[u8], num_pieces: usize) -> Self {
         let mut bits: BitVec = BitVec::new();
@@ -227,22 +601,18 @@ impl Bitfield {
         Self { bits, num_pieces }
     }
 
-    //=== Convert to raw bytes ===//
+    //=== Convert to raw bytes; spare bits past the last piece are always zero ===//
     pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::new();
-        let mut current_byte = 0u8;
-
-        for (i, bit) in self.bits.iter().enumerate() {
-            if *bit {
-                current_byte |= 1 << (7 - (i % 8));
-            }
-
-            if (i + 1) % 8 == 0 || i == self.bits.len() - 1 {
-                bytes.push(current_byte);
-                current_byte = 0;
-            }
+        let mut bytes = vec![0u8; self.num_pieces.div_ceil(8)];
+        for i in self.bits.iter_ones().take_while(|&i| i < self.num_pieces) {
+            bytes[i / 8] |= 1 << (7 - (i % 8));
         }
 
+        //=== Peers may drop us for a set spare bit, so mask them whatever `bits` holds ===//
+        let spare = bytes.len() * 8 - self.num_pieces;
+        if let Some(last) = bytes.last_mut() {
+            *last &= 0xffu8 << spare;
+        }
         bytes
     }
 
@@ -304,15 +674,21 @@ impl Bitfield {
 //=== Statistics for tracking download/upload progress ===//
 #[derive(Debug, Clone, Default)]
 pub struct Statistics {
-    pub downloaded: u64,
-    pub uploaded: u64,
+    // Bytes transferred on the wire, corrupt pieces included; reported to trackers //
+    pub wire_downloaded: u64,
+    pub wire_uploaded: u64,
+    // Bytes of pieces that passed verification; drives `left` and completion //
+    pub verified_downloaded: u64,
     pub left: u64,
     pub corrupt: u64,
+    // Blocks that arrived for pieces we already had //
+    pub wasted: u64,
     pub download_rate: u64,
     pub upload_rate: u64,
     pub num_peers: usize,
     pub num_seeds: usize,
     pub num_leechers: usize,
+    verified_pieces: HashSet<PieceIndex>,
 }
 
 impl Statistics {
@@ -323,20 +699,253 @@ impl Statistics {
         }
     }
 
+    //=== Count bytes received off the wire ===//
     pub fn update_downloaded(&mut self, bytes: u64) {
-        self.downloaded += bytes;
-        self.left = self.left.saturating_sub(bytes);
+        self.wire_downloaded += bytes;
+    }
+
+    //=== Count a piece toward progress the first time it verifies ===//
+    pub fn piece_verified(&mut self, piece_index: PieceIndex, bytes: u64) {
+        if self.verified_pieces.insert(piece_index) {
+            self.verified_downloaded += bytes;
+            self.left = self.left.saturating_sub(bytes);
+        }
+    }
+
+    pub fn piece_failed(&mut self, bytes: u64) {
+        self.corrupt += bytes;
+    }
+
+    pub fn block_wasted(&mut self, bytes: u64) {
+        self.wasted += bytes;
     }
 
     pub fn update_uploaded(&mut self, bytes: u64) {
-        self.uploaded += bytes;
+        self.wire_uploaded += bytes;
+    }
+
+    //=== Distinct pieces that have passed verification ===//
+    pub fn pieces_verified(&self) -> usize {
+        self.verified_pieces.len()
     }
 
     pub fn completion_percentage(&self) -> f64 {
-        let total = self.downloaded + self.left;
+        let total = self.verified_downloaded + self.left;
         if total == 0 {
             return 100.0;
         }
-        (self.downloaded as f64 / total as f64) * 100.0
+        (self.verified_downloaded as f64 / total as f64) * 100.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unchoke_slots_follow_upload_limit() {
+        let mut config = Config {
+            max_unchoked: 6,
+            upload_limit: Some(160 * 1024),
+            ..Config::default()
+        };
+        assert_eq!(config.unchoke_slots(), 6);
+
+        config.auto_unchoke_slots = true;
+        assert_eq!(config.unchoke_slots(), 10);
+
+        config.upload_limit = Some(1024);
+        assert_eq!(config.unchoke_slots(), MIN_AUTO_UNCHOKE_SLOTS);
+
+        //=== Unlimited upload falls back to the fixed count ===//
+        config.upload_limit = None;
+        assert_eq!(config.unchoke_slots(), 6);
+    }
+
+    #[test]
+    fn test_redownloaded_corrupt_piece_counts_once() {
+        let mut stats = Statistics::new(2 * 1024);
+
+        //=== Piece 0 arrives corrupt, then again intact ===//
+        stats.update_downloaded(1024);
+        stats.piece_failed(1024);
+        stats.update_downloaded(1024);
+        stats.piece_verified(0, 1024);
+
+        //=== A duplicate verification of the same piece changes nothing ===//
+        stats.piece_verified(0, 1024);
+
+        assert_eq!(stats.wire_downloaded, 2048);
+        assert_eq!(stats.verified_downloaded, 1024);
+        assert_eq!(stats.left, 1024);
+        assert_eq!(stats.completion_percentage(), 50.0);
+
+        stats.update_downloaded(1024);
+        stats.piece_verified(1, 1024);
+        assert_eq!(stats.left, 0);
+        assert_eq!(stats.completion_percentage(), 100.0);
+    }
+
+    #[test]
+    fn test_full_bitfield_round_trips() {
+        let full = Bitfield::full(10);
+        assert!(full.is_complete());
+        assert_eq!(full.to_bytes(), vec![0xff, 0xc0]);
+
+        let parsed = Bitfield::from_bytes(&full.to_bytes(), 10);
+        assert!(parsed.is_complete());
+        assert_eq!(parsed.available_pieces(), full.available_pieces());
+    }
+
+    #[test]
+    fn test_bitfield_from_indices() {
+        let bitfield = Bitfield::from_indices(12, &[0, 3, 11, 3, 40]);
+        assert_eq!(bitfield.available_pieces(), vec![0, 3, 11]);
+        assert_eq!(bitfield.to_bytes(), vec![0x90, 0x10]);
+        assert!(!bitfield.is_complete());
+    }
+
+    #[test]
+    fn test_random_bitfield_sets_requested_fraction() {
+        use rand::SeedableRng;
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let bitfield = Bitfield::random(100, 0.25, &mut rng);
+        assert_eq!(bitfield.count_pieces(), 25);
+        assert_eq!(bitfield.total_pieces(), 100);
+
+        //=== Same seed, same pieces ===//
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        assert_eq!(
+            Bitfield::random(100, 0.25, &mut rng).available_pieces(),
+            bitfield.available_pieces()
+        );
+
+        assert!(Bitfield::random(8, 1.5, &mut rng).is_complete());
+        assert_eq!(Bitfield::random(8, 0.0, &mut rng).count_pieces(), 0);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_bitfield_bytes_round_trip_across_sizes(
+            num_pieces in 0usize..=130,
+            set in proptest::collection::vec(proptest::bool::ANY, 130),
+        ) {
+            let pieces: Vec<PieceIndex> = (0..num_pieces)
+                .filter(|&i| set[i])
+                .map(|i| i as PieceIndex)
+                .collect();
+            let bitfield = Bitfield::from_indices(num_pieces, &pieces);
+            let bytes = bitfield.to_bytes();
+            proptest::prop_assert_eq!(bytes.len(), num_pieces.div_ceil(8));
+            proptest::prop_assert_eq!(Bitfield::from_bytes(&bytes, num_pieces), bitfield);
+
+            //=== Spare bits of the last byte stay clear ===//
+            let spare = bytes.len() * 8 - num_pieces;
+            if let Some(last) = bytes.last() {
+                proptest::prop_assert_eq!(last & !(0xffu8 << spare), 0);
+            }
+        }
+
+        #[test]
+        fn test_bitfield_drops_spare_bits_from_peers(
+            num_pieces in 0usize..=130,
+            raw in proptest::collection::vec(0u8.., 17),
+        ) {
+            //=== Whatever the peer sent past the last piece never comes back out ===//
+            let bytes = &raw[..num_pieces.div_ceil(8)];
+            let out = Bitfield::from_bytes(bytes, num_pieces).to_bytes();
+            let spare = out.len() * 8 - num_pieces;
+            if let Some(last) = out.last() {
+                proptest::prop_assert_eq!(last & !(0xffu8 << spare), 0);
+            }
+            for i in 0..num_pieces {
+                let bit = |b: &[u8]| b[i / 8] & (1 << (7 - i % 8));
+                proptest::prop_assert_eq!(bit(&out), bit(bytes));
+            }
+        }
+    }
+
+    #[test]
+    fn test_bitfield_masks_set_spare_bits() {
+        let bitfield = Bitfield::from_bytes(&[0xff, 0xff], 9);
+        assert_eq!(bitfield.to_bytes(), vec![0xff, 0x80]);
+    }
+
+    #[test]
+    fn test_config_vars_layer_over_file() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        std::io::Write::write_all(
+            &mut file,
+            br#"{"listen_port": 7000, "max_connections": 10, "upload_limit": 2048}"#,
+        )
+        .unwrap();
+
+        let vars = [
+            ("FSS_LISTEN_PORT", "6999"),
+            ("FSS_DOWNLOAD_PATH", "/tmp/fss-downloads"),
+            ("FSS_DOWNLOAD_LIMIT", "4096"),
+            ("FSS_TRACKER_TIMEOUT", "45"),
+        ];
+        //=== What `from_env` does, with the variables handed in rather than set process-wide ===//
+        let config = Config::from_file(file.path())
+            .unwrap()
+            .with_vars(|key| {
+                vars.iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| value.to_string())
+            })
+            .unwrap();
+
+        //=== Variables win over the file, the file over the defaults ===//
+        assert_eq!(config.listen_port, 6999);
+        assert_eq!(config.max_connections, 10);
+        assert_eq!(config.upload_limit, Some(2048));
+        assert_eq!(config.download_limit, Some(4096));
+        assert_eq!(config.download_path, PathBuf::from("/tmp/fss-downloads"));
+        assert_eq!(config.tracker_timeout, Duration::from_secs(45));
+        assert_eq!(config.max_unchoked, Config::default().max_unchoked);
+    }
+
+    #[test]
+    fn test_config_vars_clear_optionals_and_reject_garbage() {
+        let vars = |pairs: &'static [(&'static str, &'static str)]| {
+            move |key: &str| {
+                pairs
+                    .iter()
+                    .find(|(name, _)| *name == key)
+                    .map(|(_, value)| value.to_string())
+            }
+        };
+
+        let base = Config {
+            upload_limit: Some(1),
+            ..Config::default()
+        };
+        let config = base
+            .with_vars(vars(&[
+                ("FSS_UPLOAD_LIMIT", "none"),
+                ("FSS_DHT_PORT", "6881"),
+            ]))
+            .unwrap();
+        assert_eq!(config.upload_limit, None);
+        assert_eq!(config.dht_port, Some(6881));
+
+        for (key, value) in [
+            ("FSS_LISTEN_PORT", "70000"),
+            ("FSS_MAX_CONNECTIONS", "many"),
+            ("FSS_ANNOUNCE_INTERVAL", "1.5"),
+            ("FSS_UPLOAD_LIMIT", "-1"),
+        ] {
+            let err = Config::default()
+                .with_vars(|name| (name == key).then(|| value.to_string()))
+                .unwrap_err();
+            match err {
+                TorrentError::Validation(ValidationError::InvalidConfig { message }) => {
+                    assert!(message.contains(key), "{}", message)
+                }
+                other => panic!("unexpected error {:?}", other),
+            }
+        }
     }
 }
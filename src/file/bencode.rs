@@ -0,0 +1,367 @@
+use crate::core::{Result, TorrentError, ValidationError};
+use std::collections::BTreeMap;
+
+//=== Lists and dicts nested deeper than this are refused rather than recursed into ===//
+pub const MAX_NESTING: usize = 64;
+
+//=== A decoded bencode value; dict keys are raw bytes, kept in sorted order ===//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BencodeValue {
+    Int(i64),
+    Bytes(Vec<u8>),
+    List(Vec<BencodeValue>),
+    Dict(BTreeMap<Vec<u8>, BencodeValue>),
+}
+
+impl BencodeValue {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            BencodeValue::Int(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            BencodeValue::Bytes(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    //=== Byte strings that are valid UTF-8 ===//
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes()
+            .and_then(|bytes| std::str::from_utf8(bytes).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[BencodeValue]> {
+        match self {
+            BencodeValue::List(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict_mut(&mut self) -> Option<&mut BTreeMap<Vec<u8>, BencodeValue>> {
+        match self {
+            BencodeValue::Dict(entries) => Some(entries),
+            _ => None,
+        }
+    }
+
+    //=== Look up a key of a dict; None for other values too ===//
+    pub fn get(&self, key: &str) -> Option<&BencodeValue> {
+        self.as_dict()?.get(key.as_bytes())
+    }
+}
+
+impl From<i64> for BencodeValue {
+    fn from(value: i64) -> Self {
+        BencodeValue::Int(value)
+    }
+}
+
+impl From<&str> for BencodeValue {
+    fn from(value: &str) -> Self {
+        BencodeValue::Bytes(value.as_bytes().to_vec())
+    }
+}
+
+impl From<String> for BencodeValue {
+    fn from(value: String) -> Self {
+        BencodeValue::Bytes(value.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for BencodeValue {
+    fn from(value: Vec<u8>) -> Self {
+        BencodeValue::Bytes(value)
+    }
+}
+
+fn invalid() -> TorrentError {
+    TorrentError::Validation(ValidationError::InvalidTorrentInfo)
+}
+
+//=== Decode exactly one value; trailing bytes are an error ===//
+pub fn decode(data: &[u8]) -> Result<BencodeValue> {
+    let mut decoder = Decoder { data, pos: 0 };
+    let value = decoder.value(0)?;
+    if decoder.pos != data.len() {
+        return Err(invalid());
+    }
+    Ok(value)
+}
+
+//=== The exact bytes of `key`'s value in a top-level dict, as they appear in `data` ===//
+pub fn raw_dict_value<'a>(data: &'a [u8], key: &str) -> Result<Option<&'a [u8]>> {
+    let mut decoder = Decoder { data, pos: 0 };
+    if decoder.peek()? != b'd' {
+        return Err(invalid());
+    }
+    decoder.pos += 1;
+
+    let mut found = None;
+    while decoder.peek()? != b'e' {
+        let name = decoder.bytes()?;
+        let start = decoder.pos;
+        decoder.value(1)?;
+        if name == key.as_bytes() {
+            found = Some(&data[start..decoder.pos]);
+        }
+    }
+    if decoder.pos + 1 != data.len() {
+        return Err(invalid());
+    }
+    Ok(found)
+}
+
+//=== Encode a value; dicts come out in key order, so equal values give equal bytes ===//
+pub fn encode(value: &BencodeValue) -> Vec<u8> {
+    let mut out = Vec::new();
+    encode_into(value, &mut out);
+    out
+}
+
+//=== Encode a top-level dict, writing `raw` verbatim as `key`'s value instead of re-encoding it ===//
+pub fn encode_with_raw_value(
+    entries: &BTreeMap<Vec<u8>, BencodeValue>,
+    key: &str,
+    raw: &[u8],
+) -> Vec<u8> {
+    let mut out = vec![b'd'];
+    for (name, value) in entries {
+        encode_bytes(name, &mut out);
+        if name == key.as_bytes() {
+            out.extend_from_slice(raw);
+        } else {
+            encode_into(value, &mut out);
+        }
+    }
+    out.push(b'e');
+    out
+}
+
+fn encode_into(value: &BencodeValue, out: &mut Vec<u8>) {
+    match value {
+        BencodeValue::Int(value) => {
+            out.push(b'i');
+            out.extend_from_slice(value.to_string().as_bytes());
+            out.push(b'e');
+        }
+        BencodeValue::Bytes(bytes) => encode_bytes(bytes, out),
+        BencodeValue::List(items) => {
+            out.push(b'l');
+            for item in items {
+                encode_into(item, out);
+            }
+            out.push(b'e');
+        }
+        BencodeValue::Dict(entries) => {
+            out.push(b'd');
+            for (key, value) in entries {
+                encode_bytes(key, out);
+                encode_into(value, out);
+            }
+            out.push(b'e');
+        }
+    }
+}
+
+fn encode_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(bytes.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(bytes);
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl Decoder<'_> {
+    fn peek(&self) -> Result<u8> {
+        self.data.get(self.pos).copied().ok_or_else(invalid)
+    }
+
+    fn value(&mut self, depth: usize) -> Result<BencodeValue> {
+        if depth > MAX_NESTING {
+            return Err(invalid());
+        }
+        match self.peek()? {
+            b'i' => {
+                self.pos += 1;
+                let digits = self.until(b'e')?;
+                Ok(BencodeValue::Int(parse_int(digits)?))
+            }
+            b'l' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+                while self.peek()? != b'e' {
+                    items.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(BencodeValue::List(items))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut entries = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key = self.bytes()?;
+                    let value = self.value(depth + 1)?;
+                    //=== Out-of-order keys are tolerated, repeated ones are not ===//
+                    if entries.insert(key, value).is_some() {
+                        return Err(invalid());
+                    }
+                }
+                self.pos += 1;
+                Ok(BencodeValue::Dict(entries))
+            }
+            b'0'..=b'9' => Ok(BencodeValue::Bytes(self.bytes()?)),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let digits = self.until(b':')?;
+        if digits.is_empty()
+            || !digits.iter().all(u8::is_ascii_digit)
+            || (digits.len() > 1 && digits[0] == b'0')
+        {
+            return Err(invalid());
+        }
+        let length: usize = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(invalid)?;
+        let end = self.pos.checked_add(length).ok_or_else(invalid)?;
+        let bytes = self.data.get(self.pos..end).ok_or_else(invalid)?;
+        self.pos = end;
+        Ok(bytes.to_vec())
+    }
+
+    //=== The bytes up to `terminator`, which is consumed ===//
+    fn until(&mut self, terminator: u8) -> Result<&[u8]> {
+        let rest = &self.data[self.pos..];
+        let len = rest
+            .iter()
+            .position(|&b| b == terminator)
+            .ok_or_else(invalid)?;
+        self.pos += len + 1;
+        Ok(&rest[..len])
+    }
+}
+
+//=== Canonical integers only: no leading zeros, no "-0", no empty "ie" ===//
+fn parse_int(digits: &[u8]) -> Result<i64> {
+    let unsigned = digits.strip_prefix(b"-").unwrap_or(digits);
+    if unsigned.is_empty()
+        || !unsigned.iter().all(u8::is_ascii_digit)
+        || (unsigned.len() > 1 && unsigned[0] == b'0')
+        || digits == b"-0"
+    {
+        return Err(invalid());
+    }
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse().ok())
+        .ok_or_else(invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_each_kind() {
+        assert_eq!(decode(b"i42e").unwrap(), BencodeValue::Int(42));
+        assert_eq!(decode(b"i-7e").unwrap(), BencodeValue::Int(-7));
+        assert_eq!(decode(b"4:spam").unwrap(), BencodeValue::from("spam"));
+        assert_eq!(decode(b"0:").unwrap(), BencodeValue::Bytes(Vec::new()));
+        assert_eq!(
+            decode(b"l4:spami3ee").unwrap(),
+            BencodeValue::List(vec![BencodeValue::from("spam"), BencodeValue::Int(3)])
+        );
+
+        let dict = decode(b"d3:cow3:moo4:spaml1:a1:bee").unwrap();
+        assert_eq!(dict.get("cow").and_then(BencodeValue::as_str), Some("moo"));
+        assert_eq!(
+            dict.get("spam")
+                .and_then(BencodeValue::as_list)
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(dict.get("missing").is_none());
+    }
+
+    #[test]
+    fn test_encode_sorts_keys_and_round_trips() {
+        //=== Keys arrive out of order but are written back sorted ===//
+        let value = decode(b"d4:zetai1e5:alpha3:onee").unwrap();
+        assert_eq!(encode(&value), b"d5:alpha3:one4:zetai1ee".to_vec());
+
+        let nested = b"d4:infod6:lengthi1024e4:name4:test6:pieces20:aaaaaaaaaaaaaaaaaaaaee";
+        assert_eq!(encode(&decode(nested).unwrap()), nested.to_vec());
+    }
+
+    #[test]
+    fn test_raw_dict_value_keeps_original_bytes() {
+        //=== Unsorted keys inside `info` would be reordered by a re-encode ===//
+        let data = b"d8:announce3:url4:infod4:name1:a6:lengthi1eee";
+        assert_eq!(
+            raw_dict_value(data, "info").unwrap(),
+            Some(b"d4:name1:a6:lengthi1ee".as_slice())
+        );
+        assert_ne!(
+            encode(&decode(b"d4:name1:a6:lengthi1ee").unwrap()),
+            b"d4:name1:a6:lengthi1ee".to_vec()
+        );
+
+        assert_eq!(raw_dict_value(data, "missing").unwrap(), None);
+        assert!(raw_dict_value(b"li1ee", "info").is_err());
+        assert!(raw_dict_value(b"d4:infoi1eeXX", "info").is_err());
+    }
+
+    #[test]
+    fn test_malformed_input_rejected() {
+        let malformed: [&[u8]; 14] = [
+            b"",
+            b"i12",
+            b"ie",
+            b"i-0e",
+            b"i03e",
+            b"i1x2e",
+            b"5:abc",
+            b"02:ab",
+            b"l4:spam",
+            b"d3:keye",
+            b"d1:ai1e1:ai2ee",
+            b"di1ei2ee",
+            b"i1ei2e",
+            b"x",
+        ];
+        for data in malformed {
+            assert!(
+                matches!(
+                    decode(data),
+                    Err(TorrentError::Validation(
+                        ValidationError::InvalidTorrentInfo
+                    ))
+                ),
+                "{:?} should not decode",
+                String::from_utf8_lossy(data)
+            );
+        }
+
+        //=== Deep nesting is an error, not a stack overflow ===//
+        let deep = [vec![b'l'; 10_000], vec![b'e'; 10_000]].concat();
+        assert!(decode(&deep).is_err());
+    }
+}
@@ -0,0 +1,318 @@
+use crate::core::{Hash, PieceIndex, Result, TorrentInfo};
+use crate::file::FileManager;
+use crate::logging::{debug, info};
+use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+
+//=== A complete file some managed torrent already has on disk ===//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IndexedFile {
+    pub path: PathBuf,
+    pub length: u64,
+}
+
+//=== Complete files across managed torrents, keyed by the SHA-1 of their content ===//
+#[derive(Debug, Default)]
+pub struct ContentIndex {
+    files: HashMap<Hash, IndexedFile>,
+}
+
+impl ContentIndex {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, hash: Hash, file: IndexedFile) {
+        self.files.insert(hash, file);
+    }
+
+    pub fn get(&self, hash: &Hash) -> Option<&IndexedFile> {
+        self.files.get(hash)
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    //=== Add every file of `manager` whose pieces have all verified, returning how many ===//
+    pub async fn index_complete_files(&mut self, manager: &FileManager) -> Result<usize> {
+        let torrent_info = manager.torrent_info();
+        let mut indexed = 0;
+        for (file_index, (file_info, bytes)) in torrent_info
+            .files
+            .iter()
+            .zip(file_ranges(torrent_info))
+            .enumerate()
+        {
+            let Some(path) = manager.get_file_path(file_index) else {
+                continue;
+            };
+            let complete = torrent_info
+                .pieces_in_range(bytes.start, file_info.length)
+                .all(|piece_index| manager.piece_manager().has_piece(piece_index));
+            if file_info.length == 0 || !complete {
+                continue;
+            }
+
+            let hash = hash_file(path).await?;
+            self.insert(
+                hash,
+                IndexedFile {
+                    path: path.clone(),
+                    length: file_info.length,
+                },
+            );
+            indexed += 1;
+        }
+        Ok(indexed)
+    }
+}
+
+//=== SHA-1 of a whole file, read in chunks ===//
+pub async fn hash_file(path: &Path) -> Result<Hash> {
+    let mut file = File::open(path).await?;
+    let mut hasher = Sha1::new();
+    let mut buf = vec![0u8; 64 * 1024];
+    loop {
+        let read = file.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+    }
+    Ok(hasher.finalize().into())
+}
+
+//=== Byte range of each file within the torrent ===//
+fn file_ranges(torrent_info: &TorrentInfo) -> Vec<Range<u64>> {
+    let mut start = 0;
+    torrent_info
+        .files
+        .iter()
+        .map(|file| {
+            let range = start..start + file.length;
+            start = range.end;
+            range
+        })
+        .collect()
+}
+
+impl FileManager {
+    //=== Link in indexed copies of our files, returning the indices of the files reused ===//
+    // Files are found by the SHA-1 the torrent lists for them, so those without one are skipped. //
+    // A copy must match every piece wholly inside the file; pieces spanning files still download //
+    pub async fn reuse_indexed_files(&mut self, index: &ContentIndex) -> Result<Vec<usize>> {
+        let mut reused = Vec::new();
+        for (file_index, bytes) in file_ranges(self.torrent_info()).into_iter().enumerate() {
+            let file_info = &self.torrent_info().files[file_index];
+            let Some(target) = self.get_file_path(file_index).cloned() else {
+                continue;
+            };
+            let pieces = self.pieces_within(&bytes);
+            let started = self
+                .torrent_info()
+                .pieces_in_range(bytes.start, file_info.length)
+                .any(|piece_index| self.piece_manager().has_piece(piece_index));
+            if pieces.is_empty() || started {
+                continue;
+            }
+
+            let Some(candidate) = file_info.sha1.and_then(|sha1| index.get(&sha1)) else {
+                continue;
+            };
+            if candidate.length != file_info.length
+                || candidate.path == target
+                || !self
+                    .matches_pieces(candidate, &bytes, pieces.clone())
+                    .await?
+            {
+                continue;
+            }
+
+            link_or_copy(&candidate.path, &target).await?;
+            for piece_index in pieces {
+                self.piece_manager_mut().mark_piece_on_disk(piece_index)?;
+            }
+            info!(
+                "Reused {} for {}",
+                candidate.path.display(),
+                target.display()
+            );
+            reused.push(file_index);
+        }
+        Ok(reused)
+    }
+
+    //=== Pieces that lie entirely inside `bytes` ===//
+    fn pieces_within(&self, bytes: &Range<u64>) -> Range<PieceIndex> {
+        let torrent_info = self.torrent_info();
+        let mut pieces = torrent_info.pieces_in_range(bytes.start, bytes.end - bytes.start);
+        let inside = |piece_index: PieceIndex| {
+            let piece = torrent_info.byte_range_for_piece(piece_index);
+            bytes.start <= piece.start && piece.end <= bytes.end
+        };
+        while pieces.start < pieces.end && !inside(pieces.start) {
+            pieces.start += 1;
+        }
+        while pieces.start < pieces.end && !inside(pieces.end - 1) {
+            pieces.end -= 1;
+        }
+        pieces
+    }
+
+    //=== Whether every one of `pieces` hashes correctly in the candidate, read one at a time ===//
+    async fn matches_pieces(
+        &self,
+        candidate: &IndexedFile,
+        bytes: &Range<u64>,
+        pieces: Range<PieceIndex>,
+    ) -> Result<bool> {
+        let torrent_info = self.torrent_info();
+        let mut file = File::open(&candidate.path).await?;
+        let mut data = Vec::new();
+        for piece_index in pieces {
+            let piece = torrent_info.byte_range_for_piece(piece_index);
+            data.resize((piece.end - piece.start) as usize, 0);
+            file.seek(SeekFrom::Start(piece.start - bytes.start))
+                .await?;
+            file.read_exact(&mut data).await?;
+
+            let hash: Hash = Sha1::digest(&data).into();
+            if hash != torrent_info.pieces[piece_index as usize] {
+                debug!(
+                    "{} differs from piece {}",
+                    candidate.path.display(),
+                    piece_index
+                );
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+//=== Hardlink when the filesystem allows it, otherwise copy ===//
+async fn link_or_copy(source: &Path, target: &Path) -> Result<()> {
+    if fs::try_exists(target).await? {
+        fs::remove_file(target).await?;
+    }
+    if fs::hard_link(source, target).await.is_err() {
+        fs::copy(source, target).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::FileInfo;
+
+    fn piece_hashes(data: &[u8], piece_length: usize) -> Vec<Hash> {
+        data.chunks(piece_length)
+            .map(|piece| Sha1::digest(piece).into())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_second_torrent_reuses_identical_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared: Vec<u8> = (0..256u32).map(|i| (i * 7) as u8).collect();
+
+        //=== The first torrent is complete on disk and gets indexed ===//
+        let first_path = dir.path().join("first");
+        fs::create_dir_all(&first_path).await.unwrap();
+        fs::write(first_path.join("shared.bin"), &shared)
+            .await
+            .unwrap();
+        let first = TorrentInfo::new(
+            "shared.bin".to_string(),
+            64,
+            piece_hashes(&shared, 64),
+            vec![FileInfo::new(vec!["shared.bin".to_string()], 256)],
+        );
+        let mut seeding = FileManager::new(first, first_path.clone(), 4);
+        seeding
+            .open_for_seeding(vec![first_path.join("shared.bin")])
+            .await
+            .unwrap();
+
+        let mut index = ContentIndex::new();
+        assert_eq!(index.index_complete_files(&seeding).await.unwrap(), 1);
+
+        //=== The second torrent lists the same file, by hash, after a short one ===//
+        let mut content = b"readme".to_vec();
+        content.extend_from_slice(&shared);
+        let second = TorrentInfo::new(
+            "bundle".to_string(),
+            64,
+            piece_hashes(&content, 64),
+            vec![
+                FileInfo::new(vec!["readme.txt".to_string()], 6),
+                FileInfo {
+                    sha1: Some(Sha1::digest(&shared).into()),
+                    ..FileInfo::new(vec!["shared.bin".to_string()], 256)
+                },
+            ],
+        );
+        let mut downloading = FileManager::new(second, dir.path().join("second"), 4);
+        downloading.initialize().await.unwrap();
+
+        assert_eq!(
+            downloading.reuse_indexed_files(&index).await.unwrap(),
+            vec![1]
+        );
+        let copied = fs::read(dir.path().join("second").join("shared.bin"))
+            .await
+            .unwrap();
+        assert_eq!(copied, shared);
+
+        //=== Piece 0 also holds the readme, so only it is left to download ===//
+        let pieces = downloading.piece_manager();
+        assert!(!pieces.has_piece(0));
+        assert!((1..5).all(|piece_index| pieces.has_piece(piece_index)));
+        //=== The reused pieces stay on disk rather than in memory ===//
+        assert_eq!(pieces.unflushed_bytes() + pieces.cache_bytes(), 0);
+        assert!(downloading.verify_integrity().await.unwrap().is_clean());
+    }
+
+    #[tokio::test]
+    async fn test_file_without_a_listed_hash_is_not_reused() {
+        let dir = tempfile::tempdir().unwrap();
+        let shared = vec![9u8; 128];
+        let info = TorrentInfo::new(
+            "shared.bin".to_string(),
+            64,
+            piece_hashes(&shared, 64),
+            vec![FileInfo::new(vec!["shared.bin".to_string()], 128)],
+        );
+        fs::write(dir.path().join("indexed.bin"), &shared)
+            .await
+            .unwrap();
+        let mut index = ContentIndex::new();
+        index.insert(
+            Sha1::digest(&shared).into(),
+            IndexedFile {
+                path: dir.path().join("indexed.bin"),
+                length: 128,
+            },
+        );
+
+        //=== Same length and content, but nothing to look it up by ===//
+        let mut downloading = FileManager::new(info, dir.path().join("second"), 4);
+        downloading.initialize().await.unwrap();
+        assert!(downloading
+            .reuse_indexed_files(&index)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(downloading.piece_manager().completed_pieces().is_empty());
+    }
+}
@@ -0,0 +1,243 @@
+use crate::core::{Hash, Result, TorrentError, TorrentInfo, ValidationError};
+use crate::file::TorrentParser;
+use std::fmt;
+use url::form_urlencoded::byte_serialize;
+use url::Url;
+
+//=== RFC 4648 alphabet used by base32 `btih` hashes ===//
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+//=== What a `magnet:` URI tells us before the metadata itself is fetched ===//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MagnetLink {
+    info_hash: Hash,
+    // `dn`, a suggested name for display only //
+    name: Option<String>,
+    // `tr`, in the order given, without repeats //
+    trackers: Vec<String>,
+}
+
+fn invalid(reason: impl Into<String>) -> TorrentError {
+    TorrentError::Validation(ValidationError::InvalidMagnetLink {
+        reason: reason.into(),
+    })
+}
+
+impl MagnetLink {
+    pub fn new(info_hash: Hash, name: Option<String>, trackers: Vec<String>) -> Self {
+        Self {
+            info_hash,
+            name,
+            trackers,
+        }
+    }
+
+    //=== Parse `magnet:?xt=urn:btih:<hex or base32>&dn=..&tr=..` ===//
+    pub fn parse(uri: &str) -> Result<Self> {
+        let url = Url::parse(uri).map_err(|e| invalid(e.to_string()))?;
+        if url.scheme() != "magnet" {
+            return Err(invalid(format!("not a magnet URI: {}", uri)));
+        }
+
+        let mut info_hash = None;
+        let mut name = None;
+        let mut trackers: Vec<String> = Vec::new();
+        for (key, value) in url.query_pairs() {
+            match key.as_ref() {
+                //=== Other exact topics, e.g. v2 `btmh`, are skipped ===//
+                "xt" if info_hash.is_none() => {
+                    let topic = value.get(..9).unwrap_or_default();
+                    if topic.eq_ignore_ascii_case("urn:btih:") {
+                        info_hash = Some(decode_info_hash(&value[9..])?);
+                    }
+                }
+                "dn" if name.is_none() => name = Some(value.into_owned()),
+                "tr" if !trackers.iter().any(|tracker| *tracker == value) => {
+                    trackers.push(value.into_owned())
+                }
+                _ => {}
+            }
+        }
+
+        let info_hash = info_hash.ok_or_else(|| invalid("no urn:btih exact topic (xt)"))?;
+        Ok(Self {
+            info_hash,
+            name,
+            trackers,
+        })
+    }
+
+    pub fn info_hash(&self) -> Hash {
+        self.info_hash
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn trackers(&self) -> Vec<String> {
+        self.trackers.clone()
+    }
+}
+
+//=== `magnet:?xt=urn:btih:<hex>&dn=..&tr=..`, which `MagnetLink::parse` reads back ===//
+impl fmt::Display for MagnetLink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "magnet:?xt=urn:btih:{}", hex::encode(self.info_hash))?;
+        if let Some(name) = &self.name {
+            write!(
+                f,
+                "&dn={}",
+                byte_serialize(name.as_bytes()).collect::<String>()
+            )?;
+        }
+        for tracker in &self.trackers {
+            write!(
+                f,
+                "&tr={}",
+                byte_serialize(tracker.as_bytes()).collect::<String>()
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl TorrentInfo {
+    //=== A magnet link for this torrent, keyed by the hash of its info dict as parsed ===//
+    pub fn to_magnet(&self, trackers: &[String]) -> String {
+        MagnetLink::new(
+            self.info_hash
+                .unwrap_or_else(|| TorrentParser::info_hash_of(self)),
+            Some(self.name.clone()),
+            trackers.to_vec(),
+        )
+        .to_string()
+    }
+}
+
+//=== 40 hex digits or 32 base32 characters, either case ===//
+fn decode_info_hash(encoded: &str) -> Result<Hash> {
+    let bytes = match encoded.len() {
+        40 => hex::decode(encoded).map_err(|e| invalid(format!("bad hex info hash: {}", e)))?,
+        32 => decode_base32(encoded)
+            .ok_or_else(|| invalid(format!("bad base32 info hash: {}", encoded)))?,
+        len => {
+            return Err(invalid(format!(
+                "info hash is {} characters, expected 40 (hex) or 32 (base32)",
+                len
+            )))
+        }
+    };
+    bytes
+        .try_into()
+        .map_err(|_| invalid("info hash is not 20 bytes"))
+}
+
+fn decode_base32(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u64, 0);
+    for c in encoded.bytes() {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())?;
+        buffer = (buffer << 5) | value as u64;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "c12fe1c06bba254a9dc9f519b335aa7c1367a88a";
+
+    #[test]
+    fn test_parse_hex_magnet() {
+        let uri = format!(
+            "magnet:?xt=urn:btih:{}&dn=Some+File%20Name&tr=udp%3A%2F%2Ftracker.example.org%3A6969&tr=http://t.example.com/announce&tr=udp%3A%2F%2Ftracker.example.org%3A6969",
+            HEX.to_uppercase()
+        );
+        let magnet = MagnetLink::parse(&uri).unwrap();
+
+        assert_eq!(hex::encode(magnet.info_hash()), HEX);
+        assert_eq!(magnet.name(), Some("Some File Name"));
+        assert_eq!(
+            magnet.trackers(),
+            vec![
+                "udp://tracker.example.org:6969".to_string(),
+                "http://t.example.com/announce".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_base32_hash_matches_hex() {
+        //=== The same hash as HEX, base32 encoded ===//
+        let magnet =
+            MagnetLink::parse("magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKEK").unwrap();
+        assert_eq!(hex::encode(magnet.info_hash()), HEX);
+        assert_eq!(magnet.name(), None);
+        assert!(magnet.trackers().is_empty());
+
+        let lower =
+            MagnetLink::parse("magnet:?xt=urn:btih:yex6dqdlxisuvhoj6um3gnnkpqjwpkek").unwrap();
+        assert_eq!(lower.info_hash(), magnet.info_hash());
+    }
+
+    #[test]
+    fn test_torrent_exports_magnet_that_parses_back() {
+        let info = TorrentInfo::new(
+            "My Files & more".to_string(),
+            16384,
+            vec![[7u8; 20]; 2],
+            vec![crate::core::FileInfo::new(
+                vec!["a.bin".to_string()],
+                20_000,
+            )],
+        );
+        let data = TorrentParser::serialize_torrent(&info).unwrap();
+        let (_, info_hash) = TorrentParser::parse_bytes_with_info_hash(&data).unwrap();
+        let trackers = vec![
+            "udp://tracker.example.org:6969/announce".to_string(),
+            "http://t.example.com/announce?key=a&b=c".to_string(),
+        ];
+
+        let uri = info.to_magnet(&trackers);
+        assert!(uri.starts_with(&format!("magnet:?xt=urn:btih:{}&", hex::encode(info_hash))));
+        assert!(uri.contains("&dn=My+Files+%26+more&"));
+
+        let magnet = MagnetLink::parse(&uri).unwrap();
+        assert_eq!(magnet.info_hash(), info_hash);
+        assert_eq!(magnet.name(), Some("My Files & more"));
+        assert_eq!(magnet.trackers(), trackers);
+    }
+
+    #[test]
+    fn test_bad_magnets_rejected() {
+        let bad = [
+            "http://example.com/?xt=urn:btih:c12fe1c06bba254a9dc9f519b335aa7c1367a88a".to_string(),
+            "magnet:?dn=no-hash&tr=udp://t.example.org:1".to_string(),
+            "magnet:?xt=urn:sha1:c12fe1c06bba254a9dc9f519b335aa7c1367a88a".to_string(),
+            "magnet:?xt=urn:btih:c12fe1c0".to_string(),
+            format!("magnet:?xt=urn:btih:{}zz", &HEX[..38]),
+            "magnet:?xt=urn:btih:YEX6DQDLXISUVHOJ6UM3GNNKPQJWPKE1".to_string(),
+        ];
+        for uri in bad {
+            assert!(
+                matches!(
+                    MagnetLink::parse(&uri),
+                    Err(TorrentError::Validation(
+                        ValidationError::InvalidMagnetLink { .. }
+                    ))
+                ),
+                "{} should be rejected",
+                uri
+            );
+        }
+    }
+}
@@ -1,15 +1,26 @@
-use crate::core::{FileError, FileInfo, Result, TorrentError, TorrentInfo};
-use crate::file::PieceManager;
-use std::collections::HashMap;
+use crate::core::{
+    BlockLength, BlockOffset, CancellationToken, FileError, PieceIndex, ProtocolError, Result,
+    TorrentError, TorrentInfo, ValidationError,
+};
+use crate::file::{write_piece_at, PieceManager, VerificationReport};
+use std::collections::{HashMap, HashSet};
+use std::io;
 use std::path::{Path, PathBuf};
-use tokio::fs::create_dir_all;
+use tokio::fs::{create_dir_all, File};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio::sync::RwLock;
+use tokio::time::{timeout_at, Duration, Instant};
 
 #[derive(Debug)]
 pub struct FileManager {
     torrent_info: TorrentInfo,
     piece_manager: PieceManager,
     download_path: PathBuf,
-    file_paths: HashMap<String, PathBuf>,
+    // Indexed like `torrent_info.files`, so files sharing a path stay distinct //
+    file_paths: Vec<Option<PathBuf>>,
+    path_overrides: HashMap<usize, PathBuf>,
+    // Files switched off; pieces only they cover aren't fetched //
+    deselected_files: HashSet<usize>,
     files_allocated: bool,
 }
 
@@ -20,13 +31,17 @@ impl FileManager {
             torrent_info.pieces.clone(),
             torrent_info.piece_length,
             cache_size,
-        );
+        )
+        .with_total_size(torrent_info.total_size());
 
+        let file_paths = vec![None; torrent_info.files.len()];
         Self {
             torrent_info,
             piece_manager,
             download_path,
-            file_paths: HashMap::new(),
+            file_paths,
+            path_overrides: HashMap::new(),
+            deselected_files: HashSet::new(),
             files_allocated: false,
         }
     }
@@ -47,29 +62,119 @@ impl FileManager {
     pub async fn initialize(&mut self) -> Result<()> {
         create_dir_all(&self.download_path).await?;
 
-        for file_info in &self.torrent_info.files {
-            let file_path = self.download_path.join(file_info.full_path());
+        let mut taken = HashSet::new();
+        for (file_index, file_info) in self.torrent_info.files.iter().enumerate() {
+            let file_path = match self.path_overrides.get(&file_index) {
+                Some(path) => path.clone(),
+                None => {
+                    //=== A later entry with an earlier entry's path gets its own file ===//
+                    //=== That name may be taken too, so count up until one is free ===//
+                    let path = self.download_path.join(file_info.full_path());
+                    let mut unique = path.clone();
+                    let mut suffix = file_index;
+                    while taken.contains(&unique) {
+                        unique = deduplicated_path(&path, suffix);
+                        suffix += 1;
+                    }
+                    unique
+                }
+            };
 
             if let Some(parent) = file_path.parent() {
                 create_dir_all(parent).await?;
             }
-            let key = file_info.full_path().to_string_lossy().to_string();
-            self.file_paths.insert(key, file_path);
+            taken.insert(file_path.clone());
+            self.file_paths[file_index] = Some(file_path);
+        }
+
+        Ok(())
+    }
+
+    //=== Place one file somewhere other than under `download_path` ===//
+    pub fn set_file_path(&mut self, file_index: usize, path: PathBuf) -> Result<()> {
+        let slot = self
+            .file_paths
+            .get_mut(file_index)
+            .ok_or(TorrentError::Validation(
+                ValidationError::InvalidTorrentInfo,
+            ))?;
+
+        *slot = Some(path.clone());
+        self.path_overrides.insert(file_index, path);
+        Ok(())
+    }
+
+    //=== Switch a file's download on or off; the picker sees it on its next pass ===//
+    pub fn set_file_selected(&mut self, file_index: usize, selected: bool) -> Result<()> {
+        if file_index >= self.torrent_info.files.len() {
+            return Err(TorrentError::Validation(
+                ValidationError::InvalidTorrentInfo,
+            ));
         }
 
+        if selected {
+            self.deselected_files.remove(&file_index);
+        } else {
+            self.deselected_files.insert(file_index);
+        }
         Ok(())
     }
 
+    pub fn is_file_selected(&self, file_index: usize) -> bool {
+        !self.deselected_files.contains(&file_index)
+    }
+
+    //=== A piece is wanted while any selected file has bytes in it ===//
+    pub fn is_piece_wanted(&self, piece_index: PieceIndex) -> bool {
+        if self.deselected_files.is_empty() {
+            return true;
+        }
+
+        let piece = self.torrent_info.byte_range_for_piece(piece_index);
+        let mut file_start = 0u64;
+        self.torrent_info
+            .files
+            .iter()
+            .enumerate()
+            .any(|(file_index, file_info)| {
+                let file_end = file_start + file_info.length;
+                let overlaps = file_start < piece.end && piece.start < file_end;
+                file_start = file_end;
+                overlaps && self.is_file_selected(file_index)
+            })
+    }
+
     pub async fn allocate_files(&mut self) -> Result<()> {
         if self.files_allocated {
             return Ok(());
         }
 
-        for file_info in &self.torrent_info.files {
-            let key = file_info.full_path().to_string_lossy().to_string();
-            if let Some(file_path) = self.file_paths.get(&key) {
+        //=== Bytes already on disk count towards the files, so only the growth must fit ===//
+        let mut needed = 0u64;
+        for (file_info, file_path) in self.torrent_info.files.iter().zip(&self.file_paths) {
+            let Some(file_path) = file_path else {
+                continue;
+            };
+            let existing = match tokio::fs::metadata(file_path).await {
+                Ok(metadata) => metadata.len(),
+                Err(_) => 0,
+            };
+            needed += file_info.length.saturating_sub(existing);
+        }
+        match available_space(&self.download_path) {
+            Ok(available) if needed > available => {
+                return Err(TorrentError::File(FileError::InsufficientSpace));
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        for (file_info, file_path) in self.torrent_info.files.iter().zip(&self.file_paths) {
+            if let Some(file_path) = file_path {
                 let file = tokio::fs::OpenOptions::new()
                     .create(true)
+                    .truncate(false)
                     .write(true)
                     .open(file_path)
                     .await
@@ -86,13 +191,67 @@ impl FileManager {
         Ok(())
     }
 
+    //=== Serve the torrent from files already on disk, given in torrent file order ===//
+    pub async fn open_for_seeding(&mut self, existing_paths: Vec<PathBuf>) -> Result<()> {
+        if existing_paths.len() != self.torrent_info.files.len() {
+            return Err(TorrentError::Validation(
+                ValidationError::InvalidTorrentInfo,
+            ));
+        }
+
+        for (file_info, path) in self.torrent_info.files.iter().zip(&existing_paths) {
+            let metadata = tokio::fs::metadata(path).await.map_err(|_| {
+                TorrentError::File(FileError::NotFound {
+                    path: path.to_string_lossy().to_string(),
+                })
+            })?;
+            if metadata.len() != file_info.length {
+                return Err(TorrentError::File(FileError::Corruption));
+            }
+        }
+
+        self.file_paths = existing_paths.into_iter().map(Some).collect();
+
+        let file_paths = self.ordered_file_paths()?;
+        let file_sizes: Vec<u64> = self.torrent_info.files.iter().map(|f| f.length).collect();
+        self.piece_manager
+            .load_from_files(&file_paths, &file_sizes)
+            .await?;
+
+        //=== The files are already laid out, so nothing needs allocating ===//
+        self.files_allocated = true;
+        Ok(())
+    }
+
+    //=== File paths in torrent order, which piece offsets are laid out in ===//
+    // A file without a path would shift every later one, so that is an error //
+    fn ordered_file_paths(&self) -> Result<Vec<String>> {
+        self.file_paths
+            .iter()
+            .zip(&self.torrent_info.files)
+            .map(|(path, file_info)| {
+                path.as_ref()
+                    .map(|p| p.to_string_lossy().to_string())
+                    .ok_or_else(|| {
+                        TorrentError::File(FileError::NotFound {
+                            path: file_info.full_path().to_string_lossy().to_string(),
+                        })
+                    })
+            })
+            .collect()
+    }
+
     //== Check which pieces are already present on disk ==//
     pub async fn scan_existing_files(&mut self) -> Result<()> {
-        let file_paths: Vec<String> = self
-            .file_paths
-            .values()
-            .map(|p| p.to_string_lossy().to_string())
-            .collect();
+        self.scan_existing_files_cancellable(&CancellationToken::new())
+            .await
+    }
+
+    pub async fn scan_existing_files_cancellable(
+        &mut self,
+        cancel: &CancellationToken,
+    ) -> Result<()> {
+        let file_paths = self.ordered_file_paths()?;
 
         let file_sizes: Vec<u64> = self.torrent_info.files.iter().map(|f| f.length).collect();
 
@@ -105,7 +264,7 @@ impl FileManager {
 
         //== Load existing pieces ==//
         self.piece_manager
-            .load_from_files(&file_paths, &file_sizes)
+            .load_from_files_cancellable(&file_paths, &file_sizes, cancel)
             .await?;
 
         Ok(())
@@ -113,28 +272,37 @@ impl FileManager {
 
     //== Write completed pieces to disk ==//
     pub async fn flush_to_disk(&mut self) -> Result<()> {
-        let file_paths: Vec<String> = self
-            .file_paths
-            .values()
-            .map(|p| p.to_string_lossy().to_string())
-            .collect();
+        self.flush_to_disk_cancellable(&CancellationToken::new())
+            .await
+    }
+
+    pub async fn flush_to_disk_cancellable(&mut self, cancel: &CancellationToken) -> Result<()> {
+        let file_paths = self.ordered_file_paths()?;
 
         let file_sizes: Vec<u64> = self.torrent_info.files.iter().map(|f| f.length).collect();
 
         self.piece_manager
-            .write_to_files(&file_paths, &file_sizes)
+            .write_to_files_cancellable(&file_paths, &file_sizes, cancel)
             .await?;
 
         Ok(())
     }
 
-    //== Get file path for a specific file ==//
-    pub fn get_file_path(&self, file_info: &FileInfo) -> Option<&PathBuf> {
-        let key = file_info.full_path().to_string_lossy().to_string();
-        self.file_paths.get(&key)
+    //== Write one verified piece to disk ==//
+    pub async fn write_piece(&self, piece_index: PieceIndex, data: &[u8]) -> Result<()> {
+        let file_paths = self.ordered_file_paths()?;
+        let file_sizes: Vec<u64> = self.torrent_info.files.iter().map(|f| f.length).collect();
+        let offset = self.torrent_info.piece_offset(piece_index);
+
+        write_piece_at(data, offset, &file_paths, &file_sizes).await
+    }
+
+    //== Get file path for a specific file, by its index in the torrent ==//
+    pub fn get_file_path(&self, file_index: usize) -> Option<&PathBuf> {
+        self.file_paths.get(file_index)?.as_ref()
     }
-    //== Get all file paths ==//
-    pub fn file_paths(&self) -> &HashMap<String, PathBuf> {
+    //== Get all file paths, in torrent order; None until initialized ==//
+    pub fn file_paths(&self) -> &[Option<PathBuf>] {
         &self.file_paths
     }
 
@@ -166,19 +334,62 @@ impl FileManager {
     }
 
     //== Verify integrity of all downloaded pieces ==//
-    pub async fn verify_integrity(&mut self) -> Result<Vec<u32>> {
-        let failed_pieces = self.piece_manager.verify_all_pieces()?;
+    pub async fn verify_integrity(&mut self) -> Result<VerificationReport> {
+        self.verify_integrity_cancellable(&CancellationToken::new())
+            .await
+    }
+
+    //=== Just the pieces that failed, for callers that need nothing more ===//
+    pub async fn verify_failed_pieces(&mut self) -> Result<Vec<PieceIndex>> {
+        Ok(self.verify_integrity().await?.failed)
+    }
 
-        if !failed_pieces.is_empty() {
-            log::warn!("Found {} corrupted pieces", failed_pieces.len());
+    //=== Pieces checked before a cancel keep their result; the rest stay as they were ===//
+    pub async fn verify_integrity_cancellable(
+        &mut self,
+        cancel: &CancellationToken,
+    ) -> Result<VerificationReport> {
+        let mut report = self
+            .piece_manager
+            .verify_all_pieces_cancellable(cancel)
+            .await?;
+
+        //=== Flushed pieces are re-read from disk; one that can't be read has failed ===//
+        for piece_index in self.piece_manager.flushed_pieces() {
+            cancel.check()?;
+            let piece_size = self.torrent_info.piece_size(piece_index);
+            let data = self.read_block(piece_index, 0, piece_size).await.ok();
+            self.piece_manager
+                .reverify_flushed_piece(piece_index, data.as_deref(), &mut report);
+        }
+
+        if !report.is_clean() {
+            crate::logging::warn!("Found {} corrupted pieces", report.failed.len());
+        }
+
+        //=== A piece spanning a file boundary counts against every file it touches ===//
+        for &piece_index in &report.failed {
+            let piece = self.torrent_info.byte_range_for_piece(piece_index);
+            let mut file_start = 0u64;
+            for (file_index, file_info) in self.torrent_info.files.iter().enumerate() {
+                let file_end = file_start + file_info.length;
+                if file_start < piece.end && piece.start < file_end {
+                    report
+                        .failed_by_file
+                        .entry(file_index)
+                        .or_default()
+                        .push(piece_index);
+                }
+                file_start = file_end;
+            }
         }
 
-        Ok(failed_pieces)
+        Ok(report)
     }
 
-    //== Get file download progress ==//
-    pub fn file_progress(&self) -> HashMap<String, f64> {
-        let mut progress = HashMap::new();
+    //== Get file download progress, one percentage per file in torrent order ==//
+    pub fn file_progress(&self) -> Vec<f64> {
+        let mut progress = Vec::with_capacity(self.torrent_info.files.len());
         let mut current_offset = 0u64;
 
         for file_info in &self.torrent_info.files {
@@ -192,12 +403,11 @@ impl FileManager {
                     continue;
                 }
 
-                let piece_start = piece_index as u64 * self.torrent_info.piece_length as u64;
-                let piece_end = piece_start + self.torrent_info.piece_size(piece_index) as u64;
+                let piece = self.torrent_info.byte_range_for_piece(piece_index);
 
                 //== Check if piece overlaps with file ==//
-                let overlap_start = std::cmp::max(piece_start, file_start);
-                let overlap_end = std::cmp::min(piece_end, file_end);
+                let overlap_start = std::cmp::max(piece.start, file_start);
+                let overlap_end = std::cmp::min(piece.end, file_end);
 
                 if overlap_start < overlap_end {
                     downloaded_bytes += overlap_end - overlap_start;
@@ -210,8 +420,7 @@ impl FileManager {
                 100.0
             };
 
-            let key = file_info.full_path().to_string_lossy().to_string();
-            progress.insert(key, file_progress);
+            progress.push(file_progress);
 
             current_offset += file_info.length;
         }
@@ -219,18 +428,738 @@ impl FileManager {
         progress
     }
 
+    //=== Read one block straight from disk, touching only the files it spans ===//
+    pub async fn read_block(
+        &self,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockLength,
+    ) -> Result<Vec<u8>> {
+        let in_piece = offset
+            .checked_add(length)
+            .is_some_and(|end| end <= self.torrent_info.piece_size(piece_index));
+        if !self.torrent_info.is_valid_piece_index(piece_index) || !in_piece {
+            return Err(TorrentError::Protocol(ProtocolError::InvalidBlockRequest));
+        }
+
+        let start = self.torrent_info.piece_offset(piece_index) + offset as u64;
+        let end = start + length as u64;
+        let mut block = Vec::with_capacity(length as usize);
+        let mut file_start = 0u64;
+
+        for (file_index, file_info) in self.torrent_info.files.iter().enumerate() {
+            let file_end = file_start + file_info.length;
+            let overlap_start = start.max(file_start);
+            let overlap_end = end.min(file_end);
+
+            if overlap_start < overlap_end {
+                let path = self.get_file_path(file_index).ok_or_else(|| {
+                    TorrentError::File(FileError::NotFound {
+                        path: file_info.full_path().to_string_lossy().to_string(),
+                    })
+                })?;
+
+                let mut file = File::open(path).await.map_err(|_| {
+                    TorrentError::File(FileError::NotFound {
+                        path: path.to_string_lossy().to_string(),
+                    })
+                })?;
+                file.seek(SeekFrom::Start(overlap_start - file_start))
+                    .await?;
+
+                let filled = block.len();
+                block.resize(filled + (overlap_end - overlap_start) as usize, 0);
+                file.read_exact(&mut block[filled..]).await?;
+            }
+
+            file_start = file_end;
+        }
+
+        Ok(block)
+    }
+
+    //=== Read bytes of the torrent's content, provided every piece covering them is verified ===//
+    pub async fn read_range(&self, offset: u64, length: u64) -> Result<Vec<u8>> {
+        let in_torrent = offset
+            .checked_add(length)
+            .is_some_and(|end| end <= self.total_size());
+        if !in_torrent {
+            return Err(TorrentError::File(FileError::OutOfRange { offset, length }));
+        }
+
+        let pieces = self.torrent_info.pieces_in_range(offset, length);
+        let pending: Vec<PieceIndex> = pieces
+            .clone()
+            .filter(|&piece_index| !self.piece_manager.has_piece(piece_index))
+            .collect();
+        if !pending.is_empty() {
+            return Err(TorrentError::File(FileError::PiecesPending {
+                pieces: pending,
+            }));
+        }
+
+        //=== Verified pieces may still sit in memory, so prefer that over disk ===//
+        let end = offset + length;
+        let mut data = Vec::with_capacity(length as usize);
+        for piece_index in pieces {
+            let piece = self.torrent_info.byte_range_for_piece(piece_index);
+            let from = offset.max(piece.start) - piece.start;
+            let to = end.min(piece.end) - piece.start;
+
+            match self.piece_manager.get_piece_data(piece_index) {
+                Some(piece) => data.extend_from_slice(&piece[from as usize..to as usize]),
+                None => {
+                    let block = self
+                        .read_block(piece_index, from as BlockOffset, (to - from) as BlockLength)
+                        .await?;
+                    data.extend_from_slice(&block);
+                }
+            }
+        }
+
+        Ok(data)
+    }
+
+    //=== Like `read_range`, but waits up to `wait` for pending pieces to verify ===//
+    // Takes the shared manager so pieces can complete meanwhile; dropping the future //
+    // cancels the wait, and a timeout reports the pieces still pending //
+    pub async fn read_range_await(
+        manager: &RwLock<FileManager>,
+        offset: u64,
+        length: u64,
+        wait: Duration,
+    ) -> Result<Vec<u8>> {
+        let deadline = Instant::now() + wait;
+
+        loop {
+            let guard = manager.read().await;
+            let pending = match guard.read_range(offset, length).await {
+                Err(TorrentError::File(FileError::PiecesPending { pieces })) => pieces,
+                result => return result,
+            };
+
+            //=== Register before releasing the lock so a completion can't slip past ===//
+            let notify = guard
+                .piece_manager
+                .completion_notify(pending[0])
+                .ok_or(TorrentError::File(FileError::OutOfRange { offset, length }))?;
+            let notified = notify.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            drop(guard);
+
+            if timeout_at(deadline, notified).await.is_err() {
+                return Err(TorrentError::File(FileError::PiecesPending {
+                    pieces: pending,
+                }));
+            }
+        }
+    }
+
     //== Get storage statistics ==//
     pub fn storage_stats(&self) -> Result<(u64, u64, u64)> {
         let total_size = self.total_size();
         let downloaded_size = self.downloaded_size();
 
-        //== Get available disk space ==//
-        let available_space = if let Some(_first_path) = self.file_paths.values().next() {
-            0u64
-        } else {
-            0u64
+        //== Free space on the filesystem holding `download_path`; 0 where we can't ask ==//
+        let available_space = match available_space(&self.download_path) {
+            Ok(available) => available,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => 0,
+            Err(e) => return Err(e.into()),
         };
 
         Ok((total_size, downloaded_size, available_space))
     }
 }
+
+//=== Bytes an unprivileged user may still write to the filesystem holding `path` ===//
+// `path` need not exist yet; its nearest existing ancestor is asked instead //
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or(Path::new("."));
+    let c_path = CString::new(existing.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    //=== SAFETY: `c_path` is NUL-terminated and `stat` is only read after a successful call ===//
+    let stat = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "free space query not supported on this platform",
+    ))
+}
+
+//=== `dir/name.ext` becomes `dir/name.<index>.ext`, as other clients name duplicates ===//
+fn deduplicated_path(path: &Path, file_index: usize) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}.{}.{}", stem, file_index, extension.to_string_lossy()),
+        None => format!("{}.{}", stem, file_index),
+    };
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::FileInfo;
+
+    #[tokio::test]
+    async fn test_read_block_reads_requested_range() {
+        let dir = tempfile::tempdir().unwrap();
+        let first: Vec<u8> = (0..100u8).collect();
+        let second: Vec<u8> = (100..200u8).collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            64,
+            vec![[0u8; 20]; 4],
+            vec![
+                FileInfo::new(vec!["a".to_string()], 100),
+                FileInfo::new(vec!["b".to_string()], 100),
+            ],
+        );
+
+        let mut manager = FileManager::new(info, dir.path().to_path_buf(), 4);
+        manager.initialize().await.unwrap();
+        tokio::fs::write(dir.path().join("a"), &first)
+            .await
+            .unwrap();
+        tokio::fs::write(dir.path().join("b"), &second)
+            .await
+            .unwrap();
+
+        //=== Piece 1 covers bytes 64..128, spanning both files ===//
+        let block = manager.read_block(1, 30, 10).await.unwrap();
+        assert_eq!(block, (94..104u8).collect::<Vec<_>>());
+
+        //=== The short last piece ends at byte 200 ===//
+        let block = manager.read_block(3, 0, 8).await.unwrap();
+        assert_eq!(block, (192..200u8).collect::<Vec<_>>());
+        assert!(manager.read_block(3, 0, 9).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_range_across_files_once_verified() {
+        use sha1::{Digest, Sha1};
+
+        let data: Vec<u8> = (0..200u8).collect();
+        let hashes = data
+            .chunks(64)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            64,
+            hashes,
+            vec![
+                FileInfo::new(vec!["a".to_string()], 100),
+                FileInfo::new(vec!["b".to_string()], 100),
+            ],
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = FileManager::new(info, dir.path().to_path_buf(), 4);
+        manager.initialize().await.unwrap();
+        manager.allocate_files().await.unwrap();
+
+        //=== Bytes 90..140 span both files and pieces 1 and 2 ===//
+        manager
+            .piece_manager_mut()
+            .add_piece_data(1, data[64..128].to_vec())
+            .unwrap();
+        match manager.read_range(90, 50).await {
+            Err(TorrentError::File(FileError::PiecesPending { pieces })) => {
+                assert_eq!(pieces, vec![2])
+            }
+            other => panic!("expected pending pieces, got {:?}", other),
+        }
+
+        manager
+            .piece_manager_mut()
+            .add_piece_data(2, data[128..192].to_vec())
+            .unwrap();
+        assert_eq!(manager.read_range(90, 50).await.unwrap(), data[90..140]);
+
+        //=== Flushed and evicted pieces are read back from disk ===//
+        manager.flush_to_disk().await.unwrap();
+        manager.piece_manager_mut().clear_cache();
+        for piece_index in [1, 2] {
+            if let Some(piece) = manager.piece_manager_mut().get_piece_mut(piece_index) {
+                piece.data = None;
+            }
+        }
+        assert_eq!(manager.read_range(90, 50).await.unwrap(), data[90..140]);
+        assert!(manager.read_range(190, 11).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_read_range_await_wakes_on_completion() {
+        use sha1::{Digest, Sha1};
+        use std::sync::Arc;
+
+        let data: Vec<u8> = (0..128u8).collect();
+        let hashes = data
+            .chunks(64)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            64,
+            hashes,
+            vec![FileInfo::new(vec!["a".to_string()], 128)],
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let manager = Arc::new(RwLock::new(FileManager::new(
+            info,
+            dir.path().to_path_buf(),
+            4,
+        )));
+
+        //=== Nothing arrives: the wait gives up cleanly ===//
+        match FileManager::read_range_await(&manager, 60, 10, Duration::from_millis(20)).await {
+            Err(TorrentError::File(FileError::PiecesPending { pieces })) => {
+                assert_eq!(pieces, vec![0, 1])
+            }
+            other => panic!("expected pending pieces, got {:?}", other),
+        }
+
+        let writer = {
+            let manager = Arc::clone(&manager);
+            let data = data.clone();
+            tokio::spawn(async move {
+                for (index, chunk) in data.chunks(64).enumerate() {
+                    tokio::time::sleep(Duration::from_millis(20)).await;
+                    manager
+                        .write()
+                        .await
+                        .piece_manager_mut()
+                        .add_piece_data(index as PieceIndex, chunk.to_vec())
+                        .unwrap();
+                }
+            })
+        };
+
+        let read = FileManager::read_range_await(&manager, 60, 10, Duration::from_secs(5))
+            .await
+            .unwrap();
+        assert_eq!(read, data[60..70]);
+        writer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_remapped_file_receives_its_pieces() {
+        use sha1::{Digest, Sha1};
+
+        let data: Vec<u8> = (0..150u8).collect();
+        let hashes = data
+            .chunks(64)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            64,
+            hashes,
+            vec![
+                FileInfo::new(vec!["video".to_string()], 100),
+                FileInfo::new(vec!["subs".to_string()], 50),
+            ],
+        );
+
+        let downloads = tempfile::tempdir().unwrap();
+        let other_disk = tempfile::tempdir().unwrap();
+        let remapped = other_disk.path().join("nested").join("subs.srt");
+
+        let mut manager = FileManager::new(info, downloads.path().to_path_buf(), 4);
+        manager.set_file_path(1, remapped.clone()).unwrap();
+        assert!(manager.set_file_path(2, remapped.clone()).is_err());
+        //=== Until every file has a path, writes fail rather than land in the wrong file ===//
+        assert!(matches!(
+            manager.write_piece(2, &data[128..]).await,
+            Err(TorrentError::File(FileError::NotFound { .. }))
+        ));
+        assert!(!remapped.exists());
+        manager.initialize().await.unwrap();
+        manager.allocate_files().await.unwrap();
+
+        for (index, chunk) in data.chunks(64).enumerate() {
+            let piece_manager = manager.piece_manager_mut();
+            assert!(piece_manager
+                .add_piece_data(index as PieceIndex, chunk.to_vec())
+                .unwrap());
+        }
+        manager.flush_to_disk().await.unwrap();
+
+        //=== Piece 1 straddles the boundary between the two locations ===//
+        assert_eq!(
+            tokio::fs::read(downloads.path().join("video"))
+                .await
+                .unwrap(),
+            data[..100]
+        );
+        assert_eq!(tokio::fs::read(&remapped).await.unwrap(), data[100..]);
+        assert!(!downloads.path().join("subs").exists());
+        assert_eq!(
+            manager.read_block(1, 30, 10).await.unwrap(),
+            (94..104u8).collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ragged_last_piece_round_trips() {
+        use sha1::{Digest, Sha1};
+
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (1..=10u8).collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            4,
+            data.chunks(4)
+                .map(|chunk| Sha1::digest(chunk).into())
+                .collect(),
+            vec![
+                FileInfo::new(vec!["a".to_string()], 7),
+                FileInfo::new(vec!["b".to_string()], 3),
+            ],
+        );
+        assert!(info.verify_self_consistent().is_ok());
+        assert_eq!(
+            (0..3)
+                .map(|piece| info.piece_size(piece))
+                .collect::<Vec<_>>(),
+            vec![4, 4, 2]
+        );
+        assert_eq!(info.byte_range_for_piece(2), 8..10);
+
+        //=== Download and write every piece, including the 2-byte one ===//
+        let mut manager = FileManager::new(info.clone(), dir.path().to_path_buf(), 4);
+        manager.initialize().await.unwrap();
+        manager.allocate_files().await.unwrap();
+        for piece in 0..3 {
+            let range = info.byte_range_for_piece(piece);
+            let block = data[range.start as usize..range.end as usize].to_vec();
+            assert!(manager
+                .piece_manager_mut()
+                .add_piece_data(piece, block)
+                .unwrap());
+        }
+        assert_eq!(manager.downloaded_size(), 10);
+        manager.flush_to_disk().await.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("a")).unwrap(), &data[..7]);
+        assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), &data[7..]);
+
+        //=== Read it back from disk and verify ===//
+        let mut reloaded = FileManager::new(info, dir.path().to_path_buf(), 4);
+        reloaded.initialize().await.unwrap();
+        reloaded.scan_existing_files().await.unwrap();
+        assert!(reloaded.is_complete());
+        assert!(reloaded.verify_failed_pieces().await.unwrap().is_empty());
+        assert!(reloaded.file_progress().iter().all(|&p| p == 100.0));
+        assert_eq!(reloaded.read_block(2, 0, 2).await.unwrap(), vec![9, 10]);
+        assert!(reloaded.read_block(2, 0, 3).await.is_err());
+        assert_eq!(reloaded.read_range(6, 4).await.unwrap(), vec![7, 8, 9, 10]);
+    }
+
+    #[tokio::test]
+    async fn test_zero_length_file_alongside_normal_ones() {
+        use sha1::{Digest, Sha1};
+
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..150u8).collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            64,
+            data.chunks(64)
+                .map(|chunk| Sha1::digest(chunk).into())
+                .collect(),
+            vec![
+                FileInfo::new(vec!["a".to_string()], 100),
+                FileInfo::new(vec!["empty".to_string()], 0),
+                FileInfo::new(vec!["b".to_string()], 50),
+            ],
+        );
+
+        let mut manager = FileManager::new(info.clone(), dir.path().to_path_buf(), 4);
+        manager.initialize().await.unwrap();
+        manager.allocate_files().await.unwrap();
+        assert_eq!(
+            std::fs::metadata(dir.path().join("empty")).unwrap().len(),
+            0
+        );
+
+        for (index, chunk) in data.chunks(64).enumerate() {
+            assert!(manager
+                .piece_manager_mut()
+                .add_piece_data(index as PieceIndex, chunk.to_vec())
+                .unwrap());
+        }
+        manager.flush_to_disk().await.unwrap();
+        assert_eq!(std::fs::read(dir.path().join("a")).unwrap(), &data[..100]);
+        assert_eq!(std::fs::read(dir.path().join("b")).unwrap(), &data[100..]);
+        assert_eq!(
+            std::fs::metadata(dir.path().join("empty")).unwrap().len(),
+            0
+        );
+
+        //=== Loading it back finds every piece ===//
+        let mut reloaded = FileManager::new(info, dir.path().to_path_buf(), 4);
+        reloaded.initialize().await.unwrap();
+        reloaded.scan_existing_files().await.unwrap();
+        assert!(reloaded.is_complete());
+    }
+
+    #[tokio::test]
+    async fn test_open_for_seeding_from_existing_files() {
+        use sha1::{Digest, Sha1};
+
+        let content = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..150u8).collect();
+        let hashes = data
+            .chunks(64)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            64,
+            hashes,
+            vec![
+                FileInfo::new(vec!["a".to_string()], 100),
+                FileInfo::new(vec!["b".to_string()], 50),
+            ],
+        );
+
+        //=== Content lives under arbitrary names, outside the download path ===//
+        let paths = vec![content.path().join("first"), content.path().join("second")];
+        tokio::fs::write(&paths[0], &data[..100]).await.unwrap();
+        tokio::fs::write(&paths[1], &data[100..]).await.unwrap();
+
+        let downloads = tempfile::tempdir().unwrap();
+        let mut manager = FileManager::new(info, downloads.path().to_path_buf(), 4);
+        manager.open_for_seeding(paths.clone()).await.unwrap();
+
+        assert!(manager.is_complete());
+        assert!(manager.files_allocated());
+        assert_eq!(
+            manager.read_block(1, 30, 10).await.unwrap(),
+            (94..104u8).collect::<Vec<_>>()
+        );
+        assert!(std::fs::read_dir(downloads.path())
+            .unwrap()
+            .next()
+            .is_none());
+
+        //=== Missing or mismatched files are rejected ===//
+        assert!(manager
+            .open_for_seeding(vec![paths[0].clone(), content.path().join("missing")])
+            .await
+            .is_err());
+        assert!(manager
+            .open_for_seeding(vec![paths[1].clone(), paths[0].clone()])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_files_sharing_a_path_are_kept_apart() {
+        use sha1::{Digest, Sha1};
+
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (1..=12u8).collect();
+        let pieces = data
+            .chunks(4)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let info = TorrentInfo::new(
+            "dups".to_string(),
+            4,
+            pieces,
+            vec![
+                FileInfo::new(vec!["sub".to_string(), "same.txt".to_string()], 5),
+                FileInfo::new(vec!["sub".to_string(), "same.txt".to_string()], 7),
+            ],
+        );
+
+        let mut manager = FileManager::new(info.clone(), dir.path().to_path_buf(), 4);
+        manager.initialize().await.unwrap();
+        let first = manager.get_file_path(0).unwrap().clone();
+        let second = manager.get_file_path(1).unwrap().clone();
+        assert_eq!(first, dir.path().join("sub").join("same.txt"));
+        assert_eq!(second, dir.path().join("sub").join("same.1.txt"));
+
+        for (piece, chunk) in data.chunks(4).enumerate() {
+            assert!(manager
+                .piece_manager_mut()
+                .add_piece_data(piece as PieceIndex, chunk.to_vec())
+                .unwrap());
+        }
+        manager.flush_to_disk().await.unwrap();
+        assert_eq!(std::fs::read(&first).unwrap(), &data[..5]);
+        assert_eq!(std::fs::read(&second).unwrap(), &data[5..]);
+
+        //=== Each entry reports its own progress, and a rescan finds both ===//
+        let mut reloaded = FileManager::new(info, dir.path().to_path_buf(), 4);
+        reloaded.initialize().await.unwrap();
+        reloaded.scan_existing_files().await.unwrap();
+        assert!(reloaded.is_complete());
+        assert_eq!(reloaded.file_progress(), vec![100.0, 100.0]);
+
+        //=== The name a duplicate would get may already belong to another entry ===//
+        let info = TorrentInfo::new(
+            "dups".to_string(),
+            4,
+            vec![[0u8; 20]; 3],
+            ["a.txt", "a.2.txt", "a.txt"]
+                .iter()
+                .map(|name| FileInfo::new(vec![name.to_string()], 4))
+                .collect(),
+        );
+        let mut manager = FileManager::new(info, dir.path().join("clash"), 4);
+        manager.initialize().await.unwrap();
+        let names: Vec<_> = (0..3)
+            .map(|file_index| manager.get_file_path(file_index).unwrap().file_name())
+            .map(|name| name.unwrap().to_string_lossy().to_string())
+            .collect();
+        assert_eq!(names, vec!["a.txt", "a.2.txt", "a.3.txt"]);
+    }
+
+    #[tokio::test]
+    async fn test_storage_stats_reports_free_space() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = TorrentInfo::new(
+            "space".to_string(),
+            16,
+            vec![[0u8; 20]; 1],
+            vec![FileInfo::new(vec!["a".to_string()], 16)],
+        );
+
+        //=== The download directory doesn't exist yet; its parent is asked ===//
+        let manager = FileManager::new(info, dir.path().join("not-yet"), 4);
+        let (total, downloaded, available) = manager.storage_stats().unwrap();
+        assert_eq!((total, downloaded), (16, 0));
+        assert!(available > 0);
+    }
+
+    #[tokio::test]
+    async fn test_allocation_refused_when_the_disk_is_too_small() {
+        let dir = tempfile::tempdir().unwrap();
+        let info = TorrentInfo::new(
+            "huge".to_string(),
+            1 << 24,
+            vec![[0u8; 20]; 1 << 20],
+            vec![FileInfo::new(vec!["huge.bin".to_string()], 1 << 44)],
+        );
+
+        let mut manager = FileManager::new(info, dir.path().to_path_buf(), 4);
+        manager.initialize().await.unwrap();
+        assert!(matches!(
+            manager.allocate_files().await,
+            Err(TorrentError::File(FileError::InsufficientSpace))
+        ));
+        assert!(!manager.files_allocated());
+        assert!(!dir.path().join("huge.bin").exists());
+    }
+
+    #[tokio::test]
+    async fn test_verification_report_groups_failures_by_file() {
+        use sha1::{Digest, Sha1};
+
+        //=== Pieces of 4 over files of 6, 6 and 4 bytes: piece 1 spans a and b ===//
+        let data: Vec<u8> = (1..=16u8).collect();
+        let pieces = data
+            .chunks(4)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let info = TorrentInfo::new(
+            "report".to_string(),
+            4,
+            pieces,
+            vec![
+                FileInfo::new(vec!["a".to_string()], 6),
+                FileInfo::new(vec!["b".to_string()], 6),
+                FileInfo::new(vec!["c".to_string()], 4),
+            ],
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = FileManager::new(info, dir.path().to_path_buf(), 4);
+        for (piece, chunk) in data.chunks(4).enumerate() {
+            assert!(manager
+                .piece_manager_mut()
+                .add_piece_data(piece as PieceIndex, chunk.to_vec())
+                .unwrap());
+        }
+        for piece in [1, 3] {
+            let held = manager.piece_manager_mut().get_piece_mut(piece).unwrap();
+            held.data.as_mut().unwrap()[0] ^= 0xff;
+        }
+
+        let report = manager.verify_integrity().await.unwrap();
+        assert_eq!((report.checked, report.passed), (4, 2));
+        assert_eq!(report.failed, vec![1, 3]);
+        assert_eq!(report.bytes_verified, 8);
+        assert_eq!(
+            report.failed_by_file.into_iter().collect::<Vec<_>>(),
+            vec![(0, vec![1]), (1, vec![1]), (2, vec![3])]
+        );
+        assert_eq!(manager.piece_manager().missing_pieces(), vec![1, 3]);
+    }
+
+    #[tokio::test]
+    async fn test_flushed_pieces_are_verified_against_disk() {
+        use sha1::{Digest, Sha1};
+
+        let data: Vec<u8> = (1..=12u8).collect();
+        let pieces = data
+            .chunks(4)
+            .map(|chunk| Sha1::digest(chunk).into())
+            .collect();
+        let info = TorrentInfo::new(
+            "flushed".to_string(),
+            4,
+            pieces,
+            vec![FileInfo::new(vec!["a".to_string()], 12)],
+        );
+        let dir = tempfile::tempdir().unwrap();
+        let mut manager = FileManager::new(info, dir.path().to_path_buf(), 0);
+        manager.initialize().await.unwrap();
+        for (piece, chunk) in data.chunks(4).enumerate() {
+            assert!(manager
+                .piece_manager_mut()
+                .add_piece_data(piece as PieceIndex, chunk.to_vec())
+                .unwrap());
+        }
+
+        //=== Once flushed, the bytes leave memory ===//
+        manager.flush_to_disk().await.unwrap();
+        assert_eq!(manager.piece_manager().unflushed_bytes(), 0);
+        assert_eq!(manager.piece_manager().flushed_pieces(), vec![0, 1, 2]);
+
+        let mut on_disk = data.clone();
+        on_disk[5] ^= 0xff;
+        tokio::fs::write(dir.path().join("a"), &on_disk)
+            .await
+            .unwrap();
+        let report = manager.verify_integrity().await.unwrap();
+        assert_eq!((report.checked, report.passed), (3, 2));
+        assert_eq!(report.failed, vec![1]);
+        assert_eq!(manager.piece_manager().missing_pieces(), vec![1]);
+    }
+}
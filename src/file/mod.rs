@@ -1,7 +1,15 @@
+pub mod bencode;
+pub mod dedup;
+pub mod magnet;
 pub mod manager;
+pub mod persist;
 pub mod piece_manager;
 pub mod torrent_parser;
 
+pub use bencode::BencodeValue;
+pub use dedup::*;
+pub use magnet::*;
 pub use manager::*;
+pub use persist::*;
 pub use piece_manager::*;
 pub use torrent_parser::*;
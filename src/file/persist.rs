@@ -0,0 +1,107 @@
+use crate::core::{FileError, Result, TorrentError};
+use sha1::{Digest, Sha1};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+//=== Header of every state file: magic, format version, payload length, payload SHA-1 ===//
+pub const STATE_FILE_MAGIC: &[u8; 4] = b"FSST";
+pub const STATE_FILE_VERSION: u32 = 1;
+const HEADER_LEN: usize = 4 + 4 + 8 + 20;
+
+//=== Write `bytes` so a crash leaves either the old file or the new one, never a mix ===//
+pub async fn persist_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut contents = Vec::with_capacity(HEADER_LEN + bytes.len());
+    contents.extend_from_slice(STATE_FILE_MAGIC);
+    contents.extend_from_slice(&STATE_FILE_VERSION.to_be_bytes());
+    contents.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    contents.extend_from_slice(&Sha1::digest(bytes));
+    contents.extend_from_slice(bytes);
+
+    let temp = temp_path(path);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(true)
+        .write(true)
+        .open(&temp)
+        .await?;
+    file.write_all(&contents).await?;
+    file.sync_all().await?;
+    drop(file);
+
+    fs::rename(&temp, path).await?;
+
+    //=== Make the rename itself durable; not every platform can open a directory ===//
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if let Ok(dir) = fs::File::open(parent).await {
+            let _ = dir.sync_all().await;
+        }
+    }
+    Ok(())
+}
+
+//=== Read back a `persist_atomic` file, rejecting truncated, corrupt or other-version ones ===//
+pub async fn load_persisted(path: &Path) -> Result<Vec<u8>> {
+    let contents = fs::read(path).await.map_err(|_| {
+        TorrentError::File(FileError::NotFound {
+            path: path.to_string_lossy().to_string(),
+        })
+    })?;
+
+    if contents.len() < HEADER_LEN || &contents[..4] != STATE_FILE_MAGIC {
+        return Err(TorrentError::File(FileError::InvalidFormat));
+    }
+    let version = u32::from_be_bytes(contents[4..8].try_into().unwrap());
+    if version != STATE_FILE_VERSION {
+        return Err(TorrentError::File(FileError::InvalidFormat));
+    }
+
+    let length = u64::from_be_bytes(contents[8..16].try_into().unwrap());
+    let payload = &contents[HEADER_LEN..];
+    if payload.len() as u64 != length || Sha1::digest(payload)[..] != contents[16..HEADER_LEN] {
+        return Err(TorrentError::File(FileError::Corruption));
+    }
+
+    Ok(payload.to_vec())
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_truncated_state_file_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+        let payload = br#"{"key":"abcd","trackers":[]}"#;
+
+        persist_atomic(&path, payload).await.unwrap();
+        assert_eq!(load_persisted(&path).await.unwrap(), payload);
+        assert!(!temp_path(&path).exists());
+
+        //=== A crash mid-write would leave a short file ===//
+        let contents = fs::read(&path).await.unwrap();
+        fs::write(&path, &contents[..contents.len() - 5])
+            .await
+            .unwrap();
+        assert!(matches!(
+            load_persisted(&path).await,
+            Err(TorrentError::File(FileError::Corruption))
+        ));
+
+        //=== Files from another format version are not trusted either ===//
+        let mut other_version = contents.clone();
+        other_version[4..8].copy_from_slice(&(STATE_FILE_VERSION + 1).to_be_bytes());
+        fs::write(&path, &other_version).await.unwrap();
+        assert!(matches!(
+            load_persisted(&path).await,
+            Err(TorrentError::File(FileError::InvalidFormat))
+        ));
+    }
+}
@@ -1,20 +1,196 @@
 use crate::core::{
-    Bitfield, FileError, Hash, Piece, PieceIndex, Result, TorrentError, ValidationError,
+    Bitfield, BlockLength, BlockOffset, CancellationToken, FileError, Hash, PeerId, Piece,
+    PieceIndex, ProtocolError, Result, TorrentError, TorrentInfo, ValidationError, BLOCK_SIZE,
 };
-use std::collections::HashMap;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use tokio::sync::Notify;
 
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
 
+//=== Outcome of re-hashing the pieces we hold ===//
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct VerificationReport {
+    pub checked: usize,
+    pub passed: usize,
+    pub failed: Vec<PieceIndex>,
+    // Bytes of the pieces that passed //
+    pub bytes_verified: u64,
+    // Failed pieces under the index of each file they hold bytes of; filled by `FileManager` //
+    pub failed_by_file: BTreeMap<usize, Vec<PieceIndex>>,
+}
+
+impl VerificationReport {
+    pub fn is_clean(&self) -> bool {
+        self.failed.is_empty()
+    }
+}
+
+//=== Pieces of `data` that don't match `pieces`, laid out `piece_length` bytes apart ===//
+//=== A piece the buffer is too short to cover fails; bytes past the last piece are ignored ===//
+pub fn verify_bytes_against(pieces: &[Hash], piece_length: u32, data: &[u8]) -> Vec<PieceIndex> {
+    use sha1::{Digest, Sha1};
+
+    let piece_length = piece_length as usize;
+    let mut chunks = data.chunks(piece_length.max(1));
+    (0..pieces.len())
+        .filter(|&index| {
+            let matches = chunks.next().is_some_and(|chunk| {
+                let last = index + 1 == pieces.len();
+                (last || chunk.len() == piece_length) && Sha1::digest(chunk)[..] == pieces[index]
+            });
+            !matches
+        })
+        .map(|index| index as PieceIndex)
+        .collect()
+}
+
+//=== Write one piece's bytes at `offset` into the content, split across files as needed ===//
+pub async fn write_piece_at(
+    piece_data: &[u8],
+    offset: u64,
+    file_paths: &[String],
+    file_sizes: &[u64],
+) -> Result<()> {
+    let mut bytes_written = 0;
+    let mut file_index = 0;
+    let mut file_offset = offset;
+
+    //=== Write piece data to multiple files if necessary ===/
+    while bytes_written < piece_data.len() && file_index < file_paths.len() {
+        let file_path = &file_paths[file_index];
+        let file_size = file_sizes[file_index];
+
+        //=== Empty files are created on allocate; no piece writes into them ===//
+        if file_size == 0 {
+            file_index += 1;
+            continue;
+        }
+        if file_offset >= file_size {
+            file_offset -= file_size;
+            file_index += 1;
+            continue;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(file_path)
+            .await
+            .map_err(|_| {
+                TorrentError::File(FileError::PermissionDenied {
+                    path: file_path.clone(),
+                })
+            })?;
+
+        file.seek(SeekFrom::Start(file_offset)).await?;
+
+        let to_write = std::cmp::min(
+            piece_data.len() - bytes_written,
+            (file_size - file_offset) as usize,
+        );
+
+        let written = file
+            .write(&piece_data[bytes_written..bytes_written + to_write])
+            .await?;
+        //=== tokio finishes writes in the background; dropping the file can lose them ===//
+        file.flush().await?;
+        bytes_written += written;
+
+        if written == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+        }
+        if written == to_write {
+            file_offset = 0;
+            file_index += 1;
+        } else {
+            file_offset += written as u64;
+        }
+    }
+
+    Ok(())
+}
+
+//=== A piece still being downloaded: its blocks are written in place as they arrive ===//
+#[derive(Debug, Clone)]
+pub struct PartialPiece {
+    data: Vec<u8>,
+    // One bit per block, indexed by `offset / BLOCK_SIZE` //
+    received: Bitfield,
+}
+
+impl PartialPiece {
+    pub fn new(piece_size: u32) -> Self {
+        Self {
+            data: vec![0; piece_size as usize],
+            received: Bitfield::new(piece_size.div_ceil(BLOCK_SIZE) as usize),
+        }
+    }
+
+    //=== Store a block, returning false for a repeat; it must start and end on block bounds ===//
+    pub fn add_block(&mut self, offset: BlockOffset, block: &[u8]) -> Result<bool> {
+        let piece_size = self.data.len() as u32;
+        let expected = BLOCK_SIZE.min(piece_size.saturating_sub(offset));
+        if !offset.is_multiple_of(BLOCK_SIZE)
+            || offset >= piece_size
+            || block.len() != expected as usize
+        {
+            return Err(TorrentError::Protocol(ProtocolError::InvalidBlockRequest));
+        }
+
+        let index = offset / BLOCK_SIZE;
+        if self.received.has_piece(index) {
+            return Ok(false);
+        }
+        self.data[offset as usize..][..block.len()].copy_from_slice(block);
+        self.received.set_piece(index);
+        Ok(true)
+    }
+
+    pub fn has_block(&self, offset: BlockOffset) -> bool {
+        offset.is_multiple_of(BLOCK_SIZE) && self.received.has_piece(offset / BLOCK_SIZE)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.received.is_complete()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.received.count_pieces() == 0
+    }
+
+    //=== Bytes held for the whole piece, however many blocks are in ===//
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+}
+
 #[derive(Debug)]
 //=== All pieces for the torrent ===//
 pub struct PieceManager {
     pieces: HashMap<PieceIndex, Piece>,
+    // Expected hashes in piece order //
+    hashes: Vec<Hash>,
     bitfield: Bitfield,
     piece_length: u32,
     num_pieces: usize,
+    // Content length, so the last piece and its last block come out short //
+    total_size: u64,
+    // Blocks received of pieces not yet whole, one bit per block; the bytes live elsewhere //
+    partial_pieces: HashMap<PieceIndex, Bitfield>,
+    // Bytes of pieces assembled here through `add_block`, when no pipeline holds them //
+    assembling: HashMap<PieceIndex, PartialPiece>,
     piece_cache: HashMap<PieceIndex, Vec<u8>>,
     cache_size: usize,
+    piece_sources: HashMap<PieceIndex, HashSet<PeerId>>,
+    // Woken whenever the matching piece verifies //
+    completions: Vec<Arc<Notify>>,
 }
 
 impl PieceManager {
@@ -22,20 +198,32 @@ impl PieceManager {
         let num_pieces = piece_hashes.len();
         let mut pieces = HashMap::new();
 
-        for (index, hash) in piece_hashes.into_iter().enumerate() {
+        for (index, &hash) in piece_hashes.iter().enumerate() {
             pieces.insert(index as PieceIndex, Piece::new(index as PieceIndex, hash));
         }
 
         Self {
             pieces,
+            hashes: piece_hashes,
             bitfield: Bitfield::new(num_pieces),
             piece_length,
             num_pieces,
+            total_size: num_pieces as u64 * piece_length as u64,
+            partial_pieces: HashMap::new(),
+            assembling: HashMap::new(),
             piece_cache: HashMap::new(),
             cache_size,
+            piece_sources: HashMap::new(),
+            completions: (0..num_pieces).map(|_| Arc::new(Notify::new())).collect(),
         }
     }
 
+    //=== Set the content length when the last piece isn't full ===//
+    pub fn with_total_size(mut self, total_size: u64) -> Self {
+        self.total_size = total_size;
+        self
+    }
+
     //=== Get the bitfield representing completed pieces ===//
     pub fn bitfield(&self) -> &Bitfield {
         &self.bitfield
@@ -46,6 +234,9 @@ impl PieceManager {
     pub fn piece_length(&self) -> u32 {
         self.piece_length
     }
+    pub fn hashes(&self) -> &[Hash] {
+        &self.hashes
+    }
 
     pub fn is_valid_piece(&self, piece_index: PieceIndex) -> bool {
         (piece_index as usize) < self.num_pieces
@@ -61,11 +252,14 @@ impl PieceManager {
         self.pieces.get_mut(&piece_index)
     }
 
-    //==  Add piece data and verify it ==//
+    //==  Add piece data and verify it; Ok(true) only when it completes the piece ==//
     pub fn add_piece_data(&mut self, piece_index: PieceIndex, data: Vec<u8>) -> Result<bool> {
         if !self.is_valid_piece(piece_index) {
             return Err(TorrentError::Validation(ValidationError::InvalidHash));
         }
+        if self.has_piece(piece_index) {
+            return Ok(false);
+        }
 
         let piece =
             self.pieces
@@ -75,9 +269,60 @@ impl PieceManager {
                 }))?;
 
         let verified = piece.set_data(data.clone());
+        self.partial_pieces.remove(&piece_index);
+        self.assembling.remove(&piece_index);
 
         if verified {
-            self.bitfield.set_piece(piece_index);
+            self.piece_completed(piece_index, Some(data));
+        } else if let Some(sources) = self.piece_sources.remove(&piece_index) {
+            //== A failed piece is downloaded afresh, so its contributors start over ==//
+            crate::logging::debug!(
+                "Piece {} failed verification, supplied by {} peer(s)",
+                piece_index,
+                sources.len()
+            );
+        }
+
+        Ok(verified)
+    }
+
+    //== Record a piece already hash-checked and written to disk; only the cache keeps its bytes ==//
+    pub fn store_verified_piece(&mut self, piece_index: PieceIndex, data: Vec<u8>) -> Result<()> {
+        let piece =
+            self.pieces
+                .get_mut(&piece_index)
+                .ok_or(TorrentError::File(FileError::NotFound {
+                    path: format!("piece {}", piece_index),
+                }))?;
+
+        piece.data = None;
+        piece.verified = true;
+        self.partial_pieces.remove(&piece_index);
+        self.assembling.remove(&piece_index);
+        self.piece_completed(piece_index, Some(data));
+        Ok(())
+    }
+
+    //== Record a piece whose bytes were checked where they already lie on disk ==//
+    pub fn mark_piece_on_disk(&mut self, piece_index: PieceIndex) -> Result<()> {
+        let piece =
+            self.pieces
+                .get_mut(&piece_index)
+                .ok_or(TorrentError::File(FileError::NotFound {
+                    path: format!("piece {}", piece_index),
+                }))?;
+
+        piece.data = None;
+        piece.verified = true;
+        self.partial_pieces.remove(&piece_index);
+        self.assembling.remove(&piece_index);
+        self.piece_completed(piece_index, None);
+        Ok(())
+    }
+
+    fn piece_completed(&mut self, piece_index: PieceIndex, data: Option<Vec<u8>>) {
+        self.bitfield.set_piece(piece_index);
+        if let Some(data) = data {
             if self.piece_cache.len() >= self.cache_size {
                 if let Some(oldest_key) = self.piece_cache.keys().next().copied() {
                     self.piece_cache.remove(&oldest_key);
@@ -85,8 +330,121 @@ impl PieceManager {
             }
             self.piece_cache.insert(piece_index, data);
         }
+        self.completions[piece_index as usize].notify_waiters();
+    }
 
-        Ok(verified)
+    pub fn piece_size(&self, piece_index: PieceIndex) -> u32 {
+        TorrentInfo::piece_size_of(self.total_size, self.piece_length, piece_index)
+    }
+
+    //=== Note a block as arrived; whoever assembles the piece keeps its bytes ===//
+    pub fn mark_block_received(&mut self, piece_index: PieceIndex, offset: BlockOffset) {
+        let piece_size = self.piece_size(piece_index);
+        if !self.is_valid_piece(piece_index)
+            || self.has_piece(piece_index)
+            || !offset.is_multiple_of(BLOCK_SIZE)
+            || offset >= piece_size
+        {
+            return;
+        }
+        self.partial_pieces
+            .entry(piece_index)
+            .or_insert_with(|| Bitfield::new(piece_size.div_ceil(BLOCK_SIZE) as usize))
+            .set_piece(offset / BLOCK_SIZE);
+    }
+
+    //=== Store one block; once the piece is whole it is hashed, and Some(verified) returned ===//
+    //=== A piece that fails is discarded, so its blocks are needed again ===//
+    pub fn add_block(
+        &mut self,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+        data: &[u8],
+    ) -> Result<Option<bool>> {
+        if !self.is_valid_piece(piece_index) {
+            return Err(TorrentError::Protocol(ProtocolError::InvalidPieceIndex {
+                index: piece_index,
+            }));
+        }
+        if self.has_piece(piece_index) {
+            return Ok(None);
+        }
+
+        let piece_size = self.piece_size(piece_index);
+        let partial = self
+            .assembling
+            .entry(piece_index)
+            .or_insert_with(|| PartialPiece::new(piece_size));
+        partial.add_block(offset, data)?;
+        let complete = partial.is_complete();
+        self.mark_block_received(piece_index, offset);
+        if !complete {
+            return Ok(None);
+        }
+
+        let data = self
+            .assembling
+            .remove(&piece_index)
+            .map(PartialPiece::into_data)
+            .unwrap_or_default();
+        self.add_piece_data(piece_index, data).map(Some)
+    }
+
+    //=== Whether the block arrived since the piece was last discarded ===//
+    pub fn has_block(&self, piece_index: PieceIndex, offset: BlockOffset) -> bool {
+        offset.is_multiple_of(BLOCK_SIZE)
+            && self
+                .partial_pieces
+                .get(&piece_index)
+                .is_some_and(|received| received.has_piece(offset / BLOCK_SIZE))
+    }
+
+    //=== Verified, or every block in and hashed correctly, which is the same thing here ===//
+    pub fn is_piece_complete(&self, piece_index: PieceIndex) -> bool {
+        self.has_piece(piece_index)
+    }
+
+    //=== The first block of a piece that hasn't arrived, as (offset, length) ===//
+    pub fn next_needed_block(&self, piece_index: PieceIndex) -> Option<(BlockOffset, BlockLength)> {
+        self.next_request_for_piece(piece_index, &HashSet::new())
+    }
+
+    //=== Next block of a piece neither received nor in `in_flight`, as (offset, length) ===//
+    pub fn next_request_for_piece(
+        &self,
+        piece_index: PieceIndex,
+        in_flight: &HashSet<BlockOffset>,
+    ) -> Option<(BlockOffset, BlockLength)> {
+        if !self.is_valid_piece(piece_index) || self.has_piece(piece_index) {
+            return None;
+        }
+
+        let piece_size = self.piece_size(piece_index);
+        (0..piece_size)
+            .step_by(BLOCK_SIZE as usize)
+            .find(|&offset| !in_flight.contains(&offset) && !self.has_block(piece_index, offset))
+            .map(|offset| (offset, BLOCK_SIZE.min(piece_size - offset)))
+    }
+
+    //=== Record that a peer supplied a block of the given piece ===//
+    pub fn record_block_source(&mut self, piece_index: PieceIndex, peer_id: PeerId) {
+        if self.is_valid_piece(piece_index) {
+            self.piece_sources
+                .entry(piece_index)
+                .or_default()
+                .insert(peer_id);
+        }
+    }
+
+    //=== Peers that contributed blocks to the given piece ===//
+    pub fn piece_sources(&self, piece_index: PieceIndex) -> Vec<PeerId> {
+        let mut sources: Vec<PeerId> = self
+            .piece_sources
+            .get(&piece_index)
+            .map(|peers| peers.iter().copied().collect())
+            .unwrap_or_default();
+        sources.sort();
+        sources
     }
 
     //=== Get piece data from cache or piece storage ===//
@@ -106,6 +464,11 @@ impl PieceManager {
     pub fn evict_from_cache(&mut self, piece_index: PieceIndex) {
         self.piece_cache.remove(&piece_index);
     }
+    //=== Notified each time `piece_index` verifies; None for indices outside the torrent ===//
+    pub fn completion_notify(&self, piece_index: PieceIndex) -> Option<Arc<Notify>> {
+        self.completions.get(piece_index as usize).cloned()
+    }
+
     pub fn missing_pieces(&self) -> Vec<PieceIndex> {
         self.bitfield.missing_pieces()
     }
@@ -120,22 +483,90 @@ impl PieceManager {
     }
 
     //== Verify all completed pieces ==//
-    pub fn verify_all_pieces(&mut self) -> Result<Vec<PieceIndex>> {
-        let mut failed_pieces = Vec::new();
+    pub fn verify_all_pieces(&mut self) -> Result<VerificationReport> {
+        let mut report = VerificationReport::default();
 
         for piece_index in self.completed_pieces() {
-            if let Some(piece) = self.pieces.get_mut(&piece_index) {
-                if !piece.verify() {
-                    failed_pieces.push(piece_index);
-                    self.bitfield.unset_piece(piece_index);
-                    piece.data = None;
-                    piece.verified = false;
-                    self.piece_cache.remove(&piece_index);
-                }
-            }
+            self.reverify_piece(piece_index, &mut report);
+        }
+
+        Ok(report)
+    }
+
+    //=== As `verify_all_pieces`, yielding between pieces; a cancel stops at a piece boundary ===//
+    pub async fn verify_all_pieces_cancellable(
+        &mut self,
+        cancel: &CancellationToken,
+    ) -> Result<VerificationReport> {
+        let mut report = VerificationReport::default();
+
+        for piece_index in self.completed_pieces() {
+            cancel.check()?;
+            self.reverify_piece(piece_index, &mut report);
+            tokio::task::yield_now().await;
+        }
+
+        Ok(report)
+    }
+
+    //=== Re-hash one held piece into `report`, dropping it if it no longer matches ===//
+    //=== Pieces already flushed are skipped; `FileManager` checks those against disk ===//
+    fn reverify_piece(&mut self, piece_index: PieceIndex, report: &mut VerificationReport) {
+        let Some(piece) = self.pieces.get_mut(&piece_index) else {
+            return;
+        };
+        let Some(length) = piece.data.as_ref().map(|data| data.len() as u64) else {
+            return;
+        };
+        report.checked += 1;
+        if piece.verify() {
+            report.passed += 1;
+            report.bytes_verified += length;
+            return;
+        }
+        self.drop_piece(piece_index);
+        report.failed.push(piece_index);
+    }
+
+    //=== Held pieces whose bytes live only on disk ===//
+    pub fn flushed_pieces(&self) -> Vec<PieceIndex> {
+        self.completed_pieces()
+            .into_iter()
+            .filter(|piece_index| {
+                self.pieces
+                    .get(piece_index)
+                    .is_some_and(|piece| piece.data.is_none())
+            })
+            .collect()
+    }
+
+    //=== Check a flushed piece against `data` read back from disk, dropping it on a mismatch ===//
+    pub fn reverify_flushed_piece(
+        &mut self,
+        piece_index: PieceIndex,
+        data: Option<&[u8]>,
+        report: &mut VerificationReport,
+    ) {
+        use sha1::{Digest, Sha1};
+
+        report.checked += 1;
+        let expected = self.hashes.get(piece_index as usize);
+        if let Some(data) = data.filter(|data| expected == Some(&Sha1::digest(data).into())) {
+            report.passed += 1;
+            report.bytes_verified += data.len() as u64;
+            return;
         }
+        self.drop_piece(piece_index);
+        report.failed.push(piece_index);
+    }
 
-        Ok(failed_pieces)
+    fn drop_piece(&mut self, piece_index: PieceIndex) {
+        self.bitfield.unset_piece(piece_index);
+        if let Some(piece) = self.pieces.get_mut(&piece_index) {
+            piece.data = None;
+            piece.verified = false;
+        }
+        self.piece_cache.remove(&piece_index);
     }
 
     //== Load pieces from file system ==//
@@ -143,16 +574,24 @@ impl PieceManager {
         &mut self,
         file_paths: &[String],
         file_sizes: &[u64],
+    ) -> Result<()> {
+        self.load_from_files_cancellable(file_paths, file_sizes, &CancellationToken::new())
+            .await
+    }
+
+    //=== Pieces read before a cancel stay loaded and verified; the rest are left missing ===//
+    pub async fn load_from_files_cancellable(
+        &mut self,
+        file_paths: &[String],
+        file_sizes: &[u64],
+        cancel: &CancellationToken,
     ) -> Result<()> {
         let mut current_offset = 0u64;
+        let total_size: u64 = file_sizes.iter().sum();
 
         for piece_index in 0..self.num_pieces as PieceIndex {
-            let piece_size = if piece_index == (self.num_pieces - 1) as PieceIndex {
-                let total_size: u64 = file_sizes.iter().sum();
-                (total_size - (piece_index as u64 * self.piece_length as u64)) as u32
-            } else {
-                self.piece_length
-            };
+            cancel.check()?;
+            let piece_size = TorrentInfo::piece_size_of(total_size, self.piece_length, piece_index);
 
             let mut piece_data = vec![0u8; piece_size as usize];
             let mut bytes_read = 0;
@@ -163,6 +602,11 @@ impl PieceManager {
                 let file_path = &file_paths[file_index];
                 let file_size = file_sizes[file_index];
 
+                //=== Empty files hold no piece bytes; they need not even exist yet ===//
+                if file_size == 0 {
+                    file_index += 1;
+                    continue;
+                }
                 if file_offset >= file_size {
                     file_offset -= file_size;
                     file_index += 1;
@@ -187,6 +631,10 @@ impl PieceManager {
                     .await?;
                 bytes_read += read;
 
+                //=== The file is shorter on disk than the torrent says; the piece is missing ===//
+                if read == 0 {
+                    break;
+                }
                 if read == to_read {
                     file_offset = 0;
                     file_index += 1;
@@ -195,8 +643,11 @@ impl PieceManager {
                 }
             }
 
-            if bytes_read == piece_size as usize {
-                self.add_piece_data(piece_index, piece_data)?;
+            //=== What verifies came from disk, so only the cache keeps a copy ===//
+            if bytes_read == piece_size as usize && self.add_piece_data(piece_index, piece_data)? {
+                if let Some(piece) = self.pieces.get_mut(&piece_index) {
+                    piece.data = None;
+                }
             }
 
             current_offset += piece_size as u64;
@@ -206,67 +657,45 @@ impl PieceManager {
     }
 
     //=== Write pieces to file system ===//
-    pub async fn write_to_files(&self, file_paths: &[String], file_sizes: &[u64]) -> Result<()> {
-        let mut current_offset = 0u64;
+    pub async fn write_to_files(
+        &mut self,
+        file_paths: &[String],
+        file_sizes: &[u64],
+    ) -> Result<()> {
+        self.write_to_files_cancellable(file_paths, file_sizes, &CancellationToken::new())
+            .await
+    }
 
+    //=== A cancel lands between pieces, so no piece is left half written ===//
+    //=== Written pieces give up their bytes; only the cache keeps a copy ===//
+    pub async fn write_to_files_cancellable(
+        &mut self,
+        file_paths: &[String],
+        file_sizes: &[u64],
+        cancel: &CancellationToken,
+    ) -> Result<()> {
         for piece_index in 0..self.num_pieces as PieceIndex {
+            cancel.check()?;
             if !self.has_piece(piece_index) {
                 continue;
             }
+            //=== Missing pieces leave gaps, so place each piece by its index ===//
+            let current_offset = piece_index as u64 * self.piece_length as u64;
 
-            let piece_data = self.get_piece_data(piece_index).ok_or(TorrentError::File(
-                FileError::NotFound {
-                    path: format!("piece {}", piece_index),
-                },
-            ))?;
-
-            let mut bytes_written = 0;
-            let mut file_index = 0;
-            let mut file_offset = current_offset;
-
-            //=== Write piece data to multiple files if necessary ===/
-            while bytes_written < piece_data.len() && file_index < file_paths.len() {
-                let file_path = &file_paths[file_index];
-                let file_size = file_sizes[file_index];
-
-                if file_offset >= file_size {
-                    file_offset -= file_size;
-                    file_index += 1;
-                    continue;
-                }
-
-                let mut file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(file_path)
-                    .await
-                    .map_err(|_| {
-                        TorrentError::File(FileError::PermissionDenied {
-                            path: file_path.clone(),
-                        })
-                    })?;
-
-                file.seek(SeekFrom::Start(file_offset)).await?;
-
-                let to_write = std::cmp::min(
-                    piece_data.len() - bytes_written,
-                    (file_size - file_offset) as usize,
-                );
-
-                let written = file
-                    .write(&piece_data[bytes_written..bytes_written + to_write])
-                    .await?;
-                bytes_written += written;
+            let Some(piece) = self.pieces.get_mut(&piece_index) else {
+                continue;
+            };
+            //=== Flushed before, or stored after the pipeline wrote it ===//
+            let Some(piece_data) = piece.data.take() else {
+                continue;
+            };
 
-                if written == to_write {
-                    file_offset = 0;
-                    file_index += 1;
-                } else {
-                    file_offset += written as u64;
-                }
+            if let Err(e) =
+                write_piece_at(&piece_data, current_offset, file_paths, file_sizes).await
+            {
+                piece.data = Some(piece_data);
+                return Err(e);
             }
-
-            current_offset += piece_data.len() as u64;
         }
 
         Ok(())
@@ -285,8 +714,293 @@ impl PieceManager {
         (cache_used, cache_total, hit_rate)
     }
 
+    pub fn cache_bytes(&self) -> usize {
+        self.piece_cache.values().map(Vec::len).sum()
+    }
+    //=== Bytes of verified pieces not yet flushed, on top of the cache ===//
+    pub fn unflushed_bytes(&self) -> usize {
+        self.pieces
+            .values()
+            .filter_map(|piece| piece.data.as_ref())
+            .map(Vec::len)
+            .sum()
+    }
+
+    //=== Evict cached pieces until at most `max_bytes` remain, returning how many went ===//
+    pub fn shrink_cache_to(&mut self, max_bytes: usize) -> usize {
+        let mut evicted = 0;
+        while self.cache_bytes() > max_bytes {
+            let Some(oldest_key) = self.piece_cache.keys().next().copied() else {
+                break;
+            };
+            self.piece_cache.remove(&oldest_key);
+            evicted += 1;
+        }
+        evicted
+    }
+
     //=== Clear the piece cache ===//
     pub fn clear_cache(&mut self) {
         self.piece_cache.clear();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha1::{Digest, Sha1};
+    use std::time::Duration;
+
+    fn hash_of(data: &[u8]) -> Hash {
+        let mut hasher = Sha1::new();
+        hasher.update(data);
+        hasher.finalize().into()
+    }
+
+    #[test]
+    fn test_next_request_ends_with_short_final_block() {
+        //=== Three pieces of two blocks; the last piece is one block and 100 bytes ===//
+        let piece_length = 2 * BLOCK_SIZE;
+        let total = 2 * piece_length as u64 + BLOCK_SIZE as u64 + 100;
+        let mut manager =
+            PieceManager::new(vec![[0u8; 20]; 3], piece_length, 10).with_total_size(total);
+
+        let none = HashSet::new();
+        assert_eq!(
+            manager.next_request_for_piece(0, &none),
+            Some((0, BLOCK_SIZE))
+        );
+        assert_eq!(
+            manager.next_request_for_piece(2, &none),
+            Some((0, BLOCK_SIZE))
+        );
+
+        let pending = HashSet::from([0]);
+        assert_eq!(
+            manager.next_request_for_piece(2, &pending),
+            Some((BLOCK_SIZE, 100))
+        );
+
+        manager.mark_block_received(2, BLOCK_SIZE);
+        assert_eq!(manager.next_request_for_piece(2, &pending), None);
+        assert_eq!(
+            manager.next_request_for_piece(2, &none),
+            Some((0, BLOCK_SIZE))
+        );
+        assert_eq!(manager.next_request_for_piece(3, &none), None);
+    }
+
+    #[test]
+    fn test_partial_piece_assembles_blocks_in_any_order() {
+        let piece_size = 2 * BLOCK_SIZE + 500;
+        let good: Vec<u8> = (0..piece_size).map(|i| (i % 251) as u8).collect();
+        let block = |offset: u32| {
+            let end = (offset + BLOCK_SIZE).min(piece_size);
+            &good[offset as usize..end as usize]
+        };
+        let mut partial = PartialPiece::new(piece_size);
+        assert!(partial.is_empty());
+
+        //=== Out of order is fine and a repeat changes nothing ===//
+        assert!(partial
+            .add_block(2 * BLOCK_SIZE, block(2 * BLOCK_SIZE))
+            .unwrap());
+        assert!(partial.add_block(0, block(0)).unwrap());
+        assert!(!partial.add_block(0, block(0)).unwrap());
+        assert!(partial.has_block(0) && !partial.has_block(BLOCK_SIZE));
+        assert!(!partial.is_complete());
+
+        //=== The short last block must be exactly as long as what is left ===//
+        assert!(partial.add_block(2 * BLOCK_SIZE, &good[..499]).is_err());
+        assert!(partial.add_block(100, block(0)).is_err());
+        assert!(partial.add_block(piece_size, block(0)).is_err());
+
+        assert!(partial.add_block(BLOCK_SIZE, block(BLOCK_SIZE)).unwrap());
+        assert!(partial.is_complete());
+        assert_eq!(partial.size(), piece_size as usize);
+        assert_eq!(partial.into_data(), good);
+    }
+
+    #[test]
+    fn test_failed_piece_forgets_its_received_blocks() {
+        let good = vec![1u8; 2 * BLOCK_SIZE as usize];
+        let mut manager = PieceManager::new(vec![hash_of(&good)], 2 * BLOCK_SIZE, 10);
+
+        manager.mark_block_received(0, BLOCK_SIZE);
+        manager.mark_block_received(0, 1);
+        assert!(manager.has_block(0, BLOCK_SIZE));
+        assert!(!manager.has_block(0, 0));
+        assert_eq!(manager.next_needed_block(0), Some((0, BLOCK_SIZE)));
+        manager.mark_block_received(0, 0);
+        assert_eq!(manager.next_needed_block(0), None);
+
+        //=== Both blocks are wanted again, and a clean copy verifies ===//
+        assert!(!manager.add_piece_data(0, vec![2u8; good.len()]).unwrap());
+        assert!(!manager.has_block(0, BLOCK_SIZE));
+        assert_eq!(manager.next_needed_block(0), Some((0, BLOCK_SIZE)));
+        assert!(manager.add_piece_data(0, good.clone()).unwrap());
+        assert!(manager.is_piece_complete(0));
+        assert_eq!(manager.get_piece_data(0), Some(&good));
+
+        //=== A second copy completes nothing ===//
+        assert!(!manager.add_piece_data(0, good.clone()).unwrap());
+
+        //=== Late blocks of a complete piece aren't tracked ===//
+        manager.mark_block_received(0, 0);
+        assert!(!manager.has_block(0, 0));
+    }
+
+    #[test]
+    fn test_blocks_assemble_into_a_verified_piece() {
+        let piece_length = 2 * BLOCK_SIZE;
+        let good: Vec<u8> = (0..piece_length).map(|i| (i % 251) as u8).collect();
+        let last = vec![9u8; 500];
+        let total = piece_length as u64 + last.len() as u64;
+        let mut manager = PieceManager::new(vec![hash_of(&good), hash_of(&last)], piece_length, 10)
+            .with_total_size(total);
+
+        let block = |offset: u32| &good[offset as usize..(offset + BLOCK_SIZE) as usize];
+        assert_eq!(
+            manager.add_block(0, BLOCK_SIZE, block(BLOCK_SIZE)).unwrap(),
+            None
+        );
+        assert_eq!(
+            manager.add_block(0, BLOCK_SIZE, block(BLOCK_SIZE)).unwrap(),
+            None
+        );
+        assert_eq!(manager.next_needed_block(0), Some((0, BLOCK_SIZE)));
+        assert_eq!(manager.add_block(0, 0, block(0)).unwrap(), Some(true));
+        assert_eq!(manager.get_piece_data(0), Some(&good));
+
+        //=== Misshapen blocks are refused; the short last piece is one short block ===//
+        assert!(manager.add_block(1, 0, &last[..499]).is_err());
+        assert!(manager.add_block(2, 0, &last).is_err());
+        assert_eq!(manager.add_block(1, 0, &last).unwrap(), Some(true));
+        assert!(manager.is_complete());
+    }
+
+    #[test]
+    fn test_corrupt_assembled_piece_is_requested_again() {
+        let good = vec![1u8; 2 * BLOCK_SIZE as usize];
+        let mut manager = PieceManager::new(vec![hash_of(&good)], 2 * BLOCK_SIZE, 10);
+        let bad = vec![2u8; BLOCK_SIZE as usize];
+
+        assert_eq!(
+            manager
+                .add_block(0, 0, &good[..BLOCK_SIZE as usize])
+                .unwrap(),
+            None
+        );
+        assert_eq!(manager.add_block(0, BLOCK_SIZE, &bad).unwrap(), Some(false));
+
+        //=== Both blocks are wanted again, and a clean copy verifies ===//
+        assert!(!manager.has_piece(0));
+        assert!(!manager.has_block(0, 0) && !manager.has_block(0, BLOCK_SIZE));
+        assert_eq!(manager.next_needed_block(0), Some((0, BLOCK_SIZE)));
+        assert_eq!(
+            manager
+                .add_block(0, BLOCK_SIZE, &good[..BLOCK_SIZE as usize])
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            manager
+                .add_block(0, 0, &good[..BLOCK_SIZE as usize])
+                .unwrap(),
+            Some(true)
+        );
+        assert!(manager.is_piece_complete(0));
+    }
+
+    #[test]
+    fn test_verify_bytes_reports_the_corrupt_piece() {
+        let mut data: Vec<u8> = (0..40u8).collect();
+        let hashes = data.chunks(16).map(hash_of).collect();
+        let manager = PieceManager::new(hashes, 16, 10);
+        assert!(verify_bytes_against(manager.hashes(), 16, &data).is_empty());
+
+        data[20] ^= 0xff;
+        assert_eq!(verify_bytes_against(manager.hashes(), 16, &data), vec![1]);
+
+        //=== A short buffer can't vouch for the pieces it doesn't reach ===//
+        assert_eq!(
+            verify_bytes_against(manager.hashes(), 16, &data[..30]),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn test_piece_sources_accumulate_across_blocks() {
+        let data = vec![7u8; 32];
+        let mut manager = PieceManager::new(vec![hash_of(&data)], 32, 10);
+        let (peer_a, peer_b) = ([1u8; 20], [2u8; 20]);
+
+        //=== Two blocks from one peer, one from another ===//
+        manager.record_block_source(0, peer_a);
+        manager.record_block_source(0, peer_b);
+        manager.record_block_source(0, peer_a);
+
+        assert!(manager.add_piece_data(0, data).unwrap());
+        assert_eq!(manager.piece_sources(0), vec![peer_a, peer_b]);
+    }
+
+    #[test]
+    fn test_piece_sources_cleared_on_failed_verification() {
+        let mut manager = PieceManager::new(vec![hash_of(b"good")], 4, 10);
+        manager.record_block_source(0, [1u8; 20]);
+
+        assert!(!manager.add_piece_data(0, b"evil".to_vec()).unwrap());
+        assert!(manager.piece_sources(0).is_empty());
+
+        //=== Out-of-range pieces are ignored ===//
+        manager.record_block_source(5, [1u8; 20]);
+        assert!(manager.piece_sources(5).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_verify_stops_at_a_piece_boundary() {
+        let pieces: Vec<Vec<u8>> = (0..64u8).map(|i| vec![i; 1024]).collect();
+        let mut manager = PieceManager::new(pieces.iter().map(|p| hash_of(p)).collect(), 1024, 64);
+        for (index, piece) in pieces.into_iter().enumerate() {
+            assert!(manager.add_piece_data(index as PieceIndex, piece).unwrap());
+        }
+        //=== Rot everything on "disk", so every piece the verify reaches is dropped ===//
+        for piece in manager.pieces.values_mut() {
+            piece.data.as_mut().unwrap()[0] ^= 0xff;
+        }
+
+        let cancel = CancellationToken::new();
+        let verify = tokio::spawn({
+            let cancel = cancel.clone();
+            async move {
+                let result = manager.verify_all_pieces_cancellable(&cancel).await;
+                (manager, result)
+            }
+        });
+        tokio::task::yield_now().await;
+        cancel.cancel();
+
+        let (mut manager, result) = tokio::time::timeout(Duration::from_secs(1), verify)
+            .await
+            .expect("cancelled verify should return promptly")
+            .unwrap();
+        assert!(matches!(result, Err(TorrentError::Cancelled)));
+
+        //=== Each piece is either dropped whole or untouched, and some of each ===//
+        let dropped = manager.missing_pieces().len();
+        assert!(dropped > 0 && dropped < 64, "dropped {}", dropped);
+        for (index, piece) in &manager.pieces {
+            assert_eq!(manager.has_piece(*index), piece.data.is_some());
+            assert_eq!(manager.has_piece(*index), piece.verified);
+        }
+
+        //=== A fresh token picks up the rest ===//
+        let report = manager
+            .verify_all_pieces_cancellable(&CancellationToken::new())
+            .await
+            .unwrap();
+        assert_eq!(report.checked, 64 - dropped);
+        assert_eq!(report.failed.len(), 64 - dropped);
+        assert!(manager.completed_pieces().is_empty());
+    }
+}
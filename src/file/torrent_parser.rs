@@ -1,40 +1,402 @@
-use crate::core::{FileError, FileInfo, Hash, Result, TorrentError, TorrentInfo, ValidationError};
-use serde::{Deserialize, Serialize};
+use crate::core::{
+    FileError, FileInfo, Hash, Result, TorrentError, TorrentInfo, ValidationError, WebSeed,
+    WebSeedStyle,
+};
+use crate::file::bencode::{self, BencodeValue};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 
 //=== Raw torrent file structure as it appears in .torrent files ===//
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 struct RawTorrent {
     info: RawTorrentInfo,
     announce: Option<String>,
-    #[serde(rename = "announce-list")]
     announce_list: Option<Vec<Vec<String>>>,
     comment: Option<String>,
-    #[serde(rename = "created by")]
     created_by: Option<String>,
-    #[serde(rename = "creation date")]
     creation_date: Option<u64>,
+    encoding: Option<String>,
+    url_list: Option<UrlList>,
+    httpseeds: Option<Vec<String>>,
+    // DHT bootstrap nodes of a trackerless torrent, as `[host, port]` pairs //
+    nodes: Option<Vec<(String, u16)>>,
+}
+
+//=== BEP 19 allows a single URL as well as a list ===//
+#[derive(Debug, Clone)]
+enum UrlList {
+    One(String),
+    Many(Vec<String>),
 }
 
 //=== Raw info dictionary from torrent file ===//
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 struct RawTorrentInfo {
     name: String,
-    #[serde(rename = "piece length")]
     piece_length: u32,
-    pieces: serde_bytes::ByteBuf,
-    #[serde(default)]
+    // Optional here so headers can be inspected; the strict parse requires it //
+    pieces: Option<Vec<u8>>,
     private: u8,
     length: Option<u64>,
     files: Option<Vec<RawFileInfo>>,
     md5sum: Option<String>,
+    sha1: Option<Hash>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone)]
 struct RawFileInfo {
     length: u64,
     path: Vec<String>,
     md5sum: Option<String>,
+    sha1: Option<Hash>,
+}
+
+fn invalid() -> TorrentError {
+    TorrentError::Validation(ValidationError::InvalidTorrentInfo)
+}
+
+//=== Typed reads from a decoded dict; a key of the wrong type is as bad as garbage ===//
+struct Fields<'a>(&'a BTreeMap<Vec<u8>, BencodeValue>);
+
+impl<'a> Fields<'a> {
+    fn of(value: &'a BencodeValue) -> Result<Self> {
+        value.as_dict().map(Fields).ok_or_else(invalid)
+    }
+
+    fn get(&self, key: &str) -> Option<&'a BencodeValue> {
+        self.0.get(key.as_bytes())
+    }
+
+    fn required<T>(&self, key: &str, read: fn(&Self, &str) -> Result<Option<T>>) -> Result<T> {
+        read(self, key)?.ok_or_else(invalid)
+    }
+
+    fn int<T: TryFrom<i64>>(&self, key: &str) -> Result<Option<T>> {
+        self.get(key)
+            .map(|value| {
+                let value = value.as_int().ok_or_else(invalid)?;
+                T::try_from(value).map_err(|_| invalid())
+            })
+            .transpose()
+    }
+
+    fn bytes(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        self.get(key)
+            .map(|value| value.as_bytes().map(<[u8]>::to_vec).ok_or_else(invalid))
+            .transpose()
+    }
+
+    fn string(&self, key: &str) -> Result<Option<String>> {
+        self.get(key).map(string).transpose()
+    }
+
+    fn hash(&self, key: &str) -> Result<Option<Hash>> {
+        self.bytes(key)?
+            .map(|bytes| Hash::try_from(bytes.as_slice()).map_err(|_| invalid()))
+            .transpose()
+    }
+
+    fn strings(&self, key: &str) -> Result<Option<Vec<String>>> {
+        self.get(key).map(strings).transpose()
+    }
+}
+
+fn string(value: &BencodeValue) -> Result<String> {
+    value.as_str().map(str::to_string).ok_or_else(invalid)
+}
+
+fn strings(value: &BencodeValue) -> Result<Vec<String>> {
+    value
+        .as_list()
+        .ok_or_else(invalid)?
+        .iter()
+        .map(string)
+        .collect()
+}
+
+fn string_list(values: &[String]) -> BencodeValue {
+    BencodeValue::List(values.iter().map(|value| value.as_str().into()).collect())
+}
+
+//=== Set `key` when there is a value; absent fields are left out, not written empty ===//
+fn put(dict: &mut BTreeMap<Vec<u8>, BencodeValue>, key: &str, value: Option<BencodeValue>) {
+    if let Some(value) = value {
+        dict.insert(key.as_bytes().to_vec(), value);
+    }
+}
+
+impl RawTorrent {
+    fn from_bencode(value: &BencodeValue) -> Result<Self> {
+        let fields = Fields::of(value)?;
+        let info = RawTorrentInfo::from_bencode(fields.get("info").ok_or_else(invalid)?)?;
+
+        let announce_list = fields
+            .get("announce-list")
+            .map(|tiers| {
+                tiers
+                    .as_list()
+                    .ok_or_else(invalid)?
+                    .iter()
+                    .map(strings)
+                    .collect()
+            })
+            .transpose()?;
+        let url_list = match fields.get("url-list") {
+            None => None,
+            Some(value @ BencodeValue::Bytes(_)) => Some(UrlList::One(string(value)?)),
+            Some(value) => Some(UrlList::Many(strings(value)?)),
+        };
+        let nodes = fields
+            .get("nodes")
+            .map(|nodes| {
+                nodes
+                    .as_list()
+                    .ok_or_else(invalid)?
+                    .iter()
+                    .map(|node| match node.as_list() {
+                        Some([host, port]) => {
+                            let port = port.as_int().and_then(|port| u16::try_from(port).ok());
+                            Ok((string(host)?, port.ok_or_else(invalid)?))
+                        }
+                        _ => Err(invalid()),
+                    })
+                    .collect()
+            })
+            .transpose()?;
+
+        Ok(RawTorrent {
+            info,
+            announce: fields.string("announce")?,
+            announce_list,
+            comment: fields.string("comment")?,
+            created_by: fields.string("created by")?,
+            creation_date: fields.int("creation date")?,
+            encoding: fields.string("encoding")?,
+            url_list,
+            httpseeds: fields.strings("httpseeds")?,
+            nodes,
+        })
+    }
+
+    fn to_bencode(&self) -> BencodeValue {
+        let mut dict = BTreeMap::new();
+        put(&mut dict, "info", Some(self.info.to_bencode()));
+        put(
+            &mut dict,
+            "announce",
+            self.announce.as_deref().map(Into::into),
+        );
+        put(
+            &mut dict,
+            "announce-list",
+            self.announce_list.as_ref().map(|tiers| {
+                BencodeValue::List(tiers.iter().map(|tier| string_list(tier)).collect())
+            }),
+        );
+        put(
+            &mut dict,
+            "comment",
+            self.comment.as_deref().map(Into::into),
+        );
+        put(
+            &mut dict,
+            "created by",
+            self.created_by.as_deref().map(Into::into),
+        );
+        put(
+            &mut dict,
+            "creation date",
+            self.creation_date
+                .map(|date| BencodeValue::Int(date as i64)),
+        );
+        put(
+            &mut dict,
+            "encoding",
+            self.encoding.as_deref().map(Into::into),
+        );
+        put(
+            &mut dict,
+            "url-list",
+            self.url_list.as_ref().map(|url_list| match url_list {
+                UrlList::One(url) => url.as_str().into(),
+                UrlList::Many(urls) => string_list(urls),
+            }),
+        );
+        put(
+            &mut dict,
+            "httpseeds",
+            self.httpseeds.as_deref().map(string_list),
+        );
+        put(
+            &mut dict,
+            "nodes",
+            self.nodes.as_ref().map(|nodes| {
+                BencodeValue::List(
+                    nodes
+                        .iter()
+                        .map(|(host, port)| {
+                            BencodeValue::List(vec![
+                                host.as_str().into(),
+                                BencodeValue::Int(*port as i64),
+                            ])
+                        })
+                        .collect(),
+                )
+            }),
+        );
+        BencodeValue::Dict(dict)
+    }
+}
+
+impl RawTorrentInfo {
+    fn from_bencode(value: &BencodeValue) -> Result<Self> {
+        let fields = Fields::of(value)?;
+        let files = fields
+            .get("files")
+            .map(|files| {
+                files
+                    .as_list()
+                    .ok_or_else(invalid)?
+                    .iter()
+                    .map(RawFileInfo::from_bencode)
+                    .collect()
+            })
+            .transpose()?;
+
+        Ok(RawTorrentInfo {
+            name: fields.required("name", Fields::string)?,
+            piece_length: fields.required("piece length", Fields::int)?,
+            pieces: fields.bytes("pieces")?,
+            private: fields.int("private")?.unwrap_or(0),
+            length: fields.int("length")?,
+            files,
+            md5sum: fields.string("md5sum")?,
+            sha1: fields.hash("sha1")?,
+        })
+    }
+
+    fn to_bencode(&self) -> BencodeValue {
+        let mut dict = BTreeMap::new();
+        put(&mut dict, "name", Some(self.name.as_str().into()));
+        put(
+            &mut dict,
+            "piece length",
+            Some(BencodeValue::Int(self.piece_length as i64)),
+        );
+        put(&mut dict, "pieces", self.pieces.clone().map(Into::into));
+        //=== Only private torrents carry the flag ===//
+        put(
+            &mut dict,
+            "private",
+            Some(BencodeValue::Int(self.private as i64)).filter(|_| self.private != 0),
+        );
+        put(
+            &mut dict,
+            "length",
+            self.length.map(|length| BencodeValue::Int(length as i64)),
+        );
+        put(
+            &mut dict,
+            "files",
+            self.files.as_ref().map(|files| {
+                BencodeValue::List(files.iter().map(RawFileInfo::to_bencode).collect())
+            }),
+        );
+        put(&mut dict, "md5sum", self.md5sum.as_deref().map(Into::into));
+        put(
+            &mut dict,
+            "sha1",
+            self.sha1.map(|hash| hash.to_vec().into()),
+        );
+        BencodeValue::Dict(dict)
+    }
+}
+
+impl RawFileInfo {
+    fn from_bencode(value: &BencodeValue) -> Result<Self> {
+        let fields = Fields::of(value)?;
+        Ok(RawFileInfo {
+            length: fields.required("length", Fields::int)?,
+            path: fields.required("path", Fields::strings)?,
+            md5sum: fields.string("md5sum")?,
+            sha1: fields.hash("sha1")?,
+        })
+    }
+
+    fn to_bencode(&self) -> BencodeValue {
+        let mut dict = BTreeMap::new();
+        put(
+            &mut dict,
+            "length",
+            Some(BencodeValue::Int(self.length as i64)),
+        );
+        put(&mut dict, "path", Some(string_list(&self.path)));
+        put(&mut dict, "md5sum", self.md5sum.as_deref().map(Into::into));
+        put(
+            &mut dict,
+            "sha1",
+            self.sha1.map(|hash| hash.to_vec().into()),
+        );
+        BencodeValue::Dict(dict)
+    }
+}
+
+pub const MIN_PIECE_LENGTH: u32 = 16 * 1024;
+pub const MAX_PIECE_LENGTH: u32 = 16 * 1024 * 1024;
+
+//=== Limits applied while parsing untrusted torrent files ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    pub min_piece_length: u32,
+    pub max_piece_length: u32,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            min_piece_length: MIN_PIECE_LENGTH,
+            max_piece_length: MAX_PIECE_LENGTH,
+        }
+    }
+}
+
+//=== Metadata written into a new torrent alongside its content ===//
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CreateOptions {
+    pub comment: Option<String>,
+    // Defaults to this client //
+    pub created_by: Option<String>,
+    // Unix time to stamp; defaults to now //
+    pub creation_date: Option<u64>,
+    // Leave the date out so the same content always yields the same file //
+    pub no_date: bool,
+    pub encoding: Option<String>,
+    // Refuse piece sizes other clients may not accept, rather than warn about them //
+    pub strict_piece_length: bool,
+}
+
+//=== What a torrent describes, short of the piece hashes needed to verify it ===//
+#[derive(Debug, Clone)]
+pub struct TorrentMetadata {
+    pub name: String,
+    pub piece_length: u32,
+    pub files: Vec<FileInfo>,
+    pub trackers: Vec<String>,
+    // None when the pieces field is missing, empty or truncated //
+    pub num_pieces: Option<usize>,
+    pub private: bool,
+    pub comment: Option<String>,
+    pub creation_date: Option<u64>,
+    pub created_by: Option<String>,
+    pub encoding: Option<String>,
+    pub dht_nodes: Vec<SocketAddr>,
+}
+
+impl TorrentMetadata {
+    pub fn total_size(&self) -> u64 {
+        self.files.iter().map(|f| f.length).sum()
+    }
 }
 
 //=== Torrent parser for reading and writing .torrent files ===//
@@ -43,13 +405,61 @@ pub struct TorrentParser;
 
 impl TorrentParser {
     pub fn parse_bytes(data: &[u8]) -> Result<TorrentInfo> {
-        //== This is a simplified implementation using JSON not bencode as in real Torrent ==//
+        Self::parse_bytes_with_options(data, &ParseOptions::default())
+    }
 
-        let raw: RawTorrent = serde_json::from_slice(data)
-            .map_err(|_e| TorrentError::Validation(ValidationError::InvalidTorrentInfo))?;
+    //=== The info hash is taken over the info dict exactly as the file has it ===//
+    pub fn parse_bytes_with_options(data: &[u8], options: &ParseOptions) -> Result<TorrentInfo> {
+        use sha1::{Digest, Sha1};
+
+        let raw = RawTorrent::from_bencode(&bencode::decode(data)?)?;
+        let mut info = Self::convert_raw_torrent(raw, options)?;
+        let raw_info = bencode::raw_dict_value(data, "info")?.ok_or_else(invalid)?;
+        info.info_hash = Some(Sha1::digest(raw_info).into());
+        Ok(info)
+    }
+
+    //=== Parse a torrent along with the hash of its info dict exactly as the file has it ===//
+    pub fn parse_bytes_with_info_hash(data: &[u8]) -> Result<(TorrentInfo, Hash)> {
+        let info = Self::parse_bytes(data)?;
+        let info_hash = info.info_hash.ok_or_else(invalid)?;
+        Ok((info, info_hash))
+    }
+    //=== Read a torrent's header without requiring or validating its piece hashes ===//
+    pub fn parse_metadata_only(data: &[u8]) -> Result<TorrentMetadata> {
+        let raw = RawTorrent::from_bencode(&bencode::decode(data)?)?;
+        let info = raw.info;
+
+        let num_pieces = info
+            .pieces
+            .as_ref()
+            .filter(|pieces| !pieces.is_empty() && pieces.len().is_multiple_of(20))
+            .map(|pieces| pieces.len() / 20);
+        let files =
+            Self::convert_files(&info.name, info.files, info.length, info.md5sum, info.sha1)?;
+
+        let mut trackers: Vec<String> = raw.announce.into_iter().collect();
+        for url in raw.announce_list.into_iter().flatten().flatten() {
+            if !trackers.contains(&url) {
+                trackers.push(url);
+            }
+        }
 
-        Self::convert_raw_torrent(raw)
+        Ok(TorrentMetadata {
+            name: info.name,
+            piece_length: info.piece_length,
+            files,
+            trackers,
+            num_pieces,
+            private: info.private != 0,
+            comment: raw.comment,
+            creation_date: raw.creation_date,
+            created_by: raw.created_by,
+            encoding: raw.encoding,
+            dht_nodes: Self::convert_nodes(raw.nodes),
+        })
     }
+
     pub async fn parse_file<P: AsRef<Path>>(path: P) -> Result<TorrentInfo> {
         let data = tokio::fs::read(path).await.map_err(|_| {
             TorrentError::File(FileError::NotFound {
@@ -61,35 +471,30 @@ impl TorrentParser {
     }
 
     //=== Convert raw torrent data to TorrentInfo ===//
-    fn convert_raw_torrent(raw: RawTorrent) -> Result<TorrentInfo> {
+    fn convert_raw_torrent(raw: RawTorrent, options: &ParseOptions) -> Result<TorrentInfo> {
         let info = raw.info;
 
-        //== Validate piece length ==//
-        if info.piece_length == 0 {
+        //== Validate piece length: bounded to keep piece buffers sane ==//
+        if info.piece_length == 0
+            || info.piece_length < options.min_piece_length
+            || info.piece_length > options.max_piece_length
+        {
             return Err(TorrentError::Validation(ValidationError::InvalidPieceSize));
         }
-        let pieces = Self::parse_pieces(&info.pieces)?;
-
-        let files = if let Some(files) = info.files {
-            files
-                .into_iter()
-                .map(|f| FileInfo {
-                    path: f.path,
-                    length: f.length,
-                    md5sum: f.md5sum,
-                })
-                .collect()
-        } else if let Some(length) = info.length {
-            vec![FileInfo {
-                path: vec![info.name.clone()],
-                length,
-                md5sum: info.md5sum,
-            }]
-        } else {
-            return Err(TorrentError::Validation(ValidationError::MissingField {
-                field: "files or length".to_string(),
-            }));
-        };
+        if !info.piece_length.is_power_of_two() {
+            crate::logging::warn!(
+                "Torrent piece length {} is not a power of two",
+                info.piece_length
+            );
+        }
+        let pieces = info.pieces.ok_or_else(|| {
+            TorrentError::Validation(ValidationError::MissingField {
+                field: "pieces".to_string(),
+            })
+        })?;
+        let pieces = Self::parse_pieces(&pieces)?;
+        let files =
+            Self::convert_files(&info.name, info.files, info.length, info.md5sum, info.sha1)?;
 
         Ok(TorrentInfo {
             name: info.name,
@@ -100,10 +505,80 @@ impl TorrentParser {
             comment: raw.comment,
             creation_date: raw.creation_date,
             created_by: raw.created_by,
+            encoding: raw.encoding,
+            web_seeds: Self::convert_web_seeds(raw.url_list, raw.httpseeds),
+            dht_nodes: Self::convert_nodes(raw.nodes),
+            info_hash: None,
         })
     }
+
+    //=== Only IP literals are kept; parsing never does a DNS lookup ===//
+    fn convert_nodes(nodes: Option<Vec<(String, u16)>>) -> Vec<SocketAddr> {
+        nodes
+            .unwrap_or_default()
+            .into_iter()
+            .filter_map(|(host, port)| {
+                let ip: IpAddr = host.parse().ok()?;
+                Some(SocketAddr::new(ip, port))
+            })
+            .collect()
+    }
+
+    //=== Both web seed flavours, url-list first ===//
+    fn convert_web_seeds(
+        url_list: Option<UrlList>,
+        httpseeds: Option<Vec<String>>,
+    ) -> Vec<WebSeed> {
+        let url_list = match url_list {
+            Some(UrlList::One(url)) => vec![url],
+            Some(UrlList::Many(urls)) => urls,
+            None => Vec::new(),
+        };
+        let seeds = |urls: Vec<String>, style| {
+            urls.into_iter()
+                .filter(|url| !url.is_empty())
+                .map(move |url| WebSeed { url, style })
+        };
+
+        seeds(url_list, WebSeedStyle::UrlList)
+            .chain(seeds(httpseeds.unwrap_or_default(), WebSeedStyle::HttpSeed))
+            .collect()
+    }
+
+    //=== Multi-file torrents list their files; single-file ones are named after the torrent ===//
+    fn convert_files(
+        name: &str,
+        files: Option<Vec<RawFileInfo>>,
+        length: Option<u64>,
+        md5sum: Option<String>,
+        sha1: Option<Hash>,
+    ) -> Result<Vec<FileInfo>> {
+        if let Some(files) = files {
+            Ok(files
+                .into_iter()
+                .map(|f| FileInfo {
+                    path: f.path,
+                    length: f.length,
+                    md5sum: f.md5sum,
+                    sha1: f.sha1,
+                })
+                .collect())
+        } else if let Some(length) = length {
+            Ok(vec![FileInfo {
+                path: vec![name.to_string()],
+                length,
+                md5sum,
+                sha1,
+            }])
+        } else {
+            Err(TorrentError::Validation(ValidationError::MissingField {
+                field: "files or length".to_string(),
+            }))
+        }
+    }
+
     fn parse_pieces(pieces_data: &[u8]) -> Result<Vec<Hash>> {
-        if pieces_data.len() % 20 != 0 {
+        if !pieces_data.len().is_multiple_of(20) {
             return Err(TorrentError::Validation(ValidationError::InvalidHash));
         }
 
@@ -124,6 +599,21 @@ impl TorrentParser {
         name: String,
         comment: Option<String>,
     ) -> Result<TorrentInfo> {
+        let options = CreateOptions {
+            comment,
+            ..CreateOptions::default()
+        };
+        Self::create_torrent_with_options(files, piece_length, name, &options).await
+    }
+
+    pub async fn create_torrent_with_options<P: AsRef<Path>>(
+        files: Vec<P>,
+        piece_length: u32,
+        name: String,
+        options: &CreateOptions,
+    ) -> Result<TorrentInfo> {
+        Self::check_piece_length(piece_length, options.strict_piece_length)?;
+
         let mut file_infos = Vec::new();
         let mut all_data = Vec::new();
 
@@ -146,6 +636,7 @@ impl TorrentParser {
                     .to_string()],
                 length: metadata.len(),
                 md5sum: None,
+                sha1: None,
             };
 
             file_infos.push(file_info);
@@ -153,21 +644,60 @@ impl TorrentParser {
 
         let pieces = Self::generate_pieces(&all_data, piece_length)?;
 
-        Ok(TorrentInfo {
+        let info = TorrentInfo {
             name,
             piece_length,
             pieces,
             files: file_infos,
             private: false,
-            comment,
-            creation_date: Some(
-                std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs(),
+            comment: options.comment.clone(),
+            creation_date: match (options.no_date, options.creation_date) {
+                (true, _) => None,
+                (false, Some(creation_date)) => Some(creation_date),
+                (false, None) => Some(
+                    std::time::SystemTime::now()
+                        .duration_since(std::time::UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs(),
+                ),
+            },
+            created_by: Some(
+                options
+                    .created_by
+                    .clone()
+                    .unwrap_or_else(|| "file-storage-system".to_string()),
             ),
-            created_by: Some("file-storage-system".to_string()),
-        })
+            encoding: options.encoding.clone(),
+            web_seeds: Vec::new(),
+            dht_nodes: Vec::new(),
+            info_hash: None,
+        };
+        info.verify_self_consistent()?;
+
+        Ok(info)
+    }
+
+    //=== Few clients take a piece size that isn't a power of two in 16 KiB..=16 MiB ===//
+    pub fn check_piece_length(piece_length: u32, strict: bool) -> Result<()> {
+        let problem = if piece_length == 0 {
+            return Err(TorrentError::Validation(ValidationError::InvalidPieceSize));
+        } else if !(MIN_PIECE_LENGTH..=MAX_PIECE_LENGTH).contains(&piece_length) {
+            "is outside 16 KiB to 16 MiB"
+        } else if !piece_length.is_power_of_two() {
+            "is not a power of two"
+        } else {
+            return Ok(());
+        };
+
+        if strict {
+            return Err(TorrentError::Validation(ValidationError::InvalidPieceSize));
+        }
+        crate::logging::warn!(
+            "Piece size {} {}; other clients may refuse this torrent",
+            piece_length,
+            problem
+        );
+        Ok(())
     }
 
     //=== Generate piece hashes for data ===//
@@ -189,7 +719,13 @@ impl TorrentParser {
 
     //== Serialize torrent info to bytes ==//
     pub fn serialize_torrent(info: &TorrentInfo) -> Result<Vec<u8>> {
-        let files = if info.files.len() == 1 {
+        Ok(bencode::encode(&Self::raw_torrent(info).to_bencode()))
+    }
+
+    fn raw_torrent(info: &TorrentInfo) -> RawTorrent {
+        //== Single-file mode names the file after the torrent, so only use it when they agree ==//
+        let single_file = info.files.len() == 1 && info.files[0].path == [info.name.as_str()];
+        let files = if single_file {
             None
         } else {
             //== Multi-file mode ==//
@@ -200,15 +736,27 @@ impl TorrentParser {
                         length: f.length,
                         path: f.path.clone(),
                         md5sum: f.md5sum.clone(),
+                        sha1: f.sha1,
                     })
                     .collect(),
             )
         };
 
-        let (length, md5sum) = if info.files.len() == 1 {
-            (Some(info.files[0].length), info.files[0].md5sum.clone())
+        let (length, md5sum, sha1) = if single_file {
+            let file = &info.files[0];
+            (Some(file.length), file.md5sum.clone(), file.sha1)
         } else {
-            (None, None)
+            (None, None, None)
+        };
+
+        let web_seed_urls = |style| {
+            let urls: Vec<String> = info
+                .web_seeds
+                .iter()
+                .filter(|seed| seed.style == style)
+                .map(|seed| seed.url.clone())
+                .collect();
+            Some(urls).filter(|urls| !urls.is_empty())
         };
 
         //== Concatenate piece hashes ==//
@@ -217,24 +765,33 @@ impl TorrentParser {
             pieces_bytes.extend_from_slice(piece);
         }
 
-        let raw = RawTorrent {
+        RawTorrent {
             info: RawTorrentInfo {
                 name: info.name.clone(),
                 piece_length: info.piece_length,
-                pieces: serde_bytes::ByteBuf::from(pieces_bytes),
+                pieces: Some(pieces_bytes),
                 private: if info.private { 1 } else { 0 },
                 length,
                 files,
                 md5sum,
+                sha1,
             },
             announce: None,
             announce_list: None,
             comment: info.comment.clone(),
             created_by: info.created_by.clone(),
             creation_date: info.creation_date,
-        };
-
-        serde_json::to_vec(&raw).map_err(|e| TorrentError::Serialization(e))
+            encoding: info.encoding.clone(),
+            url_list: web_seed_urls(WebSeedStyle::UrlList).map(UrlList::Many),
+            httpseeds: web_seed_urls(WebSeedStyle::HttpSeed),
+            nodes: Some(
+                info.dht_nodes
+                    .iter()
+                    .map(|node| (node.ip().to_string(), node.port()))
+                    .collect::<Vec<_>>(),
+            )
+            .filter(|nodes| !nodes.is_empty()),
+        }
     }
 
     //== Write torrent info to a file ==//
@@ -244,14 +801,632 @@ impl TorrentParser {
         Ok(())
     }
 
-    //== Calculate info hash for a torrent ==//
+    //== Calculate info hash for a torrent: as parsed, else SHA-1 of the canonical info dict ==//
     pub fn calculate_info_hash(info: &TorrentInfo) -> Result<Hash> {
+        Ok(info.info_hash.unwrap_or_else(|| Self::info_hash_of(info)))
+    }
+
+    pub(crate) fn info_hash_of(info: &TorrentInfo) -> Hash {
         use sha1::{Digest, Sha1};
 
-        let serialized = Self::serialize_torrent(info)?;
-        let mut hasher = Sha1::new();
-        hasher.update(&serialized);
-        let result = hasher.finalize();
-        Ok(result.into())
+        let encoded = bencode::encode(&Self::raw_torrent(info).info.to_bencode());
+        Sha1::digest(&encoded).into()
+    }
+
+    //=== Add and remove trackers in a torrent's bytes, leaving every other key untouched ===//
+    pub fn edit_trackers(data: &[u8], add: &[String], remove: &[String]) -> Result<Vec<u8>> {
+        let mut torrent = bencode::decode(data)?;
+        let raw = RawTorrent::from_bencode(&torrent)?;
+        let raw_info = bencode::raw_dict_value(data, "info")?.ok_or_else(invalid)?;
+
+        //=== A lone `announce` is a single one-tracker tier ===//
+        let mut tiers = match (raw.announce_list, raw.announce) {
+            (Some(tiers), _) => tiers,
+            (None, Some(announce)) => vec![vec![announce]],
+            (None, None) => Vec::new(),
+        };
+
+        for tier in &mut tiers {
+            tier.retain(|url| !remove.contains(url));
+        }
+        tiers.retain(|tier| !tier.is_empty());
+
+        //=== New trackers go last, each in its own tier, so existing ones keep priority ===//
+        for url in add {
+            if !tiers.iter().flatten().any(|existing| existing == url) {
+                tiers.push(vec![url.clone()]);
+            }
+        }
+
+        let dict = torrent.as_dict_mut().ok_or_else(invalid)?;
+        dict.remove(b"announce".as_slice());
+        dict.remove(b"announce-list".as_slice());
+        put(
+            dict,
+            "announce",
+            tiers
+                .first()
+                .and_then(|tier| tier.first())
+                .map(|url| url.as_str().into()),
+        );
+        put(
+            dict,
+            "announce-list",
+            Some(tiers).filter(|tiers| !tiers.is_empty()).map(|tiers| {
+                BencodeValue::List(tiers.iter().map(|tier| string_list(tier)).collect())
+            }),
+        );
+        //=== `info` goes out byte for byte, so a non-canonical one keeps its hash ===//
+        Ok(bencode::encode_with_raw_value(dict, "info", raw_info))
+    }
+}
+
+impl TorrentInfo {
+    //=== Check the invariants a parsed or hand-built torrent must hold, then round-trip it ===//
+    pub fn verify_self_consistent(&self) -> Result<()> {
+        let inconsistent = |reason: String| {
+            Err(TorrentError::Validation(
+                ValidationError::InconsistentTorrent { reason },
+            ))
+        };
+
+        if self.piece_length == 0 {
+            return inconsistent("piece length is zero".to_string());
+        }
+        let expected_pieces = self.total_size().div_ceil(self.piece_length as u64);
+        if self.num_pieces() as u64 != expected_pieces {
+            return inconsistent(format!(
+                "{} pieces for {} bytes, expected {}",
+                self.num_pieces(),
+                self.total_size(),
+                expected_pieces
+            ));
+        }
+        if self.files.is_empty() {
+            return inconsistent("no files".to_string());
+        }
+        for file in &self.files {
+            if file.path.is_empty() || file.path.iter().any(String::is_empty) {
+                return inconsistent(format!("empty path component in {:?}", file.path));
+            }
+        }
+
+        //=== Piece size limits are policy, not consistency ===//
+        let options = ParseOptions {
+            min_piece_length: 1,
+            max_piece_length: u32::MAX,
+        };
+        let mut parsed = TorrentParser::parse_bytes_with_options(
+            &TorrentParser::serialize_torrent(self)?,
+            &options,
+        )?;
+        //=== Parsing records the hash it saw; that is derived, not content ===//
+        parsed.info_hash = self.info_hash;
+        if &parsed != self {
+            return inconsistent("changed after serializing and parsing again".to_string());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn torrent_bytes(piece_length: u32) -> Vec<u8> {
+        let info = TorrentInfo {
+            name: "test".to_string(),
+            piece_length,
+            pieces: vec![[0u8; 20]],
+            files: vec![FileInfo {
+                path: vec!["test".to_string()],
+                length: 1024,
+                md5sum: None,
+                sha1: None,
+            }],
+            private: false,
+            comment: None,
+            creation_date: None,
+            created_by: None,
+            encoding: None,
+            web_seeds: Vec::new(),
+            dht_nodes: Vec::new(),
+            info_hash: None,
+        };
+        TorrentParser::serialize_torrent(&info).unwrap()
+    }
+
+    fn is_invalid_piece_size(result: Result<TorrentInfo>) -> bool {
+        matches!(
+            result,
+            Err(TorrentError::Validation(ValidationError::InvalidPieceSize))
+        )
+    }
+
+    #[test]
+    fn test_piece_length_within_bounds() {
+        let info = TorrentParser::parse_bytes(&torrent_bytes(256 * 1024)).unwrap();
+        assert_eq!(info.piece_length, 256 * 1024);
+
+        //=== Odd sizes are tolerated ===//
+        assert!(TorrentParser::parse_bytes(&torrent_bytes(100_000)).is_ok());
+    }
+
+    #[test]
+    fn test_oversized_piece_length_rejected() {
+        let data = torrent_bytes(2 * 1024 * 1024 * 1024);
+        assert!(is_invalid_piece_size(TorrentParser::parse_bytes(&data)));
+
+        //=== A raised limit admits it ===//
+        let options = ParseOptions {
+            max_piece_length: u32::MAX,
+            ..ParseOptions::default()
+        };
+        assert!(TorrentParser::parse_bytes_with_options(&data, &options).is_ok());
+    }
+
+    fn decoded_torrent(piece_length: u32) -> BencodeValue {
+        bencode::decode(&torrent_bytes(piece_length)).unwrap()
+    }
+
+    fn set(dict: &mut BencodeValue, key: &str, value: BencodeValue) {
+        dict.as_dict_mut()
+            .unwrap()
+            .insert(key.as_bytes().to_vec(), value);
+    }
+
+    fn info_mut(torrent: &mut BencodeValue) -> &mut BencodeValue {
+        torrent
+            .as_dict_mut()
+            .unwrap()
+            .get_mut(b"info".as_slice())
+            .unwrap()
+    }
+
+    fn tiers(tiers: &[&[&str]]) -> BencodeValue {
+        BencodeValue::List(
+            tiers
+                .iter()
+                .map(|tier| BencodeValue::List(tier.iter().map(|&url| url.into()).collect()))
+                .collect(),
+        )
+    }
+
+    #[test]
+    fn test_parse_single_and_multi_file_bencode() {
+        let single = [
+            b"d8:announce31:http://tracker.example/announce4:infod6:lengthi1024e".as_slice(),
+            b"4:name8:data.bin12:piece lengthi16384e6:pieces20:",
+            &[7u8; 20],
+            b"ee",
+        ]
+        .concat();
+        let info = TorrentParser::parse_bytes(&single).unwrap();
+        assert_eq!(info.name, "data.bin");
+        assert_eq!(info.piece_length, 16384);
+        assert_eq!(info.pieces, vec![[7u8; 20]]);
+        assert_eq!(
+            info.files,
+            vec![FileInfo::new(vec!["data.bin".to_string()], 1024)]
+        );
+        assert!(!info.private);
+
+        let multi = [
+            b"d4:infod5:filesld6:lengthi100e4:pathl3:dir5:a.txteed6:lengthi20e".as_slice(),
+            b"4:pathl5:b.txte4:sha120:",
+            &[5u8; 20],
+            b"ee4:name5:album12:piece lengthi64e6:pieces40:",
+            &[0u8; 40],
+            b"7:privatei1eee",
+        ]
+        .concat();
+        let info = TorrentParser::parse_bytes_with_options(
+            &multi,
+            &ParseOptions {
+                min_piece_length: 1,
+                ..ParseOptions::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(info.name, "album");
+        assert!(info.private);
+        assert_eq!(
+            info.files,
+            vec![
+                FileInfo::new(vec!["dir".to_string(), "a.txt".to_string()], 100),
+                FileInfo {
+                    sha1: Some([5u8; 20]),
+                    ..FileInfo::new(vec!["b.txt".to_string()], 20)
+                },
+            ]
+        );
+
+        //=== Writing it back gives the same bytes ===//
+        assert_eq!(TorrentParser::serialize_torrent(&info).unwrap(), multi);
+    }
+
+    #[test]
+    fn test_malformed_torrents_rejected() {
+        let mut wrong_type = decoded_torrent(256 * 1024);
+        set(info_mut(&mut wrong_type), "name", BencodeValue::Int(3));
+        let mut no_name = decoded_torrent(256 * 1024);
+        info_mut(&mut no_name)
+            .as_dict_mut()
+            .unwrap()
+            .remove(b"name".as_slice());
+        let mut short_sha1 = decoded_torrent(256 * 1024);
+        set(info_mut(&mut short_sha1), "sha1", vec![1u8; 19].into());
+
+        let valid = torrent_bytes(256 * 1024);
+        let inputs = [
+            b"{\"info\": {}}".to_vec(),
+            valid[..valid.len() - 1].to_vec(),
+            b"li1ee".to_vec(),
+            bencode::encode(&wrong_type),
+            bencode::encode(&no_name),
+            bencode::encode(&short_sha1),
+        ];
+        for data in inputs {
+            assert!(matches!(
+                TorrentParser::parse_bytes(&data),
+                Err(TorrentError::Validation(
+                    ValidationError::InvalidTorrentInfo
+                ))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_metadata_only_tolerates_empty_pieces() {
+        let mut torrent = decoded_torrent(256 * 1024);
+        set(
+            info_mut(&mut torrent),
+            "pieces",
+            BencodeValue::Bytes(Vec::new()),
+        );
+        set(
+            &mut torrent,
+            "announce",
+            "http://tracker.example/announce".into(),
+        );
+        set(
+            &mut torrent,
+            "announce-list",
+            tiers(&[
+                &["http://tracker.example/announce"],
+                &["udp://backup.example:6969"],
+            ]),
+        );
+        let data = bencode::encode(&torrent);
+
+        let metadata = TorrentParser::parse_metadata_only(&data).unwrap();
+        assert_eq!(metadata.name, "test");
+        assert_eq!(metadata.piece_length, 256 * 1024);
+        assert_eq!(metadata.total_size(), 1024);
+        assert_eq!(metadata.num_pieces, None);
+        assert_eq!(
+            metadata.trackers,
+            vec![
+                "http://tracker.example/announce".to_string(),
+                "udp://backup.example:6969".to_string()
+            ]
+        );
+
+        //=== A full torrent reports its piece count ===//
+        let metadata = TorrentParser::parse_metadata_only(&torrent_bytes(256 * 1024)).unwrap();
+        assert_eq!(metadata.num_pieces, Some(1));
+
+        //=== The strict parse still wants the hashes ===//
+        info_mut(&mut torrent)
+            .as_dict_mut()
+            .unwrap()
+            .remove(b"pieces".as_slice());
+        let data = bencode::encode(&torrent);
+        assert!(TorrentParser::parse_metadata_only(&data).is_ok());
+        assert!(matches!(
+            TorrentParser::parse_bytes(&data),
+            Err(TorrentError::Validation(
+                ValidationError::MissingField { .. }
+            ))
+        ));
+    }
+
+    #[test]
+    fn test_trackerless_torrent_nodes_parsed() {
+        let mut torrent = decoded_torrent(256 * 1024);
+        let node = |host: &str, port: i64| BencodeValue::List(vec![host.into(), port.into()]);
+        set(
+            &mut torrent,
+            "nodes",
+            BencodeValue::List(vec![
+                node("10.0.0.1", 6881),
+                node("::1", 6882),
+                node("router.example", 6881),
+            ]),
+        );
+        let data = bencode::encode(&torrent);
+
+        let expected: Vec<SocketAddr> = vec![
+            "10.0.0.1:6881".parse().unwrap(),
+            "[::1]:6882".parse().unwrap(),
+        ];
+        let info = TorrentParser::parse_bytes(&data).unwrap();
+        assert_eq!(info.dht_nodes, expected);
+        let metadata = TorrentParser::parse_metadata_only(&data).unwrap();
+        assert!(metadata.trackers.is_empty());
+        assert_eq!(metadata.dht_nodes, expected);
+
+        //=== They survive being written back out ===//
+        let written = TorrentParser::serialize_torrent(&info).unwrap();
+        assert_eq!(
+            TorrentParser::parse_bytes(&written).unwrap().dht_nodes,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_adding_tracker_keeps_info_hash() {
+        let mut torrent = decoded_torrent(256 * 1024);
+        set(&mut torrent, "announce", "http://a.example/announce".into());
+        set(
+            &mut torrent,
+            "announce-list",
+            tiers(&[
+                &["http://a.example/announce", "http://b.example/announce"],
+                &["udp://c.example:6969"],
+            ]),
+        );
+        //=== Keys the parser does not know about are carried over as they are ===//
+        set(info_mut(&mut torrent), "source", "tracker-x".into());
+        let data = bencode::encode(&torrent);
+        let (_, before) = TorrentParser::parse_bytes_with_info_hash(&data).unwrap();
+
+        let edited = TorrentParser::edit_trackers(
+            &data,
+            &["http://d.example/announce".to_string()],
+            &["http://a.example/announce".to_string()],
+        )
+        .unwrap();
+        let (_, after) = TorrentParser::parse_bytes_with_info_hash(&edited).unwrap();
+        assert_eq!(before, after);
+
+        let edited = bencode::decode(&edited).unwrap();
+        assert_eq!(edited.get("info"), torrent.get("info"));
+        assert_eq!(
+            edited.get("announce").and_then(BencodeValue::as_str),
+            Some("http://b.example/announce")
+        );
+        assert_eq!(
+            edited.get("announce-list"),
+            Some(&tiers(&[
+                &["http://b.example/announce"],
+                &["udp://c.example:6969"],
+                &["http://d.example/announce"],
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_editing_trackers_keeps_an_unsorted_info_dict() {
+        use sha1::{Digest, Sha1};
+
+        let unsorted_info = [
+            b"d4:name4:test6:lengthi1024e12:piece lengthi16384e6:pieces20:".as_slice(),
+            &[0u8; 20],
+            b"e",
+        ]
+        .concat();
+        let data = [
+            b"d8:announce25:http://a.example/announce4:info".as_slice(),
+            &unsorted_info,
+            b"e",
+        ]
+        .concat();
+        let hash: Hash = Sha1::digest(&unsorted_info).into();
+
+        let edited = TorrentParser::edit_trackers(
+            &data,
+            &["http://b.example/announce".to_string()],
+            &["http://a.example/announce".to_string()],
+        )
+        .unwrap();
+        let (_, after) = TorrentParser::parse_bytes_with_info_hash(&edited).unwrap();
+        assert_eq!(after, hash);
+        assert_eq!(
+            bencode::raw_dict_value(&edited, "info").unwrap(),
+            Some(unsorted_info.as_slice())
+        );
+        assert_eq!(
+            bencode::decode(&edited)
+                .unwrap()
+                .get("announce")
+                .and_then(BencodeValue::as_str),
+            Some("http://b.example/announce")
+        );
+    }
+
+    #[test]
+    fn test_info_hash_covers_only_the_info_dict() {
+        use sha1::{Digest, Sha1};
+
+        let info_dict = [
+            b"d6:lengthi1024e4:name4:test12:piece lengthi16384e6:pieces20:".as_slice(),
+            &[0u8; 20],
+            b"e",
+        ]
+        .concat();
+        let hash: Hash = Sha1::digest(&info_dict).into();
+        let data = [b"d7:comment5:hello4:info".as_slice(), &info_dict, b"e"].concat();
+
+        let (info, exact) = TorrentParser::parse_bytes_with_info_hash(&data).unwrap();
+        assert_eq!(exact, hash);
+        assert_eq!(TorrentParser::calculate_info_hash(&info).unwrap(), hash);
+
+        //=== Keys outside `info` do not change it ===//
+        let mut commented = info.clone();
+        commented.comment = Some("different".to_string());
+        assert_eq!(
+            TorrentParser::calculate_info_hash(&commented).unwrap(),
+            hash
+        );
+
+        //=== The exact hash follows the file's bytes even when they are not canonical ===//
+        let unsorted_info = [
+            b"d4:name4:test6:lengthi1024e12:piece lengthi16384e6:pieces20:".as_slice(),
+            &[0u8; 20],
+            b"e",
+        ]
+        .concat();
+        let data = [b"d4:info".as_slice(), &unsorted_info, b"e"].concat();
+        let (info, exact) = TorrentParser::parse_bytes_with_info_hash(&data).unwrap();
+        assert_eq!(exact, <Hash>::from(Sha1::digest(&unsorted_info)));
+        assert_eq!(TorrentParser::calculate_info_hash(&info).unwrap(), exact);
+        assert_eq!(TorrentParser::info_hash_of(&info), hash);
+    }
+
+    #[test]
+    fn test_self_consistency_checks() {
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            64,
+            vec![[0u8; 20]; 2],
+            vec![
+                FileInfo::new(vec!["a".to_string()], 100),
+                FileInfo::new(vec!["b".to_string()], 20),
+            ],
+        );
+        assert!(info.verify_self_consistent().is_ok());
+
+        //=== One file whose name differs from the torrent's must survive a round trip ===//
+        let mut single = info.clone();
+        single.files.truncate(1);
+        assert!(single.verify_self_consistent().is_ok());
+
+        let mut too_few_pieces = info.clone();
+        too_few_pieces.pieces.pop();
+        let mut empty_path = info.clone();
+        empty_path.files[1].path = vec!["dir".to_string(), String::new()];
+        let mut no_files = info.clone();
+        no_files.files.clear();
+
+        for broken in [too_few_pieces, empty_path, no_files] {
+            assert!(matches!(
+                broken.verify_self_consistent(),
+                Err(TorrentError::Validation(
+                    ValidationError::InconsistentTorrent { .. }
+                ))
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_create_checks_piece_length() {
+        #[cfg(feature = "tracing")]
+        let (logs, _guard) = crate::logging::CapturedLogs::install();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        tokio::fs::write(&path, vec![5u8; 100_000]).await.unwrap();
+        let create = |piece_length: u32, strict_piece_length: bool| {
+            let options = CreateOptions {
+                strict_piece_length,
+                ..CreateOptions::default()
+            };
+            let files = vec![path.clone()];
+            async move {
+                TorrentParser::create_torrent_with_options(
+                    files,
+                    piece_length,
+                    "data.bin".to_string(),
+                    &options,
+                )
+                .await
+            }
+        };
+
+        //=== A power of two in range passes either way ===//
+        assert_eq!(
+            create(32 * 1024, true).await.unwrap().piece_length,
+            32 * 1024
+        );
+        #[cfg(feature = "tracing")]
+        assert!(!logs.contents().contains("other clients may refuse"));
+
+        //=== Without strict, odd sizes only warn ===//
+        assert!(create(48 * 1024, false).await.is_ok());
+        #[cfg(feature = "tracing")]
+        assert!(logs.contents().contains("49152 is not a power of two"));
+        assert!(is_invalid_piece_size(create(48 * 1024, true).await));
+
+        assert!(is_invalid_piece_size(create(8 * 1024, true).await));
+        assert!(is_invalid_piece_size(create(32 * 1024 * 1024, true).await));
+        assert!(is_invalid_piece_size(create(0, false).await));
+        assert!(TorrentParser::check_piece_length(MAX_PIECE_LENGTH, true).is_ok());
+        assert!(TorrentParser::check_piece_length(8 * 1024, false).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_no_date_gives_stable_info_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("data.bin");
+        tokio::fs::write(&path, vec![5u8; 20_000]).await.unwrap();
+
+        let create = |options: CreateOptions| {
+            let path = path.clone();
+            async move {
+                let info = TorrentParser::create_torrent_with_options(
+                    vec![path],
+                    MIN_PIECE_LENGTH,
+                    "data.bin".to_string(),
+                    &options,
+                )
+                .await
+                .unwrap();
+                let hash = TorrentParser::calculate_info_hash(&info).unwrap();
+                (info, hash)
+            }
+        };
+
+        let reproducible_options = || CreateOptions {
+            created_by: Some("builder 1.0".to_string()),
+            no_date: true,
+            encoding: Some("UTF-8".to_string()),
+            ..CreateOptions::default()
+        };
+        let reproducible = reproducible_options();
+        let (info, hash) = create(reproducible.clone()).await;
+        assert_eq!(info.creation_date, None);
+        assert_eq!(info.created_by.as_deref(), Some("builder 1.0"));
+        assert_eq!(create(reproducible).await.1, hash);
+
+        //=== The encoding survives a write and read ===//
+        let parsed =
+            TorrentParser::parse_bytes(&TorrentParser::serialize_torrent(&info).unwrap()).unwrap();
+        assert_eq!(parsed.encoding.as_deref(), Some("UTF-8"));
+
+        //=== The date sits outside the info dict: the file changes, the info hash does not ===//
+        let dated = CreateOptions {
+            creation_date: Some(1_700_000_000),
+            no_date: false,
+            ..reproducible_options()
+        };
+        let (dated_info, dated_hash) = create(dated).await;
+        assert_eq!(dated_info.creation_date, Some(1_700_000_000));
+        assert_eq!(dated_hash, hash);
+        assert_ne!(
+            TorrentParser::serialize_torrent(&dated_info).unwrap(),
+            TorrentParser::serialize_torrent(&info).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_zero_and_tiny_piece_length_rejected() {
+        assert!(is_invalid_piece_size(TorrentParser::parse_bytes(
+            &torrent_bytes(0)
+        )));
+        assert!(is_invalid_piece_size(TorrentParser::parse_bytes(
+            &torrent_bytes(1024)
+        )));
     }
 }
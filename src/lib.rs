@@ -1,8 +1,12 @@
 pub mod core;
 pub mod file;
+pub mod metrics;
 pub mod network;
 pub mod peer;
 pub mod protocol;
+pub mod session;
+
+mod logging;
 
 pub use core::*;
 
@@ -13,5 +17,6 @@ pub mod prelude {
     pub use crate::peer::{ChokingState, InterestState, Peer, PeerManager, PeerState};
     pub use crate::network::{NetworkManager, ConnectionManager, ConnectionPool, TrackerManager};
     pub use crate::protocol::{Message, MessageType, ProtocolHandler, Handshake, HandshakeHandler};
+    pub use crate::session::{SessionState, TorrentSession};
     pub use anyhow::{Error, Result};
 }
@@ -0,0 +1,143 @@
+//=== Logging facade: `tracing` when the feature is enabled, plain `log` otherwise ===//
+
+use crate::core::Hash;
+use std::net::SocketAddr;
+
+#[cfg(feature = "tracing")]
+pub(crate) use tracing::{debug, error, info, warn, Instrument, Span};
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) use log::{debug, error, info, warn};
+
+//=== Without `tracing` spans carry nothing, but call sites stay the same ===//
+#[cfg(not(feature = "tracing"))]
+#[derive(Debug, Clone)]
+pub(crate) struct Span;
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) trait Instrument: Sized {
+    fn instrument(self, _span: Span) -> Self {
+        self
+    }
+}
+
+#[cfg(not(feature = "tracing"))]
+impl<T: std::future::Future> Instrument for T {}
+
+//=== Span every log of a peer connection is nested under ===//
+pub(crate) fn peer_span(addr: SocketAddr, info_hash: &Hash) -> Span {
+    #[cfg(feature = "tracing")]
+    {
+        tracing::info_span!("peer", %addr, info_hash = %hex::encode(info_hash))
+    }
+    #[cfg(not(feature = "tracing"))]
+    {
+        let _ = (addr, info_hash);
+        Span
+    }
+}
+
+//=== Collects formatted `tracing` output so tests can inspect it ===//
+#[cfg(all(test, feature = "tracing"))]
+#[derive(Clone, Default)]
+pub(crate) struct CapturedLogs(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(all(test, feature = "tracing"))]
+impl CapturedLogs {
+    //=== Capture everything logged on this thread until the guard is dropped ===//
+    pub(crate) fn install() -> (Self, tracing::subscriber::DefaultGuard) {
+        let logs = Self::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(logs.clone())
+            .with_ansi(false)
+            .with_max_level(tracing::Level::DEBUG)
+            .finish();
+        let guard = tracing::subscriber::set_default(subscriber);
+        (logs, guard)
+    }
+
+    pub(crate) fn contents(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap()).to_string()
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+impl std::io::Write for CapturedLogs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for CapturedLogs {
+    type Writer = Self;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn test_events_go_through_tracing() {
+        let (logs, _guard) = CapturedLogs::install();
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        let span = peer_span(addr, &[0xab; 20]);
+        let _entered = span.enter();
+        info!("piece {} verified", 3);
+
+        let contents = logs.contents();
+        assert!(contents.contains("piece 3 verified"), "{}", contents);
+        assert!(
+            contents.contains("peer{addr=127.0.0.1:6881"),
+            "{}",
+            contents
+        );
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    #[test]
+    fn test_events_go_through_log() {
+        use std::sync::Mutex;
+
+        struct CapturingLogger(Mutex<Vec<String>>);
+
+        impl log::Log for CapturingLogger {
+            fn enabled(&self, _metadata: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+
+            fn flush(&self) {}
+        }
+
+        static LOGGER: CapturingLogger = CapturingLogger(Mutex::new(Vec::new()));
+        log::set_logger(&LOGGER).unwrap();
+        log::set_max_level(log::LevelFilter::Debug);
+
+        //=== Instrumenting is a no-op, the event still reaches `log` ===//
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+        futures::executor::block_on(
+            async { info!("piece {} verified", 3) }.instrument(peer_span(addr, &[0xab; 20])),
+        );
+
+        assert!(LOGGER
+            .0
+            .lock()
+            .unwrap()
+            .contains(&"piece 3 verified".to_string()));
+    }
+}
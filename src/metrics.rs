@@ -0,0 +1,179 @@
+//=== Counters and gauges an operator can scrape from running sessions ===//
+
+use crate::session::TorrentSession;
+
+//=== Whether a metric only ever grows or can go both ways ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+//=== Totals across every session folded in since the registry was created ===//
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub bytes_downloaded: u64,
+    pub bytes_uploaded: u64,
+    pub active_peers: usize,
+    pub pieces_verified: usize,
+    pub tracker_errors: u64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    //=== Metrics of a single session ===//
+    pub fn from_session(session: &TorrentSession) -> Self {
+        let mut metrics = Self::new();
+        metrics.collect(session);
+        metrics
+    }
+
+    //=== Add a session's numbers to the totals; call once per session per scrape ===//
+    pub fn collect(&mut self, session: &TorrentSession) {
+        let statistics = session.statistics();
+        self.bytes_downloaded += statistics.wire_downloaded;
+        self.bytes_uploaded += statistics.wire_uploaded;
+        self.active_peers += session.peer_manager().peers().len();
+        self.pieces_verified += statistics.pieces_verified();
+        self.tracker_errors += session.tracker_manager().total_tracker_errors();
+    }
+
+    //=== Every metric by name, in a stable order ===//
+    pub fn snapshot(&self) -> Vec<(&'static str, u64)> {
+        self.described()
+            .into_iter()
+            .map(|(name, _, value)| (name, value))
+            .collect()
+    }
+
+    fn described(&self) -> Vec<(&'static str, MetricKind, u64)> {
+        vec![
+            (
+                "bytes_downloaded_total",
+                MetricKind::Counter,
+                self.bytes_downloaded,
+            ),
+            (
+                "bytes_uploaded_total",
+                MetricKind::Counter,
+                self.bytes_uploaded,
+            ),
+            ("active_peers", MetricKind::Gauge, self.active_peers as u64),
+            (
+                "pieces_verified_total",
+                MetricKind::Counter,
+                self.pieces_verified as u64,
+            ),
+            (
+                "tracker_errors_total",
+                MetricKind::Counter,
+                self.tracker_errors,
+            ),
+        ]
+    }
+
+    //=== Prometheus text exposition format, each name prefixed with `prefix_` ===//
+    #[cfg(feature = "metrics")]
+    pub fn export_text(&self, prefix: &str) -> String {
+        use std::fmt::Write;
+
+        let mut out = String::new();
+        for (name, kind, value) in self.described() {
+            let kind = match kind {
+                MetricKind::Counter => "counter",
+                MetricKind::Gauge => "gauge",
+            };
+            let _ = writeln!(out, "# TYPE {}_{} {}", prefix, name, kind);
+            let _ = writeln!(out, "{}_{} {}", prefix, name, value);
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{Config, FileInfo, PieceIndex, TorrentInfo, BLOCK_SIZE};
+    use sha1::{Digest, Sha1};
+
+    const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+
+    fn transferred_session() -> TorrentSession {
+        let pieces: Vec<Vec<u8>> = (0..2u8).map(|i| vec![i; PIECE_LENGTH as usize]).collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            PIECE_LENGTH,
+            pieces
+                .iter()
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+            vec![FileInfo::new(
+                vec!["test".to_string()],
+                PIECE_LENGTH as u64 * 2,
+            )],
+        );
+        let mut session = TorrentSession::new([9u8; 20], info, Config::default());
+        let peer_id = [1u8; 20];
+        session
+            .peer_manager_mut()
+            .add_peer(peer_id, "127.0.0.1:7001".parse().unwrap())
+            .unwrap();
+
+        //=== One piece comes in off the wire, and we serve a block back ===//
+        for offset in [0, BLOCK_SIZE] {
+            session.block_received(peer_id, 0, offset);
+        }
+        assert!(session
+            .add_piece_data(0 as PieceIndex, pieces[0].clone())
+            .unwrap());
+        session.block_sent(peer_id, BLOCK_SIZE);
+        session
+    }
+
+    #[test]
+    fn test_byte_counters_follow_transfer() {
+        let session = transferred_session();
+        let snapshot = Metrics::from_session(&session).snapshot();
+
+        assert_eq!(
+            snapshot,
+            vec![
+                ("bytes_downloaded_total", PIECE_LENGTH as u64),
+                ("bytes_uploaded_total", BLOCK_SIZE as u64),
+                ("active_peers", 1),
+                ("pieces_verified_total", 1),
+                ("tracker_errors_total", 0),
+            ]
+        );
+
+        //=== The peer that moved the bytes is credited with them too ===//
+        let peer = session.peer_manager().get_peer(&[1u8; 20]).unwrap();
+        assert_eq!(
+            (peer.downloaded, peer.uploaded),
+            (PIECE_LENGTH as u64, BLOCK_SIZE as u64)
+        );
+    }
+
+    #[test]
+    fn test_collect_sums_sessions() {
+        let mut metrics = Metrics::new();
+        metrics.collect(&transferred_session());
+        metrics.collect(&transferred_session());
+
+        assert_eq!(metrics.bytes_downloaded, 2 * PIECE_LENGTH as u64);
+        assert_eq!(metrics.active_peers, 2);
+        assert_eq!(metrics.pieces_verified, 2);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn test_export_text_format() {
+        let text = Metrics::from_session(&transferred_session()).export_text("fss");
+        assert!(text.contains("# TYPE fss_bytes_uploaded_total counter\n"));
+        assert!(text.contains(&format!("fss_bytes_uploaded_total {}\n", BLOCK_SIZE)));
+        assert!(text.contains("# TYPE fss_active_peers gauge\nfss_active_peers 1\n"));
+    }
+}
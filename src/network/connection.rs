@@ -1,13 +1,55 @@
-use crate::core::{Config, Hash, PeerId};
-use crate::protocol::{HandshakeHandler, Message, ProtocolHandler};
+use crate::core::{system_clock, Config, Hash, PeerId, SharedClock};
+use crate::logging::{error, info, warn};
+use crate::network::RateLimits;
+use crate::protocol::{HandshakeHandler, Message, MessageType, ProtocolHandler};
 use anyhow::{Context, Result};
-use log::{error, info, warn};
+use futures::future::BoxFuture;
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::sync::RwLock;
+use tokio::task::{AbortHandle, JoinHandle};
 use tokio::time::{timeout, Duration};
 
+//=== A TCP socket for `addr`'s address family with the configured buffer sizes ===//
+pub fn configured_socket(addr: &SocketAddr, config: &Config) -> std::io::Result<TcpSocket> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+    if let Some(size) = config.socket_send_buf {
+        socket.set_send_buffer_size(size)?;
+    }
+    if let Some(size) = config.socket_recv_buf {
+        socket.set_recv_buffer_size(size)?;
+    }
+    Ok(socket)
+}
+
+//=== Options set on each connected peer socket, dialed or accepted ===//
+pub fn configure_stream(stream: &TcpStream, config: &Config) -> std::io::Result<()> {
+    stream.set_nodelay(config.tcp_nodelay)
+}
+
+//=== Dial `addr` on a socket sized by `config` ===//
+pub async fn connect_with_config(addr: SocketAddr, config: &Config) -> std::io::Result<TcpStream> {
+    let stream = configured_socket(&addr, config)?.connect(addr).await?;
+    configure_stream(&stream, config)?;
+    Ok(stream)
+}
+
+//=== Listen on `addr`; accepted sockets inherit the listener's buffer sizes ===//
+pub fn listen_with_config(addr: SocketAddr, config: &Config) -> std::io::Result<TcpListener> {
+    let socket = configured_socket(&addr, config)?;
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(1024)
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum ConnectionState {
     Connecting,
@@ -17,7 +59,7 @@ pub enum ConnectionState {
     Failed,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ConnectionInfo {
     pub addr: SocketAddr,
     pub peer_id: PeerId,
@@ -41,11 +83,19 @@ impl ConnectionInfo {
     }
 
     pub fn update_activity(&mut self) {
-        self.last_activity = std::time::Instant::now();
+        self.update_activity_at(std::time::Instant::now());
+    }
+
+    pub fn update_activity_at(&mut self, now: std::time::Instant) {
+        self.last_activity = now;
     }
 
     pub fn is_stale(&self, timeout: Duration) -> bool {
-        self.last_activity.elapsed() > timeout
+        self.is_stale_at(std::time::Instant::now(), timeout)
+    }
+
+    pub fn is_stale_at(&self, now: std::time::Instant, timeout: Duration) -> bool {
+        now.saturating_duration_since(self.last_activity) > timeout
     }
 }
 
@@ -54,23 +104,44 @@ pub struct ConnectionManager {
     config: Config,
     connection_info: Arc<RwLock<ConnectionInfo>>,
     protocol_handler: Option<ProtocolHandler>,
+    clock: SharedClock,
+    rate_limits: RateLimits,
+}
+
+//=== Block bytes in a Piece message, which are what the rate limits count ===//
+fn piece_bytes(message: &Message) -> Option<u64> {
+    (message.message_type == MessageType::Piece)
+        .then(|| message.payload.len().saturating_sub(8) as u64)
 }
 
 impl ConnectionManager {
     pub fn new(config: Config, connection_info: ConnectionInfo) -> Self {
+        Self::with_clock(config, connection_info, system_clock())
+    }
+
+    //=== Create a connection manager driven by the given clock ===//
+    pub fn with_clock(config: Config, connection_info: ConnectionInfo, clock: SharedClock) -> Self {
         Self {
+            rate_limits: RateLimits::from_config(&config),
             config,
             connection_info: Arc::new(RwLock::new(connection_info)),
             protocol_handler: None,
+            clock,
         }
     }
+
+    //=== Share limiters with other connections, so the caps hold across all of them ===//
+    pub fn with_rate_limits(mut self, rate_limits: RateLimits) -> Self {
+        self.rate_limits = rate_limits;
+        self
+    }
     pub async fn connect(&mut self) -> Result<()> {
         let mut info_guard = self.connection_info.write().await;
         info_guard.state = ConnectionState::Connecting;
         drop(info_guard);
 
         let addr = self.connection_info.read().await.addr;
-        let stream = TcpStream::connect(addr)
+        let stream = connect_with_config(addr, &self.config)
             .await
             .with_context(|| format!("Failed to connect to {}", addr))?;
 
@@ -85,7 +156,7 @@ impl ConnectionManager {
         info_guard.state = ConnectionState::Handshaking;
         drop(info_guard);
 
-        let mut handshake_handler = HandshakeHandler::new(stream);
+        let mut handshake_handler = HandshakeHandler::for_config(stream, &self.config);
 
         //=== Perform handshake with timeout ===//
         let handshake_result = timeout(
@@ -123,6 +194,7 @@ impl ConnectionManager {
         self.protocol_handler = Some(ProtocolHandler::new(stream));
 
         self.set_state(ConnectionState::Connected).await;
+        self.update_activity().await;
 
         info!(
             "Successfully connected to peer at {}",
@@ -135,6 +207,9 @@ impl ConnectionManager {
     //==== Send a message to the peer ====//
     pub async fn send_message(&mut self, message: &Message) -> Result<()> {
         if let Some(protocol_handler) = &mut self.protocol_handler {
+            if let Some(bytes) = piece_bytes(message) {
+                self.rate_limits.consume_upload(bytes).await;
+            }
             protocol_handler.send_message(message).await?;
             self.update_activity().await;
             Ok(())
@@ -147,6 +222,10 @@ impl ConnectionManager {
     pub async fn receive_message(&mut self) -> Result<Message> {
         if let Some(protocol_handler) = &mut self.protocol_handler {
             let message = protocol_handler.receive_message().await?;
+            //=== Holding off the next read is what slows the sender down ===//
+            if let Some(bytes) = piece_bytes(&message) {
+                self.rate_limits.consume_download(bytes).await;
+            }
             self.update_activity().await;
             Ok(message)
         } else {
@@ -162,6 +241,9 @@ impl ConnectionManager {
 
             match message_result {
                 Ok(Ok(message)) => {
+                    if let Some(bytes) = piece_bytes(&message) {
+                        self.rate_limits.consume_download(bytes).await;
+                    }
                     self.update_activity().await;
                     Ok(message)
                 }
@@ -193,28 +275,28 @@ impl ConnectionManager {
         Ok(())
     }
 
+    //==== Time since anything was last sent or received ====//
+    pub async fn idle_for(&self) -> Duration {
+        let info_guard = self.connection_info.read().await;
+        self.clock
+            .now()
+            .saturating_duration_since(info_guard.last_activity)
+    }
+
     //==== Check for active connection ====//
     pub async fn is_active(&self) -> bool {
         let info_guard = self.connection_info.read().await;
         info_guard.state == ConnectionState::Connected
-            && !info_guard.is_stale(self.config.connection_timeout)
+            && !info_guard.is_stale_at(self.clock.now(), self.config.connection_timeout)
     }
 
     pub async fn connection_info(&self) -> ConnectionInfo {
-        let guard = self.connection_info.read().await;
-        ConnectionInfo {
-            addr: guard.addr,
-            peer_id: guard.peer_id,
-            info_hash: guard.info_hash,
-            state: guard.state.clone(),
-            connected_at: guard.connected_at,
-            last_activity: guard.last_activity,
-        }
+        self.connection_info.read().await.clone()
     }
 
     async fn update_activity(&self) {
         let mut info_guard = self.connection_info.write().await;
-        info_guard.update_activity();
+        info_guard.update_activity_at(self.clock.now());
     }
 
     async fn set_state(&self, state: ConnectionState) {
@@ -238,17 +320,32 @@ impl ConnectionManager {
     }
 }
 
+//=== Each connection has its own lock, so a slow peer never holds up the pool ===//
+type PooledConnection = Arc<tokio::sync::Mutex<ConnectionManager>>;
+type Connections = Arc<RwLock<HashMap<SocketAddr, PooledConnection>>>;
+
 //=== pool of connections for managing multiple connections ===//
 pub struct ConnectionPool {
     config: Config,
-    connections: Arc<RwLock<std::collections::HashMap<SocketAddr, ConnectionManager>>>,
+    connections: Connections,
+    clock: SharedClock,
+    reaper: Mutex<Option<AbortHandle>>,
+    rate_limits: RateLimits,
 }
 
 impl ConnectionPool {
     pub fn new(config: Config) -> Self {
+        Self::with_clock(config, system_clock())
+    }
+
+    //=== Create a pool whose connections and reaper are driven by the given clock ===//
+    pub fn with_clock(config: Config, clock: SharedClock) -> Self {
         Self {
+            rate_limits: RateLimits::from_config(&config),
             config,
-            connections: Arc::new(RwLock::new(std::collections::HashMap::new())),
+            connections: Arc::new(RwLock::new(HashMap::new())),
+            clock,
+            reaper: Mutex::new(None),
         }
     }
 
@@ -260,35 +357,63 @@ impl ConnectionPool {
         info_hash: Hash,
     ) -> Result<()> {
         let connection_info = ConnectionInfo::new(addr, peer_id, info_hash);
-        let mut connection_manager = ConnectionManager::new(self.config.clone(), connection_info);
+        let mut connection_manager =
+            ConnectionManager::with_clock(self.config.clone(), connection_info, self.clock.clone())
+                .with_rate_limits(self.rate_limits.clone());
 
         //=== set  connections ===//
         connection_manager.connect().await?;
 
         //=== Add to pool ===//
         let mut connections_guard = self.connections.write().await;
-        connections_guard.insert(addr, connection_manager);
+        connections_guard.insert(addr, Arc::new(tokio::sync::Mutex::new(connection_manager)));
 
         info!("Added connection to pool: {}", addr);
         Ok(())
     }
     pub async fn remove_connection(&self, addr: &SocketAddr) -> Result<()> {
-        let mut connections_guard = self.connections.write().await;
+        let removed = self.connections.write().await.remove(addr);
 
-        if let Some(mut connection) = connections_guard.remove(addr) {
-            connection.disconnect().await?;
+        if let Some(connection) = removed {
+            connection.lock().await.disconnect().await?;
             info!("Removed connection from pool: {}", addr);
         }
 
         Ok(())
     }
 
-    //==== Get a connection from the pool ===//
-    pub async fn get_connection(&self, addr: &SocketAddr) -> bool {
+    //==== Check whether the pool holds a connection ===//
+    pub async fn has_connection(&self, addr: &SocketAddr) -> bool {
         let connections_guard = self.connections.read().await;
         connections_guard.contains_key(addr)
     }
 
+    //==== Run `f` against a pooled connection, e.g. to send it a message ===//
+    pub async fn with_connection<F, R>(&self, addr: &SocketAddr, f: F) -> Option<R>
+    where
+        F: for<'a> FnOnce(&'a mut ConnectionManager) -> BoxFuture<'a, R>,
+    {
+        let connection = self.connections.read().await.get(addr).cloned()?;
+        let mut connection = connection.lock().await;
+        Some(f(&mut connection).await)
+    }
+
+    //=== Snapshot of every pooled connection's info, for status displays ===//
+    pub async fn connections_info(&self) -> Vec<ConnectionInfo> {
+        let connections = snapshot(&self.connections).await;
+        let mut infos = Vec::with_capacity(connections.len());
+        for (_, connection) in connections {
+            infos.push(connection.lock().await.connection_info().await);
+        }
+        infos
+    }
+
+    pub async fn get_connection_info(&self, addr: &SocketAddr) -> Option<ConnectionInfo> {
+        let connection = self.connections.read().await.get(addr).cloned()?;
+        let info = connection.lock().await.connection_info().await;
+        Some(info)
+    }
+
     //==== Get all active connections count ====//
     pub async fn get_active_connections_count(&self) -> usize {
         let connections_guard = self.connections.read().await;
@@ -297,24 +422,31 @@ impl ConnectionPool {
 
     //=== Clean up stale connections ===//
     pub async fn cleanup_stale_connections(&self) -> Result<()> {
-        let mut connections_guard = self.connections.write().await;
-        let stale_addrs: Vec<SocketAddr> = connections_guard
-            .iter()
-            .filter(|(_, conn)| {
-                let _info = conn.connection_info();
-                false
-            })
-            .map(|(addr, _)| *addr)
-            .collect();
+        remove_stale(&self.connections, self.clock.now(), &self.config).await
+    }
 
-        for addr in stale_addrs {
-            if let Some(mut connection) = connections_guard.remove(&addr) {
-                connection.disconnect().await?;
-                info!("Removed stale connection: {}", addr);
+    //=== Periodically reap stale connections and keep idle ones alive, until `close_all` ===//
+    pub fn start_reaper(&self) -> JoinHandle<()> {
+        let connections = self.connections.clone();
+        let clock = self.clock.clone();
+        let config = self.config.clone();
+
+        let handle = tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(config.reaper_interval);
+            loop {
+                ticker.tick().await;
+                let now = clock.now();
+                if let Err(e) = remove_stale(&connections, now, &config).await {
+                    warn!("Error reaping stale connections: {}", e);
+                }
+                send_keep_alives(&connections, &config).await;
             }
-        }
+        });
 
-        Ok(())
+        if let Some(previous) = self.reaper.lock().unwrap().replace(handle.abort_handle()) {
+            previous.abort();
+        }
+        handle
     }
 
     //=== Get connection count ===//
@@ -324,10 +456,14 @@ impl ConnectionPool {
 
     //==== Close all connections ====//
     pub async fn close_all(&self) -> Result<()> {
-        let mut connections_guard = self.connections.write().await;
+        if let Some(reaper) = self.reaper.lock().unwrap().take() {
+            reaper.abort();
+        }
+
+        let drained: Vec<_> = self.connections.write().await.drain().collect();
 
-        for (addr, mut connection) in connections_guard.drain() {
-            if let Err(e) = connection.disconnect().await {
+        for (addr, connection) in drained {
+            if let Err(e) = connection.lock().await.disconnect().await {
                 warn!("Error disconnecting from {}: {}", addr, e);
             }
         }
@@ -337,9 +473,126 @@ impl ConnectionPool {
     }
 }
 
+//=== The pooled connections as they are now, so none is awaited under the pool lock ===//
+async fn snapshot(connections: &Connections) -> Vec<(SocketAddr, PooledConnection)> {
+    connections
+        .read()
+        .await
+        .iter()
+        .map(|(addr, connection)| (*addr, Arc::clone(connection)))
+        .collect()
+}
+
+//=== Drop connections that have been quiet for longer than `connection_timeout` ===//
+async fn remove_stale(connections: &Connections, now: Instant, config: &Config) -> Result<()> {
+    let mut stale_addrs = Vec::new();
+    for (addr, connection) in snapshot(connections).await {
+        if connection
+            .lock()
+            .await
+            .connection_info()
+            .await
+            .is_stale_at(now, config.connection_timeout)
+        {
+            stale_addrs.push(addr);
+        }
+    }
+
+    //=== One connection failing to close must not keep the rest in the pool ===//
+    for addr in stale_addrs {
+        let removed = connections.write().await.remove(&addr);
+        if let Some(connection) = removed {
+            match connection.lock().await.disconnect().await {
+                Ok(()) => info!("Removed stale connection: {}", addr),
+                Err(e) => warn!("Error disconnecting stale connection {}: {}", addr, e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+//=== Ping connections that are nearing the idle threshold ===//
+async fn send_keep_alives(connections: &Connections, config: &Config) {
+    for (addr, connection) in snapshot(connections).await {
+        let mut connection = connection.lock().await;
+        if connection.idle_for().await < config.keep_alive_interval {
+            continue;
+        }
+        if let Err(e) = connection.send_message(&Message::keep_alive()).await {
+            warn!("Failed to send keep-alive to {}: {}", addr, e);
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::protocol::Handshake;
+
+    //=== A remote peer that answers the handshake and reports the first message it receives ===//
+    async fn spawn_remote() -> (SocketAddr, JoinHandle<Option<Message>>) {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut handler = HandshakeHandler::new(stream);
+            let theirs = handler.receive_handshake().await.unwrap();
+            handler
+                .send_handshake(&Handshake::new(theirs.info_hash, [3u8; 20]))
+                .await
+                .unwrap();
+            let mut protocol_handler = ProtocolHandler::new(handler.into_stream());
+            protocol_handler.receive_message().await.ok()
+        });
+        (addr, remote)
+    }
+
+    #[tokio::test]
+    async fn test_socket_buffer_sizes_applied() {
+        //=== Odd sizes well under the system maximum, so no default can pass for them ===//
+        let config = Config {
+            socket_send_buf: Some(20_000),
+            socket_recv_buf: Some(30_000),
+            ..Config::default()
+        };
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let socket = configured_socket(&addr, &config).unwrap();
+
+        //=== Linux reports double what was set, for its own bookkeeping ===//
+        let applied = |reported: u32, set: u32| reported == set || reported == 2 * set;
+        assert!(applied(socket.send_buffer_size().unwrap(), 20_000));
+        assert!(applied(socket.recv_buffer_size().unwrap(), 30_000));
+
+        //=== Listener and dialer both come up with the sizes applied ===//
+        let listener = listen_with_config(addr, &config).unwrap();
+        let local = listener.local_addr().unwrap();
+        let (dialed, accepted) =
+            tokio::join!(connect_with_config(local, &config), listener.accept());
+        dialed.unwrap();
+        accepted.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_peer_sockets_use_nodelay() {
+        let addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let listener = listen_with_config(addr, &Config::default()).unwrap();
+        let local = listener.local_addr().unwrap();
+
+        for nodelay in [true, false] {
+            let config = Config {
+                tcp_nodelay: nodelay,
+                ..Config::default()
+            };
+            let (dialed, accepted) =
+                tokio::join!(connect_with_config(local, &config), listener.accept());
+            let (accepted, _) = accepted.unwrap();
+            configure_stream(&accepted, &config).unwrap();
+
+            assert_eq!(dialed.unwrap().nodelay().unwrap(), nodelay);
+            assert_eq!(accepted.nodelay().unwrap(), nodelay);
+        }
+    }
 
     #[tokio::test]
     async fn test_connection_info_creation() {
@@ -370,6 +623,23 @@ mod tests {
         assert!(info.last_activity > original_activity);
     }
 
+    #[tokio::test]
+    async fn test_connection_activity_with_mock_clock() {
+        use crate::core::{Clock, MockClock};
+
+        let clock = MockClock::new();
+        let addr = "127.0.0.1:6881".parse().unwrap();
+        let mut info = ConnectionInfo::new(addr, [1u8; 20], [2u8; 20]);
+        info.update_activity_at(clock.now());
+        info.state = ConnectionState::Connected;
+
+        let manager = ConnectionManager::with_clock(Config::default(), info, clock.shared());
+        assert!(manager.is_active().await);
+
+        clock.advance(Duration::from_secs(31));
+        assert!(!manager.is_active().await);
+    }
+
     #[tokio::test]
     async fn test_connection_pool_creation() {
         let config = Config::default();
@@ -377,4 +647,131 @@ mod tests {
 
         assert_eq!(pool.connection_count().await, 0);
     }
+
+    #[tokio::test]
+    async fn test_send_through_pooled_connection() {
+        use crate::protocol::MessageType;
+
+        let info_hash = [9u8; 20];
+        let (addr, remote) = spawn_remote().await;
+
+        let pool = ConnectionPool::new(Config::default());
+        pool.add_connection(addr, [1u8; 20], info_hash)
+            .await
+            .unwrap();
+        assert!(pool.has_connection(&addr).await);
+
+        let sent = pool
+            .with_connection(&addr, |connection| {
+                Box::pin(async move { connection.send_message(&Message::interested()).await })
+            })
+            .await;
+        assert!(matches!(sent, Some(Ok(()))));
+
+        let received = remote.await.unwrap().unwrap();
+        assert_eq!(received.message_type, MessageType::Interested);
+
+        //=== A send that is still in progress doesn't lock the rest of the pool out ===//
+        let (release_tx, release_rx) = tokio::sync::oneshot::channel::<()>();
+        let pending = pool.with_connection(&addr, |_| {
+            Box::pin(async move {
+                let _ = release_rx.await;
+            })
+        });
+        let lookup = async {
+            let found = timeout(Duration::from_secs(5), pool.has_connection(&addr)).await;
+            release_tx.send(()).unwrap();
+            found
+        };
+        let (sent, found) = tokio::join!(pending, lookup);
+        assert_eq!(sent, Some(()));
+        assert!(found.expect("pool stayed locked during a send"));
+
+        //=== Unknown addresses don't run the closure ===//
+        let missing: Option<()> = pool
+            .with_connection(&"127.0.0.1:1".parse().unwrap(), |_| {
+                Box::pin(async { unreachable!() })
+            })
+            .await;
+        assert!(missing.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reaper_drops_stale_and_pings_idle() {
+        use crate::core::MockClock;
+        use crate::protocol::MessageType;
+
+        let config = Config {
+            connection_timeout: Duration::from_secs(30),
+            keep_alive_interval: Duration::from_secs(10),
+            reaper_interval: Duration::from_millis(10),
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let pool = ConnectionPool::with_clock(config, clock.shared());
+
+        let (stale_addr, _stale_remote) = spawn_remote().await;
+        pool.add_connection(stale_addr, [1u8; 20], [9u8; 20])
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(20));
+
+        let (idle_addr, idle_remote) = spawn_remote().await;
+        pool.add_connection(idle_addr, [1u8; 20], [9u8; 20])
+            .await
+            .unwrap();
+
+        //=== First connection is now past the timeout, the second only past the keep-alive interval ===//
+        clock.advance(Duration::from_secs(15));
+        let reaper = pool.start_reaper();
+
+        let received = timeout(Duration::from_secs(5), idle_remote)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            received.map(|message| message.message_type),
+            Some(MessageType::KeepAlive)
+        );
+        assert!(!pool.has_connection(&stale_addr).await);
+        assert!(pool.has_connection(&idle_addr).await);
+
+        pool.close_all().await.unwrap();
+        assert!(reaper.await.unwrap_err().is_cancelled());
+    }
+
+    #[tokio::test]
+    async fn test_connection_pool_enumerates_info() {
+        let pool = ConnectionPool::new(Config::default());
+        let addrs: Vec<SocketAddr> = ["127.0.0.1:7001", "127.0.0.1:7002"]
+            .iter()
+            .map(|a| a.parse().unwrap())
+            .collect();
+
+        for (i, addr) in addrs.iter().enumerate() {
+            let mut info = ConnectionInfo::new(*addr, [i as u8; 20], [9u8; 20]);
+            info.state = ConnectionState::Connected;
+            let manager = ConnectionManager::new(Config::default(), info);
+            pool.connections
+                .write()
+                .await
+                .insert(*addr, Arc::new(tokio::sync::Mutex::new(manager)));
+        }
+
+        let mut infos = pool.connections_info().await;
+        infos.sort_by_key(|info| info.addr);
+        assert_eq!(infos.len(), 2);
+        assert_eq!(infos[0].addr, addrs[0]);
+        assert_eq!(infos[1].peer_id, [1u8; 20]);
+        assert!(infos
+            .iter()
+            .all(|info| info.state == ConnectionState::Connected));
+
+        let info = pool.get_connection_info(&addrs[1]).await.unwrap();
+        assert_eq!(info.info_hash, [9u8; 20]);
+        assert!(pool
+            .get_connection_info(&"127.0.0.1:7003".parse().unwrap())
+            .await
+            .is_none());
+    }
 }
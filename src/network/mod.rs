@@ -1,44 +1,173 @@
-use crate::core::{Config, Hash, PeerId, TorrentInfo};
-use crate::peer::{Peer, PeerManager};
+use crate::core::{
+    system_clock, Bitfield, BlockLength, BlockOffset, BlockRequest, Config, Hash, PeerId,
+    PieceIndex, SharedClock, Statistics, TorrentInfo,
+};
+use crate::file::FileManager;
+use crate::logging::{debug, error, info, peer_span, warn, Instrument};
+use crate::peer::{allowed_fast_set, ChokingState, InterestState, PeerManager, PeerState};
 use crate::protocol::{
-    messages::MessageParser, Handshake, HandshakeHandler, Message, ProtocolHandler,
+    messages::{MessageBuilder, MessageParser, MessageValidator},
+    Handshake, HandshakeHandler, Message, ProtocolHandler,
 };
+use crate::session::{PieceEvent, PiecePipeline, ReceivedBlock};
 use anyhow::{Context, Result};
-use log::{debug, error, info, warn};
 use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
 use tokio::time::{timeout, Duration};
 
 pub mod connection;
+pub mod rate_limit;
 pub mod tracker;
+pub mod udp_tracker;
+pub mod web_seed;
+#[cfg(feature = "websocket")]
+pub mod websocket_tracker;
 
 pub use connection::*;
+pub use rate_limit::*;
 pub use tracker::*;
+pub use udp_tracker::*;
+pub use web_seed::*;
+#[cfg(feature = "websocket")]
+pub use websocket_tracker::*;
+
+//=== Outbound message queues of the connected peers, per torrent as one peer may join several ===//
+pub type PeerSenders = Arc<RwLock<HashMap<(Hash, PeerId), mpsc::UnboundedSender<Message>>>>;
+
+//=== Peer sets, one per torrent, keyed by info hash ===//
+pub type SharedPeerManager = Arc<RwLock<PeerManager>>;
+pub type PeerManagers = Arc<RwLock<HashMap<Hash, SharedPeerManager>>>;
+
+//=== Storage used to serve uploads, one per torrent ===//
+pub type SharedFileManager = Arc<RwLock<FileManager>>;
+pub type FileManagers = Arc<RwLock<HashMap<Hash, SharedFileManager>>>;
+
+//=== Transfer counters, one per torrent ===//
+pub type SharedStatistics = Arc<RwLock<Statistics>>;
+pub type TorrentStatistics = Arc<RwLock<HashMap<Hash, SharedStatistics>>>;
+
+//=== Entry points of the piece pipelines, one per torrent that has one ===//
+pub type BlockSenders = Arc<RwLock<HashMap<Hash, mpsc::Sender<ReceivedBlock>>>>;
+
+//=== Everything an incoming connection needs from the network manager ===//
+#[derive(Clone)]
+struct ConnectionContext {
+    peer_id: PeerId,
+    peer_managers: PeerManagers,
+    torrent_info: Arc<RwLock<HashMap<Hash, TorrentInfo>>>,
+    peer_senders: PeerSenders,
+    file_managers: FileManagers,
+    block_senders: BlockSenders,
+    statistics: TorrentStatistics,
+    rate_limits: RateLimits,
+    config: Config,
+}
+
+//=== What `add_torrent_info` did with the torrent it was given ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddTorrentOutcome {
+    Added,
+    // The same metadata was already registered; nothing changed //
+    AlreadyAdded,
+}
+
+//=== How the connection loop should treat a peer after one of its messages ===//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageOutcome {
+    Continue,
+    // Malformed but plausibly a bug; repeated offences still end in a ban //
+    Disconnect { reason: String },
+    // Something no honest client sends for this torrent //
+    Ban { reason: String },
+}
+
+//=== Check a message against the torrent before acting on it ===//
+pub fn classify_message(message: &Message, torrent_info: &TorrentInfo) -> MessageOutcome {
+    use crate::protocol::MessageType;
+
+    if !message.is_valid() {
+        return MessageOutcome::Disconnect {
+            reason: format!("malformed {:?} payload", message.message_type),
+        };
+    }
+    if let Err(e) = message.validate_piece_index(torrent_info.num_pieces()) {
+        return MessageOutcome::Ban {
+            reason: format!("{:?} with {}", message.message_type, e),
+        };
+    }
+
+    match message.message_type {
+        MessageType::Request => match message.validate_request_for(torrent_info) {
+            Ok(()) => MessageOutcome::Continue,
+            Err(e) => MessageOutcome::Disconnect {
+                reason: format!("bad request: {}", e),
+            },
+        },
+        MessageType::Bitfield if message.payload.len() != torrent_info.num_pieces().div_ceil(8) => {
+            MessageOutcome::Disconnect {
+                reason: format!("bitfield of {} bytes", message.payload.len()),
+            }
+        }
+        _ => MessageOutcome::Continue,
+    }
+}
+
+//=== Recent failed dials to one address ===//
+#[derive(Debug, Clone, Copy)]
+struct DialFailure {
+    count: u32,
+    last_failure: Instant,
+}
 
 //=== Network manager for handling all network operations ===//
 pub struct NetworkManager {
     config: Config,
-    peer_manager: Arc<RwLock<PeerManager>>,
+    peer_id: PeerId,
+    peer_managers: PeerManagers,
     torrent_info: Arc<RwLock<HashMap<Hash, TorrentInfo>>>,
-    listener: Option<TcpListener>,
-    shutdown_tx: mpsc::Sender<()>,
-    shutdown_rx: mpsc::Receiver<()>,
+    peer_senders: PeerSenders,
+    file_managers: FileManagers,
+    block_senders: BlockSenders,
+    statistics: TorrentStatistics,
+    // One pair of limiters for every connection, so the configured caps are global //
+    rate_limits: RateLimits,
+    dial_failures: RwLock<HashMap<SocketAddr, DialFailure>>,
+    // Where the listener bound, and the task accepting on it, while started //
+    local_addr: Option<SocketAddr>,
+    accept_task: Option<JoinHandle<()>>,
+    shutdown_tx: Option<mpsc::Sender<()>>,
+    clock: SharedClock,
 }
 
 impl NetworkManager {
-    pub fn new(config: Config) -> Self {
-        let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+    //=== `peer_id` is used for every handshake, and is what sessions should announce ===//
+    pub fn new(config: Config, peer_id: PeerId) -> Self {
+        Self::with_clock(config, peer_id, system_clock())
+    }
 
+    //=== Create a network manager driven by the given clock ===//
+    pub fn with_clock(config: Config, peer_id: PeerId, clock: SharedClock) -> Self {
         Self {
+            rate_limits: RateLimits::from_config(&config),
             config,
-            peer_manager: Arc::new(RwLock::new(PeerManager::new(100, 50))),
+            peer_id,
+            peer_managers: Arc::new(RwLock::new(HashMap::new())),
             torrent_info: Arc::new(RwLock::new(HashMap::new())),
-            listener: None,
-            shutdown_tx,
-            shutdown_rx,
+            peer_senders: Arc::new(RwLock::new(HashMap::new())),
+            file_managers: Arc::new(RwLock::new(HashMap::new())),
+            block_senders: Arc::new(RwLock::new(HashMap::new())),
+            statistics: Arc::new(RwLock::new(HashMap::new())),
+            dial_failures: RwLock::new(HashMap::new()),
+            local_addr: None,
+            accept_task: None,
+            shutdown_tx: None,
+            clock,
         }
     }
     pub async fn start(&mut self) -> Result<()> {
@@ -49,13 +178,20 @@ impl NetworkManager {
 
         //=== Bind to the listening port ===//
         let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), self.config.listen_port);
-        let listener = TcpListener::bind(addr)
-            .await
+        let listener = listen_with_config(addr, &self.config)
             .with_context(|| format!("Failed to bind to port {}", self.config.listen_port))?;
 
-        self.listener = Some(listener);
+        self.local_addr = Some(listener.local_addr()?);
 
-        self.accept_connections().await?;
+        //=== Accept in the background so torrents can still be added while we listen ===//
+        let (shutdown_tx, shutdown_rx) = mpsc::channel(1);
+        let context = self.connection_context();
+        self.accept_task = Some(tokio::spawn(Self::accept_connections(
+            listener,
+            context,
+            shutdown_rx,
+        )));
+        self.shutdown_tx = Some(shutdown_tx);
 
         Ok(())
     }
@@ -63,45 +199,61 @@ impl NetworkManager {
         info!("Stopping network manager");
 
         //=== Send shutdown signal ===//
-        if let Err(e) = self.shutdown_tx.send(()).await {
-            warn!("Failed to send shutdown signal: {}", e);
+        if let Some(shutdown_tx) = self.shutdown_tx.take() {
+            if let Err(e) = shutdown_tx.send(()).await {
+                warn!("Failed to send shutdown signal: {}", e);
+            }
         }
-        self.listener = None;
+        if let Some(accept_task) = self.accept_task.take() {
+            let _ = accept_task.await;
+        }
+        self.local_addr = None;
 
         Ok(())
     }
 
-    //=== Accept incoming connections ===//
-    async fn accept_connections(&mut self) -> Result<()> {
-        let listener = self
-            .listener
-            .as_ref()
-            .ok_or_else(|| anyhow::anyhow!("Listener not initialized"))?;
+    //=== Address the listener is bound to, once started ===//
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.local_addr
+    }
 
-        let peer_manager = Arc::clone(&self.peer_manager);
-        let torrent_info = Arc::clone(&self.torrent_info);
-        let config = self.config.clone();
+    //=== Shared handles an incoming connection task needs ===//
+    fn connection_context(&self) -> ConnectionContext {
+        ConnectionContext {
+            peer_id: self.peer_id,
+            peer_managers: Arc::clone(&self.peer_managers),
+            torrent_info: Arc::clone(&self.torrent_info),
+            peer_senders: Arc::clone(&self.peer_senders),
+            file_managers: Arc::clone(&self.file_managers),
+            block_senders: Arc::clone(&self.block_senders),
+            statistics: Arc::clone(&self.statistics),
+            rate_limits: self.rate_limits.clone(),
+            config: self.config.clone(),
+        }
+    }
 
+    //=== Accept incoming connections; each handshake looks its torrent up afresh ===//
+    async fn accept_connections(
+        listener: TcpListener,
+        context: ConnectionContext,
+        mut shutdown_rx: mpsc::Receiver<()>,
+    ) {
         loop {
             tokio::select! {
                 accept_result = listener.accept() => {
                     match accept_result {
                         Ok((socket, addr)) => {
                             debug!("New connection from {}", addr);
+                            if let Err(e) = configure_stream(&socket, &context.config) {
+                                warn!("Failed to configure socket from {}: {}", addr, e);
+                            }
 
                             //=== Spawn a task to handle the connection ===//
-                            let peer_manager_clone = Arc::clone(&peer_manager);
-                            let torrent_info_clone = Arc::clone(&torrent_info);
-                            let config_clone = config.clone();
-
+                            let context = context.clone();
                             tokio::spawn(async move {
-                                if let Err(e) = Self::handle_incoming_connection(
-                                    socket,
-                                    addr,
-                                    peer_manager_clone,
-                                    torrent_info_clone,
-                                    config_clone
-                                ).await {
+                                if let Err(e) =
+                                    Self::handle_incoming_connection(socket, addr, context).await
+                                {
                                     error!("Error handling connection from {}: {}", addr, e);
                                 }
                             });
@@ -112,32 +264,110 @@ impl NetworkManager {
                     }
                 }
 
-                _ = self.shutdown_rx.recv() => {
+                _ = shutdown_rx.recv() => {
                     info!("Received shutdown signal");
                     break;
                 }
             }
         }
-
-        Ok(())
     }
     async fn handle_incoming_connection(
         socket: TcpStream,
         addr: SocketAddr,
-        peer_manager: Arc<RwLock<PeerManager>>,
-        torrent_info: Arc<RwLock<HashMap<Hash, TorrentInfo>>>,
-        config: Config,
+        context: ConnectionContext,
     ) -> Result<()> {
-        let mut handshake_handler = HandshakeHandler::new(socket);
+        let ConnectionContext {
+            peer_id,
+            peer_managers,
+            torrent_info,
+            peer_senders,
+            file_managers,
+            block_senders,
+            statistics,
+            rate_limits,
+            config,
+        } = context;
+        let mut handshake_handler = HandshakeHandler::for_config(socket, &config);
+        let (our_handshake, their_handshake, torrent_info) = Self::answer_handshake(
+            &mut handshake_handler,
+            peer_id,
+            &torrent_info,
+            &config,
+            addr,
+        )
+        .await?;
+        let storage = file_managers
+            .read()
+            .await
+            .get(&their_handshake.info_hash)
+            .cloned();
+        let blocks = block_senders
+            .read()
+            .await
+            .get(&their_handshake.info_hash)
+            .cloned();
+        let statistics = statistics
+            .read()
+            .await
+            .get(&their_handshake.info_hash)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No statistics for torrent"))?;
 
-        let handshake_result = timeout(
-            config.connection_timeout,
-            Self::perform_handshake(&mut handshake_handler),
+        //=== Create peer connection ===//
+        let stream = handshake_handler.into_stream();
+        let protocol_handler = ProtocolHandler::new(stream);
+
+        //=== Add peer to the manager of the torrent it asked for ===//
+        let peer_manager = peer_managers
+            .read()
+            .await
+            .get(&their_handshake.info_hash)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No peer manager for torrent"))?;
+        Self::register_peer(
+            &peer_manager,
+            &our_handshake,
+            &their_handshake,
+            addr,
+            torrent_info.num_pieces(),
+            &config,
         )
-        .await;
+        .await?;
+
+        Self::handle_peer_connection(
+            protocol_handler,
+            their_handshake.info_hash,
+            their_handshake.peer_id,
+            torrent_info,
+            storage,
+            blocks,
+            statistics,
+            rate_limits,
+            peer_manager,
+            peer_senders,
+            config,
+        )
+        .instrument(peer_span(addr, &their_handshake.info_hash))
+        .await?;
+
+        Ok(())
+    }
 
-        let (_our_handshake, their_handshake) = match handshake_result {
-            Ok(Ok(result)) => result,
+    //=== The initiator speaks first; we answer only for a torrent we serve, with our peer id ===//
+    async fn answer_handshake<S: AsyncRead + AsyncWrite + Unpin>(
+        handshake_handler: &mut HandshakeHandler<S>,
+        peer_id: PeerId,
+        torrent_info: &RwLock<HashMap<Hash, TorrentInfo>>,
+        config: &Config,
+        addr: SocketAddr,
+    ) -> Result<(Handshake, Handshake, TorrentInfo)> {
+        let their_handshake = match timeout(
+            config.connection_timeout,
+            handshake_handler.receive_handshake(),
+        )
+        .await
+        {
+            Ok(Ok(handshake)) => handshake,
             Ok(Err(e)) => {
                 error!("Handshake failed with {}: {}", addr, e);
                 return Err(e.into());
@@ -149,90 +379,256 @@ impl NetworkManager {
         };
 
         //=== Verify the  torrent info ===//
-        let torrent_info_guard = torrent_info.read().await;
-        if !torrent_info_guard.contains_key(&their_handshake.info_hash) {
+        let Some(torrent_info) = torrent_info
+            .read()
+            .await
+            .get(&their_handshake.info_hash)
+            .cloned()
+        else {
             error!("Unknown torrent info hash from {}", addr);
             return Err(anyhow::anyhow!("Unknown torrent"));
-        }
-        let torrent_info = torrent_info_guard[&their_handshake.info_hash].clone();
-        drop(torrent_info_guard);
-
-        //=== Create peer connection ===//
-        let stream = handshake_handler.into_stream();
-        let protocol_handler = ProtocolHandler::new(stream);
-
-        //=== Add peer to manager ===//
-        let mut peer_manager_guard = peer_manager.write().await;
-        let _peer = Peer::new(their_handshake.peer_id, addr, torrent_info.num_pieces());
-
-        peer_manager_guard.add_peer(their_handshake.peer_id, addr)?;
-        drop(peer_manager_guard);
+        };
 
-        Self::handle_peer_connection(
-            protocol_handler,
-            format!("{:?}", their_handshake.peer_id),
-            peer_manager,
-            config,
-        )
-        .await?;
+        let our_handshake = Handshake::with_reserved(
+            their_handshake.info_hash,
+            peer_id,
+            Handshake::reserved_for(config),
+        );
+        handshake_handler
+            .send_handshake(&our_handshake)
+            .await
+            .with_context(|| format!("Failed to answer handshake from {}", addr))?;
+        Ok((our_handshake, their_handshake, torrent_info))
+    }
 
+    //=== Add a handshaken peer, noting the extensions both sides agreed on ===//
+    async fn register_peer(
+        peer_manager: &Arc<RwLock<PeerManager>>,
+        ours: &Handshake,
+        theirs: &Handshake,
+        addr: SocketAddr,
+        num_pieces: usize,
+        config: &Config,
+    ) -> Result<()> {
+        let mut peer_manager = peer_manager.write().await;
+        peer_manager.add_peer(theirs.peer_id, addr)?;
+        if let Some(peer) = peer_manager.get_peer_mut(&theirs.peer_id) {
+            peer.state = PeerState::Ready;
+            peer.supports_fast = ours.supports_fast() && theirs.supports_fast();
+            peer.supports_dht = ours.supports_dht() && theirs.supports_dht();
+            if peer.supports_fast {
+                let count = config.allowed_fast_slots(num_pieces);
+                peer.allowed_fast =
+                    allowed_fast_set(addr.ip(), &theirs.info_hash, num_pieces, count)
+                        .into_iter()
+                        .collect();
+            }
+        }
         Ok(())
     }
 
-    //=== Perform handshake with a peer ===//
-    async fn perform_handshake(
-        handshake_handler: &mut HandshakeHandler,
-    ) -> Result<(Handshake, Handshake)> {
-        // make these info hash and peer ID would come from the torrent info
-        let info_hash = [0u8; 20];
-        let peer_id = [0u8; 20];
+    //=== Our pieces, sent first; fast-extension peers always get one of the three ===//
+    async fn send_availability(
+        protocol_handler: &mut ProtocolHandler,
+        remote_id: &PeerId,
+        storage: Option<&SharedFileManager>,
+        peer_manager: &Arc<RwLock<PeerManager>>,
+    ) -> Result<()> {
+        let fast = peer_manager
+            .read()
+            .await
+            .get_peer(remote_id)
+            .is_some_and(|peer| peer.supports_fast);
+        let bitfield = match storage {
+            Some(storage) => Some(storage.read().await.piece_manager().bitfield().clone()),
+            None => None,
+        };
 
-        handshake_handler
-            .perform_handshake(info_hash, peer_id)
+        let message = match bitfield {
+            Some(bitfield) if fast && bitfield.is_complete() => Message::have_all(),
+            Some(bitfield) if bitfield.count_pieces() > 0 => {
+                Message::bitfield(&bitfield.to_bytes())
+            }
+            _ if fast => Message::have_none(),
+            //=== Without the extension, having nothing may go unsaid ===//
+            _ => return Ok(()),
+        };
+        protocol_handler
+            .send_message(&message)
             .await
-            .map_err(|e| anyhow::anyhow!("Handshake failed: {}", e))
+            .map_err(|e| anyhow::anyhow!("Failed to send {:?}: {}", message.message_type, e))
+    }
+
+    //=== Offer a fast-extension peer the allowed-fast pieces we can serve ===//
+    async fn send_allowed_fast(
+        protocol_handler: &mut ProtocolHandler,
+        remote_id: &PeerId,
+        storage: &SharedFileManager,
+        peer_manager: &Arc<RwLock<PeerManager>>,
+    ) -> Result<()> {
+        let mut allowed: Vec<_> = match peer_manager.read().await.get_peer(remote_id) {
+            Some(peer) => peer.allowed_fast.iter().copied().collect(),
+            None => return Ok(()),
+        };
+        allowed.sort_unstable();
+
+        let file_manager = storage.read().await;
+        for piece_index in allowed {
+            if file_manager.piece_manager().has_piece(piece_index) {
+                protocol_handler
+                    .send_message(&Message::allowed_fast(piece_index))
+                    .await
+                    .map_err(|e| anyhow::anyhow!("Failed to send allowed fast: {}", e))?;
+            }
+        }
+        Ok(())
     }
 
     //=== Handle an established peer connection ===//
+    #[allow(clippy::too_many_arguments)]
     async fn handle_peer_connection(
         mut protocol_handler: ProtocolHandler,
-        peer_id: String,
+        info_hash: Hash,
+        remote_id: PeerId,
+        torrent_info: TorrentInfo,
+        storage: Option<SharedFileManager>,
+        blocks: Option<mpsc::Sender<ReceivedBlock>>,
+        statistics: SharedStatistics,
+        rate_limits: RateLimits,
         peer_manager: Arc<RwLock<PeerManager>>,
-        _config: Config,
+        peer_senders: PeerSenders,
+        config: Config,
     ) -> Result<()> {
+        let peer_id = format!("{:?}", remote_id);
         info!("Handling peer connection: {}", peer_id);
 
+        //=== Register an outbound queue so other tasks can message this peer ===//
+        let (outbound_tx, mut outbound_rx) = mpsc::unbounded_channel();
+        peer_senders
+            .write()
+            .await
+            .insert((info_hash, remote_id), outbound_tx);
+
+        //=== A failed send here surfaces again on the first read below ===//
+        if let Err(e) = Self::send_availability(
+            &mut protocol_handler,
+            &remote_id,
+            storage.as_ref(),
+            &peer_manager,
+        )
+        .await
+        {
+            error!("Error sending our pieces to {}: {}", peer_id, e);
+        }
+        if let Some(storage) = &storage {
+            if let Err(e) =
+                Self::send_allowed_fast(&mut protocol_handler, &remote_id, storage, &peer_manager)
+                    .await
+            {
+                error!("Error offering allowed fast pieces to {}: {}", peer_id, e);
+            }
+        }
+
+        //=== Tell DHT-capable peers where our node listens (BEP 5) ===//
+        let supports_dht = peer_manager
+            .read()
+            .await
+            .get_peer(&remote_id)
+            .is_some_and(|peer| peer.supports_dht);
+        if let (Some(port), true) = (config.dht_port, supports_dht) {
+            if let Err(e) = protocol_handler
+                .send_message(&Message::build_port(port))
+                .await
+            {
+                error!("Error sending DHT port to {}: {}", peer_id, e);
+            }
+        }
+
         loop {
-            let message_result =
-                timeout(Duration::from_secs(30), protocol_handler.receive_message()).await;
+            let uploads_queued = peer_manager
+                .read()
+                .await
+                .get_peer(&remote_id)
+                .is_some_and(|peer| !peer.upload_queue.is_empty());
 
-            match message_result {
-                Ok(Ok(message)) => {
-                    debug!(
-                        "Received message from {}: {:?}",
-                        peer_id, message.message_type
-                    );
+            tokio::select! {
+                message_result = timeout(Duration::from_secs(30), protocol_handler.receive_message()) => {
+                    match message_result {
+                        Ok(Ok(message)) => {
+                            debug!(
+                                "Received message from {}: {:?}",
+                                peer_id, message.message_type
+                            );
+
+                            match Self::handle_message(
+                                &message,
+                                &mut protocol_handler,
+                                &remote_id,
+                                &peer_id,
+                                &torrent_info,
+                                storage.as_ref(),
+                                blocks.as_ref(),
+                                &statistics,
+                                &rate_limits,
+                                &peer_manager,
+                            )
+                            .await
+                            {
+                                Ok(MessageOutcome::Continue) => {}
+                                Ok(MessageOutcome::Disconnect { reason }) => {
+                                    warn!("Disconnecting peer {}: {}", peer_id, reason);
+                                    peer_manager.write().await.note_protocol_violation(&remote_id);
+                                    break;
+                                }
+                                Ok(MessageOutcome::Ban { reason }) => {
+                                    warn!("Banning peer {}: {}", peer_id, reason);
+                                    peer_manager.write().await.ban_peer(&remote_id);
+                                    break;
+                                }
+                                Err(e) => {
+                                    error!("Error handling message from {}: {}", peer_id, e);
+                                    break;
+                                }
+                            }
+                        }
+                        Ok(Err(e)) => {
+                            error!("Error receiving message from {}: {}", peer_id, e);
+                            break;
+                        }
+                        Err(_) => {
+                            debug!("Keep-alive timeout for peer {}", peer_id);
+                            if let Err(e) = protocol_handler.send_message(&Message::keep_alive()).await {
+                                error!("Error sending keep-alive to {}: {}", peer_id, e);
+                                break;
+                            }
+                        }
+                    }
+                }
 
-                    if let Err(e) = Self::handle_message(
-                        &message,
+                _ = std::future::ready(()), if uploads_queued => {
+                    if let Err(e) = Self::serve_next_upload(
                         &mut protocol_handler,
-                        &peer_id,
+                        &remote_id,
+                        storage.as_ref(),
+                        &statistics,
+                        &rate_limits,
                         &peer_manager,
                     )
                     .await
                     {
-                        error!("Error handling message from {}: {}", peer_id, e);
+                        error!("Error serving {}: {}", peer_id, e);
                         break;
                     }
                 }
-                Ok(Err(e)) => {
-                    error!("Error receiving message from {}: {}", peer_id, e);
-                    break;
-                }
-                Err(_) => {
-                    debug!("Keep-alive timeout for peer {}", peer_id);
-                    if let Err(e) = protocol_handler.send_message(&Message::keep_alive()).await {
-                        error!("Error sending keep-alive to {}: {}", peer_id, e);
+
+                outgoing = outbound_rx.recv() => {
+                    //=== A dropped sender means we decided to disconnect this peer ===//
+                    let Some(outgoing) = outgoing else {
+                        info!("Disconnecting peer {}", peer_id);
+                        break;
+                    };
+                    if let Err(e) = protocol_handler.send_message(&outgoing).await {
+                        error!("Error sending {:?} to {}: {}", outgoing.message_type, peer_id, e);
                         break;
                     }
                 }
@@ -240,57 +636,121 @@ impl NetworkManager {
         }
 
         //== Remove peer from manager ==//
+        peer_senders.write().await.remove(&(info_hash, remote_id));
+        peer_manager.write().await.remove_peer(&remote_id);
         info!("Peer connection closed: {}", peer_id);
         Ok(())
     }
 
     //== Handle a protocol message ==//
+    #[allow(clippy::too_many_arguments)]
     async fn handle_message(
         message: &Message,
         protocol_handler: &mut ProtocolHandler,
+        remote_id: &PeerId,
         peer_id: &str,
+        torrent_info: &TorrentInfo,
+        storage: Option<&SharedFileManager>,
+        blocks: Option<&mpsc::Sender<ReceivedBlock>>,
+        statistics: &SharedStatistics,
+        rate_limits: &RateLimits,
         peer_manager: &Arc<RwLock<PeerManager>>,
-    ) -> Result<()> {
+    ) -> Result<MessageOutcome> {
         use crate::protocol::MessageType;
 
+        //=== Bad indices, lengths and requests are turned away at the boundary ===//
+        let outcome = classify_message(message, torrent_info);
+        if outcome != MessageOutcome::Continue {
+            return Ok(outcome);
+        }
+
         match message.message_type {
             MessageType::Choke => {
                 debug!("Peer {} choked us", peer_id);
+                peer_manager
+                    .write()
+                    .await
+                    .peer_choking_changed(remote_id, ChokingState::Choked);
             }
 
             MessageType::Unchoke => {
                 debug!("Peer {} unchoked us", peer_id);
+                peer_manager
+                    .write()
+                    .await
+                    .peer_choking_changed(remote_id, ChokingState::Unchoked);
             }
 
             MessageType::Interested => {
                 debug!("Peer {} is interested", peer_id);
+                peer_manager
+                    .write()
+                    .await
+                    .peer_interest_changed(remote_id, InterestState::Interested);
             }
 
             MessageType::NotInterested => {
                 debug!("Peer {} is not interested", peer_id);
+                peer_manager
+                    .write()
+                    .await
+                    .peer_interest_changed(remote_id, InterestState::NotInterested);
             }
 
             MessageType::Have => {
                 if let Ok(piece_index) = message.parse_have() {
                     debug!("Peer {} has piece {}", peer_id, piece_index);
+                    let interest = peer_manager.write().await.peer_have(remote_id, piece_index);
+                    Self::send_interest(protocol_handler, interest).await?;
                 }
             }
 
             MessageType::Bitfield => {
-                if let Ok(_bitfield_data) = message.parse_bitfield() {
+                if let Ok(bitfield_data) = message.parse_bitfield() {
                     debug!("Peer {} sent bitfield", peer_id);
+                    let bitfield = Bitfield::from_bytes(&bitfield_data, torrent_info.num_pieces());
+                    let interest = peer_manager
+                        .write()
+                        .await
+                        .peer_bitfield(remote_id, bitfield);
+                    Self::send_interest(protocol_handler, interest).await?;
                 }
             }
 
+            //=== Fast-extension shorthand for a full or empty bitfield ===//
+            MessageType::HaveAll | MessageType::HaveNone => {
+                debug!("Peer {} sent {:?}", peer_id, message.message_type);
+                let num_pieces = torrent_info.num_pieces();
+                let bitfield = if message.message_type == MessageType::HaveAll {
+                    Bitfield::full(num_pieces)
+                } else {
+                    Bitfield::new(num_pieces)
+                };
+                let interest = peer_manager
+                    .write()
+                    .await
+                    .peer_bitfield(remote_id, bitfield);
+                Self::send_interest(protocol_handler, interest).await?;
+            }
+
             MessageType::Request => {
                 if let Ok((piece_index, offset, length)) = message.parse_request() {
                     debug!(
                         "Peer {} requested piece {} offset {} length {}",
                         peer_id, piece_index, offset, length
                     );
-                    //=== Handle piece request ===//
-                    Self::handle_piece_request(protocol_handler, piece_index, offset, length)
-                        .await?;
+
+                    //=== Served from the queue between reads; a flooding peer is cut off ===//
+                    if let Err(e) = peer_manager.write().await.queue_upload_request(
+                        remote_id,
+                        piece_index,
+                        offset,
+                        length,
+                    ) {
+                        return Ok(MessageOutcome::Disconnect {
+                            reason: e.to_string(),
+                        });
+                    }
                 }
             }
 
@@ -304,54 +764,256 @@ impl NetworkManager {
                         data.len()
                     );
                     //=== Handle received piece data ===//
-                    Self::handle_piece_data(peer_id, piece_index, offset, data, peer_manager)
-                        .await?;
+                    Self::handle_piece_data(
+                        protocol_handler,
+                        remote_id,
+                        piece_index,
+                        offset,
+                        data,
+                        storage,
+                        blocks,
+                        statistics,
+                        rate_limits,
+                        peer_manager,
+                    )
+                    .await?;
                 }
             }
 
             MessageType::Cancel => {
-                debug!("Peer {} cancelled request", peer_id);
+                if let Ok((piece_index, offset, length)) = message.parse_cancel() {
+                    debug!("Peer {} cancelled request", peer_id);
+                    peer_manager.write().await.cancel_upload_request(
+                        remote_id,
+                        piece_index,
+                        offset,
+                        length,
+                    );
+                }
             }
 
             MessageType::Port => {
                 if let Ok(port) = message.parse_port() {
-                    debug!("Peer {} announced port {}", peer_id, port);
+                    debug!("Peer {} announced DHT port {}", peer_id, port);
+                    if let Some(peer) = peer_manager.write().await.get_peer_mut(remote_id) {
+                        peer.dht_port = Some(port);
+                    }
+                }
+            }
+
+            MessageType::RejectRequest => {
+                if let Ok((piece_index, offset, _length)) = message.parse_reject_request() {
+                    debug!(
+                        "Peer {} rejected our request for piece {} offset {}",
+                        peer_id, piece_index, offset
+                    );
+                    peer_manager
+                        .write()
+                        .await
+                        .request_rejected(remote_id, piece_index, offset);
+                }
+            }
+
+            MessageType::AllowedFast => {
+                if let Ok(piece_index) = message.parse_allowed_fast() {
+                    debug!("Peer {} allows piece {} while choked", peer_id, piece_index);
                 }
             }
 
             MessageType::KeepAlive => {}
         }
 
+        Ok(MessageOutcome::Continue)
+    }
+
+    //=== Tell the peer our interest flipped; requests only follow an Interested ===//
+    async fn send_interest(
+        protocol_handler: &mut ProtocolHandler,
+        interest: Option<InterestState>,
+    ) -> Result<()> {
+        let message = match interest {
+            Some(InterestState::Interested) => Message::interested(),
+            Some(InterestState::NotInterested) => Message::not_interested(),
+            None => return Ok(()),
+        };
+        Ok(protocol_handler.send_message(&message).await?)
+    }
+
+    //=== Serve the oldest queued request, unless we have choked the peer since ===//
+    async fn serve_next_upload(
+        protocol_handler: &mut ProtocolHandler,
+        remote_id: &PeerId,
+        storage: Option<&SharedFileManager>,
+        statistics: &SharedStatistics,
+        rate_limits: &RateLimits,
+        peer_manager: &Arc<RwLock<PeerManager>>,
+    ) -> Result<()> {
+        let (piece_index, offset, length, refused, fast) = {
+            let mut peer_manager = peer_manager.write().await;
+            let Some((piece_index, offset, length)) = peer_manager.next_upload_request(remote_id)
+            else {
+                return Ok(());
+            };
+            match peer_manager.get_peer(remote_id) {
+                Some(peer) => {
+                    //=== Allowed-fast pieces skip the choke, but never the quota ===//
+                    let fast_piece = peer.am_choking == ChokingState::Choked
+                        && peer.allowed_fast.contains(&piece_index);
+                    let refused = if fast_piece {
+                        peer.upload_quota_exhausted()
+                    } else {
+                        !peer.can_upload()
+                    };
+                    (piece_index, offset, length, refused, peer.supports_fast)
+                }
+                None => return Ok(()),
+            }
+        };
+
+        //=== Ignore peers we won't serve, or tell them if they speak fast ===//
+        if refused {
+            debug!("Refusing request from peer {:?}", remote_id);
+            if fast {
+                Self::send_reject(protocol_handler, piece_index, offset, length).await?;
+            }
+            return Ok(());
+        }
+
+        rate_limits.consume_upload(length as u64).await;
+        let sent = Self::handle_piece_request(
+            protocol_handler,
+            storage,
+            statistics,
+            fast,
+            piece_index,
+            offset,
+            length,
+        )
+        .await?;
+        let mut peer_manager = peer_manager.write().await;
+        let now = peer_manager.clock().now();
+        if let Some(peer) = peer_manager.get_peer_mut(remote_id) {
+            peer.update_upload_stats_at(sent, now);
+        }
         Ok(())
     }
 
     async fn handle_piece_request(
         protocol_handler: &mut ProtocolHandler,
-        piece_index: crate::core::PieceIndex,
-        offset: crate::core::BlockOffset,
-        length: crate::core::BlockLength,
-    ) -> Result<()> {
-        //=== this data would read from the actual file ===//
-        let dummy_data = vec![0u8; length as usize];
+        storage: Option<&SharedFileManager>,
+        statistics: &SharedStatistics,
+        fast: bool,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockLength,
+    ) -> Result<u64> {
+        let Some(block) = Self::read_requested_block(storage, piece_index, offset, length).await?
+        else {
+            debug!("Ignoring request for piece {} we can't serve", piece_index);
+            if fast {
+                Self::send_reject(protocol_handler, piece_index, offset, length).await?;
+            }
+            return Ok(0);
+        };
 
-        let piece_message = Message::piece(piece_index, offset, dummy_data);
+        let sent = block.len() as u64;
+        let piece_message = Message::piece(piece_index, offset, block);
         protocol_handler
             .send_message(&piece_message)
             .await
             .map_err(|e| anyhow::anyhow!("Failed to send piece: {}", e))?;
+        statistics.write().await.update_uploaded(sent);
 
-        Ok(())
+        Ok(sent)
+    }
+
+    async fn send_reject(
+        protocol_handler: &mut ProtocolHandler,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockLength,
+    ) -> Result<()> {
+        protocol_handler
+            .send_message(&Message::reject_request(piece_index, offset, length))
+            .await
+            .map_err(|e| anyhow::anyhow!("Failed to send reject: {}", e))
+    }
+
+    //=== Read only the requested range, and only from a piece we have verified ===//
+    async fn read_requested_block(
+        storage: Option<&SharedFileManager>,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockLength,
+    ) -> Result<Option<Vec<u8>>> {
+        let Some(storage) = storage else {
+            return Ok(None);
+        };
+
+        let file_manager = storage.read().await;
+        if !file_manager.piece_manager().has_piece(piece_index) {
+            return Ok(None);
+        }
+
+        let block = file_manager.read_block(piece_index, offset, length).await?;
+        Ok(Some(block))
     }
 
     //=== Handle received piece data ===//
+    #[allow(clippy::too_many_arguments)]
     async fn handle_piece_data(
-        _peer_id: &str,
-        piece_index: crate::core::PieceIndex,
-        offset: crate::core::BlockOffset,
+        protocol_handler: &mut ProtocolHandler,
+        remote_id: &PeerId,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
         data: Vec<u8>,
-        _peer_manager: &Arc<RwLock<PeerManager>>,
+        storage: Option<&SharedFileManager>,
+        blocks: Option<&mpsc::Sender<ReceivedBlock>>,
+        statistics: &SharedStatistics,
+        rate_limits: &RateLimits,
+        peer_manager: &Arc<RwLock<PeerManager>>,
     ) -> Result<()> {
-        //==  this received data to be stored and verified ==//
+        //=== Past the download cap, hold off reading until the bucket refills ===//
+        rate_limits.consume_download(data.len() as u64).await;
+        statistics
+            .write()
+            .await
+            .update_downloaded(data.len() as u64);
+        {
+            let mut peer_manager = peer_manager.write().await;
+            let now = peer_manager.clock().now();
+            if let Some(peer) = peer_manager.get_peer_mut(remote_id) {
+                peer.update_download_stats_at(data.len() as u64, now);
+            }
+        }
+
+        //=== Blocks for a piece we already have are dropped before any hashing ===//
+        if let Some(storage) = storage {
+            if storage.read().await.piece_manager().has_piece(piece_index) {
+                debug!("Dropping block for already complete piece {}", piece_index);
+                statistics.write().await.block_wasted(data.len() as u64);
+                let pending = peer_manager
+                    .write()
+                    .await
+                    .get_peer_mut(remote_id)
+                    .map(|peer| peer.remove_request(piece_index))
+                    .unwrap_or_default();
+                if !pending.is_empty() {
+                    let piece_size = storage.read().await.torrent_info().piece_size(piece_index);
+                    for block in BlockRequest::for_piece(*remote_id, piece_index, piece_size) {
+                        if block.offset != offset && pending.contains(&block.offset) {
+                            let cancel = Message::cancel(piece_index, block.offset, block.length);
+                            protocol_handler
+                                .send_message(&cancel)
+                                .await
+                                .map_err(|e| anyhow::anyhow!("Failed to send cancel: {}", e))?;
+                        }
+                    }
+                }
+                return Ok(());
+            }
+        }
+
         debug!(
             "Received {} bytes for piece {} offset {} from peer",
             data.len(),
@@ -359,35 +1021,166 @@ impl NetworkManager {
             offset
         );
 
+        //=== Assembly, hashing and disk writes happen in the pipeline, off this task ===//
+        let Some(blocks) = blocks else {
+            match storage {
+                Some(storage) => {
+                    Self::assemble_block(
+                        remote_id,
+                        piece_index,
+                        offset,
+                        &data,
+                        storage,
+                        peer_manager,
+                    )
+                    .await
+                }
+                None => warn!(
+                    "No storage for this torrent, dropping block for piece {}",
+                    piece_index
+                ),
+            }
+            return Ok(());
+        };
+        let block = ReceivedBlock {
+            peer_id: *remote_id,
+            piece_index,
+            offset,
+            data,
+        };
+        if blocks.send(block).await.is_err() {
+            warn!(
+                "Piece pipeline closed, dropping block for piece {}",
+                piece_index
+            );
+        }
+
         Ok(())
     }
 
-    //== Connect to a peer ==//
-    pub async fn connect_to_peer(
-        &self,
-        addr: SocketAddr,
-        info_hash: Hash,
-        peer_id: PeerId,
-    ) -> Result<()> {
-        info!("Connecting to peer at {}", addr);
+    //=== Without a pipeline, the piece manager collects blocks and hashes each full piece ===//
+    async fn assemble_block(
+        remote_id: &PeerId,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+        data: &[u8],
+        storage: &SharedFileManager,
+        peer_manager: &Arc<RwLock<PeerManager>>,
+    ) {
+        let assembled = {
+            let mut storage = storage.write().await;
+            let piece_manager = storage.piece_manager_mut();
+            piece_manager.record_block_source(piece_index, *remote_id);
+            piece_manager.add_block(piece_index, offset, data)
+        };
 
-        //=== Connect to the peer ===//
-        let stream = TcpStream::connect(addr)
-            .await
-            .with_context(|| format!("Failed to connect to {}", addr))?;
+        match assembled {
+            Ok(None) => {}
+            Ok(Some(true)) => {
+                debug!("Piece {} verified", piece_index);
+                let mut peer_manager = peer_manager.write().await;
+                peer_manager.completed_piece(piece_index);
+                if let Some(peer) = peer_manager.get_peer_mut(remote_id) {
+                    peer.remove_request(piece_index);
+                }
+            }
+            //=== The piece was discarded; freeing the request lets it be picked again ===//
+            Ok(Some(false)) => {
+                warn!("Piece {} failed verification", piece_index);
+                if let Some(peer) = peer_manager.write().await.get_peer_mut(remote_id) {
+                    peer.remove_request(piece_index);
+                }
+            }
+            Err(e) => warn!(
+                "Dropping block for piece {} offset {}: {}",
+                piece_index, offset, e
+            ),
+        }
+    }
 
-        let mut handshake_handler = HandshakeHandler::new(stream);
+    //=== Remember a failed dial so the address is backed off for a while ===//
+    pub async fn note_dial_failure(&self, addr: SocketAddr) {
+        let now = self.clock.now();
+        let mut failures = self.dial_failures.write().await;
+        let failure = failures.entry(addr).or_insert(DialFailure {
+            count: 0,
+            last_failure: now,
+        });
+        failure.count += 1;
+        failure.last_failure = now;
+    }
 
-        let (_our_handshake, their_handshake) = handshake_handler
-            .perform_handshake(info_hash, peer_id)
-            .await
-            .with_context(|| format!("Handshake failed with {}", addr))?;
+    //=== Whether `addr` is out of its backoff; expired failures are forgotten ===//
+    pub async fn can_dial(&self, addr: &SocketAddr) -> bool {
+        let now = self.clock.now();
+        let mut failures = self.dial_failures.write().await;
+        let Some(failure) = failures.get(addr) else {
+            return true;
+        };
 
-        //=== Create protocol handler ===//
-        let stream = handshake_handler.into_stream();
-        let protocol_handler = ProtocolHandler::new(stream);
+        let since = now.saturating_duration_since(failure.last_failure);
+        if since >= self.config.dial_failure_cooldown {
+            failures.remove(addr);
+            return true;
+        }
+
+        let backoff = self
+            .config
+            .dial_retry_backoff
+            .saturating_mul(1 << (failure.count - 1).min(16))
+            .min(self.config.dial_failure_cooldown);
+        since >= backoff
+    }
+
+    //== Connect to a peer ==//
+    pub async fn connect_to_peer(&self, addr: SocketAddr, info_hash: Hash) -> Result<()> {
+        if let Some(peer_manager) = self.peer_manager(&info_hash).await {
+            if peer_manager.read().await.is_full() {
+                debug!("Not dialing {}: torrent is at its peer limit", addr);
+                return Err(anyhow::anyhow!("Peer limit reached for torrent"));
+            }
+        }
+
+        if !self.can_dial(&addr).await {
+            debug!("Skipping recently failed peer {}", addr);
+            return Err(anyhow::anyhow!(
+                "Peer {} failed recently, backing off",
+                addr
+            ));
+        }
 
-        let mut peer_manager_guard = self.peer_manager.write().await;
+        info!("Connecting to peer at {}", addr);
+
+        //=== Connect to the peer ===//
+        let dialed = async {
+            let stream = connect_with_config(addr, &self.config)
+                .await
+                .with_context(|| format!("Failed to connect to {}", addr))?;
+
+            let mut handshake_handler = HandshakeHandler::for_config(stream, &self.config);
+
+            let (our_handshake, their_handshake) = handshake_handler
+                .perform_handshake(info_hash, self.peer_id)
+                .await
+                .with_context(|| format!("Handshake failed with {}", addr))?;
+            Ok::<_, anyhow::Error>((handshake_handler, our_handshake, their_handshake))
+        }
+        .await;
+
+        let (handshake_handler, our_handshake, their_handshake) = match dialed {
+            Ok(dialed) => {
+                self.dial_failures.write().await.remove(&addr);
+                dialed
+            }
+            Err(e) => {
+                self.note_dial_failure(addr).await;
+                return Err(e);
+            }
+        };
+
+        //=== Create protocol handler ===//
+        let stream = handshake_handler.into_stream();
+        let protocol_handler = ProtocolHandler::new(stream);
 
         //=== Get torrent info ===//
         let torrent_info_guard = self.torrent_info.read().await;
@@ -397,38 +1190,256 @@ impl NetworkManager {
             .clone();
         drop(torrent_info_guard);
 
-        let _peer = Peer::new(their_handshake.peer_id, addr, torrent_info.num_pieces());
-
-        peer_manager_guard.add_peer(their_handshake.peer_id, addr)?;
-        drop(peer_manager_guard);
+        let peer_manager = self
+            .peer_manager(&info_hash)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No peer manager for torrent"))?;
+        Self::register_peer(
+            &peer_manager,
+            &our_handshake,
+            &their_handshake,
+            addr,
+            torrent_info.num_pieces(),
+            &self.config,
+        )
+        .await?;
+        let storage = self.file_managers.read().await.get(&info_hash).cloned();
+        let blocks = self.block_senders.read().await.get(&info_hash).cloned();
+        let statistics = self
+            .statistics(&info_hash)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No statistics for torrent"))?;
 
         //==== Handle the connection ====//
-        let peer_manager_clone = Arc::clone(&self.peer_manager);
+        let peer_senders_clone = Arc::clone(&self.peer_senders);
+        let rate_limits = self.rate_limits.clone();
         let config_clone = self.config.clone();
 
-        tokio::spawn(async move {
-            if let Err(e) = Self::handle_peer_connection(
-                protocol_handler,
-                format!("{:?}", their_handshake.peer_id),
-                peer_manager_clone,
-                config_clone,
-            )
-            .await
-            {
-                error!("Error handling outgoing connection: {}", e);
+        tokio::spawn(
+            async move {
+                if let Err(e) = Self::handle_peer_connection(
+                    protocol_handler,
+                    info_hash,
+                    their_handshake.peer_id,
+                    torrent_info,
+                    storage,
+                    blocks,
+                    statistics,
+                    rate_limits,
+                    peer_manager,
+                    peer_senders_clone,
+                    config_clone,
+                )
+                .await
+                {
+                    error!("Error handling outgoing connection: {}", e);
+                }
             }
-        });
+            .instrument(peer_span(addr, &info_hash)),
+        );
 
         Ok(())
     }
 
-    pub async fn add_torrent_info(&self, info_hash: Hash, torrent_info: TorrentInfo) -> Result<()> {
+    //=== Register a torrent's storage; uploads are served from it and downloads land in it ===//
+    pub async fn add_file_manager(
+        &self,
+        info_hash: Hash,
+        file_manager: SharedFileManager,
+    ) -> Result<()> {
+        self.file_managers
+            .write()
+            .await
+            .insert(info_hash, file_manager);
+        if self.torrent_info.read().await.contains_key(&info_hash) {
+            self.start_pipeline(info_hash).await?;
+        }
+        Ok(())
+    }
+
+    //=== Send received blocks of a torrent through a piece pipeline; it runs until stopped ===//
+    pub async fn start_pipeline(&self, info_hash: Hash) -> Result<PiecePipeline> {
+        let torrent_info = self
+            .torrent_info
+            .read()
+            .await
+            .get(&info_hash)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unknown torrent info hash"))?;
+        let storage = self
+            .file_managers
+            .read()
+            .await
+            .get(&info_hash)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("No storage registered for torrent"))?;
+        let peer_manager = self
+            .peer_manager(&info_hash)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No peer manager for torrent"))?;
+        let statistics = self
+            .statistics(&info_hash)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("No statistics for torrent"))?;
+
+        let (pipeline, events) = PiecePipeline::spawn(
+            info_hash,
+            torrent_info,
+            storage,
+            Arc::clone(&peer_manager),
+            Arc::clone(&self.peer_senders),
+        );
+        tokio::spawn(Self::apply_piece_events(events, statistics, peer_manager));
+        self.block_senders
+            .write()
+            .await
+            .insert(info_hash, pipeline.sender());
+        Ok(pipeline)
+    }
+
+    //=== Stats stage: count each outcome and free the piece's requests so it isn't stuck ===//
+    async fn apply_piece_events(
+        mut events: mpsc::UnboundedReceiver<PieceEvent>,
+        statistics: SharedStatistics,
+        peer_manager: SharedPeerManager,
+    ) {
+        while let Some(event) = events.recv().await {
+            event.apply(&mut *statistics.write().await);
+            peer_manager
+                .write()
+                .await
+                .clear_piece_requests(event.piece_index());
+        }
+    }
+
+    //=== Stop routing new connections' blocks to a torrent's pipeline ===//
+    pub async fn stop_pipeline(&self, info_hash: &Hash) {
+        self.block_senders.write().await.remove(info_hash);
+    }
+
+    //=== Register a torrent; identical metadata is a no-op, different metadata an error ===//
+    pub async fn add_torrent_info(
+        &self,
+        info_hash: Hash,
+        torrent_info: TorrentInfo,
+    ) -> Result<AddTorrentOutcome> {
         let mut torrent_info_guard = self.torrent_info.write().await;
+        if let Some(existing) = torrent_info_guard.get(&info_hash) {
+            if *existing == torrent_info {
+                return Ok(AddTorrentOutcome::AlreadyAdded);
+            }
+            return Err(anyhow::anyhow!(
+                "Torrent {} already added with different metadata",
+                hex::encode(info_hash)
+            ));
+        }
+
+        //=== Each torrent gets its own peer set, sized to its real piece count ===//
+        self.peer_managers
+            .write()
+            .await
+            .entry(info_hash)
+            .or_insert_with(|| {
+                Arc::new(RwLock::new(PeerManager::from_config(
+                    torrent_info.num_pieces(),
+                    &self.config,
+                    system_clock(),
+                )))
+            });
+
+        self.statistics
+            .write()
+            .await
+            .entry(info_hash)
+            .or_insert_with(|| Arc::new(RwLock::new(Statistics::new(torrent_info.total_size()))));
+
         torrent_info_guard.insert(info_hash, torrent_info);
+        Ok(AddTorrentOutcome::Added)
+    }
+    //=== Per-torrent peer limit, overriding `max_connections` for that torrent ===//
+    pub async fn set_max_peers(&self, info_hash: &Hash, max_peers: usize) -> Result<()> {
+        let peer_manager = self
+            .peer_manager(info_hash)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Unknown torrent info hash"))?;
+        peer_manager.write().await.set_max_peers(max_peers);
         Ok(())
     }
-    pub fn peer_manager(&self) -> Arc<RwLock<PeerManager>> {
-        Arc::clone(&self.peer_manager)
+
+    pub async fn peer_manager(&self, info_hash: &Hash) -> Option<SharedPeerManager> {
+        self.peer_managers.read().await.get(info_hash).cloned()
+    }
+
+    //=== Bytes a torrent's connections have moved, as trackers want them reported ===//
+    pub async fn statistics(&self, info_hash: &Hash) -> Option<SharedStatistics> {
+        self.statistics.read().await.get(info_hash).cloned()
+    }
+
+    //=== Spawn the periodic choke task, rechoking at the configured unchoke interval ===//
+    pub fn spawn_choker(&self) -> JoinHandle<()> {
+        let peer_managers = Arc::clone(&self.peer_managers);
+        let peer_senders = Arc::clone(&self.peer_senders);
+        let unchoke_interval = self.config.unchoke_interval;
+
+        tokio::spawn(async move {
+            //=== Rounds are gated on the interval itself, so poll within it rather than race it ===//
+            let mut ticker = tokio::time::interval(unchoke_interval / 4);
+            ticker.tick().await;
+
+            loop {
+                ticker.tick().await;
+                let managers: Vec<(Hash, SharedPeerManager)> = peer_managers
+                    .read()
+                    .await
+                    .iter()
+                    .map(|(info_hash, peer_manager)| (*info_hash, Arc::clone(peer_manager)))
+                    .collect();
+                for (info_hash, peer_manager) in managers {
+                    let sent =
+                        Self::run_choke_round(&info_hash, &peer_manager, &peer_senders).await;
+                    debug!("Choke round sent {} choke/unchoke messages", sent);
+                }
+            }
+        })
+    }
+
+    //=== Once the choke interval is up, rechoke, drop redundant seeds and notify changed peers ===//
+    pub async fn run_choke_round(
+        info_hash: &Hash,
+        peer_manager: &Arc<RwLock<PeerManager>>,
+        peer_senders: &PeerSenders,
+    ) -> usize {
+        let (changes, dropped) = {
+            let mut peer_manager = peer_manager.write().await;
+            (
+                peer_manager.update_choking(),
+                peer_manager.disconnect_redundant_seeds(),
+            )
+        };
+
+        let mut senders = peer_senders.write().await;
+        for peer_id in dropped {
+            senders.remove(&(*info_hash, peer_id));
+        }
+        let mut sent = 0;
+        for (peer_id, state) in changes {
+            let message = match state {
+                ChokingState::Choked => Message::choke(),
+                ChokingState::Unchoked => Message::unchoke(),
+            };
+            if let Some(sender) = senders.get(&(*info_hash, peer_id)) {
+                if sender.send(message).is_ok() {
+                    sent += 1;
+                }
+            }
+        }
+
+        sent
+    }
+
+    //=== Peer ID we present in handshakes ===//
+    pub fn peer_id(&self) -> PeerId {
+        self.peer_id
     }
 
     //=== Get configuration ===//
@@ -440,12 +1451,62 @@ impl NetworkManager {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::core::TorrentInfo;
+    use crate::core::{generate_peer_id, TorrentInfo};
+
+    #[test]
+    fn test_malformed_messages_map_to_outcomes() {
+        use crate::protocol::MessageType;
+
+        let torrent_info = TorrentInfo::new(
+            "test".to_string(),
+            16384,
+            vec![[0u8; 20]; 10],
+            vec![crate::core::FileInfo::new(
+                vec!["test".to_string()],
+                10 * 16384,
+            )],
+        );
+        let classify = |message: Message| classify_message(&message, &torrent_info);
+        let raw = |message_type, payload: Vec<u8>| Message {
+            message_type,
+            payload,
+        };
+
+        assert_eq!(classify(Message::have(3)), MessageOutcome::Continue);
+        assert_eq!(
+            classify(Message::request(0, 0, 16384)),
+            MessageOutcome::Continue
+        );
+
+        //=== Framing mistakes look like bugs ===//
+        assert!(matches!(
+            classify(raw(MessageType::Have, vec![0, 0, 1])),
+            MessageOutcome::Disconnect { .. }
+        ));
+        assert!(matches!(
+            classify(raw(MessageType::Bitfield, vec![0xff; 5])),
+            MessageOutcome::Disconnect { .. }
+        ));
+        assert!(matches!(
+            classify(Message::request(0, 16000, 16384)),
+            MessageOutcome::Disconnect { .. }
+        ));
+
+        //=== An index past the end of the torrent is never honest ===//
+        assert!(matches!(
+            classify(Message::have(10)),
+            MessageOutcome::Ban { .. }
+        ));
+        assert!(matches!(
+            classify(Message::request(99, 0, 16384)),
+            MessageOutcome::Ban { .. }
+        ));
+    }
 
     #[tokio::test]
     async fn test_network_manager_creation() {
         let config = Config::default();
-        let network_manager = NetworkManager::new(config);
+        let network_manager = NetworkManager::new(config, generate_peer_id());
 
         assert_eq!(network_manager.config().listen_port, 6881);
     }
@@ -453,7 +1514,7 @@ mod tests {
     #[tokio::test]
     async fn test_add_torrent_info() {
         let config = Config::default();
-        let network_manager = NetworkManager::new(config);
+        let network_manager = NetworkManager::new(config, generate_peer_id());
 
         let info_hash = [1u8; 20];
         let torrent_info = TorrentInfo::new("test".to_string(), 16384, vec![[0u8; 20]], vec![]);
@@ -466,4 +1527,1037 @@ mod tests {
         let torrent_info_guard = network_manager.torrent_info.read().await;
         assert!(torrent_info_guard.contains_key(&info_hash));
     }
+
+    #[tokio::test]
+    async fn test_peer_bitfields_match_torrent_piece_count() {
+        let network_manager = NetworkManager::new(Config::default(), generate_peer_id());
+        let torrent_info = TorrentInfo::new(
+            "test".to_string(),
+            16384,
+            vec![[0u8; 20]; 37],
+            vec![crate::core::FileInfo::new(
+                vec!["test".to_string()],
+                37 * 16384,
+            )],
+        );
+        network_manager
+            .add_torrent_info([1u8; 20], torrent_info)
+            .await
+            .unwrap();
+
+        let peer_manager = network_manager.peer_manager(&[1u8; 20]).await.unwrap();
+        let mut peer_manager = peer_manager.write().await;
+        peer_manager
+            .add_peer([2u8; 20], "127.0.0.1:6881".parse().unwrap())
+            .unwrap();
+
+        let peer = peer_manager.get_peer(&[2u8; 20]).unwrap();
+        assert_eq!(peer.bitfield.total_pieces(), 37);
+    }
+
+    #[tokio::test]
+    async fn test_incoming_peers_routed_to_their_torrent() {
+        let network_manager = NetworkManager::new(Config::default(), generate_peer_id());
+        let (small, large) = ([1u8; 20], [2u8; 20]);
+        for (info_hash, num_pieces) in [(small, 3), (large, 40)] {
+            let torrent_info = TorrentInfo::new(
+                "test".to_string(),
+                16384,
+                vec![[0u8; 20]; num_pieces],
+                vec![crate::core::FileInfo::new(
+                    vec!["test".to_string()],
+                    num_pieces as u64 * 16384,
+                )],
+            );
+            network_manager
+                .add_torrent_info(info_hash, torrent_info)
+                .await
+                .unwrap();
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut handler = HandshakeHandler::new(stream);
+            handler.perform_handshake(large, [7u8; 20]).await.unwrap();
+            handler
+        });
+
+        let (socket, remote) = listener.accept().await.unwrap();
+        let server = tokio::spawn(NetworkManager::handle_incoming_connection(
+            socket,
+            remote,
+            network_manager.connection_context(),
+        ));
+        let _client = client.await.unwrap();
+
+        let large_manager = network_manager.peer_manager(&large).await.unwrap();
+        let small_manager = network_manager.peer_manager(&small).await.unwrap();
+        let mut routed = false;
+        for _ in 0..50 {
+            if let Some(peer) = large_manager.read().await.get_peer(&[7u8; 20]) {
+                assert_eq!(peer.bitfield.total_pieces(), 40);
+                routed = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert!(routed);
+        assert!(small_manager.read().await.peers().is_empty());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_torrent_added_after_start_accepts_peers() {
+        let config = Config {
+            listen_port: 0,
+            ..Config::default()
+        };
+        let mut network_manager = NetworkManager::new(config, generate_peer_id());
+        network_manager.start().await.unwrap();
+        let port = network_manager.local_addr().unwrap().port();
+        let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+
+        let info_hash = [4u8; 20];
+        let torrent_info = TorrentInfo::new(
+            "test".to_string(),
+            16384,
+            vec![[0u8; 20]; 2],
+            vec![crate::core::FileInfo::new(vec!["test".to_string()], 32768)],
+        );
+        network_manager
+            .add_torrent_info(info_hash, torrent_info)
+            .await
+            .unwrap();
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut handler = HandshakeHandler::new(stream);
+        let (_ours, theirs) = handler
+            .perform_handshake(info_hash, [7u8; 20])
+            .await
+            .unwrap();
+        assert_eq!(theirs.info_hash, info_hash);
+        assert_eq!(theirs.peer_id, network_manager.peer_id());
+
+        let peer_manager = network_manager.peer_manager(&info_hash).await.unwrap();
+        let mut registered = false;
+        for _ in 0..50 {
+            if peer_manager.read().await.get_peer(&[7u8; 20]).is_some() {
+                registered = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(registered);
+
+        network_manager.stop().await.unwrap();
+        assert!(network_manager.local_addr().is_none());
+    }
+
+    //=== Answers every handshake with a fresh peer id and keeps the connection open ===//
+    async fn spawn_handshaking_peer() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut next_id = 0u8;
+            while let Ok((socket, _)) = listener.accept().await {
+                next_id += 1;
+                let peer_id = [next_id; 20];
+                tokio::spawn(async move {
+                    let mut handler = HandshakeHandler::new(socket);
+                    let theirs = handler.receive_handshake().await.unwrap();
+                    handler
+                        .send_handshake(&Handshake::new(theirs.info_hash, peer_id))
+                        .await
+                        .unwrap();
+                    let _stream = handler.into_stream();
+                    std::future::pending::<()>().await;
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn test_port_message_sent_to_dht_peer() {
+        use crate::protocol::{MessageType, RESERVED_DHT};
+
+        let config = Config {
+            dht_port: Some(6882),
+            ..Config::default()
+        };
+        let network_manager = NetworkManager::new(config, generate_peer_id());
+        let info_hash = [3u8; 20];
+        let torrent_info = TorrentInfo::new(
+            "test".to_string(),
+            16384,
+            vec![[0u8; 20]; 2],
+            vec![crate::core::FileInfo::new(vec!["test".to_string()], 32768)],
+        );
+        network_manager
+            .add_torrent_info(info_hash, torrent_info)
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut reserved = [0u8; 8];
+            reserved[RESERVED_DHT.0] |= RESERVED_DHT.1;
+            let mut handler = HandshakeHandler::with_reserved(stream, reserved);
+            let (_ours, theirs) = handler
+                .perform_handshake(info_hash, [7u8; 20])
+                .await
+                .unwrap();
+            assert!(theirs.supports_dht());
+
+            let mut protocol_handler = ProtocolHandler::new(handler.into_stream());
+            let port = protocol_handler.receive_message().await.unwrap();
+            protocol_handler
+                .send_message(&Message::build_port(7000))
+                .await
+                .unwrap();
+            (port, protocol_handler)
+        });
+
+        let (socket, remote) = listener.accept().await.unwrap();
+        let server = tokio::spawn(NetworkManager::handle_incoming_connection(
+            socket,
+            remote,
+            network_manager.connection_context(),
+        ));
+        let (port, _client) = client.await.unwrap();
+        assert_eq!(port.message_type, MessageType::Port);
+        assert_eq!(port.parse_port().unwrap(), 6882);
+
+        //=== Their Port reply lands in the list handed to the DHT ===//
+        let peer_manager = network_manager.peer_manager(&info_hash).await.unwrap();
+        let expected: SocketAddr = format!("{}:7000", remote.ip()).parse().unwrap();
+        let mut learned = false;
+        for _ in 0..50 {
+            if peer_manager.read().await.dht_nodes() == vec![expected] {
+                learned = true;
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(learned);
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_request_flood_disconnects_peer() {
+        let config = Config {
+            max_peer_request_rate: 4,
+            ..Config::default()
+        };
+        let network_manager = NetworkManager::new(config, generate_peer_id());
+        let info_hash = [5u8; 20];
+        let torrent_info = TorrentInfo::new(
+            "test".to_string(),
+            16384,
+            vec![[0u8; 20]; 2],
+            vec![crate::core::FileInfo::new(vec!["test".to_string()], 32768)],
+        );
+        network_manager
+            .add_torrent_info(info_hash, torrent_info)
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut handler = HandshakeHandler::new(stream);
+            handler
+                .perform_handshake(info_hash, [7u8; 20])
+                .await
+                .unwrap();
+
+            //=== Far more requests than the rate allows, then wait to be dropped ===//
+            let mut protocol_handler = ProtocolHandler::new(handler.into_stream());
+            for i in 0..32 {
+                let request = Message::request(i % 2, 0, 16384);
+                if protocol_handler.send_message(&request).await.is_err() {
+                    break;
+                }
+            }
+            while protocol_handler.receive_message().await.is_ok() {}
+        });
+
+        let (socket, remote) = listener.accept().await.unwrap();
+        let server = tokio::spawn(NetworkManager::handle_incoming_connection(
+            socket,
+            remote,
+            network_manager.connection_context(),
+        ));
+        tokio::time::timeout(Duration::from_secs(5), client)
+            .await
+            .expect("flooding peer was not disconnected")
+            .unwrap();
+        let _ = server.await;
+
+        let peer_manager = network_manager.peer_manager(&info_hash).await.unwrap();
+        assert!(peer_manager.read().await.get_peer(&[7u8; 20]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_handshake_answered_only_for_served_torrent() {
+        use tokio::io::AsyncReadExt;
+
+        let network_manager = NetworkManager::new(Config::default(), generate_peer_id());
+        let served = [6u8; 20];
+        let torrent_info = TorrentInfo::new(
+            "test".to_string(),
+            16384,
+            vec![[0u8; 20]; 2],
+            vec![crate::core::FileInfo::new(vec!["test".to_string()], 32768)],
+        );
+        network_manager
+            .add_torrent_info(served, torrent_info)
+            .await
+            .unwrap();
+        let addr: SocketAddr = "127.0.0.1:6881".parse().unwrap();
+
+        let answer = |info_hash: Hash| {
+            let context = network_manager.connection_context();
+            async move {
+                let (ours, theirs) = tokio::io::duplex(1024);
+                let mut server = HandshakeHandler::new(ours);
+                let mut client = HandshakeHandler::new(theirs);
+                client
+                    .send_handshake(&Handshake::new(info_hash, [7u8; 20]))
+                    .await
+                    .unwrap();
+
+                let answered = NetworkManager::answer_handshake(
+                    &mut server,
+                    context.peer_id,
+                    &context.torrent_info,
+                    &context.config,
+                    addr,
+                )
+                .await;
+                drop(server);
+                (answered, client.into_stream())
+            }
+        };
+
+        //=== Our real peer id comes back for the torrent the peer named ===//
+        let (answered, mut stream) = answer(served).await;
+        assert!(answered.is_ok());
+        let mut reply = [0u8; 68];
+        stream.read_exact(&mut reply).await.unwrap();
+        let reply = Handshake::deserialize(&reply).unwrap();
+        assert_eq!(reply.info_hash, served);
+        assert_eq!(reply.peer_id, network_manager.peer_id());
+
+        //=== A torrent we don't serve gets no reply, just a closed stream ===//
+        let (answered, mut stream) = answer([9u8; 20]).await;
+        assert!(answered.is_err());
+        let mut rest = Vec::new();
+        assert_eq!(stream.read_to_end(&mut rest).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_adding_torrent_twice_keeps_its_state() {
+        let network_manager = NetworkManager::new(Config::default(), generate_peer_id());
+        let info_hash = [1u8; 20];
+        let torrent_info = TorrentInfo::new(
+            "test".to_string(),
+            16384,
+            vec![[0u8; 20]; 4],
+            vec![crate::core::FileInfo::new(
+                vec!["test".to_string()],
+                4 * 16384,
+            )],
+        );
+
+        let added = network_manager
+            .add_torrent_info(info_hash, torrent_info.clone())
+            .await
+            .unwrap();
+        assert_eq!(added, AddTorrentOutcome::Added);
+        network_manager.set_max_peers(&info_hash, 2).await.unwrap();
+
+        let again = network_manager
+            .add_torrent_info(info_hash, torrent_info.clone())
+            .await
+            .unwrap();
+        assert_eq!(again, AddTorrentOutcome::AlreadyAdded);
+
+        let mut renamed = torrent_info.clone();
+        renamed.name = "other".to_string();
+        assert!(network_manager
+            .add_torrent_info(info_hash, renamed)
+            .await
+            .is_err());
+
+        //=== Neither call replaced the metadata or the running peer set ===//
+        let peer_manager = network_manager.peer_manager(&info_hash).await.unwrap();
+        assert_eq!(peer_manager.read().await.max_peers(), 2);
+        assert_eq!(
+            network_manager.torrent_info.read().await[&info_hash],
+            torrent_info
+        );
+    }
+
+    #[tokio::test]
+    async fn test_per_torrent_peer_limit() {
+        let network_manager = NetworkManager::new(Config::default(), generate_peer_id());
+        let (limited, open) = ([1u8; 20], [2u8; 20]);
+        for info_hash in [limited, open] {
+            let torrent_info = TorrentInfo::new(
+                "test".to_string(),
+                16384,
+                vec![[0u8; 20]; 4],
+                vec![crate::core::FileInfo::new(
+                    vec!["test".to_string()],
+                    4 * 16384,
+                )],
+            );
+            network_manager
+                .add_torrent_info(info_hash, torrent_info)
+                .await
+                .unwrap();
+        }
+        network_manager.set_max_peers(&limited, 2).await.unwrap();
+
+        let addr = spawn_handshaking_peer().await;
+        for _ in 0..3 {
+            let _ = network_manager.connect_to_peer(addr, limited).await;
+            network_manager.connect_to_peer(addr, open).await.unwrap();
+        }
+
+        let limited_peers = network_manager.peer_manager(&limited).await.unwrap();
+        let open_peers = network_manager.peer_manager(&open).await.unwrap();
+        assert_eq!(limited_peers.read().await.peers().len(), 2);
+        assert_eq!(open_peers.read().await.peers().len(), 3);
+        assert!(network_manager
+            .connect_to_peer(addr, limited)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_dials_with_the_given_peer_id() {
+        let our_id = [5u8; 20];
+        let network_manager = NetworkManager::new(Config::default(), our_id);
+        let info_hash = [1u8; 20];
+        let torrent_info = TorrentInfo::new(
+            "test".to_string(),
+            16384,
+            vec![[0u8; 20]],
+            vec![crate::core::FileInfo::new(vec!["test".to_string()], 16384)],
+        );
+        network_manager
+            .add_torrent_info(info_hash, torrent_info)
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let remote = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let mut handler = HandshakeHandler::new(socket);
+            let theirs = handler.receive_handshake().await.unwrap();
+            handler
+                .send_handshake(&Handshake::new(theirs.info_hash, [6u8; 20]))
+                .await
+                .unwrap();
+            (theirs.peer_id, handler.into_stream())
+        });
+
+        network_manager
+            .connect_to_peer(addr, info_hash)
+            .await
+            .unwrap();
+        let (dialed_as, _stream) = remote.await.unwrap();
+        assert_eq!(dialed_as, our_id);
+        assert_eq!(network_manager.peer_id(), our_id);
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    async fn test_peer_logs_carry_connection_span() {
+        let (logs, _guard) = crate::logging::CapturedLogs::install();
+
+        let network_manager = NetworkManager::new(Config::default(), generate_peer_id());
+        let info_hash = [2u8; 20];
+        let torrent_info = TorrentInfo::new(
+            "test".to_string(),
+            16384,
+            vec![[0u8; 20]; 4],
+            vec![crate::core::FileInfo::new(
+                vec!["test".to_string()],
+                4 * 16384,
+            )],
+        );
+        network_manager
+            .add_torrent_info(info_hash, torrent_info)
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut handler = HandshakeHandler::new(stream);
+            handler
+                .perform_handshake(info_hash, [7u8; 20])
+                .await
+                .unwrap();
+            let mut protocol_handler = ProtocolHandler::new(handler.into_stream());
+            protocol_handler
+                .send_message(&Message::interested())
+                .await
+                .unwrap();
+            protocol_handler
+        });
+
+        let (socket, remote) = listener.accept().await.unwrap();
+        let server = tokio::spawn(NetworkManager::handle_incoming_connection(
+            socket,
+            remote,
+            network_manager.connection_context(),
+        ));
+        let _client = client.await.unwrap();
+
+        let mut line = None;
+        for _ in 0..50 {
+            line = logs
+                .contents()
+                .lines()
+                .find(|line| line.contains("is interested"))
+                .map(str::to_string);
+            if line.is_some() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        //=== The message handler's log is attributed to this peer and torrent ===//
+        let line = line.expect("interested message was not logged");
+        assert!(line.contains(&format!("addr={}", remote)), "{}", line);
+        assert!(
+            line.contains(&format!("info_hash={}", hex::encode(info_hash))),
+            "{}",
+            line
+        );
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_failed_dials_back_off_until_cooldown() {
+        use crate::core::MockClock;
+
+        let clock = MockClock::new();
+        let config = Config::default();
+        let network_manager =
+            NetworkManager::with_clock(config.clone(), generate_peer_id(), clock.shared());
+
+        //=== Nothing listens on this port any more ===//
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        assert!(network_manager
+            .connect_to_peer(addr, [1u8; 20])
+            .await
+            .is_err());
+        assert!(!network_manager.can_dial(&addr).await);
+
+        //=== After the first backoff one more attempt is allowed ===//
+        clock.advance(config.dial_retry_backoff);
+        assert!(network_manager.can_dial(&addr).await);
+        network_manager.note_dial_failure(addr).await;
+
+        //=== Twice-failed: skipped past the first backoff, right up to the cooldown ===//
+        clock.advance(config.dial_retry_backoff);
+        assert!(!network_manager.can_dial(&addr).await);
+        let skipped = network_manager
+            .connect_to_peer(addr, [1u8; 20])
+            .await
+            .unwrap_err();
+        assert!(skipped.to_string().contains("backing off"));
+
+        clock.advance(config.dial_failure_cooldown);
+        assert!(network_manager.can_dial(&addr).await);
+        assert!(network_manager.dial_failures.read().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_choke_round_sends_messages() {
+        use crate::core::MockClock;
+        use crate::peer::InterestState;
+        use crate::protocol::MessageType;
+
+        let clock = MockClock::new();
+        let config = Config::default();
+        let mut manager = PeerManager::from_config(10, &config, clock.shared());
+
+        let peer_id = [7u8; 20];
+        manager
+            .add_peer(peer_id, "127.0.0.1:6881".parse().unwrap())
+            .unwrap();
+        manager.get_peer_mut(&peer_id).unwrap().peer_interested = InterestState::Interested;
+
+        let peer_manager = Arc::new(RwLock::new(manager));
+        let peer_senders: PeerSenders = Arc::new(RwLock::new(HashMap::new()));
+        let info_hash = [1u8; 20];
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        peer_senders.write().await.insert((info_hash, peer_id), tx);
+        //=== The same peer in another torrent hears nothing of this one's choking ===//
+        let (other_tx, mut other_rx) = mpsc::unbounded_channel();
+        peer_senders
+            .write()
+            .await
+            .insert(([2u8; 20], peer_id), other_tx);
+
+        //=== Before the interval elapses a round changes nothing ===//
+        clock.advance(config.unchoke_interval / 2);
+        assert_eq!(
+            NetworkManager::run_choke_round(&info_hash, &peer_manager, &peer_senders).await,
+            0
+        );
+        assert!(rx.try_recv().is_err());
+        assert!(peer_manager.read().await.unchoked_peers().is_empty());
+
+        clock.advance(config.unchoke_interval / 2);
+        let sent = NetworkManager::run_choke_round(&info_hash, &peer_manager, &peer_senders).await;
+        assert_eq!(sent, 1);
+        assert_eq!(rx.recv().await.unwrap().message_type, MessageType::Unchoke);
+        assert!(peer_manager
+            .read()
+            .await
+            .unchoked_peers()
+            .contains(&peer_id));
+
+        //=== Losing interest gets the peer choked on the next round ===//
+        peer_manager
+            .write()
+            .await
+            .get_peer_mut(&peer_id)
+            .unwrap()
+            .peer_interested = InterestState::NotInterested;
+        clock.advance(config.unchoke_interval);
+        NetworkManager::run_choke_round(&info_hash, &peer_manager, &peer_senders).await;
+        assert_eq!(rx.recv().await.unwrap().message_type, MessageType::Choke);
+        assert!(other_rx.try_recv().is_err());
+    }
+
+    //=== One file of single-block pieces, with the content they hash to ===//
+    fn block_torrent(num_pieces: usize) -> (TorrentInfo, Vec<u8>) {
+        use sha1::{Digest, Sha1};
+
+        let block = crate::core::BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..num_pieces * block).map(|i| (i % 251) as u8).collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            block as u32,
+            data.chunks(block)
+                .map(|chunk| Sha1::digest(chunk).into())
+                .collect(),
+            vec![crate::core::FileInfo::new(
+                vec!["test".to_string()],
+                data.len() as u64,
+            )],
+        );
+        (info, data)
+    }
+
+    //=== Storage in `dir` that has verified the first `held` pieces ===//
+    async fn seeded_storage(
+        info: &TorrentInfo,
+        data: &[u8],
+        dir: &std::path::Path,
+        held: usize,
+    ) -> SharedFileManager {
+        let mut manager = FileManager::new(info.clone(), dir.to_path_buf(), 8);
+        manager.initialize().await.unwrap();
+        manager.allocate_files().await.unwrap();
+        for piece_index in 0..held as PieceIndex {
+            let range = info.byte_range_for_piece(piece_index);
+            let piece = data[range.start as usize..range.end as usize].to_vec();
+            manager.write_piece(piece_index, &piece).await.unwrap();
+            manager
+                .piece_manager_mut()
+                .store_verified_piece(piece_index, piece)
+                .unwrap();
+        }
+        Arc::new(RwLock::new(manager))
+    }
+
+    //=== Poll until `done` holds, giving the connection task time to act ===//
+    async fn eventually<F, Fut>(mut done: F) -> bool
+    where
+        F: FnMut() -> Fut,
+        Fut: std::future::Future<Output = bool>,
+    {
+        for _ in 0..100 {
+            if done().await {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn test_fast_peers_trade_have_all_and_have_none() {
+        use crate::protocol::MessageType;
+
+        let config = Config {
+            fast_extension: true,
+            ..Config::default()
+        };
+        let network_manager = NetworkManager::new(config.clone(), generate_peer_id());
+        let info_hash = [8u8; 20];
+        let (info, data) = block_torrent(2);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = seeded_storage(&info, &data, dir.path(), 2).await;
+        network_manager
+            .add_torrent_info(info_hash, info.clone())
+            .await
+            .unwrap();
+        network_manager
+            .add_file_manager(info_hash, storage)
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut handler = HandshakeHandler::for_config(stream, &config);
+            handler
+                .perform_handshake(info_hash, [7u8; 20])
+                .await
+                .unwrap();
+            let mut protocol_handler = ProtocolHandler::new(handler.into_stream());
+
+            //=== A seed says so in one message instead of a bitfield ===//
+            let pieces = protocol_handler.receive_message().await.unwrap();
+            assert_eq!(pieces.message_type, MessageType::HaveAll);
+            protocol_handler
+                .send_message(&Message::have_all())
+                .await
+                .unwrap();
+            protocol_handler
+        });
+
+        let (socket, remote) = listener.accept().await.unwrap();
+        let server = tokio::spawn(NetworkManager::handle_incoming_connection(
+            socket,
+            remote,
+            network_manager.connection_context(),
+        ));
+        let _client = client.await.unwrap();
+
+        let peer_manager = network_manager.peer_manager(&info_hash).await.unwrap();
+        assert!(
+            eventually(|| async {
+                peer_manager
+                    .read()
+                    .await
+                    .get_peer(&[7u8; 20])
+                    .is_some_and(|peer| peer.bitfield.is_complete())
+            })
+            .await
+        );
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn test_piece_traffic_counted_per_torrent() {
+        use crate::protocol::MessageType;
+
+        let network_manager = NetworkManager::new(Config::default(), generate_peer_id());
+        let info_hash = [8u8; 20];
+        let (info, data) = block_torrent(2);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = seeded_storage(&info, &data, dir.path(), 1).await;
+        network_manager
+            .add_torrent_info(info_hash, info.clone())
+            .await
+            .unwrap();
+        network_manager
+            .add_file_manager(info_hash, storage)
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (unchoked_tx, unchoked_rx) = tokio::sync::oneshot::channel();
+        let block = data[crate::core::BLOCK_SIZE as usize..].to_vec();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut handler = HandshakeHandler::new(stream);
+            handler
+                .perform_handshake(info_hash, [7u8; 20])
+                .await
+                .unwrap();
+            let mut protocol_handler = ProtocolHandler::new(handler.into_stream());
+            let pieces = protocol_handler.receive_message().await.unwrap();
+            assert_eq!(pieces.message_type, MessageType::Bitfield);
+            assert_eq!(pieces.payload, vec![0x80]);
+
+            //=== Fetch the piece they hold, then hand over the one they lack ===//
+            protocol_handler
+                .send_message(&Message::interested())
+                .await
+                .unwrap();
+            unchoked_rx.await.unwrap();
+            protocol_handler
+                .send_message(&Message::request(0, 0, crate::core::BLOCK_SIZE))
+                .await
+                .unwrap();
+            let reply = protocol_handler.receive_message().await.unwrap();
+            assert_eq!(reply.message_type, MessageType::Piece);
+            protocol_handler
+                .send_message(&Message::piece(1, 0, block))
+                .await
+                .unwrap();
+            protocol_handler
+        });
+
+        let (socket, remote) = listener.accept().await.unwrap();
+        let server = tokio::spawn(NetworkManager::handle_incoming_connection(
+            socket,
+            remote,
+            network_manager.connection_context(),
+        ));
+        let peer_manager = network_manager.peer_manager(&info_hash).await.unwrap();
+        assert!(
+            eventually(|| async {
+                match peer_manager.write().await.get_peer_mut(&[7u8; 20]) {
+                    Some(peer) => {
+                        peer.am_choking = ChokingState::Unchoked;
+                        true
+                    }
+                    None => false,
+                }
+            })
+            .await
+        );
+        unchoked_tx.send(()).unwrap();
+        let _client = client.await.unwrap();
+
+        let statistics = network_manager.statistics(&info_hash).await.unwrap();
+        let expected = crate::core::BLOCK_SIZE as u64;
+        assert!(eventually(|| async { statistics.read().await.wire_downloaded == expected }).await);
+        assert_eq!(statistics.read().await.wire_uploaded, expected);
+
+        //=== The peer's own counters move with the torrent's ===//
+        let peer_manager = peer_manager.read().await;
+        let peer = peer_manager.get_peer(&[7u8; 20]).unwrap();
+        assert_eq!((peer.downloaded, peer.uploaded), (expected, expected));
+        server.abort();
+    }
+
+    //=== A fast-speaking, unchoked, interested peer with `requests` blocks queued ===//
+    fn uploading_peer(requests: PieceIndex) -> (PeerId, SharedPeerManager) {
+        use crate::core::BLOCK_SIZE;
+
+        let peer_id = [7u8; 20];
+        let mut manager = PeerManager::new(requests as usize, 10);
+        manager
+            .add_peer(peer_id, "127.0.0.1:6881".parse().unwrap())
+            .unwrap();
+        let peer = manager.get_peer_mut(&peer_id).unwrap();
+        peer.state = PeerState::Ready;
+        peer.am_choking = ChokingState::Unchoked;
+        peer.peer_interested = InterestState::Interested;
+        peer.supports_fast = true;
+        for piece_index in 0..requests {
+            manager
+                .queue_upload_request(&peer_id, piece_index, 0, BLOCK_SIZE)
+                .unwrap();
+        }
+        (peer_id, Arc::new(RwLock::new(manager)))
+    }
+
+    //=== Both ends of a local connection: ours, and the remote peer's ===//
+    async fn connected_pair() -> (ProtocolHandler, ProtocolHandler) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let remote = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let ours = ProtocolHandler::new(listener.accept().await.unwrap().0);
+        (ours, ProtocolHandler::new(remote))
+    }
+
+    #[tokio::test]
+    async fn test_requests_past_the_upload_quota_are_rejected() {
+        use crate::core::BLOCK_SIZE;
+        use crate::protocol::MessageType;
+
+        let (info, data) = block_torrent(2);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = seeded_storage(&info, &data, dir.path(), 2).await;
+        let (peer_id, peer_manager) = uploading_peer(2);
+        peer_manager
+            .write()
+            .await
+            .get_peer_mut(&peer_id)
+            .unwrap()
+            .upload_quota = Some(BLOCK_SIZE as u64);
+        let statistics: SharedStatistics = Arc::new(RwLock::new(Statistics::new(0)));
+        let (mut ours, mut theirs) = connected_pair().await;
+
+        //=== The first block uses up the quota; the second is turned away ===//
+        for expected in [MessageType::Piece, MessageType::RejectRequest] {
+            NetworkManager::serve_next_upload(
+                &mut ours,
+                &peer_id,
+                Some(&storage),
+                &statistics,
+                &RateLimits::default(),
+                &peer_manager,
+            )
+            .await
+            .unwrap();
+            assert_eq!(
+                theirs.receive_message().await.unwrap().message_type,
+                expected
+            );
+        }
+        let peer_manager = peer_manager.read().await;
+        assert_eq!(
+            peer_manager.get_peer(&peer_id).unwrap().uploaded,
+            BLOCK_SIZE as u64
+        );
+        assert_eq!(statistics.read().await.wire_uploaded, BLOCK_SIZE as u64);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_uploads_wait_for_the_shared_rate_limit() {
+        use crate::core::BLOCK_SIZE;
+
+        let (info, data) = block_torrent(3);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = seeded_storage(&info, &data, dir.path(), 3).await;
+        let (peer_id, peer_manager) = uploading_peer(3);
+        let statistics: SharedStatistics = Arc::new(RwLock::new(Statistics::new(0)));
+        let (mut ours, mut theirs) = connected_pair().await;
+        let network_manager = NetworkManager::new(
+            Config {
+                upload_limit: Some(BLOCK_SIZE as u64),
+                ..Config::default()
+            },
+            generate_peer_id(),
+        );
+
+        //=== A second's worth goes out at once; each further block waits a second ===//
+        let start = tokio::time::Instant::now();
+        for _ in 0..3 {
+            NetworkManager::serve_next_upload(
+                &mut ours,
+                &peer_id,
+                Some(&storage),
+                &statistics,
+                &network_manager.connection_context().rate_limits,
+                &peer_manager,
+            )
+            .await
+            .unwrap();
+            theirs.receive_message().await.unwrap();
+        }
+        let waited = start.elapsed();
+        assert!(waited >= Duration::from_secs(2), "{:?}", waited);
+        assert!(waited < Duration::from_secs(3), "{:?}", waited);
+    }
+
+    #[tokio::test]
+    async fn test_received_pieces_go_through_the_pipeline() {
+        let network_manager = NetworkManager::new(Config::default(), generate_peer_id());
+        let info_hash = [8u8; 20];
+        let (info, data) = block_torrent(2);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = seeded_storage(&info, &data, dir.path(), 0).await;
+        network_manager
+            .add_torrent_info(info_hash, info.clone())
+            .await
+            .unwrap();
+        network_manager
+            .add_file_manager(info_hash, Arc::clone(&storage))
+            .await
+            .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (requested_tx, requested_rx) = tokio::sync::oneshot::channel();
+        let block = crate::core::BLOCK_SIZE as usize;
+        let good = data[block..].to_vec();
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut handler = HandshakeHandler::new(stream);
+            handler
+                .perform_handshake(info_hash, [7u8; 20])
+                .await
+                .unwrap();
+            let mut protocol_handler = ProtocolHandler::new(handler.into_stream());
+
+            //=== Piece 0 arrives corrupt, piece 1 intact ===//
+            requested_rx.await.unwrap();
+            for (piece_index, payload) in [(0, vec![0u8; block]), (1, good)] {
+                protocol_handler
+                    .send_message(&Message::piece(piece_index, 0, payload))
+                    .await
+                    .unwrap();
+            }
+            protocol_handler
+        });
+
+        let (socket, remote) = listener.accept().await.unwrap();
+        let server = tokio::spawn(NetworkManager::handle_incoming_connection(
+            socket,
+            remote,
+            network_manager.connection_context(),
+        ));
+        let peer_manager = network_manager.peer_manager(&info_hash).await.unwrap();
+        assert!(
+            eventually(|| async {
+                match peer_manager.write().await.get_peer_mut(&[7u8; 20]) {
+                    Some(peer) => {
+                        peer.add_request(0, 0);
+                        peer.add_request(1, 0);
+                        true
+                    }
+                    None => false,
+                }
+            })
+            .await
+        );
+        requested_tx.send(()).unwrap();
+        let _client = client.await.unwrap();
+
+        //=== Both outcomes are counted and leave nothing owed ===//
+        let statistics = network_manager.statistics(&info_hash).await.unwrap();
+        assert!(
+            eventually(|| async {
+                let statistics = statistics.read().await;
+                statistics.corrupt == block as u64 && statistics.verified_downloaded == block as u64
+            })
+            .await
+        );
+        assert!(
+            eventually(|| async {
+                let peer_manager = peer_manager.read().await;
+                let peer = peer_manager.get_peer(&[7u8; 20]).unwrap();
+                peer.pending_request_count() == 0
+            })
+            .await
+        );
+
+        //=== Only the good piece is ours, and it is on disk where it belongs ===//
+        let pieces = storage.read().await.piece_manager().completed_pieces();
+        assert_eq!(pieces, vec![1]);
+        let on_disk = tokio::fs::read(dir.path().join("test")).await.unwrap();
+        assert_eq!(on_disk[block..], data[block..]);
+        server.abort();
+    }
 }
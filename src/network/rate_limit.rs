@@ -0,0 +1,141 @@
+use crate::core::Config;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::time::{sleep, Duration, Instant};
+
+//=== Token bucket: `rate` bytes refill each second, holding at most one second's worth ===//
+#[derive(Debug)]
+pub struct RateLimiter {
+    rate: u64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    // Goes negative when a caller takes more than is there; later callers queue behind it //
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    //=== A limiter for `rate` bytes per second, starting with a full bucket ===//
+    pub fn new(rate: u64) -> Self {
+        let rate = rate.max(1);
+        Self {
+            rate,
+            bucket: Mutex::new(Bucket {
+                tokens: rate as f64,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    //=== A shared limiter for a configured limit; None means unlimited ===//
+    pub fn from_limit(limit: Option<u64>) -> Option<Arc<Self>> {
+        limit.map(|rate| Arc::new(Self::new(rate)))
+    }
+
+    pub fn rate(&self) -> u64 {
+        self.rate
+    }
+
+    //=== Take `bytes` tokens, sleeping only until the bucket has refilled enough ===//
+    pub async fn consume(&self, bytes: u64) {
+        let wait = {
+            let mut bucket = self.bucket.lock().await;
+            let now = Instant::now();
+            let elapsed = now.saturating_duration_since(bucket.refilled_at);
+            bucket.tokens =
+                (bucket.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.rate as f64);
+            bucket.refilled_at = now;
+
+            //=== Reserve now and wait outside the lock, so the next caller sees the debt ===//
+            bucket.tokens -= bytes as f64;
+            if bucket.tokens >= 0.0 {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / self.rate as f64)
+        };
+        sleep(wait).await;
+    }
+}
+
+//=== The upload and download limiters every connection shares, so the caps are global ===//
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub upload: Option<Arc<RateLimiter>>,
+    pub download: Option<Arc<RateLimiter>>,
+}
+
+impl RateLimits {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            upload: RateLimiter::from_limit(config.upload_limit),
+            download: RateLimiter::from_limit(config.download_limit),
+        }
+    }
+
+    pub async fn consume_upload(&self, bytes: u64) {
+        if let Some(limiter) = &self.upload {
+            limiter.consume(bytes).await;
+        }
+    }
+
+    pub async fn consume_download(&self, bytes: u64) {
+        if let Some(limiter) = &self.download {
+            limiter.consume(bytes).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_consume_waits_only_for_the_shortfall() {
+        let limiter = RateLimiter::new(10_000);
+
+        //=== A full bucket goes out without waiting ===//
+        let start = Instant::now();
+        limiter.consume(10_000).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        //=== 2000 more bytes need a fifth of a second of refill ===//
+        let start = Instant::now();
+        limiter.consume(2_000).await;
+        assert_eq!(start.elapsed(), Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_shared_limiter_caps_all_callers_together() {
+        let limiter = Arc::new(RateLimiter::new(10_000));
+        limiter.consume(10_000).await;
+
+        //=== Two connections taking 1500 each wait out 3000 bytes between them ===//
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..2)
+            .map(|_| {
+                let limiter = Arc::clone(&limiter);
+                tokio::spawn(async move { limiter.consume(1_500).await })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(start.elapsed(), Duration::from_millis(300));
+    }
+
+    #[test]
+    fn test_unset_limits_are_bypassed() {
+        let limits = RateLimits::from_config(&Config::default());
+        assert!(limits.upload.is_none() && limits.download.is_none());
+
+        let limits = RateLimits::from_config(&Config {
+            upload_limit: Some(4096),
+            ..Config::default()
+        });
+        assert_eq!(limits.upload.map(|limiter| limiter.rate()), Some(4096));
+        assert!(limits.download.is_none());
+    }
+}
@@ -1,10 +1,18 @@
-use crate::core::{Config, Hash, PeerId, Statistics};
+use crate::core::{system_clock, Config, Hash, NetworkError, PeerId, SharedClock, Statistics};
+use crate::file::bencode::{self, BencodeValue};
+use crate::file::{load_persisted, persist_atomic};
+use crate::logging::{debug, error, info, warn};
+use crate::network::udp_tracker::UdpTrackerClient;
 use anyhow::{Context, Result};
-use log::{debug, error, info};
-use serde::Deserialize;
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use std::time::{Duration, Instant};
+use futures::future::{join_all, BoxFuture};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::time::timeout;
 use url::Url;
 
@@ -52,6 +60,9 @@ pub struct TrackerRequest {
     pub numwant: Option<u32>,
     pub key: Option<String>,
     pub tracker_id: Option<String>,
+    // Explicit per-family endpoints so the tracker lists us in `peers` and `peers6` //
+    pub ipv4: Option<SocketAddrV4>,
+    pub ipv6: Option<SocketAddrV6>,
 }
 
 impl TrackerRequest {
@@ -76,9 +87,30 @@ impl TrackerRequest {
             numwant: Some(50),
             key: None,
             tracker_id: None,
+            ipv4: None,
+            ipv6: None,
         }
     }
 
+    //=== Request reporting wire transfer counters, as trackers expect ===//
+    pub fn from_statistics(
+        info_hash: Hash,
+        peer_id: PeerId,
+        port: u16,
+        statistics: &Statistics,
+        event: TrackerEvent,
+    ) -> Self {
+        Self::new(
+            info_hash,
+            peer_id,
+            port,
+            statistics.wire_uploaded,
+            statistics.wire_downloaded,
+            statistics.left,
+            event,
+        )
+    }
+
     //=== Convert to URL query parameters ===//
     pub fn to_query_params(&self) -> String {
         let mut params = Vec::new();
@@ -117,6 +149,13 @@ impl TrackerRequest {
             params.push(format!("trackerid={}", tracker_id));
         }
 
+        if let Some(ipv4) = self.ipv4 {
+            params.push(format!("ipv4={}", urlencoding::encode(&ipv4.to_string())));
+        }
+        if let Some(ipv6) = self.ipv6 {
+            params.push(format!("ipv6={}", urlencoding::encode(&ipv6.to_string())));
+        }
+
         params.join("&")
     }
 }
@@ -146,15 +185,112 @@ pub struct PeerInfo {
     pub port: u16,
 }
 
+//=== Bytes per compact peer: the address, then the port big-endian ===//
+pub(crate) const COMPACT_PEER_V4: usize = 6;
+pub(crate) const COMPACT_PEER_V6: usize = 18;
+
+impl TrackerResponse {
+    //=== Read a decoded announce reply; None unless it is a dict ===//
+    pub fn from_bencode(value: &BencodeValue) -> Option<Self> {
+        value.as_dict()?;
+        let string = |key| {
+            value
+                .get(key)
+                .and_then(BencodeValue::as_bytes)
+                .map(|bytes| String::from_utf8_lossy(bytes).into_owned())
+        };
+        let int = |key| {
+            value
+                .get(key)
+                .and_then(BencodeValue::as_int)
+                .and_then(|int| u32::try_from(int).ok())
+        };
+
+        Some(TrackerResponse {
+            failure_reason: string("failure reason"),
+            warning_message: string("warning message"),
+            interval: int("interval"),
+            min_interval: int("min interval"),
+            tracker_id: string("tracker id"),
+            complete: int("complete"),
+            incomplete: int("incomplete"),
+            peers: value
+                .get("peers")
+                .map(|peers| PeerInfo::from_bencode(peers, COMPACT_PEER_V4)),
+            peers6: value
+                .get("peers6")
+                .map(|peers| PeerInfo::from_bencode(peers, COMPACT_PEER_V6)),
+        })
+    }
+}
+
 impl PeerInfo {
+    //=== Peers as a list of dicts, or packed `record`-byte entries; unreadable ones are skipped ===//
+    fn from_bencode(value: &BencodeValue, record: usize) -> Vec<PeerInfo> {
+        match value {
+            BencodeValue::Bytes(packed) => Self::from_compact(packed, record),
+            BencodeValue::List(peers) => peers
+                .iter()
+                .filter_map(|peer| {
+                    Some(PeerInfo {
+                        peer_id: peer
+                            .get("peer id")
+                            .and_then(BencodeValue::as_bytes)
+                            .map(|id| String::from_utf8_lossy(id).into_owned()),
+                        ip: peer.get("ip")?.as_str()?.to_string(),
+                        port: u16::try_from(peer.get("port")?.as_int()?).ok()?,
+                    })
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    //=== Packed `record`-byte entries: an IPv4 or IPv6 address, then the port ===//
+    pub(crate) fn from_compact(packed: &[u8], record: usize) -> Vec<PeerInfo> {
+        packed
+            .chunks_exact(record)
+            .filter_map(|peer| {
+                let (ip, port) = peer.split_at(record.checked_sub(2)?);
+                let ip = match ip.len() {
+                    4 => Ipv4Addr::from(<[u8; 4]>::try_from(ip).ok()?).to_string(),
+                    16 => Ipv6Addr::from(<[u8; 16]>::try_from(ip).ok()?).to_string(),
+                    _ => return None,
+                };
+                Some(PeerInfo {
+                    peer_id: None,
+                    ip,
+                    port: u16::from_be_bytes([port[0], port[1]]),
+                })
+            })
+            .collect()
+    }
+
+    //=== Built from the parsed IP, so IPv6 literals need no brackets (but may carry them) ===//
     pub fn to_socket_addr(&self) -> Result<SocketAddr> {
-        let addr = format!("{}:{}", self.ip, self.port)
-            .parse::<SocketAddr>()
+        let ip = self.ip.trim_start_matches('[').trim_end_matches(']');
+        let ip = ip
+            .parse::<IpAddr>()
             .with_context(|| format!("Failed to parse peer address: {}:{}", self.ip, self.port))?;
-        Ok(addr)
+        Ok(SocketAddr::new(ip, self.port))
     }
 }
 
+//=== A tracker transport; the manager picks one per announce URL scheme ===//
+pub trait Tracker: Send + Sync {
+    fn announce<'a>(
+        &'a self,
+        tracker_url: &'a str,
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse>>;
+}
+
+//=== Redirect hops followed before an announce gives up ===//
+pub const MAX_TRACKER_REDIRECTS: usize = 5;
+
+//=== Characters of an unparseable tracker body quoted in the error ===//
+const TRACKER_BODY_SNIPPET: usize = 80;
+
 //=== Tracker client for communicating with BitTorrent trackers ===//
 pub struct TrackerClient {
     config: Config,
@@ -163,8 +299,14 @@ pub struct TrackerClient {
 
 impl TrackerClient {
     pub fn new(config: Config) -> Self {
+        let redirect = if config.follow_tracker_redirects {
+            reqwest::redirect::Policy::limited(MAX_TRACKER_REDIRECTS)
+        } else {
+            reqwest::redirect::Policy::none()
+        };
         let http_client = reqwest::Client::builder()
             .timeout(config.tracker_timeout)
+            .redirect(redirect)
             .build()
             .expect("Failed to create HTTP client");
 
@@ -199,6 +341,17 @@ impl TrackerClient {
         .with_context(|| "Tracker request timeout")?
         .with_context(|| "Failed to send tracker request")?;
 
+        //=== Only reached with redirects disabled; the client follows them otherwise ===//
+        if response.status().is_redirection() {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|location| location.to_str().ok())
+                .unwrap_or("an unspecified location")
+                .to_string();
+            return Err(NetworkError::TrackerRedirect { location }.into());
+        }
+
         //=== Check response status ===//
         if !response.status().is_success() {
             return Err(anyhow::anyhow!(
@@ -206,59 +359,40 @@ impl TrackerClient {
                 response.status()
             ));
         }
-        //=== parse succee as text ===//
-        let response_text = response
-            .text()
+        //=== Compact peer lists are binary, so keep the body as bytes ===//
+        let body = response
+            .bytes()
             .await
             .with_context(|| "Failed to read tracker response")?;
 
-        debug!("Tracker response: {}", response_text);
+        debug!("Tracker response: {}", String::from_utf8_lossy(&body));
 
         //=== parse as JSON  ===//
-        if let Ok(tracker_response) = serde_json::from_str::<TrackerResponse>(&response_text) {
+        if let Ok(tracker_response) = serde_json::from_slice::<TrackerResponse>(&body) {
             return Ok(tracker_response);
         }
 
-        //=== parse as a simple bencode ===//
-        self.parse_bencoded_response(&response_text)
+        //=== then as bencode, as real trackers send it ===//
+        self.parse_bencoded_response(&body)
     }
 
-    //=== Parse bencoded tracker response instead of bencode library in real BitTorrent ===//
-    fn parse_bencoded_response(&self, response_text: &str) -> Result<TrackerResponse> {
-        if response_text.starts_with("d") && response_text.ends_with("e") {
-            let mut response = TrackerResponse {
-                failure_reason: None,
-                warning_message: None,
-                interval: Some(1800),
-                min_interval: None,
-                tracker_id: None,
-                complete: None,
-                incomplete: None,
-                peers: Some(Vec::new()),
-                peers6: None,
-            };
-
-            //=== Extract basic fields ===//
-            if let Some(interval_start) = response_text.find("intervali") {
-                if let Some(interval_end) = response_text[interval_start..].find("e") {
-                    if let Ok(interval) = response_text
-                        [interval_start + 9..interval_start + interval_end]
-                        .parse::<u32>()
-                    {
-                        response.interval = Some(interval);
-                    }
-                }
-            }
-
-            //=== Extract simple peers ===//
-            if let Some(_peers_start) = response_text.find("peers") {
-                response.peers = Some(Vec::new());
-            }
+    fn parse_bencoded_response(&self, body: &[u8]) -> Result<TrackerResponse> {
+        if let Some(response) = bencode::decode(body)
+            .ok()
+            .and_then(|value| TrackerResponse::from_bencode(&value))
+        {
+            return Ok(response);
+        }
 
-            Ok(response)
-        } else {
-            Err(anyhow::anyhow!("Invalid bencode response"))
+        //=== Usually an HTML error or rate-limit page; quote its start ===//
+        let snippet: String = String::from_utf8_lossy(body)
+            .chars()
+            .take(TRACKER_BODY_SNIPPET)
+            .collect();
+        Err(NetworkError::UnparseableTrackerBody {
+            snippet: snippet.trim().to_string(),
         }
+        .into())
     }
 
     //=== Scrape tracker for torrent statistics ===//
@@ -322,6 +456,16 @@ impl TrackerClient {
     }
 }
 
+impl Tracker for TrackerClient {
+    fn announce<'a>(
+        &'a self,
+        tracker_url: &'a str,
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse>> {
+        Box::pin(TrackerClient::announce(self, tracker_url, request))
+    }
+}
+
 //=== Scrape information from tracker ===//
 #[derive(Debug, Clone, Deserialize)]
 pub struct ScrapeInfo {
@@ -331,26 +475,116 @@ pub struct ScrapeInfo {
     pub name: Option<String>,
 }
 
+//=== Announce state of a single tracker, as persisted across restarts ===//
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackerAnnounceState {
+    pub url: String,
+    // Unix timestamp (seconds) of the last successful announce //
+    pub last_announce: Option<u64>,
+    pub interval: Option<u64>,
+    pub min_interval: Option<u64>,
+    pub tracker_id: Option<String>,
+    pub started: bool,
+}
+
+//=== Persistable tracker state, stored alongside the resume data ===//
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrackerState {
+    pub key: String,
+    pub trackers: Vec<TrackerAnnounceState>,
+}
+
 //=== Tracker manager for multiple trackers
 pub struct TrackerManager {
     config: Config,
-    tracker_client: TrackerClient,
+    transports: HashMap<String, Arc<dyn Tracker>>,
     trackers: Vec<String>,
     last_announce: HashMap<String, Instant>,
     announce_intervals: HashMap<String, Duration>,
+    // The interval actually waited, jittered so torrents sharing a tracker drift apart //
+    jittered_intervals: HashMap<String, Duration>,
+    min_intervals: HashMap<String, Duration>,
+    tracker_ids: HashMap<String, String>,
+    started: HashSet<String>,
+    tracker_warnings: HashMap<String, String>,
+    // Consecutive failures and when the last one happened //
+    tracker_failures: HashMap<String, (u32, Instant)>,
+    // Failed announces over the manager's lifetime, across all trackers //
+    total_tracker_errors: u64,
+    // Peers to ask each tracker for; None keeps the request default //
+    numwant: Option<u32>,
+    key: String,
+    clock: SharedClock,
+    // Drives announce jitter; seedable for tests //
+    rng: StdRng,
 }
 
+//=== Shortest gap allowed between two announces, even when forced ===//
+pub const MIN_FORCED_ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
 impl TrackerManager {
     pub fn new(config: Config, trackers: Vec<String>) -> Self {
+        Self::with_clock(config, trackers, system_clock())
+    }
+
+    //=== Create a tracker manager driven by the given clock ===//
+    pub fn with_clock(config: Config, trackers: Vec<String>, clock: SharedClock) -> Self {
+        let http: Arc<dyn Tracker> = Arc::new(TrackerClient::new(config.clone()));
+        let udp: Arc<dyn Tracker> = Arc::new(UdpTrackerClient::new(config.clone()));
+        let mut transports = HashMap::new();
+        transports.insert("http".to_string(), Arc::clone(&http));
+        transports.insert("https".to_string(), http);
+        transports.insert("udp".to_string(), udp);
+        #[cfg(feature = "websocket")]
+        {
+            let websocket: Arc<dyn Tracker> = Arc::new(
+                crate::network::websocket_tracker::WebSocketTracker::new(config.clone()),
+            );
+            transports.insert("ws".to_string(), Arc::clone(&websocket));
+            transports.insert("wss".to_string(), websocket);
+        }
+
         Self {
-            tracker_client: TrackerClient::new(config.clone()),
+            transports,
             trackers,
             last_announce: HashMap::new(),
             announce_intervals: HashMap::new(),
+            jittered_intervals: HashMap::new(),
+            min_intervals: HashMap::new(),
+            tracker_ids: HashMap::new(),
+            started: HashSet::new(),
+            tracker_warnings: HashMap::new(),
+            tracker_failures: HashMap::new(),
+            total_tracker_errors: 0,
+            numwant: None,
+            key: format!("{:08x}", rand::random::<u32>()),
+            clock,
+            rng: StdRng::from_entropy(),
             config,
         }
     }
 
+    //=== Replace the random source, e.g. with a seeded one for reproducible runs ===//
+    pub fn set_rng(&mut self, rng: StdRng) {
+        self.rng = rng;
+    }
+
+    pub fn set_numwant(&mut self, numwant: Option<u32>) {
+        self.numwant = numwant;
+    }
+
+    //=== Use `tracker` for announce URLs with the given scheme ===//
+    pub fn register_transport(&mut self, scheme: &str, tracker: Arc<dyn Tracker>) {
+        self.transports.insert(scheme.to_ascii_lowercase(), tracker);
+    }
+
+    //=== Transport for a tracker URL, or `None` if its scheme isn't supported ===//
+    fn transport_for(&self, tracker_url: &str) -> Option<Arc<dyn Tracker>> {
+        let scheme = Url::parse(tracker_url).ok()?.scheme().to_ascii_lowercase();
+        self.transports.get(&scheme).cloned()
+    }
+
+    //=== Announce to every due tracker concurrently, then record each result ===//
     pub async fn announce_all(
         &mut self,
         info_hash: Hash,
@@ -359,13 +593,52 @@ impl TrackerManager {
         statistics: &Statistics,
         event: TrackerEvent,
     ) -> Result<Vec<PeerInfo>> {
-        let mut all_peers = Vec::new();
+        let mut requests = Vec::new();
+        let mut unsupported = Vec::new();
+        for tracker_url in &self.trackers {
+            //=== Stopped and completed are one-off events and go out regardless ===//
+            let due = matches!(event, TrackerEvent::Stopped | TrackerEvent::Completed)
+                || self.should_announce(tracker_url);
+            if !due {
+                debug!("Skipping announce to {} (too soon)", tracker_url);
+                continue;
+            }
 
-        let trackers = self.trackers.clone();
-        for tracker_url in &trackers {
-            match self
-                .announce_to_tracker(tracker_url, info_hash, peer_id, port, statistics, event)
-                .await
+            match self.transport_for(tracker_url) {
+                Some(transport) => {
+                    let request = self.build_request(
+                        tracker_url,
+                        info_hash,
+                        peer_id,
+                        port,
+                        statistics,
+                        event,
+                    );
+                    requests.push((tracker_url.clone(), transport, request));
+                }
+                None => unsupported.push(tracker_url.clone()),
+            }
+        }
+
+        //=== Unsupported schemes are noted and skipped rather than failing the announce ===//
+        for tracker_url in unsupported {
+            warn!("Skipping tracker with unsupported scheme: {}", tracker_url);
+            self.tracker_warnings
+                .insert(tracker_url, "unsupported tracker scheme".to_string());
+        }
+
+        //=== Fetch without touching bookkeeping so slow trackers don't hold up the rest ===//
+        let responses = join_all(
+            requests
+                .iter()
+                .map(|(tracker_url, transport, request)| transport.announce(tracker_url, request)),
+        )
+        .await;
+
+        let mut all_peers = Vec::new();
+        for ((tracker_url, _, request), response) in requests.into_iter().zip(responses) {
+            match response
+                .and_then(|response| self.record_response(&tracker_url, &request, response))
             {
                 Ok(peers) => {
                     all_peers.extend(peers);
@@ -373,6 +646,7 @@ impl TrackerManager {
                 }
                 Err(e) => {
                     error!("Failed to announce to tracker {}: {}", tracker_url, e);
+                    self.note_failure(&tracker_url);
                 }
             }
         }
@@ -380,7 +654,34 @@ impl TrackerManager {
         Ok(all_peers)
     }
 
-    async fn announce_to_tracker(
+    fn note_failure(&mut self, tracker_url: &str) {
+        let now = self.clock.now();
+        let failure = self
+            .tracker_failures
+            .entry(tracker_url.to_string())
+            .or_insert((0, now));
+        failure.0 += 1;
+        failure.1 = now;
+        self.total_tracker_errors += 1;
+    }
+
+    //=== Failed announces so far; unlike the backoff state, never reset by a success ===//
+    pub fn total_tracker_errors(&self) -> u64 {
+        self.total_tracker_errors
+    }
+
+    //=== Whether the last announce to every tracker failed, so none is giving us peers ===//
+    //=== False without trackers: a magnet or DHT-only torrent has nothing to fail ===//
+    pub fn all_trackers_failing(&self) -> bool {
+        !self.trackers.is_empty()
+            && self
+                .trackers
+                .iter()
+                .all(|tracker_url| self.tracker_failures.contains_key(tracker_url))
+    }
+
+    //=== Forced announce to one tracker, ignoring its interval but not the anti-hammer floor ===//
+    pub async fn announce_one(
         &mut self,
         tracker_url: &str,
         info_hash: Hash,
@@ -389,44 +690,124 @@ impl TrackerManager {
         statistics: &Statistics,
         event: TrackerEvent,
     ) -> Result<Vec<PeerInfo>> {
-        //=== Check to announce (respect intervals) ===//
+        if !self.trackers.iter().any(|t| t == tracker_url) {
+            return Err(anyhow::anyhow!("Unknown tracker: {}", tracker_url));
+        }
+
         if let Some(last_announce) = self.last_announce.get(tracker_url) {
-            if let Some(interval) = self.announce_intervals.get(tracker_url) {
-                if last_announce.elapsed() < *interval {
-                    debug!("Skipping announce to {} (too soon)", tracker_url);
-                    return Ok(Vec::new());
-                }
+            let since = self.clock.now().saturating_duration_since(*last_announce);
+            if since < MIN_FORCED_ANNOUNCE_INTERVAL {
+                return Err(anyhow::anyhow!(
+                    "Announce to {} refused: last announce was {}s ago",
+                    tracker_url,
+                    since.as_secs()
+                ));
             }
         }
 
-        //== Create request ==//
-        let request = TrackerRequest::new(
+        self.send_announce(tracker_url, info_hash, peer_id, port, statistics, event)
+            .await
+    }
+
+    //=== Send the announce and record the tracker's bookkeeping ===//
+    async fn send_announce(
+        &mut self,
+        tracker_url: &str,
+        info_hash: Hash,
+        peer_id: PeerId,
+        port: u16,
+        statistics: &Statistics,
+        event: TrackerEvent,
+    ) -> Result<Vec<PeerInfo>> {
+        let transport = self
+            .transport_for(tracker_url)
+            .ok_or_else(|| anyhow::anyhow!("Unsupported tracker scheme: {}", tracker_url))?;
+        let request = self.build_request(tracker_url, info_hash, peer_id, port, statistics, event);
+        let result = match transport.announce(tracker_url, &request).await {
+            Ok(response) => self.record_response(tracker_url, &request, response),
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.note_failure(tracker_url);
+        }
+        result
+    }
+
+    fn build_request(
+        &self,
+        tracker_url: &str,
+        info_hash: Hash,
+        peer_id: PeerId,
+        port: u16,
+        statistics: &Statistics,
+        event: TrackerEvent,
+    ) -> TrackerRequest {
+        let mut request = TrackerRequest::from_statistics(
             info_hash,
             peer_id,
             port,
-            statistics.uploaded,
-            statistics.downloaded,
-            statistics.left,
-            event,
+            statistics,
+            self.effective_event(tracker_url, event),
         );
+        request.key = Some(self.key.clone());
+        if let Some(numwant) = self.numwant {
+            request.numwant = Some(numwant);
+        }
+        request.tracker_id = self.tracker_ids.get(tracker_url).cloned();
+        request.ipv4 = self.config.announce_ipv4;
+        request.ipv6 = self.config.announce_ipv6;
+        request
+    }
 
-        //=== Send request ===//
-        let response = self.tracker_client.announce(tracker_url, &request).await?;
-
+    //=== Apply a tracker's response to our per-tracker state ===//
+    fn record_response(
+        &mut self,
+        tracker_url: &str,
+        request: &TrackerRequest,
+        response: TrackerResponse,
+    ) -> Result<Vec<PeerInfo>> {
         if let Some(failure_reason) = response.failure_reason {
             return Err(anyhow::anyhow!("Tracker failure: {}", failure_reason));
         }
 
         self.last_announce
-            .insert(tracker_url.to_string(), Instant::now());
+            .insert(tracker_url.to_string(), self.clock.now());
+        self.tracker_failures.remove(tracker_url);
+
+        match request.event {
+            TrackerEvent::Started => {
+                self.started.insert(tracker_url.to_string());
+            }
+            TrackerEvent::Stopped => {
+                //=== The tracker forgot us, so a later `started` goes out at once ===//
+                self.started.remove(tracker_url);
+                self.last_announce.remove(tracker_url);
+            }
+            _ => {}
+        }
 
-        if let Some(interval) = response.interval {
-            self.announce_intervals.insert(
+        if let Some(tracker_id) = response.tracker_id {
+            self.tracker_ids.insert(tracker_url.to_string(), tracker_id);
+        }
+
+        if let Some(min_interval) = response.min_interval {
+            self.min_intervals.insert(
                 tracker_url.to_string(),
-                Duration::from_secs(interval as u64),
+                Duration::from_secs(min_interval as u64),
             );
         }
 
+        //=== Fall back to the configured interval when the tracker gives none ===//
+        let interval = response
+            .interval
+            .map(|interval| Duration::from_secs(interval as u64))
+            .unwrap_or(self.config.announce_interval);
+        self.announce_intervals
+            .insert(tracker_url.to_string(), interval);
+        let jittered = self.jitter(tracker_url, interval);
+        self.jittered_intervals
+            .insert(tracker_url.to_string(), jittered);
+
         //==== Extract peers ====//
         let mut peers = Vec::new();
 
@@ -446,6 +827,11 @@ impl TrackerManager {
         &self.trackers
     }
 
+    //=== Last warning recorded for a tracker, if any ===//
+    pub fn tracker_warning(&self, tracker_url: &str) -> Option<&str> {
+        self.tracker_warnings.get(tracker_url).map(String::as_str)
+    }
+
     pub fn add_tracker(&mut self, tracker_url: String) {
         if !self.trackers.contains(&tracker_url) {
             self.trackers.push(tracker_url);
@@ -455,6 +841,224 @@ impl TrackerManager {
         self.trackers.retain(|t| t != tracker_url);
         self.last_announce.remove(tracker_url);
         self.announce_intervals.remove(tracker_url);
+        self.jittered_intervals.remove(tracker_url);
+        self.min_intervals.remove(tracker_url);
+        self.tracker_ids.remove(tracker_url);
+        self.started.remove(tracker_url);
+        self.tracker_warnings.remove(tracker_url);
+        self.tracker_failures.remove(tracker_url);
+    }
+
+    //=== Make every tracker due now, e.g. after our address changed; the key stays the same ===//
+    pub fn mark_all_due(&mut self) {
+        self.last_announce.clear();
+        self.tracker_failures.clear();
+    }
+
+    //=== `interval` moved by a random fraction within the configured jitter, never under min ===//
+    fn jitter(&mut self, tracker_url: &str, interval: Duration) -> Duration {
+        let jitter = self.config.announce_jitter.clamp(0.0, 0.5);
+        if jitter == 0.0 {
+            return interval;
+        }
+
+        let factor = 1.0 + self.rng.gen_range(-jitter..=jitter);
+        let min_interval = self.min_intervals.get(tracker_url).copied();
+        interval
+            .mul_f64(factor)
+            .max(min_interval.unwrap_or_default())
+    }
+
+    //=== When the next regular announce to a tracker is due ===//
+    pub fn next_announce_at(&self, tracker_url: &str) -> Option<Instant> {
+        let last_announce = self.last_announce.get(tracker_url)?;
+        let interval = self
+            .jittered_intervals
+            .get(tracker_url)
+            .or_else(|| self.announce_intervals.get(tracker_url))?;
+        Some(*last_announce + *interval)
+    }
+
+    //=== Check whether the tracker's announce interval has elapsed ===//
+    pub fn should_announce(&self, tracker_url: &str) -> bool {
+        //=== Failing trackers are retried with exponential backoff ===//
+        if let Some((failures, last_failure)) = self.tracker_failures.get(tracker_url) {
+            let backoff = self
+                .config
+                .tracker_retry_backoff
+                .saturating_mul(1 << (failures - 1).min(16))
+                .min(self.config.announce_interval);
+            return self.clock.now().saturating_duration_since(*last_failure) >= backoff;
+        }
+
+        self.next_announce_at(tracker_url)
+            .is_none_or(|due| self.clock.now() >= due)
+    }
+
+    //=== A tracker that already saw `started` gets a regular announce instead ===//
+    fn effective_event(&self, tracker_url: &str, event: TrackerEvent) -> TrackerEvent {
+        if event == TrackerEvent::Started && self.started.contains(tracker_url) {
+            TrackerEvent::None
+        } else {
+            event
+        }
+    }
+
+    //=== Export the announce state so it can be persisted with the resume data ===//
+    pub fn export_state(&self) -> TrackerState {
+        let now_unix = unix_now();
+
+        let trackers = self
+            .trackers
+            .iter()
+            .map(|url| TrackerAnnounceState {
+                url: url.clone(),
+                last_announce: self.last_announce.get(url).map(|last| {
+                    let ago = self.clock.now().saturating_duration_since(*last);
+                    now_unix.saturating_sub(ago.as_secs())
+                }),
+                interval: self.announce_intervals.get(url).map(|d| d.as_secs()),
+                min_interval: self.min_intervals.get(url).map(|d| d.as_secs()),
+                tracker_id: self.tracker_ids.get(url).cloned(),
+                started: self.started.contains(url),
+            })
+            .collect();
+
+        TrackerState {
+            key: self.key.clone(),
+            trackers,
+        }
+    }
+
+    //=== Persist the announce state so a crash mid-write can't corrupt it ===//
+    pub async fn save_state(&self, path: &Path) -> Result<()> {
+        let bytes = serde_json::to_vec(&self.export_state())?;
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        persist_atomic(path, &bytes).await?;
+        Ok(())
+    }
+
+    //=== Restore state saved by `save_state`; an unusable file is ignored and we start fresh ===//
+    pub async fn load_state(&mut self, path: &Path) -> bool {
+        let state = load_persisted(path)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(serde_json::from_slice::<TrackerState>(&bytes)?));
+
+        match state {
+            Ok(state) => {
+                self.import_state(state);
+                true
+            }
+            Err(e) => {
+                warn!("Ignoring tracker state at {}: {}", path.display(), e);
+                false
+            }
+        }
+    }
+
+    //=== Restore announce state persisted by a previous run ===//
+    pub fn import_state(&mut self, state: TrackerState) {
+        let now_unix = unix_now();
+        let now = self.clock.now();
+
+        self.key = state.key;
+
+        for entry in state.trackers {
+            if !self.trackers.contains(&entry.url) {
+                continue;
+            }
+
+            if let Some(last_announce) = entry.last_announce {
+                //== Unrepresentable instants fall back to "just announced" ==//
+                let ago = Duration::from_secs(now_unix.saturating_sub(last_announce));
+                let instant = now.checked_sub(ago).unwrap_or(now);
+                self.last_announce.insert(entry.url.clone(), instant);
+            }
+            if let Some(interval) = entry.interval {
+                self.announce_intervals
+                    .insert(entry.url.clone(), Duration::from_secs(interval));
+            }
+            if let Some(min_interval) = entry.min_interval {
+                self.min_intervals
+                    .insert(entry.url.clone(), Duration::from_secs(min_interval));
+            }
+            if let Some(tracker_id) = entry.tracker_id {
+                self.tracker_ids.insert(entry.url.clone(), tracker_id);
+            }
+            if entry.started {
+                self.started.insert(entry.url);
+            }
+        }
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+//=== Test transport that records every announce and answers it, or fails it once dead ===//
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct RecordingTracker {
+    // Tracker URL and request of every announce, answered or not //
+    announces: std::sync::Mutex<Vec<(String, TrackerRequest)>>,
+    dead: bool,
+}
+
+#[cfg(test)]
+impl RecordingTracker {
+    //=== Every announce fails, as if the tracker refused the connection ===//
+    pub(crate) fn dead() -> Self {
+        Self {
+            dead: true,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn urls(&self) -> Vec<String> {
+        let announces = self.announces.lock().unwrap();
+        announces.iter().map(|(url, _)| url.clone()).collect()
+    }
+
+    pub(crate) fn requests(&self) -> Vec<TrackerRequest> {
+        let announces = self.announces.lock().unwrap();
+        announces
+            .iter()
+            .map(|(_, request)| request.clone())
+            .collect()
+    }
+
+    pub(crate) fn attempts(&self) -> usize {
+        self.announces.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+impl Tracker for RecordingTracker {
+    fn announce<'a>(
+        &'a self,
+        tracker_url: &'a str,
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse>> {
+        self.announces
+            .lock()
+            .unwrap()
+            .push((tracker_url.to_string(), request.clone()));
+        let dead = self.dead;
+        Box::pin(async move {
+            if dead {
+                return Err(anyhow::anyhow!("connection refused"));
+            }
+            Ok(serde_json::from_str::<TrackerResponse>(
+                r#"{"interval": 1800}"#,
+            )?)
+        })
     }
 }
 
@@ -498,6 +1102,44 @@ mod tests {
         assert_eq!(request.event, TrackerEvent::Started);
     }
 
+    #[test]
+    fn test_announce_carries_both_address_families() {
+        let config = Config {
+            announce_ipv4: Some("203.0.113.5:6881".parse().unwrap()),
+            announce_ipv6: Some("[2001:db8::1]:6882".parse().unwrap()),
+            ..Config::default()
+        };
+        let url = "http://tracker.example/announce";
+        let manager = TrackerManager::new(config, vec![url.to_string()]);
+        let statistics = Statistics::new(100);
+
+        let request = manager.build_request(
+            url,
+            [1u8; 20],
+            [2u8; 20],
+            6881,
+            &statistics,
+            TrackerEvent::Started,
+        );
+        let params = request.to_query_params();
+        assert!(params.contains("ipv4=203.0.113.5%3A6881"));
+        assert!(params.contains("ipv6=%5B2001%3Adb8%3A%3A1%5D%3A6882"));
+
+        //=== Without configured endpoints the tracker uses the source address ===//
+        let plain = TrackerManager::new(Config::default(), vec![url.to_string()]);
+        let params = plain
+            .build_request(
+                url,
+                [1u8; 20],
+                [2u8; 20],
+                6881,
+                &statistics,
+                TrackerEvent::Started,
+            )
+            .to_query_params();
+        assert!(!params.contains("ipv4=") && !params.contains("ipv6="));
+    }
+
     #[test]
     fn test_tracker_request_query_params() {
         let info_hash = [1u8; 20];
@@ -522,6 +1164,31 @@ mod tests {
         assert!(params.contains("event=started"));
     }
 
+    #[test]
+    fn test_request_reports_wire_bytes_not_verified() {
+        let mut statistics = Statistics::new(2048);
+
+        //=== Piece 0 arrives corrupt, then is downloaded again ===//
+        statistics.update_downloaded(1024);
+        statistics.piece_failed(1024);
+        statistics.update_downloaded(1024);
+        statistics.piece_verified(0, 1024);
+        statistics.update_uploaded(512);
+
+        assert!(statistics.wire_downloaded > statistics.verified_downloaded);
+
+        let request = TrackerRequest::from_statistics(
+            [1u8; 20],
+            [2u8; 20],
+            6881,
+            &statistics,
+            TrackerEvent::None,
+        );
+        assert_eq!(request.downloaded, 2048);
+        assert_eq!(request.uploaded, 512);
+        assert_eq!(request.left, 1024);
+    }
+
     #[tokio::test]
     async fn test_tracker_manager_creation() {
         let config = Config::default();
@@ -530,4 +1197,507 @@ mod tests {
 
         assert_eq!(manager.trackers().len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_no_trackers_are_not_all_failing() {
+        let dead = Arc::new(RecordingTracker::dead());
+        let mut manager = TrackerManager::new(Config::default(), Vec::new());
+        manager.register_transport("http", dead.clone());
+        assert!(!manager.all_trackers_failing());
+
+        let statistics = Statistics::new(1000);
+        let announced = manager
+            .announce_all(
+                [1u8; 20],
+                [2u8; 20],
+                6881,
+                &statistics,
+                TrackerEvent::Started,
+            )
+            .await;
+        assert!(announced.unwrap_or_default().is_empty());
+        assert!(!manager.all_trackers_failing());
+
+        manager.add_tracker("http://a.example/announce".to_string());
+        manager
+            .announce_all(
+                [1u8; 20],
+                [2u8; 20],
+                6881,
+                &statistics,
+                TrackerEvent::Started,
+            )
+            .await
+            .ok();
+        assert_eq!(dead.attempts(), 1);
+        assert!(manager.all_trackers_failing());
+    }
+
+    #[tokio::test]
+    async fn test_tracker_state_round_trip() {
+        let url = "http://tracker.example.com/announce".to_string();
+        let mut manager = TrackerManager::new(Config::default(), vec![url.clone()]);
+
+        manager.import_state(TrackerState {
+            key: "deadbeef".to_string(),
+            trackers: vec![TrackerAnnounceState {
+                url: url.clone(),
+                last_announce: Some(unix_now() - 60),
+                interval: Some(1800),
+                min_interval: Some(900),
+                tracker_id: Some("abc".to_string()),
+                started: true,
+            }],
+        });
+
+        //=== Restore into a fresh manager, as after a restart ===//
+        let exported = manager.export_state();
+        let mut restarted = TrackerManager::new(Config::default(), vec![url.clone()]);
+        restarted.import_state(exported.clone());
+
+        let state = restarted.export_state();
+        assert_eq!(state.key, "deadbeef");
+        assert_eq!(state.trackers.len(), 1);
+        let entry = &state.trackers[0];
+        assert_eq!(entry.interval, Some(1800));
+        assert_eq!(entry.min_interval, Some(900));
+        assert_eq!(entry.tracker_id.as_deref(), Some("abc"));
+        assert!(entry.started);
+        let last = entry.last_announce.unwrap();
+        assert!(last.abs_diff(unix_now() - 60) <= 1);
+
+        //=== Interval enforcement survives and `started` is not repeated ===//
+        assert!(!restarted.should_announce(&url));
+        assert_eq!(
+            restarted.effective_event(&url, TrackerEvent::Started),
+            TrackerEvent::None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_damaged_state_file_starts_fresh() {
+        let url = "http://tracker.example.com/announce".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("trackers.state");
+
+        let mut manager = TrackerManager::new(Config::default(), vec![url.clone()]);
+        manager.import_state(TrackerState {
+            key: "deadbeef".to_string(),
+            trackers: Vec::new(),
+        });
+        manager.save_state(&path).await.unwrap();
+
+        let mut restarted = TrackerManager::new(Config::default(), vec![url.clone()]);
+        assert!(restarted.load_state(&path).await);
+        assert_eq!(restarted.export_state().key, "deadbeef");
+
+        //=== Cut the file short, as a crash mid-write would ===//
+        let contents = tokio::fs::read(&path).await.unwrap();
+        tokio::fs::write(&path, &contents[..contents.len() / 2])
+            .await
+            .unwrap();
+        let mut fresh = TrackerManager::new(Config::default(), vec![url]);
+        let key = fresh.export_state().key;
+        assert!(!fresh.load_state(&path).await);
+        assert_eq!(fresh.export_state().key, key);
+    }
+
+    //=== Minimal HTTP tracker answering every request with `body` after `delay` ===//
+    pub(crate) async fn spawn_mock_tracker(
+        body: &'static str,
+        delay: Duration,
+    ) -> (String, std::sync::Arc<std::sync::atomic::AtomicUsize>) {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/announce", listener.local_addr().unwrap());
+        let hits = std::sync::Arc::new(AtomicUsize::new(0));
+        let hits_clone = std::sync::Arc::clone(&hits);
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let hits = std::sync::Arc::clone(&hits_clone);
+                tokio::spawn(async move {
+                    let mut request = Vec::new();
+                    let mut chunk = [0u8; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match socket.read(&mut chunk).await {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&chunk[..n]),
+                        }
+                    }
+                    hits.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(delay).await;
+                    let response = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(response.as_bytes()).await;
+                });
+            }
+        });
+
+        (url, hits)
+    }
+
+    #[tokio::test]
+    async fn test_html_tracker_body_is_a_clear_error() {
+        let (url, _) = spawn_mock_tracker(
+            "<html><body>429 Too Many Requests</body></html>",
+            Duration::ZERO,
+        )
+        .await;
+        let client = TrackerClient::new(Config::default());
+        let request =
+            TrackerRequest::new([1u8; 20], [2u8; 20], 6881, 0, 0, 0, TrackerEvent::Started);
+
+        let error = client.announce(&url, &request).await.unwrap_err();
+        match error.downcast_ref::<NetworkError>() {
+            Some(NetworkError::UnparseableTrackerBody { snippet }) => {
+                assert!(snippet.starts_with("<html><body>429"));
+            }
+            other => panic!("unexpected error: {:?}", other),
+        }
+        assert!(error.to_string().contains("neither JSON nor bencode"));
+    }
+
+    #[test]
+    fn test_peers_as_dict_list_or_compact_string() {
+        let expected = |peers: &[PeerInfo]| -> Vec<SocketAddr> {
+            peers
+                .iter()
+                .map(|peer| peer.to_socket_addr().unwrap())
+                .collect()
+        };
+
+        let dict_list = bencode::decode(
+            b"d8:intervali900e5:peersld2:ip8:10.0.0.17:peer id3:abc4:porti6881eed2:ip8:10.0.0.24:porti6882eeee",
+        )
+        .unwrap();
+        let response = TrackerResponse::from_bencode(&dict_list).unwrap();
+        assert_eq!(response.interval, Some(900));
+        let peers = response.peers.unwrap();
+        assert_eq!(peers[0].peer_id.as_deref(), Some("abc"));
+        assert_eq!(
+            expected(&peers),
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+
+        let compact = [
+            b"d8:intervali900e5:peers12:".as_slice(),
+            &[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2],
+            b"6:peers618:",
+            &[0u8; 15],
+            &[1, 0x1a, 0xe3],
+            b"e",
+        ]
+        .concat();
+        let response = TrackerResponse::from_bencode(&bencode::decode(&compact).unwrap()).unwrap();
+        let peers = response.peers.unwrap();
+        assert!(peers.iter().all(|peer| peer.peer_id.is_none()));
+        assert_eq!(
+            expected(&peers),
+            vec![
+                "10.0.0.1:6881".parse().unwrap(),
+                "10.0.0.2:6882".parse().unwrap()
+            ]
+        );
+        let peers6 = response.peers6.unwrap();
+        assert_eq!(peers6.len(), 1);
+        assert_eq!(peers6[0].ip, "::1");
+        assert_eq!(
+            peers6[0].to_socket_addr().unwrap(),
+            "[::1]:6883".parse().unwrap()
+        );
+
+        //=== Bracketed literals from dict-list replies parse as well ===//
+        let bracketed = PeerInfo {
+            peer_id: None,
+            ip: "[2001:db8::7]".to_string(),
+            port: 51413,
+        };
+        assert_eq!(
+            bracketed.to_socket_addr().unwrap(),
+            "[2001:db8::7]:51413".parse().unwrap()
+        );
+        let bogus = PeerInfo {
+            ip: "not-an-ip".to_string(),
+            ..bracketed
+        };
+        assert!(bogus.to_socket_addr().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_compact_bencoded_announce() {
+        //=== 65.66.67.68 and 4142:4344:..:4f50, both on port 0x3132, spelled in printable bytes ===//
+        let (url, _) = spawn_mock_tracker(
+            "d8:intervali900e5:peers6:ABCD126:peers618:ABCDEFGHIJKLMNOP12e",
+            Duration::ZERO,
+        )
+        .await;
+        let client = TrackerClient::new(Config::default());
+        let request =
+            TrackerRequest::new([1u8; 20], [2u8; 20], 6881, 0, 0, 0, TrackerEvent::Started);
+
+        let response = client.announce(&url, &request).await.unwrap();
+        assert_eq!(response.interval, Some(900));
+        let peers = response.peers.unwrap();
+        assert_eq!(
+            peers[0].to_socket_addr().unwrap(),
+            "65.66.67.68:12594".parse().unwrap()
+        );
+
+        let peers6 = response.peers6.unwrap();
+        assert_eq!(peers6.len(), 1);
+        assert_eq!(peers6[0].ip, "4142:4344:4546:4748:494a:4b4c:4d4e:4f50");
+        assert_eq!(
+            peers6[0].to_socket_addr().unwrap(),
+            "[4142:4344:4546:4748:494a:4b4c:4d4e:4f50]:12594"
+                .parse()
+                .unwrap()
+        );
+        assert!(peers6[0].to_socket_addr().unwrap().is_ipv6());
+    }
+
+    #[tokio::test]
+    async fn test_announce_all_runs_trackers_concurrently() {
+        let delay = Duration::from_millis(400);
+        let (slow_a, _) = spawn_mock_tracker(r#"{"interval": 1800}"#, delay).await;
+        let (slow_b, _) = spawn_mock_tracker(r#"{"interval": 1800}"#, delay).await;
+        let (fast, _) = spawn_mock_tracker(r#"{"interval": 900}"#, Duration::ZERO).await;
+        let (failing, _) =
+            spawn_mock_tracker(r#"{"failure reason": "denied"}"#, Duration::ZERO).await;
+
+        let trackers = vec![
+            slow_a.clone(),
+            slow_b.clone(),
+            fast.clone(),
+            failing.clone(),
+        ];
+        let mut manager = TrackerManager::new(Config::default(), trackers);
+        let statistics = Statistics::new(1000);
+
+        let started = Instant::now();
+        manager
+            .announce_all(
+                [1u8; 20],
+                [2u8; 20],
+                6881,
+                &statistics,
+                TrackerEvent::Started,
+            )
+            .await
+            .unwrap();
+        let elapsed = started.elapsed();
+
+        //=== Bounded by the slowest tracker, not the sum of both ===//
+        assert!(elapsed >= delay);
+        assert!(elapsed < delay * 2, "took {:?}", elapsed);
+
+        //=== Each tracker's bookkeeping is recorded independently ===//
+        assert!(manager.last_announce.contains_key(&slow_a));
+        assert!(manager.last_announce.contains_key(&slow_b));
+        assert_eq!(manager.announce_intervals[&fast], Duration::from_secs(900));
+        assert!(!manager.last_announce.contains_key(&failing));
+        assert_eq!(manager.total_tracker_errors(), 1);
+        //=== A failed tracker is retried only after a backoff ===//
+        assert!(!manager.should_announce(&failing));
+    }
+
+    #[tokio::test]
+    async fn test_forced_announce_ignores_interval() {
+        use crate::core::MockClock;
+        use std::sync::atomic::Ordering;
+
+        let (url, hits) = spawn_mock_tracker(r#"{"interval": 1800}"#, Duration::ZERO).await;
+        let clock = MockClock::new();
+        let mut manager =
+            TrackerManager::with_clock(Config::default(), vec![url.clone()], clock.shared());
+        let statistics = Statistics::new(1000);
+
+        manager
+            .announce_all(
+                [1u8; 20],
+                [2u8; 20],
+                6881,
+                &statistics,
+                TrackerEvent::Started,
+            )
+            .await
+            .unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        let first = manager.last_announce[&url];
+
+        //=== Regular announces respect the stored interval ===//
+        clock.advance(MIN_FORCED_ANNOUNCE_INTERVAL);
+        manager
+            .announce_all([1u8; 20], [2u8; 20], 6881, &statistics, TrackerEvent::None)
+            .await
+            .unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        //=== A forced announce goes through and records the new time ===//
+        manager
+            .announce_one(
+                &url,
+                [1u8; 20],
+                [2u8; 20],
+                6881,
+                &statistics,
+                TrackerEvent::None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            manager.last_announce[&url],
+            first + MIN_FORCED_ANNOUNCE_INTERVAL
+        );
+
+        //=== ...but not twice within the anti-hammer floor ===//
+        let result = manager
+            .announce_one(
+                &url,
+                [1u8; 20],
+                [2u8; 20],
+                6881,
+                &statistics,
+                TrackerEvent::None,
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+        //=== A fresh manager, like the next CLI run, keeps the floor once it loads the state ===//
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("tracker-state");
+        manager.save_state(&path).await.unwrap();
+        let mut restarted = TrackerManager::new(Config::default(), vec![url.clone()]);
+        assert!(restarted.load_state(&path).await);
+        let result = restarted
+            .announce_one(
+                &url,
+                [1u8; 20],
+                [2u8; 20],
+                6881,
+                &statistics,
+                TrackerEvent::None,
+            )
+            .await;
+        assert!(result.is_err());
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_next_announce_falls_within_jitter_window() {
+        use crate::core::MockClock;
+
+        let url = "udp://tracker.example.net:6969/announce".to_string();
+        let clock = MockClock::new();
+        let config = Config {
+            announce_jitter: 0.1,
+            ..Config::default()
+        };
+        let mut manager = TrackerManager::with_clock(config, vec![url.clone()], clock.shared());
+        manager.register_transport("udp", Arc::new(RecordingTracker::default()));
+        manager.set_rng(StdRng::seed_from_u64(42));
+        let statistics = Statistics::new(1000);
+
+        let base = Duration::from_secs(1800);
+        let mut delays = HashSet::new();
+        for _ in 0..20 {
+            manager
+                .announce_one(
+                    &url,
+                    [1u8; 20],
+                    [2u8; 20],
+                    6881,
+                    &statistics,
+                    TrackerEvent::None,
+                )
+                .await
+                .unwrap();
+            let delay = manager.next_announce_at(&url).unwrap() - manager.last_announce[&url];
+            assert!(delay >= base.mul_f64(0.9) && delay <= base.mul_f64(1.1));
+            delays.insert(delay);
+
+            //=== Due exactly at the jittered time, not the base one ===//
+            clock.advance(delay - Duration::from_millis(1));
+            assert!(!manager.should_announce(&url));
+            clock.advance(Duration::from_millis(1));
+            assert!(manager.should_announce(&url));
+        }
+        assert!(delays.len() > 1);
+    }
+
+    #[tokio::test]
+    async fn test_announce_dispatches_by_scheme() {
+        let http_url = "http://tracker.example.com/announce".to_string();
+        let https_url = "https://tracker.example.org/announce".to_string();
+        let udp_url = "udp://tracker.example.net:6969/announce".to_string();
+        let unknown_url = "ftp://tracker.example.com/announce".to_string();
+        let trackers = vec![
+            http_url.clone(),
+            udp_url.clone(),
+            unknown_url.clone(),
+            https_url.clone(),
+        ];
+
+        let mut manager = TrackerManager::new(Config::default(), trackers);
+        let http = Arc::new(RecordingTracker::default());
+        let udp = Arc::new(RecordingTracker::default());
+        manager.register_transport("http", http.clone());
+        manager.register_transport("https", http.clone());
+        manager.register_transport("udp", udp.clone());
+
+        let statistics = Statistics::new(1000);
+        manager
+            .announce_all(
+                [1u8; 20],
+                [2u8; 20],
+                6881,
+                &statistics,
+                TrackerEvent::Started,
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(http.urls(), vec![http_url, https_url]);
+        assert_eq!(udp.urls(), vec![udp_url.clone()]);
+        assert!(manager.last_announce.contains_key(&udp_url));
+
+        //=== The unknown scheme is skipped with a warning, not an error ===//
+        assert!(!manager.last_announce.contains_key(&unknown_url));
+        assert!(manager.tracker_warning(&unknown_url).is_some());
+        assert!(manager.tracker_warning(&udp_url).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_tracker_state_expired_interval() {
+        let url = "http://tracker.example.com/announce".to_string();
+        let mut manager = TrackerManager::new(Config::default(), vec![url.clone()]);
+
+        manager.import_state(TrackerState {
+            key: "deadbeef".to_string(),
+            trackers: vec![TrackerAnnounceState {
+                url: url.clone(),
+                last_announce: Some(unix_now() - 3600),
+                interval: Some(1800),
+                min_interval: None,
+                tracker_id: None,
+                started: false,
+            }],
+        });
+
+        assert!(manager.should_announce(&url));
+        assert_eq!(
+            manager.effective_event(&url, TrackerEvent::Started),
+            TrackerEvent::Started
+        );
+    }
 }
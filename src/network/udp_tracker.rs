@@ -0,0 +1,431 @@
+use crate::core::Config;
+use crate::logging::{debug, info};
+use crate::network::tracker::{
+    PeerInfo, Tracker, TrackerEvent, TrackerRequest, TrackerResponse, COMPACT_PEER_V4,
+    COMPACT_PEER_V6,
+};
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use url::Url;
+
+//=== Magic constant every UDP tracker connect request starts with ===//
+const PROTOCOL_ID: u64 = 0x0417_2710_1980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_ERROR: u32 = 3;
+
+//=== Unanswered requests are resent at most this many times (BEP 15) ===//
+const MAX_RETRANSMITS: u32 = 8;
+//=== A connection id may be used for this long after it was handed out ===//
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+//=== Tracker client speaking the UDP tracker protocol ===//
+pub struct UdpTrackerClient {
+    config: Config,
+}
+
+impl UdpTrackerClient {
+    pub fn new(config: Config) -> Self {
+        Self { config }
+    }
+
+    //==== Announce to a udp:// tracker ====//
+    pub async fn announce(
+        &self,
+        tracker_url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        info!("Announcing to UDP tracker: {}", tracker_url);
+
+        let addr = resolve(tracker_url).await?;
+        let local: SocketAddr = if addr.is_ipv4() {
+            "0.0.0.0:0".parse()?
+        } else {
+            "[::]:0".parse()?
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(addr).await?;
+
+        self.retransmit(&socket, tracker_url, request).await
+    }
+
+    //=== Connect and announce, resending unanswered requests on the BEP 15 schedule ===//
+    async fn retransmit(
+        &self,
+        socket: &UdpSocket,
+        tracker_url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        //=== `n` counts unanswered sends of the current request; a reply starts it over ===//
+        let mut connection: Option<(u64, Instant)> = None;
+        let mut n = 0;
+        while n <= MAX_RETRANSMITS {
+            let wait = self.config.udp_retransmit_base * 2u32.pow(n);
+            n += 1;
+            match connection {
+                Some((connection_id, at)) if at.elapsed() < CONNECTION_ID_LIFETIME => {
+                    let transaction_id: u32 = rand::random();
+                    let packet = announce_packet(connection_id, transaction_id, request);
+                    let exchange =
+                        send_and_receive(socket, &packet, ACTION_ANNOUNCE, transaction_id, 20);
+                    if let Ok(reply) = timeout(wait, exchange).await {
+                        return Ok(parse_announce(&reply?, socket.local_addr()?.is_ipv6()));
+                    }
+                }
+                //=== No connection id yet, or it expired while we waited ===//
+                _ => {
+                    let transaction_id: u32 = rand::random();
+                    let packet = connect_packet(transaction_id);
+                    let exchange =
+                        send_and_receive(socket, &packet, ACTION_CONNECT, transaction_id, 16);
+                    if let Ok(reply) = timeout(wait, exchange).await {
+                        let connection_id = u64::from_be_bytes(reply?[8..16].try_into()?);
+                        connection = Some((connection_id, Instant::now()));
+                        n = 0;
+                    }
+                }
+            }
+            debug!(
+                "UDP tracker {} has not answered yet, {} sends so far",
+                tracker_url, n
+            );
+        }
+
+        Err(anyhow::anyhow!(
+            "UDP tracker {} did not answer after {} retransmits",
+            tracker_url,
+            MAX_RETRANSMITS
+        ))
+    }
+}
+
+impl Tracker for UdpTrackerClient {
+    fn announce<'a>(
+        &'a self,
+        tracker_url: &'a str,
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse>> {
+        Box::pin(UdpTrackerClient::announce(self, tracker_url, request))
+    }
+}
+
+async fn resolve(tracker_url: &str) -> Result<SocketAddr> {
+    let url =
+        Url::parse(tracker_url).with_context(|| format!("Invalid tracker URL: {}", tracker_url))?;
+    let host = url
+        .host_str()
+        .ok_or_else(|| anyhow::anyhow!("Tracker URL has no host: {}", tracker_url))?;
+    let port = url
+        .port()
+        .ok_or_else(|| anyhow::anyhow!("Tracker URL has no port: {}", tracker_url))?;
+
+    //=== IPv6 literals come back bracketed, which the resolver does not accept ===//
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let resolved = tokio::net::lookup_host((host, port)).await?.next();
+    resolved.ok_or_else(|| anyhow::anyhow!("Could not resolve tracker: {}", tracker_url))
+}
+
+//=== Request for the connection id later requests must carry ===//
+fn connect_packet(transaction_id: u32) -> Vec<u8> {
+    let mut packet = Vec::with_capacity(16);
+    packet.extend_from_slice(&PROTOCOL_ID.to_be_bytes());
+    packet.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet
+}
+
+fn announce_packet(connection_id: u64, transaction_id: u32, request: &TrackerRequest) -> Vec<u8> {
+    let key = request
+        .key
+        .as_deref()
+        .and_then(|key| u32::from_str_radix(key, 16).ok())
+        .unwrap_or(0);
+    let event: u32 = match request.event {
+        TrackerEvent::None => 0,
+        TrackerEvent::Completed => 1,
+        TrackerEvent::Started => 2,
+        TrackerEvent::Stopped => 3,
+    };
+    let numwant = request.numwant.map(|n| n as i32).unwrap_or(-1);
+
+    let mut packet = Vec::with_capacity(98);
+    packet.extend_from_slice(&connection_id.to_be_bytes());
+    packet.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+    packet.extend_from_slice(&transaction_id.to_be_bytes());
+    packet.extend_from_slice(&request.info_hash);
+    packet.extend_from_slice(&request.peer_id);
+    packet.extend_from_slice(&request.downloaded.to_be_bytes());
+    packet.extend_from_slice(&request.left.to_be_bytes());
+    packet.extend_from_slice(&request.uploaded.to_be_bytes());
+    packet.extend_from_slice(&event.to_be_bytes());
+    packet.extend_from_slice(&0u32.to_be_bytes());
+    packet.extend_from_slice(&key.to_be_bytes());
+    packet.extend_from_slice(&numwant.to_be_bytes());
+    packet.extend_from_slice(&request.port.to_be_bytes());
+    packet
+}
+
+//=== Peers come back in the socket's address family: 6-byte IPv4 or 18-byte IPv6 records ===//
+fn parse_announce(response: &[u8], ipv6: bool) -> TrackerResponse {
+    let field = |at: usize| {
+        u32::from_be_bytes([
+            response[at],
+            response[at + 1],
+            response[at + 2],
+            response[at + 3],
+        ])
+    };
+
+    let (peers, peers6) = if ipv6 {
+        (
+            None,
+            Some(PeerInfo::from_compact(&response[20..], COMPACT_PEER_V6)),
+        )
+    } else {
+        (
+            Some(PeerInfo::from_compact(&response[20..], COMPACT_PEER_V4)),
+            None,
+        )
+    };
+
+    TrackerResponse {
+        failure_reason: None,
+        warning_message: None,
+        interval: Some(field(8)),
+        min_interval: None,
+        tracker_id: None,
+        complete: Some(field(16)),
+        incomplete: Some(field(12)),
+        peers,
+        peers6,
+    }
+}
+
+async fn send_and_receive(
+    socket: &UdpSocket,
+    packet: &[u8],
+    action: u32,
+    transaction_id: u32,
+    min_len: usize,
+) -> Result<Vec<u8>> {
+    socket.send(packet).await?;
+    receive(socket, action, transaction_id, min_len).await
+}
+
+//=== Wait for the reply to `transaction_id`, surfacing tracker errors ===//
+async fn receive(
+    socket: &UdpSocket,
+    action: u32,
+    transaction_id: u32,
+    min_len: usize,
+) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; 2048];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        if len < 8 {
+            continue;
+        }
+
+        let reply_action = u32::from_be_bytes(buf[0..4].try_into()?);
+        let reply_transaction = u32::from_be_bytes(buf[4..8].try_into()?);
+        if reply_transaction != transaction_id {
+            debug!("Ignoring UDP tracker reply for another transaction");
+            continue;
+        }
+
+        if reply_action == ACTION_ERROR {
+            return Err(anyhow::anyhow!(
+                "Tracker failure: {}",
+                String::from_utf8_lossy(&buf[8..len])
+            ));
+        }
+        if reply_action != action || len < min_len {
+            return Err(anyhow::anyhow!("Malformed UDP tracker response"));
+        }
+
+        buf.truncate(len);
+        return Ok(buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    async fn spawn_mock_udp_tracker(
+        peers: Vec<[u8; 6]>,
+        ignored_connects: usize,
+        ignored_announces: usize,
+    ) -> (String, Arc<AtomicUsize>) {
+        spawn_mock_udp_tracker_on(
+            "127.0.0.1:0",
+            peers.concat(),
+            ignored_connects,
+            ignored_announces,
+        )
+        .await
+    }
+
+    //=== Minimal UDP tracker that ignores the first few connects and announces ===//
+    async fn spawn_mock_udp_tracker_on(
+        bind: &str,
+        peers: Vec<u8>,
+        ignored_connects: usize,
+        ignored_announces: usize,
+    ) -> (String, Arc<AtomicUsize>) {
+        let socket = UdpSocket::bind(bind).await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let received = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&received);
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 2048];
+            let connection_id = 0x1122_3344_5566_7788u64;
+            let (mut connects, mut announces) = (0, 0);
+
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                if len == 16 {
+                    assert_eq!(buf[0..8], PROTOCOL_ID.to_be_bytes());
+                    connects += 1;
+                    if connects <= ignored_connects {
+                        continue;
+                    }
+                    let mut reply = Vec::new();
+                    reply.extend_from_slice(&ACTION_CONNECT.to_be_bytes());
+                    reply.extend_from_slice(&buf[12..16]);
+                    reply.extend_from_slice(&connection_id.to_be_bytes());
+                    socket.send_to(&reply, from).await.unwrap();
+                    continue;
+                }
+
+                assert_eq!(len, 98);
+                assert_eq!(buf[0..8], connection_id.to_be_bytes());
+                assert_eq!(buf[16..36], [1u8; 20]);
+                //=== Event "started" and our port ===//
+                assert_eq!(buf[80..84], 2u32.to_be_bytes());
+                assert_eq!(buf[96..98], 6881u16.to_be_bytes());
+                announces += 1;
+                if announces <= ignored_announces {
+                    continue;
+                }
+
+                let mut reply = Vec::new();
+                reply.extend_from_slice(&ACTION_ANNOUNCE.to_be_bytes());
+                reply.extend_from_slice(&buf[12..16]);
+                reply.extend_from_slice(&900u32.to_be_bytes());
+                reply.extend_from_slice(&3u32.to_be_bytes());
+                reply.extend_from_slice(&5u32.to_be_bytes());
+                reply.extend_from_slice(&peers);
+                socket.send_to(&reply, from).await.unwrap();
+            }
+        });
+
+        (url, received)
+    }
+
+    fn started_request() -> TrackerRequest {
+        TrackerRequest::new(
+            [1u8; 20],
+            [2u8; 20],
+            6881,
+            0,
+            0,
+            1000,
+            TrackerEvent::Started,
+        )
+    }
+
+    fn retransmit_after(base: Duration) -> Config {
+        Config {
+            udp_retransmit_base: base,
+            ..Config::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_udp_announce_returns_compact_peers() {
+        let (url, _) =
+            spawn_mock_udp_tracker(vec![[10, 0, 0, 1, 0x1a, 0xe1], [10, 0, 0, 2, 0, 80]], 0, 0)
+                .await;
+        let client = UdpTrackerClient::new(Config::default());
+
+        let response = client.announce(&url, &started_request()).await.unwrap();
+        assert_eq!(response.interval, Some(900));
+        assert_eq!(response.incomplete, Some(3));
+        assert_eq!(response.complete, Some(5));
+
+        let peers = response.peers.unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers[0].ip, "10.0.0.1");
+        assert_eq!(peers[0].port, 6881);
+        assert_eq!(peers[1].port, 80);
+    }
+
+    #[tokio::test]
+    async fn test_lost_packets_are_retransmitted() {
+        let (url, received) = spawn_mock_udp_tracker(vec![[10, 0, 0, 1, 0, 80]], 2, 3).await;
+        let client = UdpTrackerClient::new(retransmit_after(Duration::from_millis(20)));
+
+        let response = client.announce(&url, &started_request()).await.unwrap();
+        assert_eq!(response.peers.unwrap().len(), 1);
+        //=== Each reply restarts the schedule, so both requests fit well inside it ===//
+        assert_eq!(received.load(Ordering::SeqCst), 3 + 4);
+    }
+
+    #[tokio::test]
+    async fn test_silent_tracker_gets_eight_retransmits() {
+        let (url, received) = spawn_mock_udp_tracker(Vec::new(), usize::MAX, 0).await;
+        let client = UdpTrackerClient::new(retransmit_after(Duration::from_millis(1)));
+
+        let error = client.announce(&url, &started_request()).await.unwrap_err();
+        assert!(error.to_string().contains("did not answer"));
+        assert_eq!(
+            received.load(Ordering::SeqCst),
+            1 + MAX_RETRANSMITS as usize
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_silent_tracker_gets_the_full_schedule_at_default_config() {
+        let (url, received) = spawn_mock_udp_tracker(Vec::new(), usize::MAX, 0).await;
+        let config = Config::default();
+        let client = UdpTrackerClient::new(config.clone());
+
+        //=== 15·2^n seconds for n = 0..=8, well past the HTTP tracker timeout ===//
+        let started = tokio::time::Instant::now();
+        let error = client.announce(&url, &started_request()).await.unwrap_err();
+        assert!(error.to_string().contains("did not answer"));
+        assert_eq!(
+            started.elapsed(),
+            config.udp_retransmit_base * (2u32.pow(MAX_RETRANSMITS + 1) - 1)
+        );
+        assert_eq!(
+            received.load(Ordering::SeqCst),
+            1 + MAX_RETRANSMITS as usize
+        );
+    }
+
+    #[tokio::test]
+    async fn test_udp_announce_over_ipv6_returns_v6_peers() {
+        let mut peer = std::net::Ipv6Addr::LOCALHOST.octets().to_vec();
+        peer.extend_from_slice(&6881u16.to_be_bytes());
+        let (url, _) = spawn_mock_udp_tracker_on("[::1]:0", peer, 0, 0).await;
+        let client = UdpTrackerClient::new(Config::default());
+
+        let response = client.announce(&url, &started_request()).await.unwrap();
+        assert!(response.peers.is_none());
+        let peers6 = response.peers6.unwrap();
+        assert_eq!(peers6.len(), 1);
+        assert_eq!(peers6[0].ip, "::1");
+        assert_eq!(peers6[0].port, 6881);
+    }
+}
@@ -0,0 +1,203 @@
+use crate::core::{Config, Hash, PieceIndex, TorrentInfo, WebSeed, WebSeedStyle};
+use crate::logging::debug;
+use anyhow::{Context, Result};
+use reqwest::header::RANGE;
+use reqwest::StatusCode;
+
+//=== One HTTP GET serving part of a block; `range` is inclusive, for a Range header ===//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WebSeedRequest {
+    pub url: String,
+    pub range: Option<(u64, u64)>,
+}
+
+//=== The GETs that fetch `length` bytes at `offset` into a piece from `seed` ===//
+pub fn web_seed_requests(
+    seed: &WebSeed,
+    torrent_info: &TorrentInfo,
+    info_hash: &Hash,
+    piece_index: PieceIndex,
+    offset: u32,
+    length: u32,
+) -> Vec<WebSeedRequest> {
+    if length == 0 {
+        return Vec::new();
+    }
+
+    match seed.style {
+        //=== BEP 17: the seed script slices the piece itself ===//
+        WebSeedStyle::HttpSeed => {
+            let separator = if seed.url.contains('?') { '&' } else { '?' };
+            let url = format!(
+                "{}{}info_hash={}&piece={}&ranges={}-{}",
+                seed.url,
+                separator,
+                urlencoding::encode_binary(info_hash),
+                piece_index,
+                offset,
+                offset + length - 1
+            );
+            vec![WebSeedRequest { url, range: None }]
+        }
+        //=== BEP 19: the seed serves plain files, so a block may span several of them ===//
+        WebSeedStyle::UrlList => {
+            let start = torrent_info.byte_range_for_piece(piece_index).start + offset as u64;
+            let end = start + length as u64;
+
+            let mut requests = Vec::new();
+            let mut file_start = 0;
+            for file in &torrent_info.files {
+                let file_end = file_start + file.length;
+                if file_start < end && start < file_end {
+                    requests.push(WebSeedRequest {
+                        url: file_url(&seed.url, torrent_info, &file.path),
+                        range: Some((
+                            start.max(file_start) - file_start,
+                            end.min(file_end) - file_start - 1,
+                        )),
+                    });
+                }
+                file_start = file_end;
+            }
+            requests
+        }
+    }
+}
+
+//=== A single file may be addressed directly; otherwise name and path are appended ===//
+fn file_url(base: &str, torrent_info: &TorrentInfo, path: &[String]) -> String {
+    let single_file = torrent_info.files.len() == 1 && path == [torrent_info.name.as_str()];
+    if single_file && !base.ends_with('/') {
+        return base.to_string();
+    }
+
+    let mut url = base.trim_end_matches('/').to_string();
+    let segments = if single_file { &[][..] } else { path };
+    for segment in std::iter::once(&torrent_info.name).chain(segments) {
+        url.push('/');
+        url.push_str(&urlencoding::encode(segment));
+    }
+    url
+}
+
+//=== Downloads blocks from web seeds, as a fallback when no peer has them ===//
+pub struct WebSeedClient {
+    http_client: reqwest::Client,
+}
+
+impl WebSeedClient {
+    pub fn new(config: Config) -> Self {
+        let http_client = reqwest::Client::builder()
+            .timeout(config.request_timeout)
+            .build()
+            .expect("Failed to create HTTP client");
+
+        Self { http_client }
+    }
+
+    //==== Fetch one block of a piece ====//
+    pub async fn fetch_block(
+        &self,
+        seed: &WebSeed,
+        torrent_info: &TorrentInfo,
+        info_hash: &Hash,
+        piece_index: PieceIndex,
+        offset: u32,
+        length: u32,
+    ) -> Result<Vec<u8>> {
+        let mut block = Vec::with_capacity(length as usize);
+        for request in web_seed_requests(seed, torrent_info, info_hash, piece_index, offset, length)
+        {
+            debug!("Fetching from web seed: {}", request.url);
+
+            let mut get = self.http_client.get(&request.url);
+            if let Some((first, last)) = request.range {
+                get = get.header(RANGE, format!("bytes={}-{}", first, last));
+            }
+            let response = get
+                .send()
+                .await
+                .with_context(|| format!("Failed to reach web seed {}", seed.url))?;
+
+            //=== BEP 17 seeds answer 503 with the seconds to wait before retrying ===//
+            if response.status() == StatusCode::SERVICE_UNAVAILABLE {
+                let retry = response.text().await.unwrap_or_default();
+                return Err(anyhow::anyhow!(
+                    "Web seed busy, retry in {} seconds",
+                    retry.trim()
+                ));
+            }
+            if !response.status().is_success() {
+                return Err(anyhow::anyhow!(
+                    "Web seed request failed with status: {}",
+                    response.status()
+                ));
+            }
+
+            block.extend_from_slice(&response.bytes().await?);
+        }
+
+        if block.len() != length as usize {
+            return Err(anyhow::anyhow!(
+                "Web seed returned {} bytes, expected {}",
+                block.len(),
+                length
+            ));
+        }
+        Ok(block)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::TorrentParser;
+
+    #[test]
+    fn test_web_seed_urls_for_multi_file_torrent() {
+        let torrent = [
+            b"d9:httpseedsl32:http://seed.example.com/seed.phpe4:infod5:filesl".as_slice(),
+            b"d6:lengthi10000e4:pathl4:cd 16:a.flacee",
+            b"d6:lengthi20000e4:pathl6:b.flaceee",
+            b"4:name5:album12:piece lengthi16384e6:pieces40:",
+            &[0u8; 40],
+            b"e8:url-list30:http://mirror.example.com/pub/e",
+        ]
+        .concat();
+        let torrent_info = TorrentParser::parse_bytes(&torrent).unwrap();
+        assert_eq!(torrent_info.web_seeds.len(), 2);
+        let (url_list, http_seed) = (&torrent_info.web_seeds[0], &torrent_info.web_seeds[1]);
+        assert_eq!(url_list.style, WebSeedStyle::UrlList);
+        assert_eq!(http_seed.style, WebSeedStyle::HttpSeed);
+
+        //=== A block straddling both files becomes two ranged GETs ===//
+        let info_hash = [0xab; 20];
+        let requests = web_seed_requests(url_list, &torrent_info, &info_hash, 0, 8000, 4000);
+        assert_eq!(
+            requests,
+            vec![
+                WebSeedRequest {
+                    url: "http://mirror.example.com/pub/album/cd%201/a.flac".to_string(),
+                    range: Some((8000, 9999)),
+                },
+                WebSeedRequest {
+                    url: "http://mirror.example.com/pub/album/b.flac".to_string(),
+                    range: Some((0, 1999)),
+                },
+            ]
+        );
+
+        //=== The same block from a BEP 17 seed is addressed by piece and range ===//
+        let requests = web_seed_requests(http_seed, &torrent_info, &info_hash, 1, 0, 16384);
+        assert_eq!(
+            requests,
+            vec![WebSeedRequest {
+                url: format!(
+                    "http://seed.example.com/seed.php?info_hash={}&piece=1&ranges=0-16383",
+                    "%AB".repeat(20)
+                ),
+                range: None,
+            }]
+        );
+    }
+}
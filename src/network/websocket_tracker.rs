@@ -0,0 +1,230 @@
+use crate::core::{Config, PeerId};
+use crate::logging::{debug, info};
+use crate::network::tracker::{Tracker, TrackerEvent, TrackerRequest, TrackerResponse};
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::sync::Mutex;
+use tokio::time::timeout;
+use tokio_tungstenite::tungstenite::Message as WsMessage;
+
+//=== A WebRTC offer relayed by the tracker; connecting to it is left to a future transport ===//
+#[derive(Debug, Clone, PartialEq)]
+pub struct WebRtcOffer {
+    pub peer_id: PeerId,
+    pub offer_id: Vec<u8>,
+    pub sdp: String,
+}
+
+//=== Announces to WebTorrent trackers over ws:// and wss:// ===//
+pub struct WebSocketTracker {
+    config: Config,
+    offers: Mutex<Vec<WebRtcOffer>>,
+}
+
+impl WebSocketTracker {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            offers: Mutex::new(Vec::new()),
+        }
+    }
+
+    //=== Offers received from other peers since the last call ===//
+    pub fn take_offers(&self) -> Vec<WebRtcOffer> {
+        std::mem::take(&mut *self.offers.lock().unwrap())
+    }
+
+    //==== Announce to a websocket tracker ====//
+    pub async fn announce(
+        &self,
+        tracker_url: &str,
+        request: &TrackerRequest,
+    ) -> Result<TrackerResponse> {
+        info!("Announcing to websocket tracker: {}", tracker_url);
+
+        timeout(self.config.tracker_timeout, async {
+            let (mut socket, _) = tokio_tungstenite::connect_async(tracker_url)
+                .await
+                .with_context(|| format!("Failed to connect to {}", tracker_url))?;
+
+            let message = announce_message(request);
+            socket
+                .send(WsMessage::Text(message.to_string()))
+                .await
+                .with_context(|| "Failed to send tracker announce")?;
+
+            //=== Offers may arrive before the announce reply; keep them for later ===//
+            while let Some(frame) = socket.next().await {
+                let text = match frame? {
+                    WsMessage::Text(text) => text,
+                    WsMessage::Close(_) => break,
+                    _ => continue,
+                };
+                debug!("Tracker message: {}", text);
+
+                let message: Value = match serde_json::from_str(&text) {
+                    Ok(message) => message,
+                    Err(_) => continue,
+                };
+                if let Some(offer) = parse_offer(&message) {
+                    self.offers.lock().unwrap().push(offer);
+                } else if let Some(response) = parse_announce_response(&message) {
+                    let _ = socket.close(None).await;
+                    return Ok(response);
+                }
+            }
+
+            Err(anyhow::anyhow!(
+                "Tracker closed before answering the announce"
+            ))
+        })
+        .await
+        .with_context(|| "Tracker request timeout")?
+    }
+}
+
+impl Tracker for WebSocketTracker {
+    fn announce<'a>(
+        &'a self,
+        tracker_url: &'a str,
+        request: &'a TrackerRequest,
+    ) -> BoxFuture<'a, Result<TrackerResponse>> {
+        Box::pin(WebSocketTracker::announce(self, tracker_url, request))
+    }
+}
+
+//=== WebTorrent carries binary ids as strings with one char per byte ===//
+fn binary_string(bytes: &[u8]) -> String {
+    bytes.iter().map(|&b| b as char).collect()
+}
+
+fn string_bytes(value: &Value) -> Option<Vec<u8>> {
+    value
+        .as_str()?
+        .chars()
+        .map(|c| u8::try_from(c as u32).ok())
+        .collect()
+}
+
+//=== JSON announce; we make no WebRTC offers ourselves yet ===//
+pub fn announce_message(request: &TrackerRequest) -> Value {
+    let mut message = json!({
+        "action": "announce",
+        "info_hash": binary_string(&request.info_hash),
+        "peer_id": binary_string(&request.peer_id),
+        "uploaded": request.uploaded,
+        "downloaded": request.downloaded,
+        "left": request.left,
+        "numwant": request.numwant.unwrap_or(0),
+        "offers": [],
+    });
+
+    if request.event != TrackerEvent::None {
+        message["event"] = json!(<&str>::from(request.event));
+    }
+    if let Some(tracker_id) = &request.tracker_id {
+        message["trackerid"] = json!(tracker_id);
+    }
+
+    message
+}
+
+fn parse_announce_response(message: &Value) -> Option<TrackerResponse> {
+    if message["action"] != "announce" {
+        return None;
+    }
+    let failure_reason = message["failure reason"].as_str().map(str::to_string);
+    if failure_reason.is_none() && message.get("interval").is_none() {
+        return None;
+    }
+
+    let field = |name: &str| message[name].as_u64().map(|n| n as u32);
+    Some(TrackerResponse {
+        failure_reason,
+        warning_message: message["warning message"].as_str().map(str::to_string),
+        interval: field("interval"),
+        min_interval: field("min interval"),
+        tracker_id: message["trackerid"].as_str().map(str::to_string),
+        complete: field("complete"),
+        incomplete: field("incomplete"),
+        peers: Some(Vec::new()),
+        peers6: None,
+    })
+}
+
+fn parse_offer(message: &Value) -> Option<WebRtcOffer> {
+    let sdp = message["offer"]["sdp"].as_str()?.to_string();
+    let peer_id = string_bytes(&message["peer_id"])?.try_into().ok()?;
+    let offer_id = string_bytes(&message["offer_id"])?;
+
+    Some(WebRtcOffer {
+        peer_id,
+        offer_id,
+        sdp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_announce_message_json() {
+        let mut request = TrackerRequest::new(
+            [0xff; 20],
+            *b"-FS0001-abcdefghijkl",
+            6881,
+            10,
+            20,
+            30,
+            TrackerEvent::Started,
+        );
+        request.numwant = Some(5);
+
+        let message = announce_message(&request);
+        assert_eq!(message["action"], "announce");
+        assert_eq!(message["event"], "started");
+        assert_eq!(message["peer_id"], "-FS0001-abcdefghijkl");
+        assert_eq!(message["uploaded"], 10);
+        assert_eq!(message["downloaded"], 20);
+        assert_eq!(message["left"], 30);
+        assert_eq!(message["numwant"], 5);
+        assert_eq!(message["offers"], json!([]));
+
+        //=== Each hash byte becomes one char, so it survives the JSON round trip ===//
+        let round_trip: Value = serde_json::from_str(&message.to_string()).unwrap();
+        assert_eq!(
+            string_bytes(&round_trip["info_hash"]).unwrap(),
+            vec![0xff; 20]
+        );
+
+        request.event = TrackerEvent::None;
+        assert!(announce_message(&request).get("event").is_none());
+    }
+
+    #[test]
+    fn test_parse_tracker_messages() {
+        let reply = json!({
+            "action": "announce",
+            "interval": 120,
+            "complete": 2,
+            "incomplete": 7,
+        });
+        let response = parse_announce_response(&reply).unwrap();
+        assert_eq!(response.interval, Some(120));
+        assert_eq!(response.incomplete, Some(7));
+
+        let offer = json!({
+            "action": "announce",
+            "peer_id": binary_string(&[3u8; 20]),
+            "offer_id": binary_string(&[9u8; 20]),
+            "offer": { "type": "offer", "sdp": "v=0" },
+        });
+        assert!(parse_announce_response(&offer).is_none());
+        let offer = parse_offer(&offer).unwrap();
+        assert_eq!(offer.peer_id, [3u8; 20]);
+        assert_eq!(offer.sdp, "v=0");
+    }
+}
@@ -1,9 +1,26 @@
-use crate::core::{Bitfield, PeerError, PeerId, PieceIndex, Result, TorrentError};
+use crate::core::{
+    system_clock, Bitfield, BlockLength, BlockOffset, Config, Hash, PeerError, PeerId, PieceIndex,
+    Result, SharedClock, TorrentError,
+};
 use crate::peer::{ChokingState, InterestState, Peer, PeerState};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
 use std::collections::{HashMap, HashSet};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Mutex;
 use std::time::{Duration, Instant};
 
+//=== Peers connected this recently are favoured for the optimistic unchoke ===//
+pub const NEW_PEER_WINDOW: Duration = Duration::from_secs(60);
+pub const NEW_PEER_WEIGHT: u32 = 3;
+
+//=== Protocol violations from one IP before it is banned ===//
+pub const MAX_PROTOCOL_VIOLATIONS: u32 = 3;
+
+//=== Span over which a peer's requests are counted against `max_peer_request_rate` ===//
+pub const REQUEST_RATE_WINDOW: Duration = Duration::from_secs(1);
+
 //=== Manages all peer connections for a torrent ===//
 #[derive(Debug)]
 pub struct PeerManager {
@@ -16,34 +33,112 @@ pub struct PeerManager {
     unchoked_peers: HashSet<PeerId>,
     max_unchoked: usize,
     optimistic_unchoke: Option<PeerId>,
+    disconnect_seeds_when_seeding: bool,
+    upload_quota: Option<u64>,
+    download_quota: Option<u64>,
+    // Violations per IP so far, and the IPs banned for them //
+    protocol_violations: HashMap<IpAddr, u32>,
+    banned: HashSet<IpAddr>,
+    max_queued_uploads: usize,
+    max_request_rate: u32,
+    clock: SharedClock,
+    // Drives optimistic unchoke and rarest-first tie-breaks; seedable for tests //
+    rng: Mutex<StdRng>,
 }
 
 impl PeerManager {
     //=== Create a new peer manager ===//
     pub fn new(num_pieces: usize, max_peers: usize) -> Self {
+        Self::with_clock(num_pieces, max_peers, system_clock())
+    }
+
+    //=== Create a peer manager driven by the given clock ===//
+    pub fn with_clock(num_pieces: usize, max_peers: usize, clock: SharedClock) -> Self {
+        let config = Config {
+            max_connections: max_peers,
+            ..Config::default()
+        };
+        Self::from_config(num_pieces, &config, clock)
+    }
+
+    //=== Create a peer manager whose limits and timers come from the config ===//
+    pub fn from_config(num_pieces: usize, config: &Config, clock: SharedClock) -> Self {
         Self {
             peers: HashMap::new(),
             our_bitfield: Bitfield::new(num_pieces),
-            max_peers,
-            connection_timeout: Duration::from_secs(30),
-            last_choke_time: Instant::now(),
-            choke_interval: Duration::from_secs(10),
+            max_peers: config.max_connections,
+            connection_timeout: config.connection_timeout,
+            last_choke_time: clock.now(),
+            choke_interval: config.unchoke_interval,
             unchoked_peers: HashSet::new(),
-            max_unchoked: 4,
+            max_unchoked: config.unchoke_slots(),
             optimistic_unchoke: None,
+            disconnect_seeds_when_seeding: config.disconnect_seeds_when_seeding,
+            upload_quota: config.peer_upload_quota,
+            download_quota: config.peer_download_quota,
+            protocol_violations: HashMap::new(),
+            banned: HashSet::new(),
+            max_queued_uploads: config.max_queued_uploads_per_peer,
+            max_request_rate: config.max_peer_request_rate,
+            clock,
+            rng: Mutex::new(StdRng::from_entropy()),
         }
     }
 
+    //=== Replace the random source, e.g. with a seeded one for reproducible runs ===//
+    pub fn set_rng(&mut self, rng: StdRng) {
+        self.rng = Mutex::new(rng);
+    }
+
+    //=== Run `f`, then rewind the random source so the next real pick sees the same draws ===//
+    pub fn keeping_rng_state<T>(&self, f: impl FnOnce() -> T) -> T {
+        let saved = self.rng.lock().unwrap().clone();
+        let result = f();
+        *self.rng.lock().unwrap() = saved;
+        result
+    }
+
+    pub fn choke_interval(&self) -> Duration {
+        self.choke_interval
+    }
+
+    pub fn max_unchoked(&self) -> usize {
+        self.max_unchoked
+    }
+
+    pub fn clock(&self) -> &SharedClock {
+        &self.clock
+    }
+
+    pub fn max_peers(&self) -> usize {
+        self.max_peers
+    }
+
+    //=== Change this torrent's peer limit; peers already above it are kept ===//
+    pub fn set_max_peers(&mut self, max_peers: usize) {
+        self.max_peers = max_peers;
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.peers.len() >= self.max_peers
+    }
+
     //=== Add a new peer ===//
     pub fn add_peer(&mut self, peer_id: PeerId, address: SocketAddr) -> Result<()> {
-        if self.peers.len() >= self.max_peers {
+        if self.is_full() {
             return Err(TorrentError::Peer(PeerError::NotFound {
                 peer_id: format!("{:?}", peer_id),
             }));
         }
+        if self.is_banned(&address.ip()) {
+            return Err(TorrentError::Peer(PeerError::Banned));
+        }
 
         if !self.peers.contains_key(&peer_id) {
-            let peer = Peer::new(peer_id, address, self.our_bitfield.total_pieces());
+            let num_pieces = self.our_bitfield.total_pieces();
+            let mut peer = Peer::new_at(peer_id, address, num_pieces, self.clock.now());
+            peer.upload_quota = self.upload_quota;
+            peer.download_quota = self.download_quota;
             self.peers.insert(peer_id, peer);
         }
 
@@ -59,6 +154,91 @@ impl PeerManager {
         self.peers.remove(peer_id)
     }
 
+    //=== Count a protocol violation against the peer's IP, returning whether it is now banned ===//
+    pub fn note_protocol_violation(&mut self, peer_id: &PeerId) -> bool {
+        let Some(ip) = self.peers.get(peer_id).map(|peer| peer.address.ip()) else {
+            return false;
+        };
+        let violations = self.protocol_violations.entry(ip).or_insert(0);
+        *violations += 1;
+        if *violations >= MAX_PROTOCOL_VIOLATIONS {
+            self.banned.insert(ip);
+        }
+        self.banned.contains(&ip)
+    }
+
+    pub fn ban_peer(&mut self, peer_id: &PeerId) {
+        if let Some(peer) = self.peers.get(peer_id) {
+            self.banned.insert(peer.address.ip());
+        }
+    }
+
+    //=== Queue a peer's request to be served; a peer over the rate or queue cap is flooding ===//
+    pub fn queue_upload_request(
+        &mut self,
+        peer_id: &PeerId,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockLength,
+    ) -> Result<()> {
+        let now = self.clock.now();
+        let peer = self
+            .peers
+            .get_mut(peer_id)
+            .ok_or(TorrentError::Peer(PeerError::NotFound {
+                peer_id: format!("{:?}", peer_id),
+            }))?;
+
+        let (start, requests) = match peer.request_window {
+            Some((start, count)) if now.saturating_duration_since(start) < REQUEST_RATE_WINDOW => {
+                (start, count + 1)
+            }
+            _ => (now, 1),
+        };
+        peer.request_window = Some((start, requests));
+        if requests > self.max_request_rate {
+            return Err(TorrentError::Peer(PeerError::RequestFlood { requests }));
+        }
+
+        let request = (piece_index, offset, length);
+        if peer.upload_queue.contains(&request) {
+            return Ok(());
+        }
+        if peer.upload_queue.len() >= self.max_queued_uploads {
+            return Err(TorrentError::Peer(PeerError::UploadQueueFull {
+                queued: peer.upload_queue.len(),
+            }));
+        }
+        peer.upload_queue.push_back(request);
+        Ok(())
+    }
+
+    //=== Oldest request the peer is still waiting on ===//
+    pub fn next_upload_request(
+        &mut self,
+        peer_id: &PeerId,
+    ) -> Option<(PieceIndex, BlockOffset, BlockLength)> {
+        self.peers.get_mut(peer_id)?.upload_queue.pop_front()
+    }
+
+    //=== The peer no longer wants this block ===//
+    pub fn cancel_upload_request(
+        &mut self,
+        peer_id: &PeerId,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockLength,
+    ) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.upload_queue
+                .retain(|&request| request != (piece_index, offset, length));
+        }
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.banned.contains(ip)
+    }
+
     pub fn get_peer(&self, peer_id: &PeerId) -> Option<&Peer> {
         self.peers.get(peer_id)
     }
@@ -68,6 +248,17 @@ impl PeerManager {
     pub fn peers(&self) -> &HashMap<PeerId, Peer> {
         &self.peers
     }
+    //=== DHT nodes learned from peers' Port messages, for seeding a routing table ===//
+    pub fn dht_nodes(&self) -> Vec<SocketAddr> {
+        let mut nodes: Vec<SocketAddr> = self
+            .peers
+            .values()
+            .filter_map(|peer| Some(SocketAddr::new(peer.address.ip(), peer.dht_port?)))
+            .collect();
+        nodes.sort();
+        nodes
+    }
+
     pub fn peer_ids(&self) -> Vec<PeerId> {
         self.peers.keys().copied().collect()
     }
@@ -96,6 +287,57 @@ impl PeerManager {
         }
     }
 
+    //=== A peer sent its bitfield; returns the interest message to send if ours changed ===//
+    pub fn peer_bitfield(&mut self, peer_id: &PeerId, bitfield: Bitfield) -> Option<InterestState> {
+        let peer = self.peers.get_mut(peer_id)?;
+        peer.bitfield = bitfield;
+        peer.update_interest(&self.our_bitfield)
+    }
+
+    //=== A peer announced a new piece; returns the interest message to send if ours changed ===//
+    pub fn peer_have(
+        &mut self,
+        peer_id: &PeerId,
+        piece_index: PieceIndex,
+    ) -> Option<InterestState> {
+        let peer = self.peers.get_mut(peer_id)?;
+        peer.bitfield.set_piece(piece_index);
+        peer.update_interest(&self.our_bitfield)
+    }
+
+    //=== A peer choked or unchoked us; requests to it wait for an unchoke ===//
+    pub fn peer_choking_changed(&mut self, peer_id: &PeerId, choking: ChokingState) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.peer_choking = choking;
+        }
+    }
+
+    //=== A peer told us whether it wants our pieces; only interested peers are served ===//
+    pub fn peer_interest_changed(&mut self, peer_id: &PeerId, interest: InterestState) {
+        if let Some(peer) = self.peers.get_mut(peer_id) {
+            peer.peer_interested = interest;
+        }
+    }
+
+    //=== A peer refused one block, so just that block can be picked again ===//
+    pub fn request_rejected(
+        &mut self,
+        peer_id: &PeerId,
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+    ) -> bool {
+        self.peers
+            .get_mut(peer_id)
+            .is_some_and(|peer| peer.remove_block_request(piece_index, offset))
+    }
+
+    //=== The piece is settled either way, so no peer still owes us a block of it ===//
+    pub fn clear_piece_requests(&mut self, piece_index: PieceIndex) {
+        for peer in self.peers.values_mut() {
+            peer.remove_request(piece_index);
+        }
+    }
+
     //=== Find peers that have a specific piece ===//
     pub fn peers_with_piece(&self, piece_index: PieceIndex) -> Vec<PeerId> {
         self.peers
@@ -118,12 +360,36 @@ impl PeerManager {
             }
         }
 
-        //=== Convert to sorted vec (rarest first) ===//
+        //=== Convert to sorted vec (rarest first), ties in random order ===//
         let mut pieces: Vec<(PieceIndex, usize)> = piece_counts.into_iter().collect();
+        pieces.sort_unstable();
+        pieces.shuffle(&mut *self.rng.lock().unwrap());
         pieces.sort_by_key(|(_, count)| *count);
         pieces
     }
 
+    //=== Number of connected peers that have the given piece ===//
+    pub fn piece_availability(&self, piece_index: PieceIndex) -> usize {
+        self.peers
+            .values()
+            .filter(|peer| peer.peer_has_piece(piece_index))
+            .count()
+    }
+
+    //=== Complete copies of the given pieces in the swarm, plus the fraction above that ===//
+    pub fn distributed_copies(&self, pieces: &[PieceIndex]) -> f64 {
+        let counts: Vec<usize> = pieces
+            .iter()
+            .map(|&piece_index| self.piece_availability(piece_index))
+            .collect();
+        let Some(&min) = counts.iter().min() else {
+            return 0.0;
+        };
+
+        let above = counts.iter().filter(|&&count| count > min).count();
+        min as f64 + above as f64 / counts.len() as f64
+    }
+
     //=== Get pieces we're missing that at least one peer has ===//
     pub fn missing_pieces_available(&self) -> Vec<PieceIndex> {
         let missing = self.our_bitfield.missing_pieces();
@@ -159,24 +425,49 @@ impl PeerManager {
         candidates.into_iter().map(|(id, _)| *id).collect()
     }
 
-    //=== Perform choking algorithm (tit-for-tat) ===//
-    pub fn update_choking(&mut self) {
-        if self.last_choke_time.elapsed() < self.choke_interval {
-            return;
+    //=== Perform choking algorithm (tit-for-tat) once the choke interval has elapsed ===//
+    pub fn update_choking(&mut self) -> Vec<(PeerId, ChokingState)> {
+        let now = self.clock.now();
+        if now.saturating_duration_since(self.last_choke_time) < self.choke_interval {
+            return Vec::new();
+        }
+
+        self.rechoke()
+    }
+
+    //=== Recompute the unchoked set now, returning the peers whose state changed ===//
+    pub fn rechoke(&mut self) -> Vec<(PeerId, ChokingState)> {
+        let now = self.clock.now();
+        self.last_choke_time = now;
+
+        //=== Rank on current rates, so a peer that went quiet stops looking fast ===//
+        for peer in self.peers.values_mut() {
+            peer.recalculate_rates(now);
         }
 
-        self.last_choke_time = Instant::now();
+        //=== Peers that used up their upload quota stay choked until it is reset ===//
+        if let Some(opt_peer) = self.optimistic_unchoke {
+            if self
+                .peers
+                .get(&opt_peer)
+                .is_some_and(|peer| peer.upload_quota_exhausted())
+            {
+                self.optimistic_unchoke = None;
+            }
+        }
 
         let mut interested_peers: Vec<_> = self
             .peers
             .iter()
             .filter(|(_, peer)| matches!(peer.peer_interested, InterestState::Interested))
+            .filter(|(_, peer)| !peer.upload_quota_exhausted())
             .collect();
 
-        interested_peers.sort_by(|(_, a), (_, b)| {
+        interested_peers.sort_by(|(id_a, a), (id_b, b)| {
             b.upload_rate
                 .partial_cmp(&a.upload_rate)
                 .unwrap_or(std::cmp::Ordering::Equal)
+                .then_with(|| id_a.cmp(id_b))
         });
 
         //== Unchoke top uploaders ===//
@@ -189,14 +480,19 @@ impl PeerManager {
         }
 
         //=== Optimistic unchoke ===//
-        if self.optimistic_unchoke.is_none() || rand::random::<f32>() < 0.1 {
-            let choked_interested: Vec<_> = interested_peers
+        let mut rng = self.rng.lock().unwrap();
+        if self.optimistic_unchoke.is_none() || rng.gen::<f32>() < 0.1 {
+            let mut choked_interested: Vec<&Peer> = interested_peers
                 .iter()
                 .filter(|(id, _)| !new_unchoked.contains(*id))
+                .map(|(_, peer)| *peer)
                 .collect();
+            choked_interested.sort_by_key(|peer| peer.id);
 
-            if let Some((peer_id, _)) = choked_interested.get(0) {
-                self.optimistic_unchoke = Some(**peer_id);
+            if let Some(peer_id) =
+                pick_optimistic_unchoke(&choked_interested, self.clock.now(), &mut *rng)
+            {
+                self.optimistic_unchoke = Some(peer_id);
             }
         }
 
@@ -205,24 +501,50 @@ impl PeerManager {
         }
 
         //=== Update choking states ===//
+        let mut changes = Vec::new();
         for (peer_id, peer) in self.peers.iter_mut() {
             let should_unchoke = new_unchoked.contains(peer_id);
-            peer.am_choking = if should_unchoke {
+            let new_state = if should_unchoke {
                 ChokingState::Unchoked
             } else {
                 ChokingState::Choked
             };
+            if peer.am_choking != new_state {
+                peer.am_choking = new_state;
+                changes.push((*peer_id, new_state));
+            }
         }
 
         self.unchoked_peers = new_unchoked;
+        changes
+    }
+
+    //=== Choke every peer, returning the peers whose state changed ===//
+    pub fn choke_all(&mut self) -> Vec<(PeerId, ChokingState)> {
+        self.unchoked_peers.clear();
+        self.optimistic_unchoke = None;
+
+        let mut changes = Vec::new();
+        for (peer_id, peer) in self.peers.iter_mut() {
+            if peer.am_choking != ChokingState::Choked {
+                peer.am_choking = ChokingState::Choked;
+                changes.push((*peer_id, ChokingState::Choked));
+            }
+        }
+        changes
+    }
+
+    pub fn unchoked_peers(&self) -> &HashSet<PeerId> {
+        &self.unchoked_peers
     }
 
     //=== Clean up stale peer connections ===//
     pub fn cleanup_stale_peers(&mut self) {
+        let now = self.clock.now();
         let stale_peers: Vec<PeerId> = self
             .peers
             .iter()
-            .filter(|(_, peer)| peer.is_stale(self.connection_timeout))
+            .filter(|(_, peer)| peer.is_stale_at(now, self.connection_timeout))
             .map(|(id, _)| *id)
             .collect();
 
@@ -230,6 +552,24 @@ impl PeerManager {
             self.remove_peer(&peer_id);
         }
     }
+    //=== While seeding, drop peers that are seeds too, returning who was dropped ===//
+    pub fn disconnect_redundant_seeds(&mut self) -> Vec<PeerId> {
+        if !self.disconnect_seeds_when_seeding || !self.our_bitfield.is_complete() {
+            return Vec::new();
+        }
+
+        let seeds: Vec<PeerId> = self
+            .peers
+            .values()
+            .filter(|peer| peer.is_seeder())
+            .map(|peer| peer.id)
+            .collect();
+        for peer_id in &seeds {
+            self.remove_peer(peer_id);
+        }
+        seeds
+    }
+
     pub fn completion_percentage(&self) -> f64 {
         self.our_bitfield.completion_percentage()
     }
@@ -252,3 +592,389 @@ impl PeerManager {
         )
     }
 }
+
+//=== Random choice among candidates, with newly connected peers weighted higher ===//
+pub fn pick_optimistic_unchoke<R: Rng + ?Sized>(
+    candidates: &[&Peer],
+    now: Instant,
+    rng: &mut R,
+) -> Option<PeerId> {
+    candidates
+        .choose_weighted(rng, |peer| {
+            if now.saturating_duration_since(peer.connected_at) < NEW_PEER_WINDOW {
+                NEW_PEER_WEIGHT
+            } else {
+                1
+            }
+        })
+        .ok()
+        .map(|peer| peer.id)
+}
+
+//=== The canonical allowed-fast set for a peer address (BEP 6); IPv4 only ===//
+pub fn allowed_fast_set(
+    ip: IpAddr,
+    info_hash: &Hash,
+    num_pieces: usize,
+    count: usize,
+) -> Vec<PieceIndex> {
+    use sha1::{Digest, Sha1};
+
+    let IpAddr::V4(ip) = ip else {
+        return Vec::new();
+    };
+    let count = count.min(num_pieces);
+
+    let mut x = Vec::with_capacity(24);
+    x.extend_from_slice(&(u32::from(ip) & 0xffff_ff00).to_be_bytes());
+    x.extend_from_slice(info_hash);
+
+    let mut set = Vec::with_capacity(count);
+    while set.len() < count {
+        x = Sha1::digest(&x).to_vec();
+        for chunk in x.chunks_exact(4) {
+            if set.len() == count {
+                break;
+            }
+            let y = u32::from_be_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            let index = (y as u64 % num_pieces as u64) as PieceIndex;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+    set
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{Clock, MockClock, BLOCK_SIZE};
+
+    #[test]
+    fn test_allowed_fast_set_matches_bep6() {
+        let ip = "80.4.4.200".parse().unwrap();
+        assert_eq!(
+            allowed_fast_set(ip, &[0xaa; 20], 1313, 7),
+            vec![1059, 431, 808, 1217, 287, 376, 1188]
+        );
+    }
+
+    #[test]
+    fn test_small_torrent_offers_fewer_allowed_fast() {
+        let config = Config::default();
+        let ip = "10.0.0.1".parse().unwrap();
+
+        let count = config.allowed_fast_slots(8);
+        let set = allowed_fast_set(ip, &[1; 20], 8, count);
+        assert_eq!(set.len(), count);
+        assert!(set.len() < 8);
+        assert!(set.iter().all(|&piece| piece < 8));
+
+        //=== Large torrents get the configured count ===//
+        assert_eq!(config.allowed_fast_slots(1000), config.allowed_fast_count);
+    }
+
+    fn interested_peer(manager: &mut PeerManager, id: u8) -> PeerId {
+        let peer_id = [id; 20];
+        let addr = format!("127.0.0.1:{}", 6000 + id as u16).parse().unwrap();
+        manager.add_peer(peer_id, addr).unwrap();
+        manager.get_peer_mut(&peer_id).unwrap().peer_interested = InterestState::Interested;
+        peer_id
+    }
+
+    #[test]
+    fn test_peer_past_upload_quota_is_choked() {
+        let config = Config {
+            peer_upload_quota: Some(1000),
+            ..Config::default()
+        };
+        let mut manager = PeerManager::from_config(10, &config, system_clock());
+        let peer_id = interested_peer(&mut manager, 1);
+
+        manager.rechoke();
+        assert!(manager.unchoked_peers().contains(&peer_id));
+
+        manager
+            .get_peer_mut(&peer_id)
+            .unwrap()
+            .update_upload_stats(1000);
+        assert_eq!(manager.rechoke(), vec![(peer_id, ChokingState::Choked)]);
+        assert!(manager.get_peer(&peer_id).unwrap().upload_quota_exhausted());
+
+        //=== A reset lets it back in ===//
+        manager.get_peer_mut(&peer_id).unwrap().reset_quotas();
+        manager.rechoke();
+        assert!(manager.unchoked_peers().contains(&peer_id));
+    }
+
+    #[test]
+    fn test_mock_clock_triggers_choke_round() {
+        let clock = MockClock::new();
+        let mut manager = PeerManager::with_clock(10, 50, clock.shared());
+        let peer_id = interested_peer(&mut manager, 1);
+
+        //=== Interval not yet elapsed: nothing is recomputed ===//
+        manager.update_choking();
+        assert!(manager.unchoked_peers().is_empty());
+
+        clock.advance(Duration::from_secs(11));
+        manager.update_choking();
+        assert!(manager.unchoked_peers().contains(&peer_id));
+        assert_eq!(
+            manager.get_peer(&peer_id).unwrap().am_choking,
+            ChokingState::Unchoked
+        );
+    }
+
+    #[test]
+    fn test_choke_round_lets_idle_peer_rates_decay() {
+        let clock = MockClock::new();
+        let mut manager = PeerManager::with_clock(10, 50, clock.shared());
+        let peer_id = interested_peer(&mut manager, 1);
+        assert_eq!(
+            manager.get_peer(&peer_id).unwrap().connected_at,
+            clock.now()
+        );
+
+        clock.advance(Duration::from_secs(10));
+        let peer = manager.get_peer_mut(&peer_id).unwrap();
+        peer.update_download_stats_at(100_000, clock.now());
+        assert!(peer.download_rate > 0.0);
+
+        //=== Nothing more arrives; the next round sees the peer as idle ===//
+        clock.advance(crate::peer::RATE_WINDOW);
+        manager.update_choking();
+        assert_eq!(manager.get_peer(&peer_id).unwrap().download_rate, 0.0);
+    }
+
+    #[test]
+    fn test_choke_interval_comes_from_config() {
+        let config = Config {
+            unchoke_interval: Duration::from_secs(3),
+            ..Config::default()
+        };
+        let clock = MockClock::new();
+        let mut manager = PeerManager::from_config(10, &config, clock.shared());
+        let peer_id = interested_peer(&mut manager, 1);
+        assert_eq!(manager.choke_interval(), Duration::from_secs(3));
+
+        clock.advance(Duration::from_secs(3));
+        let changes = manager.update_choking();
+        assert_eq!(changes, vec![(peer_id, ChokingState::Unchoked)]);
+
+        //=== A second round with no change reports nothing ===//
+        clock.advance(Duration::from_secs(3));
+        assert!(manager.update_choking().is_empty());
+    }
+
+    #[test]
+    fn test_higher_unchoke_limit_unchokes_more_peers() {
+        let unchoked_with = |max_unchoked| {
+            let config = Config {
+                max_unchoked,
+                ..Config::default()
+            };
+            let mut manager = PeerManager::from_config(10, &config, MockClock::new().shared());
+            for id in 1..=10 {
+                interested_peer(&mut manager, id);
+            }
+            manager.rechoke();
+            manager.unchoked_peers().len()
+        };
+
+        assert_eq!(unchoked_with(4), 4);
+        assert_eq!(unchoked_with(8), 8);
+    }
+
+    #[test]
+    fn test_optimistic_unchoke_favours_new_peers() {
+        let clock = MockClock::new();
+        let mut manager = PeerManager::with_clock(10, 50, clock.shared());
+        let old_peer = interested_peer(&mut manager, 1);
+        clock.advance(NEW_PEER_WINDOW * 2);
+        let new_peer = interested_peer(&mut manager, 2);
+
+        let candidates: Vec<&Peer> = manager.peers().values().collect();
+        let mut rng = StdRng::seed_from_u64(7);
+        let (mut old_picks, mut new_picks) = (0, 0);
+        for _ in 0..4000 {
+            match pick_optimistic_unchoke(&candidates, clock.now(), &mut rng) {
+                Some(id) if id == old_peer => old_picks += 1,
+                Some(id) if id == new_peer => new_picks += 1,
+                other => panic!("unexpected pick {:?}", other),
+            }
+        }
+
+        //=== Roughly 3:1, and the old peer is still picked sometimes ===//
+        assert!(old_picks > 0);
+        assert!(new_picks > old_picks * 2, "{} vs {}", new_picks, old_picks);
+        assert!(pick_optimistic_unchoke(&[], clock.now(), &mut rng).is_none());
+    }
+
+    #[test]
+    fn test_seeded_rng_gives_fixed_selection_sequence() {
+        let run = |seed| {
+            let mut manager = PeerManager::with_clock(8, 50, MockClock::new().shared());
+            manager.set_rng(StdRng::seed_from_u64(seed));
+            for id in 1..=5 {
+                let peer_id = interested_peer(&mut manager, id);
+                let peer = manager.get_peer_mut(&peer_id).unwrap();
+                peer.state = PeerState::Ready;
+                for piece in 0..8 {
+                    peer.bitfield.set_piece(piece);
+                }
+            }
+
+            let mut sequence = Vec::new();
+            for _ in 0..3 {
+                let order: Vec<PieceIndex> =
+                    manager.rarest_pieces().iter().map(|(i, _)| *i).collect();
+                sequence.push(order);
+            }
+            manager.rechoke();
+            (sequence, manager.optimistic_unchoke)
+        };
+
+        let (sequence, optimistic) = run(42);
+        assert_eq!(run(42), (sequence.clone(), optimistic));
+        assert!(optimistic.is_some());
+
+        //=== Ties are broken randomly rather than in a fixed order ===//
+        assert!(sequence.iter().any(|order| order != &sequence[0]));
+        assert!(sequence.iter().all(|order| order.len() == 8));
+    }
+
+    #[test]
+    fn test_repeated_protocol_violations_ban_the_ip() {
+        let mut manager = PeerManager::new(1, 10);
+        let addr: SocketAddr = "10.0.0.1:6881".parse().unwrap();
+
+        for attempt in 1..=MAX_PROTOCOL_VIOLATIONS {
+            let peer_id = [attempt as u8; 20];
+            manager.add_peer(peer_id, addr).unwrap();
+            let banned = manager.note_protocol_violation(&peer_id);
+            assert_eq!(banned, attempt == MAX_PROTOCOL_VIOLATIONS);
+            manager.remove_peer(&peer_id);
+        }
+
+        //=== A fresh peer id from the same address is turned away ===//
+        assert!(manager.is_banned(&addr.ip()));
+        assert!(manager.add_peer([9u8; 20], addr).is_err());
+        assert!(manager
+            .add_peer([9u8; 20], "10.0.0.2:6881".parse().unwrap())
+            .is_ok());
+    }
+
+    #[test]
+    fn test_request_flood_is_refused() {
+        let clock = MockClock::new();
+        let config = Config {
+            max_peer_request_rate: 4,
+            ..Config::default()
+        };
+        let mut manager = PeerManager::from_config(10, &config, clock.shared());
+        let peer_id = interested_peer(&mut manager, 1);
+
+        for block in 0..4 {
+            manager
+                .queue_upload_request(&peer_id, 0, block * BLOCK_SIZE, BLOCK_SIZE)
+                .unwrap();
+        }
+        assert!(matches!(
+            manager.queue_upload_request(&peer_id, 1, 0, BLOCK_SIZE),
+            Err(TorrentError::Peer(PeerError::RequestFlood { requests: 5 }))
+        ));
+
+        //=== The next window starts the count afresh ===//
+        clock.advance(REQUEST_RATE_WINDOW);
+        assert!(manager
+            .queue_upload_request(&peer_id, 1, 0, BLOCK_SIZE)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_upload_queue_is_capped() {
+        let config = Config {
+            max_queued_uploads_per_peer: 3,
+            ..Config::default()
+        };
+        let mut manager = PeerManager::from_config(10, &config, MockClock::new().shared());
+        let peer_id = interested_peer(&mut manager, 1);
+
+        for piece in 0..3 {
+            manager
+                .queue_upload_request(&peer_id, piece, 0, BLOCK_SIZE)
+                .unwrap();
+        }
+        //=== A repeat of a queued request takes no extra slot ===//
+        assert!(manager
+            .queue_upload_request(&peer_id, 2, 0, BLOCK_SIZE)
+            .is_ok());
+        assert!(matches!(
+            manager.queue_upload_request(&peer_id, 3, 0, BLOCK_SIZE),
+            Err(TorrentError::Peer(PeerError::UploadQueueFull { queued: 3 }))
+        ));
+
+        //=== Cancelled and served requests free their slots ===//
+        manager.cancel_upload_request(&peer_id, 1, 0, BLOCK_SIZE);
+        assert_eq!(
+            manager.next_upload_request(&peer_id),
+            Some((0, 0, BLOCK_SIZE))
+        );
+        assert_eq!(
+            manager.next_upload_request(&peer_id),
+            Some((2, 0, BLOCK_SIZE))
+        );
+        assert_eq!(manager.next_upload_request(&peer_id), None);
+    }
+
+    #[test]
+    fn test_seeds_dropped_once_we_complete() {
+        let mut manager = PeerManager::with_clock(2, 50, MockClock::new().shared());
+        let seed = interested_peer(&mut manager, 1);
+        let leecher = interested_peer(&mut manager, 2);
+        let seed_peer = manager.get_peer_mut(&seed).unwrap();
+        seed_peer.has_piece(0);
+        seed_peer.has_piece(1);
+
+        //=== Still downloading: the seed is useful ===//
+        manager.completed_piece(0);
+        assert!(manager.disconnect_redundant_seeds().is_empty());
+
+        manager.completed_piece(1);
+        assert_eq!(manager.disconnect_redundant_seeds(), vec![seed]);
+        assert!(manager.get_peer(&seed).is_none());
+        assert!(manager.get_peer(&leecher).is_some());
+    }
+
+    #[test]
+    fn test_seeds_kept_when_policy_disabled() {
+        let config = Config {
+            disconnect_seeds_when_seeding: false,
+            ..Config::default()
+        };
+        let mut manager = PeerManager::from_config(1, &config, MockClock::new().shared());
+        let seed = interested_peer(&mut manager, 1);
+        manager.get_peer_mut(&seed).unwrap().has_piece(0);
+        manager.completed_piece(0);
+
+        assert!(manager.disconnect_redundant_seeds().is_empty());
+        assert!(manager.get_peer(&seed).is_some());
+    }
+
+    #[test]
+    fn test_mock_clock_expires_stale_peers() {
+        let clock = MockClock::new();
+        let mut manager = PeerManager::with_clock(10, 50, clock.shared());
+        interested_peer(&mut manager, 1);
+
+        clock.advance(Duration::from_secs(29));
+        manager.cleanup_stale_peers();
+        assert_eq!(manager.peers().len(), 1);
+
+        clock.advance(Duration::from_secs(2));
+        manager.cleanup_stale_peers();
+        assert!(manager.peers().is_empty());
+    }
+}
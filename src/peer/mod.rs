@@ -1,5 +1,8 @@
 pub mod manager;
+#[allow(clippy::module_inception)]
 pub mod peer;
+pub mod reputation;
 
 pub use manager::*;
 pub use peer::*;
+pub use reputation::*;
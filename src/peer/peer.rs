@@ -1,8 +1,18 @@
-use crate::core::{Bitfield, PeerId, PieceIndex};
+use crate::core::{Bitfield, BlockLength, BlockOffset, PeerId, PieceIndex};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::SocketAddr;
-use std::time::Instant;
+use std::time::{Duration, Instant};
+
+//=== Bounds on the per-peer request window that AIMD moves within ===//
+pub const MIN_REQUEST_WINDOW: usize = 1;
+pub const MAX_REQUEST_WINDOW: usize = 32;
+
+//=== Smoothed block latency this many times the best seen counts as congestion ===//
+pub const CONGESTION_LATENCY_FACTOR: f64 = 2.0;
+
+//=== Transfer rates average the bytes moved over this much recent time ===//
+pub const RATE_WINDOW: Duration = Duration::from_secs(20);
 
 //=== Possible states for a peer connection ===//
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -44,18 +54,45 @@ pub struct Peer {
     pub upload_rate: f64,
     pub last_seen: Instant,
     pub last_sent: Instant,
+    pub connected_at: Instant,
     pub downloaded: u64,
     pub uploaded: u64,
-    pub pending_requests: HashMap<PieceIndex, Instant>,
+    // Blocks we asked this peer for and when; `max_requests` caps the pieces they span //
+    pub pending_requests: HashMap<(PieceIndex, BlockOffset), Instant>,
     pub max_requests: usize,
     pub supports_fast: bool,
     pub supports_extended: bool,
+    pub supports_dht: bool,
+    // DHT node port the peer sent in a Port message //
+    pub dht_port: Option<u16>,
+    // Pieces this peer may request from us even while choked //
+    pub allowed_fast: HashSet<PieceIndex>,
+    // Byte caps counted from the last quota reset //
+    pub upload_quota: Option<u64>,
+    pub download_quota: Option<u64>,
+    pub uploaded_at_reset: u64,
+    pub downloaded_at_reset: u64,
+    // Block latency, smoothed and best seen, that moves `max_requests` up or down //
+    pub smoothed_latency: Option<Duration>,
+    pub base_latency: Option<Duration>,
+    pub last_block_at: Option<Instant>,
+    // Blocks since `max_requests` last changed; it changes at most once per window //
+    pub blocks_this_window: usize,
+    // Requests from this peer waiting to be served, oldest first //
+    pub upload_queue: VecDeque<(PieceIndex, BlockOffset, BlockLength)>,
+    // Start of the current one-second rate window and the requests seen in it //
+    pub request_window: Option<(Instant, u32)>,
+    // Bytes moved within the last `RATE_WINDOW`, oldest first //
+    pub download_samples: VecDeque<(Instant, u64)>,
+    pub upload_samples: VecDeque<(Instant, u64)>,
 }
 
 impl Peer {
     //=== Create a new peer with the given ID and address ===//
     pub fn new(id: PeerId, address: SocketAddr, num_pieces: usize) -> Self {
-        let now = Instant::now();
+        Self::new_at(id, address, num_pieces, Instant::now())
+    }
+    pub fn new_at(id: PeerId, address: SocketAddr, num_pieces: usize, now: Instant) -> Self {
         Self {
             id,
             address,
@@ -69,48 +106,197 @@ impl Peer {
             upload_rate: 0.0,
             last_seen: now,
             last_sent: now,
+            connected_at: now,
             downloaded: 0,
             uploaded: 0,
             pending_requests: HashMap::new(),
             max_requests: 5,
             supports_fast: false,
             supports_extended: false,
+            supports_dht: false,
+            dht_port: None,
+            allowed_fast: HashSet::new(),
+            upload_quota: None,
+            download_quota: None,
+            uploaded_at_reset: 0,
+            downloaded_at_reset: 0,
+            smoothed_latency: None,
+            base_latency: None,
+            last_block_at: None,
+            blocks_this_window: 0,
+            upload_queue: VecDeque::new(),
+            request_window: None,
+            download_samples: VecDeque::new(),
+            upload_samples: VecDeque::new(),
         }
     }
     pub fn can_request(&self) -> bool {
+        self.can_request_more(0)
+    }
+    //=== Whether a request fits once `planned` more are counted as pending ===//
+    pub fn can_request_more(&self, planned: usize) -> bool {
         matches!(self.state, PeerState::Ready)
             && matches!(self.peer_choking, ChokingState::Unchoked)
             && matches!(self.am_interested, InterestState::Interested)
-            && self.pending_requests.len() < self.max_requests
+            && self.requested_pieces().len() + planned < self.max_requests
+            && !self.download_quota_exhausted()
     }
     pub fn can_upload(&self) -> bool {
         matches!(self.state, PeerState::Ready)
             && matches!(self.am_choking, ChokingState::Unchoked)
             && matches!(self.peer_interested, InterestState::Interested)
+            && !self.upload_quota_exhausted()
+    }
+
+    pub fn upload_quota_exhausted(&self) -> bool {
+        self.upload_quota
+            .is_some_and(|quota| self.uploaded - self.uploaded_at_reset >= quota)
     }
-    pub fn add_request(&mut self, piece_index: PieceIndex) {
-        self.pending_requests.insert(piece_index, Instant::now());
+    pub fn download_quota_exhausted(&self) -> bool {
+        self.download_quota
+            .is_some_and(|quota| self.downloaded - self.downloaded_at_reset >= quota)
     }
-    pub fn remove_request(&mut self, piece_index: PieceIndex) {
-        self.pending_requests.remove(&piece_index);
+
+    //=== Start counting both quotas afresh from the current totals ===//
+    pub fn reset_quotas(&mut self) {
+        self.uploaded_at_reset = self.uploaded;
+        self.downloaded_at_reset = self.downloaded;
+    }
+    pub fn add_request(&mut self, piece_index: PieceIndex, offset: BlockOffset) {
+        self.add_request_at(piece_index, offset, Instant::now());
+    }
+    pub fn add_request_at(&mut self, piece_index: PieceIndex, offset: BlockOffset, now: Instant) {
+        self.pending_requests.insert((piece_index, offset), now);
+    }
+
+    //=== Forget every block of a piece, returning the offsets that were still pending ===//
+    pub fn remove_request(&mut self, piece_index: PieceIndex) -> Vec<BlockOffset> {
+        let mut removed: Vec<BlockOffset> = self
+            .pending_requests
+            .keys()
+            .filter(|(piece, _)| *piece == piece_index)
+            .map(|&(_, offset)| offset)
+            .collect();
+        removed.sort_unstable();
+        for offset in &removed {
+            self.pending_requests.remove(&(piece_index, *offset));
+        }
+        removed
+    }
+
+    //=== Forget one block, returning whether it was pending ===//
+    pub fn remove_block_request(&mut self, piece_index: PieceIndex, offset: BlockOffset) -> bool {
+        self.pending_requests
+            .remove(&(piece_index, offset))
+            .is_some()
     }
+
+    //=== Whether any block of the piece is pending ===//
     pub fn has_request(&self, piece_index: PieceIndex) -> bool {
-        self.pending_requests.contains_key(&piece_index)
+        self.pending_requests
+            .keys()
+            .any(|(piece, _)| *piece == piece_index)
+    }
+    pub fn has_block_request(&self, piece_index: PieceIndex, offset: BlockOffset) -> bool {
+        self.pending_requests.contains_key(&(piece_index, offset))
+    }
+
+    //=== Pieces with at least one block pending ===//
+    pub fn requested_pieces(&self) -> HashSet<PieceIndex> {
+        self.pending_requests
+            .keys()
+            .map(|&(piece_index, _)| piece_index)
+            .collect()
     }
+
+    //=== When the piece's oldest pending block was requested ===//
+    pub fn requested_at(&self, piece_index: PieceIndex) -> Option<Instant> {
+        self.pending_requests
+            .iter()
+            .filter(|((piece, _), _)| *piece == piece_index)
+            .map(|(_, &requested_at)| requested_at)
+            .min()
+    }
+
+    //=== Blocks pending, across every piece ===//
     pub fn pending_request_count(&self) -> usize {
         self.pending_requests.len()
     }
 
+    //=== Time a block by the later of its request and the previous block from this peer ===//
+    pub fn block_arrived_at(&mut self, piece_index: PieceIndex, offset: BlockOffset, now: Instant) {
+        let Some(&requested_at) = self.pending_requests.get(&(piece_index, offset)) else {
+            return;
+        };
+        let since = self
+            .last_block_at
+            .map_or(requested_at, |last| last.max(requested_at));
+        self.last_block_at = Some(now);
+        self.record_block_latency(now.saturating_duration_since(since));
+    }
+
+    //=== AIMD: halve the request window when latency climbs, grow it by one when it doesn't ===//
+    pub fn record_block_latency(&mut self, latency: Duration) {
+        let smoothed = self
+            .smoothed_latency
+            .map_or(latency, |smoothed| (smoothed * 7 + latency) / 8);
+        let base = self.base_latency.map_or(latency, |base| base.min(latency));
+        self.smoothed_latency = Some(smoothed);
+        self.base_latency = Some(base);
+
+        self.blocks_this_window += 1;
+        if self.blocks_this_window < self.max_requests {
+            return;
+        }
+        self.blocks_this_window = 0;
+
+        self.max_requests = if smoothed > base.mul_f64(CONGESTION_LATENCY_FACTOR) {
+            (self.max_requests / 2).max(MIN_REQUEST_WINDOW)
+        } else {
+            (self.max_requests + 1).min(MAX_REQUEST_WINDOW)
+        };
+    }
+
     //=== Update download statistics ===//
     pub fn update_download_stats(&mut self, bytes: u64) {
+        self.update_download_stats_at(bytes, Instant::now());
+    }
+    pub fn update_download_stats_at(&mut self, bytes: u64, now: Instant) {
         self.downloaded += bytes;
-        self.last_seen = Instant::now();
+        self.last_seen = now;
+        self.download_samples.push_back((now, bytes));
+        self.recalculate_rates(now);
     }
 
     //=== Update upload statistics ===//
     pub fn update_upload_stats(&mut self, bytes: u64) {
+        self.update_upload_stats_at(bytes, Instant::now());
+    }
+    pub fn update_upload_stats_at(&mut self, bytes: u64, now: Instant) {
         self.uploaded += bytes;
-        self.last_sent = Instant::now();
+        self.last_sent = now;
+        self.upload_samples.push_back((now, bytes));
+        self.recalculate_rates(now);
+    }
+
+    //=== Bytes per second over the last `RATE_WINDOW`; call periodically so idle peers decay ===//
+    pub fn recalculate_rates(&mut self, now: Instant) {
+        //=== A young connection is averaged over its lifetime, but never under a second ===//
+        let span = now
+            .saturating_duration_since(self.connected_at)
+            .clamp(Duration::from_secs(1), RATE_WINDOW)
+            .as_secs_f64();
+        let rate = |samples: &mut VecDeque<(Instant, u64)>| {
+            while samples
+                .front()
+                .is_some_and(|&(at, _)| now.saturating_duration_since(at) >= RATE_WINDOW)
+            {
+                samples.pop_front();
+            }
+            samples.iter().map(|&(_, bytes)| bytes).sum::<u64>() as f64 / span
+        };
+        self.download_rate = rate(&mut self.download_samples);
+        self.upload_rate = rate(&mut self.upload_samples);
     }
 
     //=== Set the peer's bitfield  ===//
@@ -139,14 +325,19 @@ impl Peer {
         interesting
     }
 
-    //=== Update interest state based on available pieces ===//
-    pub fn update_interest(&mut self, our_bitfield: &Bitfield) {
+    //=== Update interest state based on available pieces, returning it if it flipped ===//
+    pub fn update_interest(&mut self, our_bitfield: &Bitfield) -> Option<InterestState> {
         let interesting = self.interesting_pieces(our_bitfield);
-        self.am_interested = if interesting.is_empty() {
+        let interest = if interesting.is_empty() {
             InterestState::NotInterested
         } else {
             InterestState::Interested
         };
+        if interest == self.am_interested {
+            return None;
+        }
+        self.am_interested = interest;
+        Some(interest)
     }
     pub fn is_seeder(&self) -> bool {
         self.bitfield.is_complete()
@@ -157,7 +348,11 @@ impl Peer {
 
     //=== Check if the peer connection is stale ===//
     pub fn is_stale(&self, timeout: std::time::Duration) -> bool {
-        self.last_seen.elapsed() > timeout
+        self.is_stale_at(Instant::now(), timeout)
+    }
+
+    pub fn is_stale_at(&self, now: Instant, timeout: std::time::Duration) -> bool {
+        now.saturating_duration_since(self.last_seen) > timeout
     }
 
     //=== Get the peer's reputation score (simple calculation) ===//
@@ -184,3 +379,97 @@ impl std::hash::Hash for Peer {
         self.id.hash(state);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rising_latency_shrinks_request_window() {
+        let mut peer = Peer::new([1u8; 20], "127.0.0.1:6881".parse().unwrap(), 8);
+        let start = Instant::now();
+        peer.add_request_at(0, 0, start);
+
+        //=== A steady link earns a deeper pipeline ===//
+        let mut now = start;
+        for _ in 0..40 {
+            now += Duration::from_millis(10);
+            peer.block_arrived_at(0, 0, now);
+        }
+        let ramped = peer.max_requests;
+        assert!(ramped > 5);
+
+        //=== Blocks slow down as the queue at the peer builds ===//
+        let mut latency = Duration::from_millis(10);
+        for _ in 0..40 {
+            latency += Duration::from_millis(10);
+            now += latency;
+            peer.block_arrived_at(0, 0, now);
+        }
+        let backed_off = peer.max_requests;
+        assert!(backed_off < ramped / 2);
+        assert!(backed_off >= MIN_REQUEST_WINDOW);
+
+        //=== Once latency falls back the window grows again ===//
+        for _ in 0..80 {
+            now += Duration::from_millis(10);
+            peer.block_arrived_at(0, 0, now);
+        }
+        assert!(peer.max_requests > backed_off);
+    }
+
+    #[test]
+    fn test_rates_average_over_window_and_decay() {
+        let mut peer = Peer::new([1u8; 20], "127.0.0.1:6881".parse().unwrap(), 8);
+        let start = peer.connected_at;
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        //=== 10 KB/s down and 2 KB/s up, one sample a second ===//
+        for second in 1..=30 {
+            peer.update_download_stats_at(10_000, at(second));
+            peer.update_upload_stats_at(2_000, at(second));
+        }
+        assert!(
+            (peer.download_rate - 10_000.0).abs() < 1.0,
+            "{}",
+            peer.download_rate
+        );
+        assert!(
+            (peer.upload_rate - 2_000.0).abs() < 1.0,
+            "{}",
+            peer.upload_rate
+        );
+        assert_eq!(peer.downloaded, 300_000);
+
+        //=== With nothing arriving the rates fall away ===//
+        peer.recalculate_rates(at(40));
+        assert!(
+            (peer.download_rate - 5_000.0).abs() < 1.0,
+            "{}",
+            peer.download_rate
+        );
+        peer.recalculate_rates(at(60));
+        assert_eq!(peer.download_rate, 0.0);
+        assert_eq!(peer.upload_rate, 0.0);
+        assert!(peer.download_samples.is_empty());
+    }
+
+    #[test]
+    fn test_young_connection_rate_uses_its_age() {
+        let mut peer = Peer::new([1u8; 20], "127.0.0.1:6881".parse().unwrap(), 8);
+        let start = peer.connected_at;
+
+        peer.update_download_stats_at(16_384, start + Duration::from_secs(2));
+        peer.update_download_stats_at(16_384, start + Duration::from_secs(4));
+        assert!(
+            (peer.download_rate - 8_192.0).abs() < 1.0,
+            "{}",
+            peer.download_rate
+        );
+
+        //=== Even a block in the first instant is spread over a whole second ===//
+        let mut fresh = Peer::new([2u8; 20], "127.0.0.1:6882".parse().unwrap(), 8);
+        fresh.update_download_stats_at(16_384, fresh.connected_at);
+        assert_eq!(fresh.download_rate, 16_384.0);
+    }
+}
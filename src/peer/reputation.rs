@@ -0,0 +1,164 @@
+use crate::core::Result;
+use crate::file::{load_persisted, persist_atomic};
+use crate::logging::warn;
+use crate::network::PeerInfo;
+use crate::peer::Peer;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::Path;
+
+//=== Peers that sent this many pieces failing the hash check are no longer dialed ===//
+pub const BAN_HASH_FAILURES: u32 = 3;
+
+//=== What we remember about one IP across sessions ===//
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct PeerReputation {
+    pub downloaded: u64,
+    pub uploaded: u64,
+    // Fastest download rate we saw from this IP, in bytes per second //
+    pub best_download_rate: f64,
+    pub hash_failures: u32,
+}
+
+impl PeerReputation {
+    pub fn is_banned(&self) -> bool {
+        self.hash_failures >= BAN_HASH_FAILURES
+    }
+}
+
+//=== Reputation summaries keyed by IP, optionally kept on disk between runs ===//
+#[derive(Debug, Clone, Default)]
+pub struct ReputationStore {
+    entries: HashMap<IpAddr, PeerReputation>,
+}
+
+impl ReputationStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, ip: &IpAddr) -> Option<&PeerReputation> {
+        self.entries.get(ip)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    //=== Fold a peer's transfer totals in, usually as it disconnects ===//
+    pub fn record_peer(&mut self, peer: &Peer) {
+        let entry = self.entries.entry(peer.address.ip()).or_default();
+        entry.downloaded += peer.downloaded;
+        entry.uploaded += peer.uploaded;
+        entry.best_download_rate = entry.best_download_rate.max(peer.download_rate);
+    }
+
+    pub fn record_hash_failure(&mut self, ip: IpAddr) {
+        self.entries.entry(ip).or_default().hash_failures += 1;
+    }
+
+    //=== The ban list: IPs that kept sending bad data ===//
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.entries.get(ip).is_some_and(PeerReputation::is_banned)
+    }
+
+    //=== Drop banned peers and put the historically fast, honest ones first ===//
+    pub fn order_candidates(&self, peers: Vec<PeerInfo>) -> Vec<PeerInfo> {
+        let mut ranked: Vec<(PeerInfo, PeerReputation)> = peers
+            .into_iter()
+            .filter_map(|peer| {
+                let reputation = match peer.ip.parse::<IpAddr>() {
+                    Ok(ip) => self.entries.get(&ip).cloned().unwrap_or_default(),
+                    Err(_) => PeerReputation::default(),
+                };
+                (!reputation.is_banned()).then_some((peer, reputation))
+            })
+            .collect();
+
+        //=== Stable sort, so unknown peers keep the tracker's order ===//
+        ranked.sort_by(|(_, a), (_, b)| {
+            a.hash_failures.cmp(&b.hash_failures).then(
+                b.best_download_rate
+                    .partial_cmp(&a.best_download_rate)
+                    .unwrap_or(Ordering::Equal),
+            )
+        });
+        ranked.into_iter().map(|(peer, _)| peer).collect()
+    }
+
+    //=== Persist the store; a crash mid-write leaves the previous file intact ===//
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let entries: Vec<(&IpAddr, &PeerReputation)> = self.entries.iter().collect();
+        let bytes = serde_json::to_vec(&entries)?;
+        persist_atomic(path, &bytes).await
+    }
+
+    //=== Load a store saved by `save`; a missing or damaged file gives an empty one ===//
+    pub async fn load(path: &Path) -> Self {
+        let entries = load_persisted(path).await.and_then(|bytes| {
+            Ok(serde_json::from_slice::<Vec<(IpAddr, PeerReputation)>>(
+                &bytes,
+            )?)
+        });
+
+        match entries {
+            Ok(entries) => Self {
+                entries: entries.into_iter().collect(),
+            },
+            Err(e) => {
+                warn!("Ignoring peer reputation at {}: {}", path.display(), e);
+                Self::new()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(ip: &str) -> PeerInfo {
+        PeerInfo {
+            peer_id: None,
+            ip: ip.to_string(),
+            port: 6881,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_store_round_trip_dials_known_good_peer_first() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("peers.state");
+
+        let mut good = Peer::new([1u8; 20], "10.0.0.2:6881".parse().unwrap(), 4);
+        good.downloaded = 1 << 20;
+        good.download_rate = 500_000.0;
+        let mut store = ReputationStore::new();
+        store.record_peer(&good);
+        for _ in 0..BAN_HASH_FAILURES {
+            store.record_hash_failure("10.0.0.3".parse().unwrap());
+        }
+        store.save(&path).await.unwrap();
+
+        let restored = ReputationStore::load(&path).await;
+        assert_eq!(restored.len(), 2);
+        assert_eq!(
+            restored.get(&"10.0.0.2".parse().unwrap()),
+            store.get(&"10.0.0.2".parse().unwrap())
+        );
+
+        let order = restored.order_candidates(vec![
+            candidate("10.0.0.1"),
+            candidate("10.0.0.3"),
+            candidate("10.0.0.2"),
+        ]);
+        let ips: Vec<&str> = order.iter().map(|peer| peer.ip.as_str()).collect();
+        assert_eq!(ips, ["10.0.0.2", "10.0.0.1"]);
+    }
+}
@@ -1,8 +1,9 @@
-use crate::core::{Hash, PeerId};
+use crate::core::{Config, Hash, PeerId};
 use bytes::{Buf, BufMut, BytesMut};
 use std::io;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::time::{timeout, Duration};
 
 #[derive(Debug, Clone)]
 pub struct Handshake {
@@ -12,16 +13,67 @@ pub struct Handshake {
     pub peer_id: PeerId,
 }
 
+//=== Reserved-byte capability bits (BEP 10, BEP 6, BEP 5) ===//
+pub const RESERVED_EXTENDED: (usize, u8) = (5, 0x10);
+pub const RESERVED_FAST: (usize, u8) = (7, 0x04);
+pub const RESERVED_DHT: (usize, u8) = (7, 0x01);
+
+//=== How long a peer gets to send its whole handshake unless the config says otherwise ===//
+pub const DEFAULT_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
 impl Handshake {
     pub fn new(info_hash: Hash, peer_id: PeerId) -> Self {
+        Self::with_reserved(info_hash, peer_id, [0; 8])
+    }
+
+    pub fn with_reserved(info_hash: Hash, peer_id: PeerId, reserved: [u8; 8]) -> Self {
         Self {
             protocol_identifier: *b"BitTorrent protocol",
-            reserved: [0; 8],
+            reserved,
             info_hash,
             peer_id,
         }
     }
 
+    //=== Reserved bytes we advertise: the configured override, else our enabled features ===//
+    pub fn reserved_for(config: &Config) -> [u8; 8] {
+        if let Some(reserved) = config.reserved_override {
+            return reserved;
+        }
+
+        let mut reserved = [0; 8];
+        if config.fast_extension {
+            let (byte, mask) = RESERVED_FAST;
+            reserved[byte] |= mask;
+        }
+        if config.dht_port.is_some() {
+            let (byte, mask) = RESERVED_DHT;
+            reserved[byte] |= mask;
+        }
+        reserved
+    }
+
+    fn has_reserved_bit(&self, (byte, mask): (usize, u8)) -> bool {
+        self.reserved[byte] & mask != 0
+    }
+
+    pub fn supports_extended(&self) -> bool {
+        self.has_reserved_bit(RESERVED_EXTENDED)
+    }
+
+    pub fn supports_fast(&self) -> bool {
+        self.has_reserved_bit(RESERVED_FAST)
+    }
+
+    pub fn supports_dht(&self) -> bool {
+        self.has_reserved_bit(RESERVED_DHT)
+    }
+
+    //=== An all-zero id is legal but collides with every other peer sending one ===//
+    pub fn has_zero_peer_id(&self) -> bool {
+        self.peer_id == [0; 20]
+    }
+
     //=== Serialize handshake to bytes ===//
     pub fn serialize(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
@@ -90,14 +142,37 @@ impl Handshake {
     }
 }
 
-//=== Handshake  for managing peer handshakes ===//
-pub struct HandshakeHandler {
-    stream: TcpStream,
+//=== Handshake  for managing peer handshakes, over TCP or any other byte stream ===//
+pub struct HandshakeHandler<S = TcpStream> {
+    stream: S,
+    reserved: [u8; 8],
+    reject_zero_peer_id: bool,
+    // Bounds each receive, so a peer that stalls mid-handshake can't hang us //
+    timeout: Duration,
 }
 
-impl HandshakeHandler {
-    pub fn new(stream: TcpStream) -> Self {
-        Self { stream }
+impl<S: AsyncRead + AsyncWrite + Unpin> HandshakeHandler<S> {
+    pub fn new(stream: S) -> Self {
+        Self::with_reserved(stream, [0; 8])
+    }
+
+    //=== Handler that advertises the given reserved bytes ===//
+    pub fn with_reserved(stream: S, reserved: [u8; 8]) -> Self {
+        Self {
+            stream,
+            reserved,
+            reject_zero_peer_id: false,
+            timeout: DEFAULT_HANDSHAKE_TIMEOUT,
+        }
+    }
+
+    //=== Handler advertising our features and checking peer ids as configured ===//
+    pub fn for_config(stream: S, config: &Config) -> Self {
+        Self {
+            reject_zero_peer_id: config.reject_zero_peer_id,
+            timeout: config.connection_timeout,
+            ..Self::with_reserved(stream, Handshake::reserved_for(config))
+        }
     }
 
     //==== Send a handshake to the peer ====//
@@ -110,8 +185,18 @@ impl HandshakeHandler {
 
     pub async fn receive_handshake(&mut self) -> io::Result<Handshake> {
         let mut buffer = [0u8; 68];
-        self.stream.read_exact(&mut buffer).await?;
-        Handshake::deserialize(&buffer)
+        timeout(self.timeout, self.stream.read_exact(&mut buffer))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Handshake timed out"))??;
+        let handshake = Handshake::deserialize(&buffer)?;
+
+        if self.reject_zero_peer_id && handshake.has_zero_peer_id() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "All-zero peer id in handshake",
+            ));
+        }
+        Ok(handshake)
     }
 
     //==== Perform a complete handshake  ====//
@@ -120,7 +205,7 @@ impl HandshakeHandler {
         info_hash: Hash,
         peer_id: PeerId,
     ) -> io::Result<(Handshake, Handshake)> {
-        let our_handshake = Handshake::new(info_hash, peer_id);
+        let our_handshake = Handshake::with_reserved(info_hash, peer_id, self.reserved);
         self.send_handshake(&our_handshake).await?;
         let their_handshake = self.receive_handshake().await?;
 
@@ -134,8 +219,8 @@ impl HandshakeHandler {
         Ok((our_handshake, their_handshake))
     }
 
-    //=== Get the TCP stream ===//
-    pub fn into_stream(self) -> TcpStream {
+    //=== Get the underlying stream ===//
+    pub fn into_stream(self) -> S {
         self.stream
     }
 }
@@ -170,4 +255,119 @@ mod tests {
         let serialized = handshake.serialize();
         assert_eq!(serialized.len(), 68);
     }
+
+    #[test]
+    fn test_reserved_override_in_serialized_handshake() {
+        let override_bytes = [0, 0, 0, 0, 0, 0x10, 0, 0x05];
+        let config = Config {
+            reserved_override: Some(override_bytes),
+            ..Config::default()
+        };
+
+        let reserved = Handshake::reserved_for(&config);
+        let handshake = Handshake::with_reserved([1u8; 20], [2u8; 20], reserved);
+        let serialized = handshake.serialize();
+        assert_eq!(&serialized[20..28], &override_bytes);
+
+        let parsed = Handshake::deserialize(&serialized).unwrap();
+        assert!(parsed.supports_extended());
+        assert!(parsed.supports_fast());
+        assert!(parsed.supports_dht());
+
+        //=== Without an override nothing is advertised ===//
+        assert_eq!(Handshake::reserved_for(&Config::default()), [0; 8]);
+
+        let fast = Config {
+            fast_extension: true,
+            ..Config::default()
+        };
+        assert_eq!(Handshake::reserved_for(&fast), [0, 0, 0, 0, 0, 0, 0, 0x04]);
+    }
+
+    #[tokio::test]
+    async fn test_handler_sends_configured_reserved_bytes() {
+        let override_bytes = [0xff, 0, 0, 0, 0, 0, 0, 0x01];
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut handler = HandshakeHandler::with_reserved(stream, override_bytes);
+            handler
+                .perform_handshake([1u8; 20], [2u8; 20])
+                .await
+                .unwrap();
+        });
+
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut handler = HandshakeHandler::new(stream);
+        let theirs = handler.receive_handshake().await.unwrap();
+        assert_eq!(theirs.reserved, override_bytes);
+        handler
+            .send_handshake(&Handshake::new([1u8; 20], [3u8; 20]))
+            .await
+            .unwrap();
+
+        client.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stalled_partial_handshake_times_out() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        //=== The peer sends one byte of its handshake and then goes quiet ===//
+        let peer = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(&[19]).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(5)).await;
+        });
+
+        let config = Config {
+            connection_timeout: Duration::from_millis(100),
+            ..Config::default()
+        };
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut handler = HandshakeHandler::for_config(stream, &config);
+        let started = std::time::Instant::now();
+        let result = handler.perform_handshake([1u8; 20], [2u8; 20]).await;
+
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        peer.abort();
+    }
+
+    #[tokio::test]
+    async fn test_zero_peer_id_rejected_in_strict_mode() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let strict = Config {
+            reject_zero_peer_id: true,
+            ..Config::default()
+        };
+
+        for (config, accepted) in [(Config::default(), true), (strict, false)] {
+            let client = tokio::spawn(async move {
+                let stream = TcpStream::connect(addr).await.unwrap();
+                let mut handler = HandshakeHandler::new(stream);
+                handler
+                    .send_handshake(&Handshake::new([1u8; 20], [0u8; 20]))
+                    .await
+                    .unwrap();
+            });
+
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut handler = HandshakeHandler::for_config(stream, &config);
+            let received = handler.receive_handshake().await;
+            assert_eq!(received.is_ok(), accepted);
+            if let Err(e) = received {
+                assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+            }
+            client.await.unwrap();
+        }
+
+        //=== Ids we generate carry our prefix, so they are never all zeros ===//
+        let ours = Handshake::new([1u8; 20], crate::core::generate_peer_id());
+        assert!(!ours.has_zero_peer_id());
+    }
 }
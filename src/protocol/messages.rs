@@ -1,4 +1,4 @@
-use crate::core::{BlockLength, BlockOffset, PieceIndex};
+use crate::core::{BlockLength, BlockOffset, PieceIndex, ProtocolError, TorrentInfo, BLOCK_SIZE};
 use crate::protocol::{Message, MessageType};
 use bytes::{Buf, BufMut, BytesMut};
 use std::io;
@@ -11,6 +11,8 @@ pub trait MessageParser {
     fn parse_piece(&self) -> io::Result<(PieceIndex, BlockOffset, Vec<u8>)>;
     fn parse_cancel(&self) -> io::Result<(PieceIndex, BlockOffset, BlockLength)>;
     fn parse_port(&self) -> io::Result<u16>;
+    fn parse_reject_request(&self) -> io::Result<(PieceIndex, BlockOffset, BlockLength)>;
+    fn parse_allowed_fast(&self) -> io::Result<PieceIndex>;
 }
 
 impl MessageParser for Message {
@@ -131,6 +133,48 @@ impl MessageParser for Message {
         let mut buffer = BytesMut::from(&self.payload[..]);
         Ok(buffer.get_u16())
     }
+
+    fn parse_reject_request(&self) -> io::Result<(PieceIndex, BlockOffset, BlockLength)> {
+        if self.message_type != MessageType::RejectRequest {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a reject request message",
+            ));
+        }
+
+        if self.payload.len() != 12 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid reject request message payload length",
+            ));
+        }
+
+        let mut buffer = BytesMut::from(&self.payload[..]);
+        let piece_index = buffer.get_u32();
+        let offset = buffer.get_u32();
+        let length = buffer.get_u32();
+
+        Ok((piece_index, offset, length))
+    }
+
+    fn parse_allowed_fast(&self) -> io::Result<PieceIndex> {
+        if self.message_type != MessageType::AllowedFast {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not an allowed fast message",
+            ));
+        }
+
+        if self.payload.len() != 4 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid allowed fast message payload length",
+            ));
+        }
+
+        let mut buffer = BytesMut::from(&self.payload[..]);
+        Ok(buffer.get_u32())
+    }
 }
 
 //=== Message builder utilities ===//
@@ -176,6 +220,8 @@ pub trait MessageValidator {
     fn is_valid(&self) -> bool;
     fn validate_request(&self, max_piece_size: u32) -> bool;
     fn validate_piece(&self, max_piece_size: u32) -> bool;
+    fn validate_piece_index(&self, num_pieces: usize) -> Result<(), ProtocolError>;
+    fn validate_request_for(&self, torrent: &TorrentInfo) -> Result<(), ProtocolError>;
 }
 
 impl MessageValidator for Message {
@@ -184,10 +230,14 @@ impl MessageValidator for Message {
             MessageType::Choke
             | MessageType::Unchoke
             | MessageType::Interested
-            | MessageType::NotInterested => self.payload.is_empty(),
-            MessageType::Have => self.payload.len() == 4,
+            | MessageType::NotInterested
+            | MessageType::HaveAll
+            | MessageType::HaveNone => self.payload.is_empty(),
+            MessageType::Have | MessageType::AllowedFast => self.payload.len() == 4,
             MessageType::Bitfield => !self.payload.is_empty(),
-            MessageType::Request | MessageType::Cancel => self.payload.len() == 12,
+            MessageType::Request | MessageType::Cancel | MessageType::RejectRequest => {
+                self.payload.len() == 12
+            }
             MessageType::Piece => self.payload.len() >= 8,
             MessageType::Port => self.payload.len() == 2,
             MessageType::KeepAlive => self.payload.is_empty(),
@@ -200,7 +250,11 @@ impl MessageValidator for Message {
         }
 
         if let Ok((_piece_index, offset, length)) = self.parse_request() {
-            offset + length <= max_piece_size && length > 0 && length <= 16384
+            offset
+                .checked_add(length)
+                .is_some_and(|end| end <= max_piece_size)
+                && length > 0
+                && length <= BLOCK_SIZE
         } else {
             false
         }
@@ -212,11 +266,58 @@ impl MessageValidator for Message {
         }
 
         if let Ok((_piece_index, offset, data)) = self.parse_piece() {
-            offset + data.len() as u32 <= max_piece_size
+            u32::try_from(data.len())
+                .ok()
+                .and_then(|len| offset.checked_add(len))
+                .is_some_and(|end| end <= max_piece_size)
         } else {
             false
         }
     }
+
+    //=== Reject piece indices outside the torrent before they reach any bitfield ===//
+    fn validate_piece_index(&self, num_pieces: usize) -> Result<(), ProtocolError> {
+        match self.message_type {
+            MessageType::Have
+            | MessageType::Request
+            | MessageType::Piece
+            | MessageType::Cancel
+            | MessageType::RejectRequest
+            | MessageType::AllowedFast => {
+                if self.payload.len() < 4 {
+                    return Ok(());
+                }
+
+                let mut buffer = BytesMut::from(&self.payload[..4]);
+                let index = buffer.get_u32();
+                if (index as usize) < num_pieces {
+                    Ok(())
+                } else {
+                    Err(ProtocolError::InvalidPieceIndex { index })
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    //=== Check a request against the actual size of the piece it names ===//
+    fn validate_request_for(&self, torrent: &TorrentInfo) -> Result<(), ProtocolError> {
+        let (piece_index, offset, length) = self
+            .parse_request()
+            .map_err(|_| ProtocolError::InvalidBlockRequest)?;
+
+        if !torrent.is_valid_piece_index(piece_index) {
+            return Err(ProtocolError::InvalidPieceIndex { index: piece_index });
+        }
+        if length == 0 || length > BLOCK_SIZE {
+            return Err(ProtocolError::InvalidBlockRequest);
+        }
+
+        match offset.checked_add(length) {
+            Some(end) if end <= torrent.piece_size(piece_index) => Ok(()),
+            _ => Err(ProtocolError::InvalidBlockRequest),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -249,6 +350,31 @@ mod tests {
         assert_eq!(received_data, data);
     }
 
+    #[test]
+    fn test_fast_extension_message_parsing() {
+        let reject = Message::reject_request(2, BLOCK_SIZE, BLOCK_SIZE);
+        let reject = Message::deserialize(&reject.serialize()).unwrap();
+        assert_eq!(reject.message_type, MessageType::RejectRequest);
+        assert_eq!(
+            reject.parse_reject_request().unwrap(),
+            (2, BLOCK_SIZE, BLOCK_SIZE)
+        );
+
+        for message in [Message::have_all(), Message::have_none()] {
+            let parsed = Message::try_from_frame(&message.serialize()).unwrap();
+            assert_eq!(parsed.message_type, message.message_type);
+        }
+        assert!(!Message::new(MessageType::HaveAll, vec![0]).is_valid());
+
+        let allowed = Message::deserialize(&Message::allowed_fast(7).serialize()).unwrap();
+        assert_eq!(allowed.message_type, MessageType::AllowedFast);
+        assert_eq!(allowed.parse_allowed_fast().unwrap(), 7);
+        assert!(matches!(
+            Message::allowed_fast(10).validate_piece_index(10),
+            Err(ProtocolError::InvalidPieceIndex { index: 10 })
+        ));
+    }
+
     #[test]
     fn test_message_validation() {
         let valid_message = Message::have(123);
@@ -266,4 +392,84 @@ mod tests {
         let invalid_request = Message::request(1, 0, 0); // Zero length
         assert!(!invalid_request.validate_request(65536));
     }
+
+    fn two_and_a_half_pieces() -> TorrentInfo {
+        TorrentInfo::new(
+            "test".to_string(),
+            2 * BLOCK_SIZE,
+            vec![[0u8; 20]; 3],
+            vec![crate::core::FileInfo::new(
+                vec!["test".to_string()],
+                5 * BLOCK_SIZE as u64,
+            )],
+        )
+    }
+
+    #[test]
+    fn test_request_bounded_by_short_last_piece() {
+        let torrent = two_and_a_half_pieces();
+
+        assert!(Message::request(1, BLOCK_SIZE, BLOCK_SIZE)
+            .validate_request_for(&torrent)
+            .is_ok());
+        assert!(Message::request(2, 0, BLOCK_SIZE)
+            .validate_request_for(&torrent)
+            .is_ok());
+
+        //=== The last piece holds one block only ===//
+        assert!(matches!(
+            Message::request(2, BLOCK_SIZE, BLOCK_SIZE).validate_request_for(&torrent),
+            Err(ProtocolError::InvalidBlockRequest)
+        ));
+        assert!(matches!(
+            Message::request(3, 0, BLOCK_SIZE).validate_request_for(&torrent),
+            Err(ProtocolError::InvalidPieceIndex { index: 3 })
+        ));
+        assert!(Message::request(0, 0, 0)
+            .validate_request_for(&torrent)
+            .is_err());
+        assert!(Message::request(0, 0, BLOCK_SIZE + 1)
+            .validate_request_for(&torrent)
+            .is_err());
+    }
+
+    #[test]
+    fn test_overflowing_request_rejected() {
+        let torrent = two_and_a_half_pieces();
+        let request = Message::request(0, u32::MAX - 10, BLOCK_SIZE);
+
+        assert!(request.validate_request_for(&torrent).is_err());
+        assert!(!request.validate_request(u32::MAX));
+    }
+
+    #[test]
+    fn test_piece_index_validation() {
+        assert!(Message::have(9).validate_piece_index(10).is_ok());
+        assert!(Message::request(0, 0, 16384)
+            .validate_piece_index(10)
+            .is_ok());
+        assert!(Message::interested().validate_piece_index(0).is_ok());
+    }
+
+    #[test]
+    fn test_out_of_range_piece_indices_rejected() {
+        let num_pieces = 10;
+
+        let messages = vec![
+            (Message::have(u32::MAX), u32::MAX),
+            (Message::have(10), 10),
+            (Message::request(u32::MAX, 0, 16384), u32::MAX),
+            (Message::piece(10, 0, vec![1, 2, 3]), 10),
+            (Message::cancel(42, 0, 16384), 42),
+        ];
+
+        for (message, index) in messages {
+            match message.validate_piece_index(num_pieces) {
+                Err(ProtocolError::InvalidPieceIndex { index: rejected }) => {
+                    assert_eq!(rejected, index)
+                }
+                other => panic!("expected InvalidPieceIndex, got {:?}", other),
+            }
+        }
+    }
 }
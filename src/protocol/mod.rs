@@ -1,4 +1,4 @@
-use crate::core::{BlockLength, BlockOffset, PieceIndex};
+use crate::core::{BlockLength, BlockOffset, PieceIndex, ProtocolError};
 use bytes::{Buf, BufMut, BytesMut};
 use std::io;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -13,6 +13,8 @@ pub use messages::*;
 //==== protocol constants ====//
 pub const PROTOCOL_IDENTIFIER: &[u8] = b"BitTorrent protocol";
 pub const PROTOCOL_VERSION: u8 = 1;
+//==== Longest message body accepted; fits a block or the bitfield of 16M pieces ====//
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 21;
 
 //==== Protocol message types ===//
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -27,6 +29,11 @@ pub enum MessageType {
     Piece = 7,
     Cancel = 8,
     Port = 9,
+    // Fast extension (BEP 6) //
+    HaveAll = 14,
+    HaveNone = 15,
+    RejectRequest = 16,
+    AllowedFast = 17,
     KeepAlive = 255,
 }
 
@@ -43,6 +50,10 @@ impl From<u8> for MessageType {
             7 => MessageType::Piece,
             8 => MessageType::Cancel,
             9 => MessageType::Port,
+            14 => MessageType::HaveAll,
+            15 => MessageType::HaveNone,
+            16 => MessageType::RejectRequest,
+            17 => MessageType::AllowedFast,
             _ => MessageType::KeepAlive,
         }
     }
@@ -157,6 +168,46 @@ impl Message {
         }
     }
 
+    //=== Fast-extension stand-ins for a full or empty bitfield ===//
+    pub fn have_all() -> Self {
+        Self {
+            message_type: MessageType::HaveAll,
+            payload: Vec::new(),
+        }
+    }
+
+    pub fn have_none() -> Self {
+        Self {
+            message_type: MessageType::HaveNone,
+            payload: Vec::new(),
+        }
+    }
+
+    //=== Tell a fast-extension peer we won't serve its request ===//
+    pub fn reject_request(
+        piece_index: PieceIndex,
+        offset: BlockOffset,
+        length: BlockLength,
+    ) -> Self {
+        let mut payload = Vec::new();
+        payload.put_u32(piece_index);
+        payload.put_u32(offset);
+        payload.put_u32(length);
+        Self {
+            message_type: MessageType::RejectRequest,
+            payload,
+        }
+    }
+
+    pub fn allowed_fast(piece_index: PieceIndex) -> Self {
+        let mut payload = Vec::new();
+        payload.put_u32(piece_index);
+        Self {
+            message_type: MessageType::AllowedFast,
+            payload,
+        }
+    }
+
     //=== Serialize message to bytes  ===//
     pub fn serialize(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
@@ -173,22 +224,21 @@ impl Message {
 
     /// Deserialize message from bytes
     pub fn deserialize(data: &[u8]) -> io::Result<Self> {
-        if data.len() < 5 {
+        if data.len() < 4 {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Message too short",
             ));
         }
 
-        let mut buffer = BytesMut::from(data);
-
-        let message_length = buffer.get_u32() as usize;
+        let message_length = u32::from_be_bytes([data[0], data[1], data[2], data[3]]) as usize;
 
         if message_length == 0 {
             return Ok(Message::keep_alive());
         }
 
-        if data.len() < 5 + message_length - 1 {
+        //=== Compare against what's left rather than adding, so huge lengths can't overflow ===//
+        if data.len() - 4 < message_length {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Incomplete message",
@@ -196,15 +246,56 @@ impl Message {
         }
 
         //=== Read message type, payload ===//
-        let message_type = MessageType::from(buffer.get_u8());
+        let message_type = MessageType::from(data[4]);
 
-        let payload = buffer[..message_length - 1].to_vec();
+        let payload = data[5..4 + message_length].to_vec();
 
         Ok(Message {
             message_type,
             payload,
         })
     }
+
+    //=== Strict parse of exactly one frame; any input gives a message or an error ===//
+    pub fn try_from_frame(frame: &[u8]) -> Result<Self, ProtocolError> {
+        let Some((prefix, body)) = frame.split_first_chunk::<4>() else {
+            return Err(ProtocolError::FrameLengthMismatch {
+                declared: 4,
+                actual: frame.len(),
+            });
+        };
+
+        let declared = u32::from_be_bytes(*prefix) as usize;
+        if declared > MAX_MESSAGE_LENGTH {
+            return Err(ProtocolError::MessageTooLarge { size: declared });
+        }
+        if body.len() != declared {
+            return Err(ProtocolError::FrameLengthMismatch {
+                declared,
+                actual: body.len(),
+            });
+        }
+
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(Message::keep_alive());
+        };
+
+        //=== Unknown ids fall back to KeepAlive in `From<u8>`; on the wire that's an error ===//
+        let message_type = MessageType::from(id);
+        if message_type == MessageType::KeepAlive {
+            return Err(ProtocolError::InvalidMessageType { message_type: id });
+        }
+
+        let message = Message::new(message_type, payload.to_vec());
+        if !message.is_valid() {
+            return Err(ProtocolError::InvalidPayload {
+                message_type: id,
+                length: payload.len(),
+            });
+        }
+
+        Ok(message)
+    }
 }
 
 //==== Protocol handler for peer connections ====//
@@ -267,6 +358,16 @@ impl ProtocolHandler {
             return Ok(Some(Message::keep_alive()));
         }
 
+        //=== Refuse before buffering, or a hostile length prefix pins gigabytes ===//
+        if message_length > MAX_MESSAGE_LENGTH {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                ProtocolError::MessageTooLarge {
+                    size: message_length,
+                },
+            ));
+        }
+
         let total_length = 4 + message_length;
         if self.buffer.len() < total_length {
             return Ok(None);
@@ -316,6 +417,54 @@ mod tests {
         assert_eq!(message.payload, vec![] as Vec<u8>);
     }
 
+    #[test]
+    fn test_bare_keep_alive_frame() {
+        let message = Message::deserialize(&Message::serialize_keep_alive()).unwrap();
+        assert_eq!(message.message_type, MessageType::KeepAlive);
+
+        let message = Message::try_from_frame(&Message::serialize_keep_alive()).unwrap();
+        assert_eq!(message.message_type, MessageType::KeepAlive);
+    }
+
+    proptest::proptest! {
+        #[test]
+        fn test_try_from_frame_never_panics(bytes in proptest::collection::vec(0u8.., 0..64)) {
+            //=== Both parsers must survive arbitrary input ===//
+            let _ = Message::deserialize(&bytes);
+            if let Ok(message) = Message::try_from_frame(&bytes) {
+                proptest::prop_assert!(message.is_valid());
+                if message.message_type != MessageType::KeepAlive {
+                    proptest::prop_assert_eq!(message.serialize(), bytes);
+                }
+            }
+        }
+
+        #[test]
+        fn test_try_from_frame_round_trips(
+            id in proptest::sample::select(vec![0u8, 1, 2, 3, 4, 5, 6, 7, 8, 9, 14, 15, 16, 17]),
+            payload in proptest::collection::vec(0u8.., 0..32),
+        ) {
+            let message = Message::new(MessageType::from(id), payload);
+            match Message::try_from_frame(&message.serialize()) {
+                Ok(parsed) => {
+                    proptest::prop_assert_eq!(parsed.message_type, message.message_type);
+                    proptest::prop_assert_eq!(parsed.payload, message.payload);
+                }
+                Err(_) => proptest::prop_assert!(!message.is_valid()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_oversized_length_prefix_is_rejected() {
+        let frame = [0xff, 0xff, 0xff, 0xff, 7];
+        assert!(matches!(
+            Message::try_from_frame(&frame),
+            Err(ProtocolError::MessageTooLarge { .. })
+        ));
+        assert!(Message::deserialize(&frame).is_err());
+    }
+
     #[test]
     fn test_request_message() {
         let message = Message::request(1, 1024, 16384);
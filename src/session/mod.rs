@@ -0,0 +1,11 @@
+//=== Per-torrent sessions tying storage, peers and trackers together ===//
+
+pub mod pipeline;
+pub mod snapshot;
+pub mod strategy;
+pub mod torrent;
+
+pub use pipeline::*;
+pub use snapshot::*;
+pub use strategy::*;
+pub use torrent::*;
@@ -0,0 +1,615 @@
+use crate::core::{BlockOffset, Hash, PeerId, PieceIndex, Result, Statistics, TorrentInfo};
+use crate::file::PartialPiece;
+use crate::logging::{debug, error, warn};
+use crate::network::{PeerSenders, SharedFileManager, SharedPeerManager};
+use crate::protocol::Message;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+//=== Pieces a stage may queue for the next one before it has to wait ===//
+pub const PIPELINE_PIECE_QUEUE: usize = 8;
+//=== Blocks the receive path may queue before the accumulator catches up ===//
+pub const PIPELINE_BLOCK_QUEUE: usize = 256;
+
+//=== A block as it came off the wire ===//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceivedBlock {
+    pub peer_id: PeerId,
+    pub piece_index: PieceIndex,
+    pub offset: BlockOffset,
+    pub data: Vec<u8>,
+}
+
+//=== Every block of a piece in order, and the peers that sent them ===//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssembledPiece {
+    pub piece_index: PieceIndex,
+    pub data: Vec<u8>,
+    pub sources: Vec<PeerId>,
+}
+
+//=== What the pipeline did with a finished piece ===//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PieceEvent {
+    // Verified, on disk, in our bitfield and announced to peers //
+    Stored {
+        piece_index: PieceIndex,
+        length: u64,
+    },
+    // The hash did not match; every source may have sent bad data //
+    Failed {
+        piece_index: PieceIndex,
+        length: u64,
+        sources: Vec<PeerId>,
+    },
+    // Verified but could not be written; the piece will be fetched again //
+    FlushFailed {
+        piece_index: PieceIndex,
+        error: String,
+    },
+}
+
+impl PieceEvent {
+    pub fn piece_index(&self) -> PieceIndex {
+        match self {
+            PieceEvent::Stored { piece_index, .. }
+            | PieceEvent::Failed { piece_index, .. }
+            | PieceEvent::FlushFailed { piece_index, .. } => *piece_index,
+        }
+    }
+
+    //=== Stats stage: fold the outcome into the torrent's counters ===//
+    pub fn apply(&self, statistics: &mut Statistics) {
+        match self {
+            PieceEvent::Stored {
+                piece_index,
+                length,
+            } => statistics.piece_verified(*piece_index, *length),
+            PieceEvent::Failed { length, .. } => statistics.piece_failed(*length),
+            PieceEvent::FlushFailed { .. } => {}
+        }
+    }
+}
+
+//=== Blocks gathered so far for one piece ===//
+#[derive(Debug)]
+struct PieceBuffer {
+    piece: PartialPiece,
+    sources: Vec<PeerId>,
+}
+
+//=== Accumulate stage: gathers blocks until a piece is whole ===//
+#[derive(Debug)]
+pub struct BlockAccumulator {
+    total_size: u64,
+    piece_length: u32,
+    num_pieces: usize,
+    buffers: HashMap<PieceIndex, PieceBuffer>,
+}
+
+impl BlockAccumulator {
+    pub fn new(torrent_info: &TorrentInfo) -> Self {
+        Self {
+            total_size: torrent_info.total_size(),
+            piece_length: torrent_info.piece_length,
+            num_pieces: torrent_info.num_pieces(),
+            buffers: HashMap::new(),
+        }
+    }
+
+    //=== Add a block, returning the piece once its last byte is in; strays are dropped ===//
+    pub fn add_block(&mut self, block: ReceivedBlock) -> Option<AssembledPiece> {
+        if block.piece_index as usize >= self.num_pieces {
+            return None;
+        }
+        let piece_size =
+            TorrentInfo::piece_size_of(self.total_size, self.piece_length, block.piece_index);
+
+        let buffer = self
+            .buffers
+            .entry(block.piece_index)
+            .or_insert_with(|| PieceBuffer {
+                piece: PartialPiece::new(piece_size),
+                sources: Vec::new(),
+            });
+        //=== Only whole blocks at block boundaries, as we request them ===//
+        match buffer.piece.add_block(block.offset, &block.data) {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(_) => {
+                debug!(
+                    "Dropping misshapen block for piece {} at {} ({} bytes)",
+                    block.piece_index,
+                    block.offset,
+                    block.data.len()
+                );
+                if buffer.piece.is_empty() {
+                    self.buffers.remove(&block.piece_index);
+                }
+                return None;
+            }
+        }
+        if !buffer.sources.contains(&block.peer_id) {
+            buffer.sources.push(block.peer_id);
+        }
+        if !buffer.piece.is_complete() {
+            return None;
+        }
+
+        let buffer = self.buffers.remove(&block.piece_index)?;
+        Some(AssembledPiece {
+            piece_index: block.piece_index,
+            data: buffer.piece.into_data(),
+            sources: buffer.sources,
+        })
+    }
+
+    //=== Forget a partly received piece, e.g. after its requests were cancelled ===//
+    pub fn discard(&mut self, piece_index: PieceIndex) {
+        self.buffers.remove(&piece_index);
+    }
+
+    //=== Bytes held for pieces still being assembled ===//
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffers
+            .values()
+            .map(|buffer| buffer.piece.size())
+            .sum()
+    }
+}
+
+//=== Verify stage: hash on the blocking pool; a mismatch hands the piece back as `Err` ===//
+pub async fn verify_piece(
+    piece: AssembledPiece,
+    expected: Hash,
+) -> std::result::Result<AssembledPiece, AssembledPiece> {
+    let hashed = tokio::task::spawn_blocking(move || {
+        use sha1::{Digest, Sha1};
+        let matches = Sha1::digest(&piece.data)[..] == expected;
+        (piece, matches)
+    })
+    .await
+    .expect("hashing a piece does not panic");
+
+    match hashed {
+        (piece, true) => Ok(piece),
+        (piece, false) => Err(piece),
+    }
+}
+
+//=== Flush stage: write a verified piece to its place on disk ===//
+pub async fn flush_piece(storage: &SharedFileManager, piece: &AssembledPiece) -> Result<()> {
+    storage
+        .read()
+        .await
+        .write_piece(piece.piece_index, &piece.data)
+        .await
+}
+
+//=== Mark stage: the piece joins our bitfield, in storage and towards peers ===//
+pub async fn mark_piece(
+    storage: &SharedFileManager,
+    peer_manager: &SharedPeerManager,
+    piece: AssembledPiece,
+) -> Result<()> {
+    storage
+        .write()
+        .await
+        .piece_manager_mut()
+        .store_verified_piece(piece.piece_index, piece.data)?;
+    peer_manager
+        .write()
+        .await
+        .completed_piece(piece.piece_index);
+    Ok(())
+}
+
+//=== Have stage: tell every connected peer of the torrent, returning how many were told ===//
+pub async fn broadcast_have(
+    info_hash: &Hash,
+    peer_senders: &PeerSenders,
+    piece_index: PieceIndex,
+) -> usize {
+    peer_senders
+        .read()
+        .await
+        .iter()
+        .filter(|((torrent, _), _)| torrent == info_hash)
+        .filter(|(_, sender)| sender.send(Message::have(piece_index)).is_ok())
+        .count()
+}
+
+//=== The stages of one torrent, each its own task, linked by bounded channels ===//
+pub struct PiecePipeline {
+    blocks: mpsc::Sender<ReceivedBlock>,
+    tasks: Vec<JoinHandle<()>>,
+}
+
+impl PiecePipeline {
+    //=== Start the stages; outcomes arrive on the returned receiver for the stats stage ===//
+    pub fn spawn(
+        info_hash: Hash,
+        torrent_info: TorrentInfo,
+        storage: SharedFileManager,
+        peer_manager: SharedPeerManager,
+        peer_senders: PeerSenders,
+    ) -> (Self, mpsc::UnboundedReceiver<PieceEvent>) {
+        let (blocks_tx, mut blocks_rx) = mpsc::channel::<ReceivedBlock>(PIPELINE_BLOCK_QUEUE);
+        let (assembled_tx, mut assembled_rx) =
+            mpsc::channel::<AssembledPiece>(PIPELINE_PIECE_QUEUE);
+        let (verified_tx, mut verified_rx) = mpsc::channel::<AssembledPiece>(PIPELINE_PIECE_QUEUE);
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
+
+        let mut accumulator = BlockAccumulator::new(&torrent_info);
+        let accumulate = tokio::spawn(async move {
+            while let Some(block) = blocks_rx.recv().await {
+                if let Some(piece) = accumulator.add_block(block) {
+                    if assembled_tx.send(piece).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let hashes = torrent_info.pieces.clone();
+        let verify_events = events_tx.clone();
+        let verify = tokio::spawn(async move {
+            while let Some(piece) = assembled_rx.recv().await {
+                let expected = hashes[piece.piece_index as usize];
+                match verify_piece(piece, expected).await {
+                    Ok(piece) => {
+                        if verified_tx.send(piece).await.is_err() {
+                            break;
+                        }
+                    }
+                    Err(piece) => {
+                        warn!(
+                            "Piece {} failed verification, supplied by {} peer(s)",
+                            piece.piece_index,
+                            piece.sources.len()
+                        );
+                        let _ = verify_events.send(PieceEvent::Failed {
+                            piece_index: piece.piece_index,
+                            length: piece.data.len() as u64,
+                            sources: piece.sources,
+                        });
+                    }
+                }
+            }
+        });
+
+        //=== Disk work lives here, so a slow disk only backs up the verified queue ===//
+        let store = tokio::spawn(async move {
+            while let Some(piece) = verified_rx.recv().await {
+                let piece_index = piece.piece_index;
+                let length = piece.data.len() as u64;
+                let stored = match flush_piece(&storage, &piece).await {
+                    Ok(()) => mark_piece(&storage, &peer_manager, piece).await,
+                    Err(e) => Err(e),
+                };
+                let event = match stored {
+                    Ok(()) => {
+                        let told = broadcast_have(&info_hash, &peer_senders, piece_index).await;
+                        debug!("Stored piece {}, told {} peer(s)", piece_index, told);
+                        PieceEvent::Stored {
+                            piece_index,
+                            length,
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to store piece {}: {}", piece_index, e);
+                        PieceEvent::FlushFailed {
+                            piece_index,
+                            error: e.to_string(),
+                        }
+                    }
+                };
+                let _ = events_tx.send(event);
+            }
+        });
+
+        let pipeline = Self {
+            blocks: blocks_tx,
+            tasks: vec![accumulate, verify, store],
+        };
+        (pipeline, events_rx)
+    }
+
+    //=== Where the receive path drops blocks into the pipeline ===//
+    pub fn sender(&self) -> mpsc::Sender<ReceivedBlock> {
+        self.blocks.clone()
+    }
+
+    //=== Wait for everything queued to go through; returns once every sender is dropped ===//
+    pub async fn close(self) {
+        drop(self.blocks);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FileInfo, BLOCK_SIZE};
+    use crate::file::FileManager;
+    use crate::peer::PeerManager;
+    use crate::protocol::messages::MessageParser;
+    use sha1::{Digest, Sha1};
+    use std::sync::Arc;
+    use tokio::sync::RwLock;
+
+    const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+
+    //=== Two full pieces and a one-block tail, with the content they hash to ===//
+    fn test_torrent() -> (TorrentInfo, Vec<u8>) {
+        let total = 2 * PIECE_LENGTH as usize + BLOCK_SIZE as usize;
+        let data: Vec<u8> = (0..total).map(|i| (i % 251) as u8).collect();
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            PIECE_LENGTH,
+            data.chunks(PIECE_LENGTH as usize)
+                .map(|chunk| Sha1::digest(chunk).into())
+                .collect(),
+            vec![FileInfo::new(vec!["test".to_string()], total as u64)],
+        );
+        (info, data)
+    }
+
+    fn blocks_of(info: &TorrentInfo, data: &[u8], peer_id: PeerId) -> Vec<ReceivedBlock> {
+        (0..info.num_pieces() as PieceIndex)
+            .flat_map(|piece_index| {
+                let piece = info.byte_range_for_piece(piece_index);
+                let piece_data = &data[piece.start as usize..piece.end as usize];
+                piece_data
+                    .chunks(BLOCK_SIZE as usize)
+                    .enumerate()
+                    .map(move |(i, chunk)| ReceivedBlock {
+                        peer_id,
+                        piece_index,
+                        offset: i as u32 * BLOCK_SIZE,
+                        data: chunk.to_vec(),
+                    })
+            })
+            .collect()
+    }
+
+    async fn test_storage(info: &TorrentInfo, dir: &std::path::Path) -> SharedFileManager {
+        let mut manager = FileManager::new(info.clone(), dir.to_path_buf(), 8);
+        manager.initialize().await.unwrap();
+        manager.allocate_files().await.unwrap();
+        Arc::new(RwLock::new(manager))
+    }
+
+    #[test]
+    fn test_accumulator_assembles_blocks_in_any_order() {
+        let (info, data) = test_torrent();
+        let mut accumulator = BlockAccumulator::new(&info);
+        let mut blocks = blocks_of(&info, &data, [1u8; 20]);
+        blocks[1].peer_id = [2u8; 20];
+
+        //=== Second block first; a repeat of it changes nothing ===//
+        assert_eq!(accumulator.add_block(blocks[1].clone()), None);
+        assert_eq!(accumulator.add_block(blocks[1].clone()), None);
+        assert_eq!(accumulator.buffered_bytes(), PIECE_LENGTH as usize);
+        let piece = accumulator.add_block(blocks[0].clone()).unwrap();
+        assert_eq!(piece.piece_index, 0);
+        assert_eq!(piece.data, &data[..PIECE_LENGTH as usize]);
+        assert_eq!(piece.sources, vec![[2u8; 20], [1u8; 20]]);
+        assert_eq!(accumulator.buffered_bytes(), 0);
+
+        //=== The short last piece completes with its single block ===//
+        let tail = accumulator.add_block(blocks[4].clone()).unwrap();
+        assert_eq!(tail.piece_index, 2);
+        assert_eq!(tail.data.len(), BLOCK_SIZE as usize);
+    }
+
+    #[test]
+    fn test_accumulator_drops_misshapen_blocks() {
+        let (info, _) = test_torrent();
+        let mut accumulator = BlockAccumulator::new(&info);
+        let block = |piece_index, offset, len| ReceivedBlock {
+            peer_id: [1u8; 20],
+            piece_index,
+            offset,
+            data: vec![0; len],
+        };
+
+        assert_eq!(
+            accumulator.add_block(block(9, 0, BLOCK_SIZE as usize)),
+            None
+        );
+        assert_eq!(
+            accumulator.add_block(block(0, 1, BLOCK_SIZE as usize)),
+            None
+        );
+        assert_eq!(accumulator.add_block(block(0, 0, 10)), None);
+        assert_eq!(accumulator.add_block(block(2, BLOCK_SIZE, 0)), None);
+        assert_eq!(accumulator.buffered_bytes(), 0);
+
+        //=== A discarded piece starts over ===//
+        assert_eq!(
+            accumulator.add_block(block(0, 0, BLOCK_SIZE as usize)),
+            None
+        );
+        accumulator.discard(0);
+        assert_eq!(accumulator.buffered_bytes(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_verify_piece_hands_back_mismatches() {
+        let (info, data) = test_torrent();
+        let piece = AssembledPiece {
+            piece_index: 0,
+            data: data[..PIECE_LENGTH as usize].to_vec(),
+            sources: vec![[1u8; 20]],
+        };
+
+        let verified = verify_piece(piece.clone(), info.pieces[0]).await.unwrap();
+        assert_eq!(verified, piece);
+        let rejected = verify_piece(piece.clone(), info.pieces[1])
+            .await
+            .unwrap_err();
+        assert_eq!(rejected, piece);
+    }
+
+    #[tokio::test]
+    async fn test_flush_then_mark_piece() {
+        let (info, data) = test_torrent();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = test_storage(&info, dir.path()).await;
+        let peer_manager = Arc::new(RwLock::new(PeerManager::new(info.num_pieces(), 10)));
+        let piece = AssembledPiece {
+            piece_index: 1,
+            data: data[PIECE_LENGTH as usize..2 * PIECE_LENGTH as usize].to_vec(),
+            sources: vec![[1u8; 20]],
+        };
+
+        flush_piece(&storage, &piece).await.unwrap();
+        let on_disk = std::fs::read(dir.path().join("test")).unwrap();
+        assert_eq!(
+            &on_disk[PIECE_LENGTH as usize..2 * PIECE_LENGTH as usize],
+            &piece.data[..]
+        );
+        //=== Written is not yet ours ===//
+        assert!(!storage.read().await.piece_manager().has_piece(1));
+
+        mark_piece(&storage, &peer_manager, piece).await.unwrap();
+        assert!(storage.read().await.piece_manager().has_piece(1));
+        assert!(peer_manager.read().await.completion_percentage() > 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_have_reaches_only_torrent_peers() {
+        let info_hash = [9u8; 20];
+        let peer_senders: PeerSenders = Arc::new(RwLock::new(HashMap::new()));
+        let mut receivers = Vec::new();
+        //=== Peer 1 is also connected for some other torrent ===//
+        for (torrent, id) in [(info_hash, 1u8), (info_hash, 2), ([8u8; 20], 1)] {
+            let (tx, rx) = mpsc::unbounded_channel();
+            peer_senders.write().await.insert((torrent, [id; 20]), tx);
+            receivers.push(rx);
+        }
+
+        assert_eq!(broadcast_have(&info_hash, &peer_senders, 2).await, 2);
+        for rx in &mut receivers[..2] {
+            assert_eq!(rx.try_recv().unwrap().parse_have().unwrap(), 2);
+        }
+        assert!(receivers[2].try_recv().is_err());
+    }
+
+    #[test]
+    fn test_events_update_statistics() {
+        let mut statistics = Statistics::new(3 * PIECE_LENGTH as u64);
+        let length = PIECE_LENGTH as u64;
+        PieceEvent::Stored {
+            piece_index: 0,
+            length,
+        }
+        .apply(&mut statistics);
+        PieceEvent::Failed {
+            piece_index: 1,
+            length,
+            sources: vec![[1u8; 20]],
+        }
+        .apply(&mut statistics);
+        PieceEvent::FlushFailed {
+            piece_index: 2,
+            error: "disk full".to_string(),
+        }
+        .apply(&mut statistics);
+
+        assert_eq!(statistics.verified_downloaded, length);
+        assert_eq!(statistics.corrupt, length);
+        assert_eq!(statistics.left, 2 * length);
+        assert_eq!(statistics.pieces_verified(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_pipeline_stores_good_pieces_and_reports_bad_ones() {
+        let (info, data) = test_torrent();
+        let dir = tempfile::tempdir().unwrap();
+        let storage = test_storage(&info, dir.path()).await;
+        let peer_manager = Arc::new(RwLock::new(PeerManager::new(info.num_pieces(), 10)));
+        let peer_senders: PeerSenders = Arc::new(RwLock::new(HashMap::new()));
+        let (have_tx, mut have_rx) = mpsc::unbounded_channel();
+        peer_senders
+            .write()
+            .await
+            .insert(([9u8; 20], [1u8; 20]), have_tx);
+        peer_manager
+            .write()
+            .await
+            .add_peer([1u8; 20], "127.0.0.1:7001".parse().unwrap())
+            .unwrap();
+
+        let (pipeline, mut events) = PiecePipeline::spawn(
+            [9u8; 20],
+            info.clone(),
+            Arc::clone(&storage),
+            Arc::clone(&peer_manager),
+            peer_senders,
+        );
+        let sender = pipeline.sender();
+        let mut blocks = blocks_of(&info, &data, [1u8; 20]);
+        blocks[3].data[0] ^= 0xff;
+        for block in blocks.into_iter().rev() {
+            sender.send(block).await.unwrap();
+        }
+        drop(sender);
+        pipeline.close().await;
+
+        let mut outcomes = Vec::new();
+        while let Ok(event) = events.try_recv() {
+            outcomes.push(event);
+        }
+        //=== Failures come from the verify stage, so they may overtake stored pieces ===//
+        outcomes.sort_by_key(|event| match event {
+            PieceEvent::Stored { piece_index, .. }
+            | PieceEvent::Failed { piece_index, .. }
+            | PieceEvent::FlushFailed { piece_index, .. } => *piece_index,
+        });
+        assert_eq!(
+            outcomes,
+            vec![
+                PieceEvent::Stored {
+                    piece_index: 0,
+                    length: PIECE_LENGTH as u64,
+                },
+                PieceEvent::Failed {
+                    piece_index: 1,
+                    length: PIECE_LENGTH as u64,
+                    sources: vec![[1u8; 20]],
+                },
+                PieceEvent::Stored {
+                    piece_index: 2,
+                    length: BLOCK_SIZE as u64,
+                },
+            ]
+        );
+
+        //=== Good pieces are on disk and ours; peers heard about exactly those ===//
+        let on_disk = std::fs::read(dir.path().join("test")).unwrap();
+        assert_eq!(
+            &on_disk[..PIECE_LENGTH as usize],
+            &data[..PIECE_LENGTH as usize]
+        );
+        assert_eq!(
+            &on_disk[2 * PIECE_LENGTH as usize..],
+            &data[2 * PIECE_LENGTH as usize..]
+        );
+        let file_manager = storage.read().await;
+        assert_eq!(file_manager.piece_manager().completed_pieces(), vec![0, 2]);
+        assert_eq!(have_rx.try_recv().unwrap().parse_have().unwrap(), 2);
+        assert_eq!(have_rx.try_recv().unwrap().parse_have().unwrap(), 0);
+        assert!(have_rx.try_recv().is_err());
+
+        let mut statistics = Statistics::new(info.total_size());
+        outcomes
+            .iter()
+            .for_each(|event| event.apply(&mut statistics));
+        assert_eq!(statistics.left, PIECE_LENGTH as u64);
+    }
+}
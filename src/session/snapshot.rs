@@ -0,0 +1,27 @@
+use crate::core::Hash;
+use crate::session::SessionState;
+
+//=== Why a running torrent is making no progress ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StallReason {
+    // Some missing pieces are not held by any connected peer //
+    NoSourceForPieces { unavailable: usize },
+    // Peers have what we need, but every one of them is choking us //
+    AllPeersChoking,
+    // Every remaining piece is requested and all of those requests have timed out //
+    RequestsTimedOut { pieces: usize },
+}
+
+//=== Point-in-time view of a session, for status displays ===//
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionSnapshot {
+    pub info_hash: Hash,
+    pub state: SessionState,
+    pub completion: f64,
+    pub num_peers: usize,
+    pub missing_pieces: usize,
+    pub distributed_copies: f64,
+    pub stall_reason: Option<StallReason>,
+    // Every tracker is failing and no peer is connected, so nothing can be found //
+    pub no_peer_sources: bool,
+}
@@ -0,0 +1,139 @@
+use crate::core::{BlockRequest, PeerId, PieceIndex, TorrentInfo, BLOCK_SIZE};
+use crate::peer::PeerManager;
+use crate::session::RangePriority;
+use std::collections::{HashMap, HashSet};
+
+//=== What a strategy sees when choosing the next requests ===//
+pub struct PickContext<'a> {
+    pub torrent_info: &'a TorrentInfo,
+    pub peer_manager: &'a PeerManager,
+    // Wanted pieces we still miss and no peer is fetching, ascending //
+    pub candidates: Vec<PieceIndex>,
+    // Pieces covering a byte range the user wants early //
+    pub priority_range: Option<(&'a HashSet<PieceIndex>, RangePriority)>,
+    pub max_blocks_per_peer_fraction: f64,
+}
+
+impl PickContext<'_> {
+    //=== Move prioritized pieces to the front of `order`, or keep only them ===//
+    pub fn apply_priority(&self, order: &mut Vec<PieceIndex>) {
+        if let Some((pieces, mode)) = self.priority_range {
+            match mode {
+                RangePriority::First => order.sort_by_key(|piece| !pieces.contains(piece)),
+                RangePriority::Only => order.retain(|piece| pieces.contains(piece)),
+            }
+        }
+    }
+
+    //=== Hand out candidate pieces in `order` round robin, within request windows and shares ===//
+    pub fn assign(&self, order: &[PieceIndex]) -> Vec<BlockRequest> {
+        let peers = self.peer_manager.peers();
+        let candidates: HashSet<PieceIndex> = self.candidates.iter().copied().collect();
+        let mut taken: HashSet<PieceIndex> = HashSet::new();
+
+        let mut peer_ids: Vec<PeerId> = peers
+            .values()
+            .filter(|peer| peer.can_request())
+            .map(|peer| peer.id)
+            .collect();
+        peer_ids.sort();
+
+        //=== Free request slots per peer, counted down as pieces are handed out ===//
+        let mut slots: HashMap<PeerId, usize> = peer_ids
+            .iter()
+            .map(|peer_id| {
+                let peer = &peers[peer_id];
+                (
+                    *peer_id,
+                    peer.max_requests
+                        .saturating_sub(peer.requested_pieces().len()),
+                )
+            })
+            .collect();
+        let mut peer_blocks: HashMap<PeerId, usize> = peers
+            .values()
+            .map(|peer| (peer.id, peer.pending_request_count()))
+            .collect();
+        let mut total_blocks: usize = peer_blocks.values().sum();
+        let max_share = self.max_blocks_per_peer_fraction;
+
+        //=== A peer held back by its share gets another turn once the others took theirs ===//
+        let mut requests = Vec::new();
+        loop {
+            let mut assigned = false;
+            for peer_id in &peer_ids {
+                let peer = &peers[peer_id];
+                for &piece_index in order {
+                    if !candidates.contains(&piece_index) || taken.contains(&piece_index) {
+                        continue;
+                    }
+                    if slots[peer_id] == 0 {
+                        break;
+                    }
+                    if !peer.peer_has_piece(piece_index) {
+                        continue;
+                    }
+
+                    //=== Past its share of in-flight blocks a peer waits; one piece is fine ===//
+                    let blocks = self.blocks_in_piece(piece_index);
+                    let mine = peer_blocks.get(peer_id).copied().unwrap_or(0) + blocks;
+                    if mine > blocks && mine as f64 > max_share * (total_blocks + blocks) as f64 {
+                        break;
+                    }
+
+                    taken.insert(piece_index);
+                    *slots.get_mut(peer_id).unwrap() -= 1;
+                    peer_blocks.insert(*peer_id, mine);
+                    total_blocks += blocks;
+                    let piece_size = self.torrent_info.piece_size(piece_index);
+                    requests.extend(BlockRequest::for_piece(*peer_id, piece_index, piece_size));
+                    assigned = true;
+                }
+            }
+            if !assigned {
+                break;
+            }
+        }
+
+        requests
+    }
+
+    fn blocks_in_piece(&self, piece_index: PieceIndex) -> usize {
+        let piece_size = self.torrent_info.piece_size(piece_index);
+        piece_size.div_ceil(BLOCK_SIZE) as usize
+    }
+}
+
+//=== Decides which blocks to request from which peers outside endgame ===//
+pub trait PieceStrategy: Send + Sync {
+    fn next_blocks(&self, ctx: &PickContext) -> Vec<BlockRequest>;
+}
+
+//=== Pieces held by the fewest peers first; the default ===//
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RarestFirst;
+
+impl PieceStrategy for RarestFirst {
+    fn next_blocks(&self, ctx: &PickContext) -> Vec<BlockRequest> {
+        let mut order: Vec<PieceIndex> = ctx
+            .peer_manager
+            .rarest_pieces()
+            .into_iter()
+            .map(|(piece_index, _)| piece_index)
+            .collect();
+        ctx.apply_priority(&mut order);
+        ctx.assign(&order)
+    }
+}
+
+//=== Pieces in file order, e.g. to play media while it downloads ===//
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sequential;
+
+impl PieceStrategy for Sequential {
+    fn next_blocks(&self, ctx: &PickContext) -> Vec<BlockRequest> {
+        let mut order = ctx.candidates.clone();
+        ctx.apply_priority(&mut order);
+        ctx.assign(&order)
+    }
+}
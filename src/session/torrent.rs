@@ -0,0 +1,2103 @@
+use crate::core::{
+    system_clock, Bitfield, BlockOffset, BlockRequest, CancellationToken, Config, Hash, PeerId,
+    PieceIndex, Result, SharedClock, Statistics, TorrentInfo, WebSeed, BLOCK_SIZE,
+};
+use crate::file::{FileManager, VerificationReport};
+use crate::logging::{debug, error, info, warn};
+use crate::network::{PeerInfo, TrackerEvent, TrackerManager};
+use crate::peer::{
+    ChokingState, InterestState, PeerManager, PeerState, ReputationStore, MIN_REQUEST_WINDOW,
+};
+use crate::session::{PickContext, PieceStrategy, RarestFirst, SessionSnapshot, StallReason};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinHandle;
+
+//=== Remaining endgame blocks at which every peer is asked for every block ===//
+pub const ENDGAME_DUPLICATE_ALL_BLOCKS: usize = 4;
+
+//=== A session shared with the tasks that tick it ===//
+pub type SharedSession = Arc<RwLock<TorrentSession>>;
+
+//=== Lifecycle state of a torrent session ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionState {
+    Running,
+    // Connections stay open, but nothing is requested, uploaded or announced //
+    Paused,
+    Stopped,
+}
+
+//=== How a prioritized byte range changes which pieces get picked ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RangePriority {
+    // Pieces in the range go first, the rest follow //
+    First,
+    // Nothing outside the range is requested //
+    Only,
+}
+
+//=== Transitions a session reports to whoever drives it ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEvent {
+    // The last missing piece verified; fired once per session //
+    BecameSeeder,
+}
+
+//=== What a progress subscriber sees: our pieces once, then every change after it ===//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProgressEvent {
+    // Our bitfield in wire format; always the first event, and resent after a recheck //
+    Snapshot {
+        bitfield: Vec<u8>,
+        num_pieces: usize,
+    },
+    PieceCompleted(PieceIndex),
+    // The piece failed its hash check and will be fetched again //
+    PieceFailed(PieceIndex),
+}
+
+//=== Transfer rates in bytes per second at one stats tick ===//
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateSample {
+    pub at: Instant,
+    pub download_rate: u64,
+    pub upload_rate: u64,
+}
+
+//=== A single torrent: its storage, peers, trackers and progress ===//
+pub struct TorrentSession {
+    info_hash: Hash,
+    config: Config,
+    file_manager: FileManager,
+    peer_manager: PeerManager,
+    tracker_manager: TrackerManager,
+    statistics: Statistics,
+    state: SessionState,
+    clock: SharedClock,
+    // Extra peers each endgame block was requested from //
+    endgame_requests: HashMap<(PieceIndex, u32), HashSet<PeerId>>,
+    seeding: bool,
+    // Pieces overlapping a byte range the user wants early, e.g. for a preview //
+    priority_range: Option<(HashSet<PieceIndex>, RangePriority)>,
+    // Orders and assigns pieces outside endgame //
+    strategy: Box<dyn PieceStrategy>,
+    // Pieces handed to a web seed and not yet stored //
+    web_seed_pieces: HashSet<PieceIndex>,
+    // Send `completed` on the next announce //
+    completed_pending: bool,
+    events: Vec<SessionEvent>,
+    // Samples within `rate_history_retention`, oldest first //
+    rate_history: VecDeque<RateSample>,
+    // When the last tick ran and the wire totals it saw //
+    last_stats_tick: Option<(Instant, u64, u64)>,
+    // External address trackers last saw us at //
+    external_ip: Option<IpAddr>,
+    // Per-IP history that orders dials and bans peers sending bad data //
+    reputation: ReputationStore,
+    // Dropped once their receiver goes away //
+    progress_subscribers: Vec<mpsc::UnboundedSender<ProgressEvent>>,
+    // Handed to long operations, which bail out between steps once it is cancelled //
+    cancel: Arc<Mutex<CancellationToken>>,
+    // Request depth each peer had before memory pressure halved it //
+    memory_shallowed: HashMap<PeerId, usize>,
+}
+
+//=== Cancels a session's in-flight work from another task while it holds `&mut self` ===//
+#[derive(Debug, Clone)]
+pub struct SessionHandle {
+    cancel: Arc<Mutex<CancellationToken>>,
+}
+
+impl SessionHandle {
+    //=== Interrupt whatever holds the current token; later operations get a fresh one ===//
+    pub fn cancel_in_flight(&self) {
+        std::mem::take(&mut *self.cancel.lock().unwrap()).cancel();
+    }
+}
+
+impl TorrentSession {
+    pub fn new(info_hash: Hash, torrent_info: TorrentInfo, config: Config) -> Self {
+        Self::with_clock(info_hash, torrent_info, config, system_clock())
+    }
+
+    //=== Create a session whose peers and trackers run on the given clock ===//
+    pub fn with_clock(
+        info_hash: Hash,
+        torrent_info: TorrentInfo,
+        config: Config,
+        clock: SharedClock,
+    ) -> Self {
+        let peer_manager =
+            PeerManager::from_config(torrent_info.num_pieces(), &config, clock.clone());
+        let tracker_manager = TrackerManager::with_clock(config.clone(), Vec::new(), clock.clone());
+        let statistics = Statistics::new(torrent_info.total_size());
+        let file_manager = FileManager::new(
+            torrent_info,
+            config.download_path.clone(),
+            config.piece_cache_size,
+        );
+
+        Self {
+            info_hash,
+            config,
+            file_manager,
+            peer_manager,
+            tracker_manager,
+            statistics,
+            state: SessionState::Running,
+            clock,
+            endgame_requests: HashMap::new(),
+            seeding: false,
+            priority_range: None,
+            strategy: Box::new(RarestFirst),
+            web_seed_pieces: HashSet::new(),
+            completed_pending: false,
+            events: Vec::new(),
+            rate_history: VecDeque::new(),
+            last_stats_tick: None,
+            external_ip: None,
+            reputation: ReputationStore::new(),
+            progress_subscribers: Vec::new(),
+            cancel: Arc::new(Mutex::new(CancellationToken::new())),
+            memory_shallowed: HashMap::new(),
+        }
+    }
+
+    pub fn info_hash(&self) -> Hash {
+        self.info_hash
+    }
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+    pub fn is_paused(&self) -> bool {
+        self.state == SessionState::Paused
+    }
+
+    pub fn file_manager(&self) -> &FileManager {
+        &self.file_manager
+    }
+    pub fn file_manager_mut(&mut self) -> &mut FileManager {
+        &mut self.file_manager
+    }
+    pub fn peer_manager(&self) -> &PeerManager {
+        &self.peer_manager
+    }
+    pub fn peer_manager_mut(&mut self) -> &mut PeerManager {
+        &mut self.peer_manager
+    }
+    pub fn tracker_manager(&self) -> &TrackerManager {
+        &self.tracker_manager
+    }
+    pub fn tracker_manager_mut(&mut self) -> &mut TrackerManager {
+        &mut self.tracker_manager
+    }
+    //=== The token the next recheck, verify, flush or announce will watch ===//
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.lock().unwrap().clone()
+    }
+
+    //=== Take one before a long operation to cancel it from another task ===//
+    pub fn handle(&self) -> SessionHandle {
+        SessionHandle {
+            cancel: self.cancel.clone(),
+        }
+    }
+
+    pub fn statistics(&self) -> &Statistics {
+        &self.statistics
+    }
+    //=== Peer limit for this torrent alone, overriding the configured default ===//
+    pub fn set_max_peers(&mut self, max_peers: usize) {
+        self.config.max_connections = max_peers;
+        self.peer_manager.set_max_peers(max_peers);
+    }
+
+    pub fn reputation(&self) -> &ReputationStore {
+        &self.reputation
+    }
+
+    //=== Load the reputation saved by an earlier run, if the config keeps one ===//
+    pub async fn load_peer_reputation(&mut self) {
+        if let Some(path) = &self.config.peer_reputation_file {
+            self.reputation = ReputationStore::load(path).await;
+            info!("Loaded reputation for {} peers", self.reputation.len());
+        }
+    }
+
+    //=== Restore the announce state an earlier run saved; call once the trackers are added ===//
+    pub async fn load_tracker_state(&mut self) {
+        if let Some(path) = self.config.tracker_state_file(&self.info_hash) {
+            if self.tracker_manager.load_state(&path).await {
+                info!("Restored tracker state for {}", hex::encode(self.info_hash));
+            }
+        }
+    }
+
+    //=== Blame a peer for a piece that failed the hash check ===//
+    pub fn peer_sent_bad_data(&mut self, peer_id: &PeerId) {
+        if let Some(peer) = self.peer_manager.get_peer(peer_id) {
+            self.reputation.record_hash_failure(peer.address.ip());
+        }
+    }
+
+    //=== Whether every piece is verified; the one answer the rest of the session uses ===//
+    pub fn is_seeding(&self) -> bool {
+        self.file_manager.piece_manager().is_complete()
+    }
+
+    //=== Events raised since the last call ===//
+    pub fn take_events(&mut self) -> Vec<SessionEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    //=== A snapshot of our pieces, then each completion or failure as it happens ===//
+    pub fn subscribe_progress(&mut self) -> mpsc::UnboundedReceiver<ProgressEvent> {
+        //=== Snapshot and registration share one `&mut self`, so no piece falls between ===//
+        let (tx, rx) = mpsc::unbounded_channel();
+        let _ = tx.send(self.progress_snapshot());
+        self.progress_subscribers.push(tx);
+        rx
+    }
+
+    fn progress_snapshot(&self) -> ProgressEvent {
+        let bitfield = self.file_manager.piece_manager().bitfield();
+        ProgressEvent::Snapshot {
+            bitfield: bitfield.to_bytes(),
+            num_pieces: bitfield.total_pieces(),
+        }
+    }
+
+    fn notify_progress(&mut self, event: ProgressEvent) {
+        self.progress_subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    //=== Store a downloaded piece, returning whether it verified and was new to us ===//
+    pub fn add_piece_data(&mut self, piece_index: PieceIndex, data: Vec<u8>) -> Result<bool> {
+        let length = data.len() as u64;
+        self.web_seed_pieces.remove(&piece_index);
+        if self.file_manager.piece_manager().has_piece(piece_index) {
+            self.statistics.block_wasted(length);
+            return Ok(false);
+        }
+
+        //=== Read the contributors first; a failed piece forgets them ===//
+        let sources = self.file_manager.piece_manager().piece_sources(piece_index);
+        let verified = self
+            .file_manager
+            .piece_manager_mut()
+            .add_piece_data(piece_index, data)?;
+
+        if verified {
+            self.peer_manager.completed_piece(piece_index);
+            self.statistics.piece_verified(piece_index, length);
+            self.update_seeding();
+            self.enforce_memory_cap();
+            self.notify_progress(ProgressEvent::PieceCompleted(piece_index));
+        } else {
+            for peer_id in &sources {
+                self.peer_sent_bad_data(peer_id);
+            }
+            self.statistics.piece_failed(length);
+            self.notify_progress(ProgressEvent::PieceFailed(piece_index));
+        }
+        Ok(verified)
+    }
+
+    //=== Bytes of the piece cache and unflushed pieces, plus room for every piece in flight ===//
+    pub fn memory_usage(&self) -> usize {
+        let torrent_info = self.file_manager.torrent_info();
+        let in_flight: usize = self
+            .peer_manager
+            .peers()
+            .values()
+            .flat_map(|peer| peer.requested_pieces())
+            .map(|piece_index| torrent_info.piece_size(piece_index) as usize)
+            .sum();
+        let piece_manager = self.file_manager.piece_manager();
+        piece_manager.cache_bytes() + piece_manager.unflushed_bytes() + in_flight
+    }
+
+    //=== Near the cap, evict cached pieces; over it, shallow every peer's pipeline too ===//
+    //=== Once usage is back under the soft cap, the pipelines get their depth back ===//
+    pub fn enforce_memory_cap(&mut self) {
+        let Some(cap) = self.config.max_memory_bytes else {
+            return;
+        };
+        let soft_cap = cap / 10 * 9;
+        let usage = self.memory_usage();
+        if usage <= soft_cap {
+            for (peer_id, depth) in std::mem::take(&mut self.memory_shallowed) {
+                if let Some(peer) = self.peer_manager.get_peer_mut(&peer_id) {
+                    peer.max_requests = peer.max_requests.max(depth);
+                }
+            }
+            return;
+        }
+
+        let cache_bytes = self.file_manager.piece_manager().cache_bytes();
+        let rest = usage - cache_bytes;
+        let evicted = self
+            .file_manager
+            .piece_manager_mut()
+            .shrink_cache_to(soft_cap.saturating_sub(rest));
+        debug!(
+            "Memory at {} of {} bytes, evicted {} cached pieces",
+            usage, cap, evicted
+        );
+
+        if self.memory_usage() > cap {
+            for peer_id in self.peer_manager.peer_ids() {
+                if let Some(peer) = self.peer_manager.get_peer_mut(&peer_id) {
+                    self.memory_shallowed
+                        .entry(peer_id)
+                        .or_insert(peer.max_requests);
+                    peer.max_requests = (peer.max_requests / 2).max(MIN_REQUEST_WINDOW);
+                }
+            }
+        }
+    }
+
+    //=== Verify what is already on disk and start from it, returning the pieces found ===//
+    pub async fn recheck(&mut self) -> Result<usize> {
+        let cancel = self.cancel_token();
+        cancel.check()?;
+        self.file_manager.initialize().await?;
+        //=== Pieces found before a cancel are still ours, so register them either way ===//
+        let scanned = self
+            .file_manager
+            .scan_existing_files_cancellable(&cancel)
+            .await;
+
+        let completed = self.file_manager.piece_manager().completed_pieces();
+        for &piece_index in &completed {
+            let length = self.file_manager.torrent_info().piece_size(piece_index) as u64;
+            self.peer_manager.completed_piece(piece_index);
+            self.statistics.piece_verified(piece_index, length);
+        }
+        let snapshot = self.progress_snapshot();
+        self.notify_progress(snapshot);
+
+        //=== Data we already had is not a completion, so no `completed` goes out for it ===//
+        if !self.seeding && self.is_seeding() {
+            info!("Recheck found the torrent complete, seeding");
+            self.seeding = true;
+        }
+        scanned?;
+        Ok(completed.len())
+    }
+
+    //=== Re-hash the pieces we hold, reporting those that no longer match ===//
+    pub async fn verify(&mut self) -> Result<VerificationReport> {
+        let cancel = self.cancel_token();
+        let report = self
+            .file_manager
+            .verify_integrity_cancellable(&cancel)
+            .await?;
+        for &piece_index in &report.failed {
+            self.notify_progress(ProgressEvent::PieceFailed(piece_index));
+        }
+        Ok(report)
+    }
+
+    //=== Write the pieces we hold to disk; a cancel stops between pieces ===//
+    pub async fn flush(&mut self) -> Result<()> {
+        let cancel = self.cancel_token();
+        let flushed = self.file_manager.flush_to_disk_cancellable(&cancel).await;
+        //=== Flushed pieces no longer count against the memory cap ===//
+        self.enforce_memory_cap();
+        flushed
+    }
+
+    fn update_seeding(&mut self) {
+        if self.seeding || !self.is_seeding() {
+            return;
+        }
+
+        info!("Download complete, now seeding");
+        self.seeding = true;
+        self.completed_pending = true;
+        self.events.push(SessionEvent::BecameSeeder);
+    }
+
+    //=== Stats tick, run every `stats_interval`: refresh the rates and record a sample ===//
+    pub fn update_stats(&mut self) {
+        let now = self.clock.now();
+        let (downloaded, uploaded) = (
+            self.statistics.wire_downloaded,
+            self.statistics.wire_uploaded,
+        );
+
+        if let Some((last, last_downloaded, last_uploaded)) = self.last_stats_tick {
+            let elapsed = now.saturating_duration_since(last).as_secs_f64();
+            if elapsed > 0.0 {
+                let rate = |bytes: u64| (bytes as f64 / elapsed) as u64;
+                self.statistics.download_rate = rate(downloaded.saturating_sub(last_downloaded));
+                self.statistics.upload_rate = rate(uploaded.saturating_sub(last_uploaded));
+                self.rate_history.push_back(RateSample {
+                    at: now,
+                    download_rate: self.statistics.download_rate,
+                    upload_rate: self.statistics.upload_rate,
+                });
+            }
+        }
+        self.last_stats_tick = Some((now, downloaded, uploaded));
+
+        while self.rate_history.front().is_some_and(|sample| {
+            now.saturating_duration_since(sample.at) > self.config.rate_history_retention
+        }) {
+            self.rate_history.pop_front();
+        }
+    }
+
+    //=== Spawn the stats tick, running `update_stats` every `stats_interval` ===//
+    pub fn spawn_stats_tick(session: &SharedSession) -> JoinHandle<()> {
+        let session = Arc::clone(session);
+        tokio::spawn(async move {
+            let stats_interval = session.read().await.config.stats_interval;
+            let mut ticker = tokio::time::interval(stats_interval);
+            loop {
+                ticker.tick().await;
+                session.write().await.update_stats();
+            }
+        })
+    }
+
+    //=== Samples from the last `window`, oldest first, for drawing a rate graph ===//
+    pub fn rate_history(&self, window: Duration) -> Vec<RateSample> {
+        let now = self.clock.now();
+        self.rate_history
+            .iter()
+            .filter(|sample| now.saturating_duration_since(sample.at) <= window)
+            .copied()
+            .collect()
+    }
+
+    pub fn statistics_mut(&mut self) -> &mut Statistics {
+        &mut self.statistics
+    }
+
+    //=== Stop requesting and uploading, returning the chokes to send ===//
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "torrent",
+        skip_all,
+        fields(info_hash = %hex::encode(self.info_hash))
+    ))]
+    pub fn pause(&mut self) -> Vec<(PeerId, ChokingState)> {
+        if self.state != SessionState::Running {
+            return Vec::new();
+        }
+
+        info!("Pausing torrent {}", hex::encode(self.info_hash));
+        self.state = SessionState::Paused;
+        self.handle().cancel_in_flight();
+        self.peer_manager.choke_all()
+    }
+
+    //=== Pick up where pause left off, returning the unchokes to send ===//
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "torrent",
+        skip_all,
+        fields(info_hash = %hex::encode(self.info_hash))
+    ))]
+    pub fn resume(&mut self) -> Vec<(PeerId, ChokingState)> {
+        if self.state != SessionState::Paused {
+            return Vec::new();
+        }
+
+        info!("Resuming torrent {}", hex::encode(self.info_hash));
+        self.state = SessionState::Running;
+        self.peer_manager.rechoke()
+    }
+
+    //=== Tear down every connection and tell the trackers we left ===//
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "torrent",
+        skip_all,
+        fields(info_hash = %hex::encode(self.info_hash))
+    ))]
+    pub async fn stop(&mut self, peer_id: PeerId, port: u16) {
+        if self.state == SessionState::Stopped {
+            return;
+        }
+
+        info!("Stopping torrent {}", hex::encode(self.info_hash));
+        self.state = SessionState::Stopped;
+        self.handle().cancel_in_flight();
+
+        let peer_ids: Vec<PeerId> = self.peer_manager.peers().keys().copied().collect();
+        for id in peer_ids {
+            if let Some(peer) = self.peer_manager.remove_peer(&id) {
+                self.reputation.record_peer(&peer);
+            }
+        }
+
+        if let Some(path) = &self.config.peer_reputation_file {
+            if let Err(e) = self.reputation.save(path).await {
+                warn!("Failed to save peer reputation: {}", e);
+            }
+        }
+
+        self.announce_event(peer_id, port, TrackerEvent::Stopped)
+            .await;
+
+        if let Some(path) = self.config.tracker_state_file(&self.info_hash) {
+            if let Err(e) = self.tracker_manager.save_state(&path).await {
+                warn!("Failed to save tracker state: {}", e);
+            }
+        }
+    }
+
+    //=== Periodic announce; skipped unless the session is running ===//
+    #[cfg_attr(feature = "tracing", tracing::instrument(
+        name = "torrent",
+        skip_all,
+        fields(info_hash = %hex::encode(self.info_hash))
+    ))]
+    pub async fn announce(&mut self, peer_id: PeerId, port: u16) -> Vec<PeerInfo> {
+        if self.state != SessionState::Running {
+            return Vec::new();
+        }
+
+        let event = if self.completed_pending {
+            TrackerEvent::Completed
+        } else {
+            TrackerEvent::Started
+        };
+        //=== A cancel abandons the announce; a Completed left unsent goes out next time ===//
+        let cancel = self.cancel_token();
+        let peers = tokio::select! {
+            peers = self.announce_event(peer_id, port, event) => peers,
+            _ = cancel.cancelled() => {
+                info!("Announce for {} cancelled", hex::encode(self.info_hash));
+                return Vec::new();
+            }
+        };
+        if event == TrackerEvent::Completed {
+            self.completed_pending = false;
+        }
+        self.reputation.order_candidates(peers)
+    }
+
+    async fn announce_event(
+        &mut self,
+        peer_id: PeerId,
+        port: u16,
+        event: TrackerEvent,
+    ) -> Vec<PeerInfo> {
+        //=== Ask only for as many peers as this torrent still has room for ===//
+        let room = self
+            .peer_manager
+            .max_peers()
+            .saturating_sub(self.peer_manager.peers().len());
+        self.tracker_manager
+            .set_numwant(Some(room.min(u32::MAX as usize) as u32));
+
+        match self
+            .tracker_manager
+            .announce_all(self.info_hash, peer_id, port, &self.statistics, event)
+            .await
+        {
+            Ok(peers) => {
+                if event != TrackerEvent::Stopped && self.no_peer_sources() {
+                    warn!(
+                        "No peer sources: every tracker failed and no peers are connected; \
+                         retrying trackers with backoff"
+                    );
+                }
+                peers
+            }
+            Err(e) => {
+                error!("Announce failed: {}", e);
+                Vec::new()
+            }
+        }
+    }
+
+    //=== Report our external address; when it changed, re-announce and return peers to dial ===//
+    pub async fn on_network_change(
+        &mut self,
+        external_ip: IpAddr,
+        peer_id: PeerId,
+        port: u16,
+    ) -> Vec<PeerInfo> {
+        let previous = self.external_ip.replace(external_ip);
+        if self.state != SessionState::Running || previous.is_none_or(|ip| ip == external_ip) {
+            return Vec::new();
+        }
+
+        info!("External address changed to {}, re-announcing", external_ip);
+        self.tracker_manager.mark_all_due();
+
+        //=== Connections made through the old address are dead; redial them ===//
+        let mut redial = Vec::new();
+        if self.config.reconnect_on_network_change {
+            let peer_ids: Vec<PeerId> = self.peer_manager.peers().keys().copied().collect();
+            for id in peer_ids {
+                if let Some(peer) = self.peer_manager.remove_peer(&id) {
+                    self.reputation.record_peer(&peer);
+                    redial.push(PeerInfo {
+                        peer_id: None,
+                        ip: peer.address.ip().to_string(),
+                        port: peer.address.port(),
+                    });
+                }
+            }
+        }
+
+        let mut peers = self.announce(peer_id, port).await;
+        peers.extend(redial);
+        peers
+    }
+
+    //=== Periodic choke round, returning choke changes and the seeds dropped, whose sockets ===//
+    //=== the caller must close; a paused session keeps everyone choked ===//
+    pub fn choke_round(&mut self) -> (Vec<(PeerId, ChokingState)>, Vec<PeerId>) {
+        match self.state {
+            SessionState::Running => {
+                let dropped = if self.is_seeding() {
+                    self.peer_manager.disconnect_redundant_seeds()
+                } else {
+                    Vec::new()
+                };
+                (self.peer_manager.update_choking(), dropped)
+            }
+            SessionState::Paused | SessionState::Stopped => {
+                (self.peer_manager.choke_all(), Vec::new())
+            }
+        }
+    }
+
+    //=== Current progress and, when stuck, the reason why ===//
+    pub fn snapshot(&self) -> SessionSnapshot {
+        let missing = self.file_manager.piece_manager().missing_pieces();
+
+        SessionSnapshot {
+            info_hash: self.info_hash,
+            state: self.state,
+            completion: self.file_manager.completion_percentage(),
+            num_peers: self.peer_manager.peers().len(),
+            missing_pieces: missing.len(),
+            distributed_copies: self.peer_manager.distributed_copies(&missing),
+            stall_reason: self.stall_reason(&missing),
+            no_peer_sources: self.no_peer_sources(),
+        }
+    }
+
+    fn no_peer_sources(&self) -> bool {
+        self.peer_manager.peers().is_empty() && self.tracker_manager.all_trackers_failing()
+    }
+
+    fn stall_reason(&self, missing: &[PieceIndex]) -> Option<StallReason> {
+        if self.state != SessionState::Running || missing.is_empty() {
+            return None;
+        }
+
+        if self.peer_manager.distributed_copies(missing) < 1.0 {
+            let unavailable = missing
+                .iter()
+                .filter(|&&piece_index| self.peer_manager.piece_availability(piece_index) == 0)
+                .count();
+            return Some(StallReason::NoSourceForPieces { unavailable });
+        }
+
+        let all_choking = self
+            .peer_manager
+            .peers()
+            .values()
+            .filter(|peer| missing.iter().any(|&p| peer.peer_has_piece(p)))
+            .all(|peer| peer.peer_choking == ChokingState::Choked);
+        if all_choking {
+            return Some(StallReason::AllPeersChoking);
+        }
+
+        //=== Only stalled on requests once nothing is left to pick ===//
+        let now = self.clock.now();
+        let timed_out = missing.iter().all(|piece_index| {
+            self.peer_manager.peers().values().any(|peer| {
+                peer.requested_at(*piece_index).is_some_and(|requested| {
+                    now.saturating_duration_since(requested) >= self.config.request_timeout
+                })
+            })
+        });
+        if timed_out {
+            return Some(StallReason::RequestsTimedOut {
+                pieces: missing.len(),
+            });
+        }
+
+        None
+    }
+
+    //=== Swap how pieces are picked; takes effect on the next `pick_requests` ===//
+    pub fn set_piece_strategy(&mut self, strategy: Box<dyn PieceStrategy>) {
+        self.strategy = strategy;
+    }
+
+    //=== Assign missing pieces to peers that can serve them, in the strategy's order ===//
+    pub fn pick_requests(&mut self) -> Vec<BlockRequest> {
+        if self.is_endgame() {
+            let requests = self.plan_endgame_requests();
+            for request in &requests {
+                self.endgame_requests
+                    .entry((request.piece_index, request.offset))
+                    .or_default()
+                    .insert(request.peer_id);
+            }
+            return requests;
+        }
+
+        let requests = self.plan_requests();
+        let now = self.clock.now();
+        for request in &requests {
+            if let Some(peer) = self.peer_manager.get_peer_mut(&request.peer_id) {
+                peer.add_request_at(request.piece_index, request.offset, now);
+            }
+        }
+        requests
+    }
+
+    //=== The first `n` requests the next pick would make, without registering any ===//
+    pub fn preview_next_requests(&self, n: usize) -> Vec<BlockRequest> {
+        let mut requests = self.peer_manager.keeping_rng_state(|| {
+            if self.is_endgame() {
+                self.plan_endgame_requests()
+            } else {
+                self.plan_requests()
+            }
+        });
+        requests.truncate(n);
+        requests
+    }
+
+    fn plan_requests(&self) -> Vec<BlockRequest> {
+        if self.state != SessionState::Running {
+            return Vec::new();
+        }
+
+        let in_flight = self.in_flight_blocks();
+        let candidates: Vec<PieceIndex> = self
+            .file_manager
+            .piece_manager()
+            .missing_pieces()
+            .into_iter()
+            .filter(|&piece_index| {
+                self.file_manager.is_piece_wanted(piece_index)
+                    && self.has_unclaimed_block(piece_index, &in_flight)
+            })
+            .collect();
+
+        let ctx = PickContext {
+            torrent_info: self.file_manager.torrent_info(),
+            peer_manager: &self.peer_manager,
+            candidates,
+            priority_range: self
+                .priority_range
+                .as_ref()
+                .map(|(pieces, mode)| (pieces, *mode)),
+            max_blocks_per_peer_fraction: self.config.max_blocks_per_peer_fraction,
+        };
+        let picked = self.strategy.next_blocks(&ctx);
+        let piece_manager = self.file_manager.piece_manager();
+
+        //=== A custom strategy may overreach; each piece goes to one peer that has it ===//
+        let mut claimed: HashMap<PieceIndex, PeerId> = HashMap::new();
+        let mut planned: HashMap<PeerId, usize> = HashMap::new();
+        let mut requests = Vec::new();
+        for request in picked {
+            let piece_index = request.piece_index;
+            if in_flight.contains(&(piece_index, request.offset))
+                || piece_manager.has_block(piece_index, request.offset)
+            {
+                continue;
+            }
+            match claimed.get(&piece_index) {
+                Some(owner) if *owner != request.peer_id => continue,
+                Some(_) => {}
+                None => {
+                    if self.file_manager.piece_manager().has_piece(piece_index) {
+                        continue;
+                    }
+                    let Some(peer) = self.peer_manager.get_peer(&request.peer_id) else {
+                        continue;
+                    };
+                    let already = planned.entry(request.peer_id).or_default();
+                    if !peer.can_request_more(*already) || !peer.peer_has_piece(piece_index) {
+                        continue;
+                    }
+                    *already += 1;
+                    claimed.insert(piece_index, request.peer_id);
+                }
+            }
+            requests.push(request);
+        }
+
+        requests
+    }
+
+    //=== Blocks some peer has been asked for and not yet delivered ===//
+    fn in_flight_blocks(&self) -> HashSet<(PieceIndex, BlockOffset)> {
+        self.peer_manager
+            .peers()
+            .values()
+            .flat_map(|peer| peer.pending_requests.keys().copied())
+            .collect()
+    }
+
+    //=== Whether any block of the piece is neither requested nor here ===//
+    fn has_unclaimed_block(
+        &self,
+        piece_index: PieceIndex,
+        in_flight: &HashSet<(PieceIndex, BlockOffset)>,
+    ) -> bool {
+        let piece_size = self.file_manager.torrent_info().piece_size(piece_index);
+        let piece_manager = self.file_manager.piece_manager();
+        BlockRequest::for_piece([0u8; 20], piece_index, piece_size)
+            .iter()
+            .any(|block| {
+                !in_flight.contains(&(piece_index, block.offset))
+                    && !piece_manager.has_block(piece_index, block.offset)
+            })
+    }
+
+    //=== Up to `max` missing pieces no connected peer has, spread over the web seeds ===//
+    pub fn pick_web_seed_pieces(&mut self, max: usize) -> Vec<(WebSeed, PieceIndex)> {
+        let web_seeds = &self.file_manager.torrent_info().web_seeds;
+        if self.state != SessionState::Running || web_seeds.is_empty() {
+            return Vec::new();
+        }
+
+        let picks: Vec<(WebSeed, PieceIndex)> = self
+            .file_manager
+            .piece_manager()
+            .missing_pieces()
+            .into_iter()
+            .filter(|piece_index| {
+                self.file_manager.is_piece_wanted(*piece_index)
+                    && !self.web_seed_pieces.contains(piece_index)
+                    && self.peer_manager.piece_availability(*piece_index) == 0
+            })
+            .take(max)
+            .enumerate()
+            .map(|(i, piece_index)| (web_seeds[i % web_seeds.len()].clone(), piece_index))
+            .collect();
+
+        self.web_seed_pieces
+            .extend(picks.iter().map(|(_, piece_index)| *piece_index));
+        picks
+    }
+
+    //=== A web seed could not deliver the piece; let it be picked again ===//
+    pub fn web_seed_failed(&mut self, piece_index: PieceIndex) {
+        self.web_seed_pieces.remove(&piece_index);
+    }
+
+    //=== Fetch the pieces covering `length` bytes at `offset` first, or exclusively ===//
+    pub fn prioritize_range(&mut self, offset: u64, length: u64, mode: RangePriority) {
+        let end = offset.saturating_add(length);
+        let torrent_info = self.file_manager.torrent_info();
+        let pieces = (0..torrent_info.num_pieces() as PieceIndex)
+            .filter(|&piece_index| {
+                let bytes = torrent_info.byte_range_for_piece(piece_index);
+                bytes.start < end && offset < bytes.end
+            })
+            .collect();
+        self.priority_range = Some((pieces, mode));
+    }
+
+    pub fn clear_range_priority(&mut self) {
+        self.priority_range = None;
+    }
+
+    //=== Wanted, and not shut out by an `Only` range ===//
+    fn is_piece_active(&self, piece_index: PieceIndex) -> bool {
+        self.file_manager.is_piece_wanted(piece_index)
+            && match &self.priority_range {
+                Some((pieces, RangePriority::Only)) => pieces.contains(&piece_index),
+                _ => true,
+            }
+    }
+
+    //=== Only a few pieces are left and every block of them is already in flight or here ===//
+    pub fn is_endgame(&self) -> bool {
+        let mut missing = self.file_manager.piece_manager().missing_pieces();
+        missing.retain(|&piece_index| self.is_piece_active(piece_index));
+        let in_flight = self.in_flight_blocks();
+        !missing.is_empty()
+            && missing.len() <= self.config.endgame_threshold
+            && missing
+                .iter()
+                .all(|&piece_index| !self.has_unclaimed_block(piece_index, &in_flight))
+    }
+
+    //=== Spread outstanding blocks over peers one at a time before doubling up ===//
+    fn plan_endgame_requests(&self) -> Vec<BlockRequest> {
+        if self.state != SessionState::Running {
+            return Vec::new();
+        }
+
+        let blocks = self.outstanding_blocks();
+        let mut requesters: Vec<HashSet<PeerId>> = blocks
+            .iter()
+            .map(|block| self.block_requesters(block.piece_index, block.offset))
+            .collect();
+        let duplicate_all = blocks.len() <= ENDGAME_DUPLICATE_ALL_BLOCKS;
+
+        let mut peer_ids: Vec<PeerId> = self
+            .peer_manager
+            .peers()
+            .values()
+            .filter(|peer| {
+                matches!(peer.state, PeerState::Ready)
+                    && peer.peer_choking == ChokingState::Unchoked
+            })
+            .map(|peer| peer.id)
+            .collect();
+        peer_ids.sort();
+
+        let mut requests = Vec::new();
+        for peer_id in peer_ids {
+            let Some(peer) = self.peer_manager.get_peer(&peer_id) else {
+                continue;
+            };
+            let candidates = (0..blocks.len()).filter(|&i| {
+                peer.peer_has_piece(blocks[i].piece_index) && !requesters[i].contains(&peer_id)
+            });
+
+            //=== The least-requested block first, so each peer gets a distinct one ===//
+            let chosen: Vec<usize> = if duplicate_all {
+                candidates.collect()
+            } else {
+                candidates
+                    .min_by_key(|&i| requesters[i].len())
+                    .into_iter()
+                    .collect()
+            };
+
+            for i in chosen {
+                requesters[i].insert(peer_id);
+                requests.push(BlockRequest {
+                    peer_id,
+                    ..blocks[i]
+                });
+            }
+        }
+
+        requests
+    }
+
+    //=== Blocks of missing pieces that haven't arrived yet ===//
+    fn outstanding_blocks(&self) -> Vec<BlockRequest> {
+        let torrent_info = self.file_manager.torrent_info();
+        self.file_manager
+            .piece_manager()
+            .missing_pieces()
+            .into_iter()
+            .filter(|&piece_index| self.is_piece_active(piece_index))
+            .flat_map(|piece_index| {
+                BlockRequest::for_piece(
+                    [0u8; 20],
+                    piece_index,
+                    torrent_info.piece_size(piece_index),
+                )
+            })
+            .filter(|block| {
+                !self
+                    .file_manager
+                    .piece_manager()
+                    .has_block(block.piece_index, block.offset)
+            })
+            .collect()
+    }
+
+    //=== The piece's original requester plus any endgame duplicates ===//
+    fn block_requesters(&self, piece_index: PieceIndex, offset: u32) -> HashSet<PeerId> {
+        let mut requesters: HashSet<PeerId> = self
+            .peer_manager
+            .peers()
+            .values()
+            .filter(|peer| peer.has_block_request(piece_index, offset))
+            .map(|peer| peer.id)
+            .collect();
+        if let Some(extra) = self.endgame_requests.get(&(piece_index, offset)) {
+            requesters.extend(extra);
+        }
+        requesters
+    }
+
+    //=== `peer_id` sent its bitfield; send the returned interest message before any request ===//
+    pub fn peer_bitfield(&mut self, peer_id: PeerId, bitfield: Bitfield) -> Option<InterestState> {
+        self.peer_manager.peer_bitfield(&peer_id, bitfield)
+    }
+
+    //=== `peer_id` has a new piece; send the returned interest message before any request ===//
+    pub fn peer_have(&mut self, peer_id: PeerId, piece_index: PieceIndex) -> Option<InterestState> {
+        self.peer_manager.peer_have(&peer_id, piece_index)
+    }
+
+    //=== Picks skip a peer until it unchokes us; the first pick after that requests from it ===//
+    pub fn peer_choking_changed(&mut self, peer_id: PeerId, choking: ChokingState) {
+        self.peer_manager.peer_choking_changed(&peer_id, choking);
+    }
+
+    //=== `peer_id` rejected a block request; free it so the next pick can retry it ===//
+    pub fn request_rejected(&mut self, peer_id: PeerId, piece_index: PieceIndex, offset: u32) {
+        self.peer_manager
+            .request_rejected(&peer_id, piece_index, offset);
+        if let Some(requesters) = self.endgame_requests.get_mut(&(piece_index, offset)) {
+            requesters.remove(&peer_id);
+        }
+    }
+
+    //=== Record `length` bytes we served to `peer_id` ===//
+    pub fn block_sent(&mut self, peer_id: PeerId, length: u32) {
+        let now = self.clock.now();
+        self.statistics.update_uploaded(length as u64);
+        if let Some(peer) = self.peer_manager.get_peer_mut(&peer_id) {
+            peer.update_upload_stats_at(length as u64, now);
+        }
+    }
+
+    //=== Record a block from `peer_id`, returning the duplicate requests to cancel ===//
+    pub fn block_received(
+        &mut self,
+        peer_id: PeerId,
+        piece_index: PieceIndex,
+        offset: u32,
+    ) -> Vec<BlockRequest> {
+        let piece_size = self.file_manager.torrent_info().piece_size(piece_index);
+        let length = BLOCK_SIZE.min(piece_size.saturating_sub(offset));
+        let now = self.clock.now();
+        self.statistics.update_downloaded(length as u64);
+        if let Some(peer) = self.peer_manager.get_peer_mut(&peer_id) {
+            peer.update_download_stats_at(length as u64, now);
+        }
+        if self.file_manager.piece_manager().has_piece(piece_index) {
+            return self.drop_block_for_complete_piece(peer_id, piece_index, offset, length);
+        }
+
+        if let Some(peer) = self.peer_manager.get_peer_mut(&peer_id) {
+            peer.block_arrived_at(piece_index, offset, now);
+            peer.remove_block_request(piece_index, offset);
+        }
+
+        let piece_manager = self.file_manager.piece_manager_mut();
+        let repeat = piece_manager.has_block(piece_index, offset);
+        piece_manager.record_block_source(piece_index, peer_id);
+        piece_manager.mark_block_received(piece_index, offset);
+
+        let requesters = self.block_requesters(piece_index, offset);
+        self.endgame_requests.remove(&(piece_index, offset));
+        if repeat {
+            return Vec::new();
+        }
+
+        let mut cancels: Vec<BlockRequest> = requesters
+            .into_iter()
+            .filter(|&other| other != peer_id)
+            .map(|other| BlockRequest {
+                peer_id: other,
+                piece_index,
+                offset,
+                length,
+            })
+            .collect();
+        cancels.sort_by_key(|cancel| cancel.peer_id);
+        cancels
+    }
+
+    //=== Drop a block for a piece we already have, returning what the sender still owes us ===//
+    fn drop_block_for_complete_piece(
+        &mut self,
+        peer_id: PeerId,
+        piece_index: PieceIndex,
+        offset: u32,
+        length: u32,
+    ) -> Vec<BlockRequest> {
+        self.statistics.block_wasted(length as u64);
+
+        let pending = self
+            .peer_manager
+            .get_peer_mut(&peer_id)
+            .map(|peer| peer.remove_request(piece_index))
+            .unwrap_or_default();
+
+        let piece_size = self.file_manager.torrent_info().piece_size(piece_index);
+        BlockRequest::for_piece(peer_id, piece_index, piece_size)
+            .into_iter()
+            .filter(|block| {
+                let endgame = self
+                    .endgame_requests
+                    .get_mut(&(piece_index, block.offset))
+                    .is_some_and(|requesters| requesters.remove(&peer_id));
+                block.offset != offset && (pending.contains(&block.offset) || endgame)
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::{FileInfo, MockClock, WebSeedStyle};
+    use crate::network::tracker::RecordingTracker;
+
+    const PIECE_LENGTH: u32 = 2 * BLOCK_SIZE;
+
+    fn test_session(num_pieces: usize) -> TorrentSession {
+        test_session_with_clock(num_pieces, system_clock())
+    }
+
+    fn test_session_with_clock(num_pieces: usize, clock: SharedClock) -> TorrentSession {
+        let total = PIECE_LENGTH as u64 * num_pieces as u64;
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            PIECE_LENGTH,
+            vec![[0u8; 20]; num_pieces],
+            vec![FileInfo::new(vec!["test".to_string()], total)],
+        );
+        TorrentSession::with_clock([9u8; 20], info, Config::default(), clock)
+    }
+
+    //=== A session over `data` cut into PIECE_LENGTH pieces, hashed so they verify ===//
+    fn hashed_session(data: &[u8], config: Config) -> TorrentSession {
+        use sha1::{Digest, Sha1};
+
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            PIECE_LENGTH,
+            data.chunks(PIECE_LENGTH as usize)
+                .map(|piece| Sha1::digest(piece).into())
+                .collect(),
+            vec![FileInfo::new(vec!["test".to_string()], data.len() as u64)],
+        );
+        TorrentSession::new([9u8; 20], info, config)
+    }
+
+    //=== Route the session's announces to one recorded tracker ===//
+    fn record_announces(session: &mut TorrentSession) -> Arc<RecordingTracker> {
+        let recorder = Arc::new(RecordingTracker::default());
+        let trackers = session.tracker_manager_mut();
+        trackers.register_transport("http", recorder.clone());
+        trackers.add_tracker("http://a.example/announce".to_string());
+        recorder
+    }
+
+    //=== A ready peer that has every piece, has unchoked us and wants our data ===//
+    fn add_seed(session: &mut TorrentSession, id: u8) -> PeerId {
+        let peer_id = [id; 20];
+        let addr = format!("127.0.0.1:{}", 7000 + id as u16).parse().unwrap();
+        let num_pieces = session.file_manager().torrent_info().num_pieces();
+        session.peer_manager_mut().add_peer(peer_id, addr).unwrap();
+
+        let peer = session.peer_manager_mut().get_peer_mut(&peer_id).unwrap();
+        peer.state = PeerState::Ready;
+        peer.peer_choking = ChokingState::Unchoked;
+        peer.am_interested = InterestState::Interested;
+        peer.peer_interested = InterestState::Interested;
+        for piece in 0..num_pieces as PieceIndex {
+            peer.bitfield.set_piece(piece);
+        }
+        peer_id
+    }
+
+    #[test]
+    fn test_running_session_requests_each_piece_once() {
+        let mut session = test_session(4);
+        add_seed(&mut session, 1);
+        add_seed(&mut session, 2);
+
+        let requests = session.pick_requests();
+        assert_eq!(requests.len(), 4 * 2);
+        let pieces: HashSet<PieceIndex> = requests.iter().map(|r| r.piece_index).collect();
+        assert_eq!(pieces.len(), 4);
+
+        //=== Everything is in flight, so a second pass asks for nothing ===//
+        assert!(session.pick_requests().is_empty());
+    }
+
+    #[test]
+    fn test_preview_matches_next_pick_without_registering() {
+        let mut session = test_session(4);
+        let first = add_seed(&mut session, 1);
+        let second = add_seed(&mut session, 2);
+        session
+            .peer_manager_mut()
+            .get_peer_mut(&first)
+            .unwrap()
+            .max_requests = 1;
+
+        let preview = session.preview_next_requests(usize::MAX);
+        assert_eq!(session.preview_next_requests(usize::MAX), preview);
+        assert_eq!(session.preview_next_requests(3), preview[..3].to_vec());
+        for peer_id in [first, second] {
+            let peer = session.peer_manager().get_peer(&peer_id).unwrap();
+            assert_eq!(peer.pending_request_count(), 0);
+        }
+
+        //=== The capped peer is planned for one piece only, just as the real pick does ===//
+        let requests = session.pick_requests();
+        assert_eq!(preview, requests);
+        assert_eq!(requests.len(), 4 * 2);
+        let peer_pieces = |peer_id| {
+            requests
+                .iter()
+                .filter(|request| request.peer_id == peer_id)
+                .map(|request| request.piece_index)
+                .collect::<HashSet<_>>()
+                .len()
+        };
+        assert_eq!(peer_pieces(first), 1);
+        assert_eq!(peer_pieces(second), 3);
+        assert!(session.preview_next_requests(usize::MAX).is_empty());
+    }
+
+    #[test]
+    fn test_requests_wait_for_interest_and_unchoke() {
+        let mut session = test_session(4);
+        let peer_id = [1u8; 20];
+        session
+            .peer_manager_mut()
+            .add_peer(peer_id, "127.0.0.1:7001".parse().unwrap())
+            .unwrap();
+        session
+            .peer_manager_mut()
+            .get_peer_mut(&peer_id)
+            .unwrap()
+            .state = PeerState::Ready;
+
+        //=== Nothing to want yet, so nothing to say and nothing to ask for ===//
+        assert_eq!(session.peer_bitfield(peer_id, Bitfield::new(4)), None);
+        assert!(session.pick_requests().is_empty());
+
+        assert_eq!(
+            session.peer_have(peer_id, 2),
+            Some(InterestState::Interested)
+        );
+        assert_eq!(session.peer_have(peer_id, 3), None);
+        assert!(session.pick_requests().is_empty());
+
+        session.peer_choking_changed(peer_id, ChokingState::Unchoked);
+        let pieces: HashSet<PieceIndex> = session
+            .pick_requests()
+            .iter()
+            .map(|request| request.piece_index)
+            .collect();
+        assert_eq!(pieces, HashSet::from([2, 3]));
+
+        //=== Choked again, the rest is held back until the next unchoke ===//
+        session.peer_choking_changed(peer_id, ChokingState::Choked);
+        assert_eq!(session.peer_bitfield(peer_id, Bitfield::full(4)), None);
+        assert!(session.pick_requests().is_empty());
+        session.peer_choking_changed(peer_id, ChokingState::Unchoked);
+        assert_eq!(session.pick_requests().len(), 2 * 2);
+    }
+
+    //=== Piece 0 first, then the rest from the back ===//
+    struct PieceZeroFirst;
+
+    impl PieceStrategy for PieceZeroFirst {
+        fn next_blocks(&self, ctx: &PickContext) -> Vec<BlockRequest> {
+            let mut order: Vec<PieceIndex> = ctx.candidates.iter().rev().copied().collect();
+            order.sort_by_key(|&piece_index| piece_index != 0);
+            ctx.assign(&order)
+        }
+    }
+
+    #[test]
+    fn test_custom_strategy_orders_requests() {
+        let mut session = test_session(4);
+        let seed = add_seed(&mut session, 1);
+        session.set_piece_strategy(Box::new(PieceZeroFirst));
+
+        let requests = session.pick_requests();
+        let pieces: Vec<PieceIndex> = requests.iter().map(|r| r.piece_index).collect();
+        assert_eq!(pieces, vec![0, 0, 3, 3, 2, 2, 1, 1]);
+        assert!(requests.iter().all(|r| r.peer_id == seed));
+
+        //=== Switching back at runtime takes effect on the next pick ===//
+        session.set_piece_strategy(Box::new(crate::session::Sequential));
+        let peer = session.peer_manager_mut().get_peer_mut(&seed).unwrap();
+        peer.pending_requests.clear();
+        let pieces: Vec<PieceIndex> = session
+            .pick_requests()
+            .iter()
+            .map(|r| r.piece_index)
+            .collect();
+        assert_eq!(pieces, vec![0, 0, 1, 1, 2, 2, 3, 3]);
+    }
+
+    //=== Requests for pieces the peer lacks or another peer already took are dropped ===//
+    struct Greedy;
+
+    impl PieceStrategy for Greedy {
+        fn next_blocks(&self, ctx: &PickContext) -> Vec<BlockRequest> {
+            let mut peers: Vec<PeerId> = ctx.peer_manager.peers().keys().copied().collect();
+            peers.sort();
+            peers
+                .into_iter()
+                .flat_map(|peer_id| {
+                    BlockRequest::for_piece(peer_id, 0, ctx.torrent_info.piece_size(0))
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn test_overreaching_strategy_is_filtered() {
+        let mut session = test_session(2);
+        let first = add_seed(&mut session, 1);
+        add_seed(&mut session, 2);
+        session.set_piece_strategy(Box::new(Greedy));
+
+        let requests = session.pick_requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|r| r.peer_id == first));
+        assert!(session.pick_requests().is_empty());
+    }
+
+    #[test]
+    fn test_no_peer_exceeds_its_share_of_in_flight_blocks() {
+        let mut session = test_session(20);
+        session.config.max_blocks_per_peer_fraction = 0.4;
+        let fast = add_seed(&mut session, 1);
+        session
+            .peer_manager_mut()
+            .get_peer_mut(&fast)
+            .unwrap()
+            .max_requests = 20;
+        for id in 2..=4 {
+            let slow = add_seed(&mut session, id);
+            session
+                .peer_manager_mut()
+                .get_peer_mut(&slow)
+                .unwrap()
+                .max_requests = 2;
+        }
+
+        let requests = session.pick_requests();
+        let mut per_peer: HashMap<PeerId, usize> = HashMap::new();
+        for request in &requests {
+            *per_peer.entry(request.peer_id).or_default() += 1;
+        }
+        for &blocks in per_peer.values() {
+            assert!(blocks as f64 <= 0.4 * requests.len() as f64);
+        }
+
+        //=== The fast peer still carries the most, just not nearly everything ===//
+        assert!(per_peer[&fast] > per_peer[&[2u8; 20]]);
+    }
+
+    #[tokio::test]
+    async fn test_exceeding_memory_cap_shrinks_piece_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = vec![5u8; PIECE_LENGTH as usize];
+        let config = Config {
+            download_path: dir.path().to_path_buf(),
+            max_memory_bytes: Some(2 * PIECE_LENGTH as usize),
+            ..Config::default()
+        };
+        let mut session = hashed_session(&data.repeat(4), config);
+        session.file_manager_mut().initialize().await.unwrap();
+        let seed = add_seed(&mut session, 1);
+        session
+            .peer_manager_mut()
+            .get_peer_mut(&seed)
+            .unwrap()
+            .max_requests = 8;
+
+        //=== Unflushed pieces count too, so the cache alone can't keep usage under the cap ===//
+        for piece_index in 0..4 {
+            assert!(session.add_piece_data(piece_index, data.clone()).unwrap());
+        }
+        let (cached, _, _) = session.file_manager().piece_manager().cache_stats();
+        assert_eq!(cached, 0);
+        assert_eq!(session.memory_usage(), 4 * PIECE_LENGTH as usize);
+        let depth =
+            |session: &TorrentSession| session.peer_manager().get_peer(&seed).unwrap().max_requests;
+        assert!(depth(&session) < 8);
+
+        //=== Flushing frees the pieces, and the pipeline gets its depth back ===//
+        session.flush().await.unwrap();
+        assert_eq!(session.memory_usage(), 0);
+        assert_eq!(depth(&session), 8);
+
+        //=== Evicted pieces are read back from disk ===//
+        let piece_manager = session.file_manager().piece_manager();
+        assert!(piece_manager.get_piece_data(0).is_none());
+        assert_eq!(
+            session
+                .file_manager()
+                .read_range(0, PIECE_LENGTH as u64)
+                .await
+                .unwrap(),
+            data
+        );
+        assert!(session.verify().await.unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_rejected_block_is_picked_again() {
+        let mut session = test_session(2);
+        let seed = add_seed(&mut session, 1);
+        session
+            .peer_manager_mut()
+            .get_peer_mut(&seed)
+            .unwrap()
+            .supports_fast = true;
+
+        assert_eq!(session.pick_requests().len(), 4);
+        assert!(session.pick_requests().is_empty());
+
+        //=== Only the rejected block is retried; its sibling is still on its way ===//
+        session.request_rejected(seed, 0, 0);
+        let retried: Vec<(PieceIndex, BlockOffset)> = session
+            .pick_requests()
+            .iter()
+            .map(|request| (request.piece_index, request.offset))
+            .collect();
+        assert_eq!(retried, vec![(0, 0)]);
+        let peer = session.peer_manager().get_peer(&seed).unwrap();
+        assert!(peer.has_block_request(0, BLOCK_SIZE));
+    }
+
+    #[test]
+    fn test_web_seeds_cover_pieces_no_peer_has() {
+        let mut session = test_session(4);
+        assert!(session.pick_web_seed_pieces(4).is_empty());
+
+        let mut info = session.file_manager().torrent_info().clone();
+        info.web_seeds = vec![WebSeed {
+            url: "http://mirror.example.com/".to_string(),
+            style: WebSeedStyle::UrlList,
+        }];
+        session = TorrentSession::new([9u8; 20], info, Config::default());
+        let peer_id = add_seed(&mut session, 1);
+        let peer = session.peer_manager_mut().get_peer_mut(&peer_id).unwrap();
+        peer.bitfield = Bitfield::new(4);
+        peer.bitfield.set_piece(0);
+        peer.bitfield.set_piece(1);
+
+        //=== Peers come first; the web seed only gets what they lack ===//
+        let picks = session.pick_web_seed_pieces(4);
+        let pieces: Vec<PieceIndex> = picks.iter().map(|(_, piece)| *piece).collect();
+        assert_eq!(pieces, vec![2, 3]);
+        assert!(session.pick_web_seed_pieces(4).is_empty());
+
+        session.web_seed_failed(3);
+        assert_eq!(session.pick_web_seed_pieces(4)[0].1, 3);
+    }
+
+    #[test]
+    fn test_rate_history_keeps_only_the_retention_window() {
+        let clock = MockClock::new();
+        let mut session = test_session_with_clock(1, clock.shared());
+        session.config.rate_history_retention = Duration::from_secs(5);
+
+        session.update_stats();
+        for second in 1..=8u64 {
+            clock.advance(Duration::from_secs(1));
+            session.statistics_mut().update_downloaded(1000 * second);
+            session.statistics_mut().update_uploaded(10);
+            session.update_stats();
+        }
+
+        //=== Eight ticks ran, but only the last five seconds are kept ===//
+        let history = session.rate_history(Duration::from_secs(60));
+        let rates: Vec<u64> = history.iter().map(|sample| sample.download_rate).collect();
+        assert_eq!(rates, vec![3000, 4000, 5000, 6000, 7000, 8000]);
+        assert!(history.iter().all(|sample| sample.upload_rate == 10));
+        assert_eq!(session.statistics().download_rate, 8000);
+
+        let recent = session.rate_history(Duration::from_secs(2));
+        assert_eq!(recent.len(), 3);
+        assert_eq!(recent[0].download_rate, 6000);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stats_tick_runs_at_the_configured_interval() {
+        let clock = MockClock::new();
+        let mut session = test_session_with_clock(1, clock.shared());
+        session.config.stats_interval = Duration::from_secs(2);
+        let session = Arc::new(RwLock::new(session));
+
+        let tick = TorrentSession::spawn_stats_tick(&session);
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for _ in 0..3 {
+            clock.advance(Duration::from_secs(2));
+            session
+                .write()
+                .await
+                .statistics_mut()
+                .update_downloaded(2000);
+            tokio::time::sleep(Duration::from_secs(2)).await;
+        }
+        tick.abort();
+
+        //=== One tick at the start, then one per two seconds ===//
+        let session = session.read().await;
+        let history = session.rate_history(Duration::from_secs(60));
+        assert_eq!(history.len(), 3);
+        assert!(history.iter().all(|sample| sample.download_rate == 1000));
+    }
+
+    #[test]
+    fn test_deselected_file_stops_its_exclusive_pieces() {
+        //=== File a ends halfway through piece 1; file b covers the rest ===//
+        let half = PIECE_LENGTH as u64 / 2;
+        let info = TorrentInfo::new(
+            "test".to_string(),
+            PIECE_LENGTH,
+            vec![[0u8; 20]; 4],
+            vec![
+                FileInfo::new(vec!["a".to_string()], 3 * half),
+                FileInfo::new(vec!["b".to_string()], 5 * half),
+            ],
+        );
+        let mut session = TorrentSession::new([9u8; 20], info, Config::default());
+        let seed = add_seed(&mut session, 1);
+        session
+            .peer_manager_mut()
+            .get_peer_mut(&seed)
+            .unwrap()
+            .max_requests = 1;
+
+        let first = session.pick_requests()[0].piece_index;
+
+        //=== Mid-download, b is switched off: pieces 2 and 3 are b's alone ===//
+        session
+            .file_manager_mut()
+            .set_file_selected(1, false)
+            .unwrap();
+        let peer = session.peer_manager_mut().get_peer_mut(&seed).unwrap();
+        peer.max_requests = 10;
+        let picked: HashSet<PieceIndex> = session
+            .pick_requests()
+            .iter()
+            .map(|r| r.piece_index)
+            .collect();
+        let expected: HashSet<PieceIndex> = [0, 1].into_iter().filter(|&p| p != first).collect();
+        assert_eq!(picked, expected);
+
+        //=== With only a's pieces left to fetch, endgame stays within them too ===//
+        assert!(session.is_endgame());
+        assert!(session.pick_requests().iter().all(|r| r.piece_index < 2));
+
+        //=== Switching it back on resumes them ===//
+        session
+            .file_manager_mut()
+            .set_file_selected(1, true)
+            .unwrap();
+        let resumed: HashSet<PieceIndex> = session
+            .pick_requests()
+            .iter()
+            .map(|r| r.piece_index)
+            .collect();
+        let expected: HashSet<PieceIndex> = [2, 3].into_iter().filter(|&p| p != first).collect();
+        assert_eq!(resumed, expected);
+        assert!(session
+            .file_manager_mut()
+            .set_file_selected(2, false)
+            .is_err());
+    }
+
+    #[test]
+    fn test_only_range_pieces_are_requested() {
+        let mut session = test_session(4);
+        //=== Two range pieces would otherwise be few enough for endgame duplicates ===//
+        session.config.endgame_threshold = 0;
+        add_seed(&mut session, 1);
+        add_seed(&mut session, 2);
+
+        //=== A few bytes either side of the boundary between pieces 1 and 2 ===//
+        let boundary = 2 * PIECE_LENGTH as u64;
+        session.prioritize_range(boundary - 10, 20, RangePriority::Only);
+
+        let requests = session.pick_requests();
+        let mut pieces: Vec<PieceIndex> = requests.iter().map(|r| r.piece_index).collect();
+        pieces.sort();
+        pieces.dedup();
+        assert_eq!(pieces, vec![1, 2]);
+        assert!(session.pick_requests().is_empty());
+
+        //=== Lifting the restriction lets the rest through ===//
+        session.clear_range_priority();
+        let rest: HashSet<PieceIndex> = session
+            .pick_requests()
+            .iter()
+            .map(|r| r.piece_index)
+            .collect();
+        assert_eq!(rest, HashSet::from([0, 3]));
+    }
+
+    #[test]
+    fn test_endgame_counts_only_range_pieces() {
+        let mut session = test_session(4);
+        session.config.endgame_threshold = 2;
+        add_seed(&mut session, 1);
+        add_seed(&mut session, 2);
+
+        //=== Pieces 1 and 2 are all that is wanted, and both go out in one pick ===//
+        let boundary = 2 * PIECE_LENGTH as u64;
+        session.prioritize_range(boundary - 10, 20, RangePriority::Only);
+        assert!(!session.is_endgame());
+        assert_eq!(session.pick_requests().len(), 4);
+        assert!(session.is_endgame());
+
+        //=== Endgame duplicates stay inside the range ===//
+        let duplicates = session.pick_requests();
+        assert!(!duplicates.is_empty());
+        assert!(duplicates
+            .iter()
+            .all(|r| r.piece_index == 1 || r.piece_index == 2));
+
+        //=== With `First`, the rest still count ===//
+        session.prioritize_range(boundary - 10, 20, RangePriority::First);
+        assert!(!session.is_endgame());
+    }
+
+    #[tokio::test]
+    async fn test_network_change_reannounces_with_same_key() {
+        let mut session = test_session(2);
+        let recorder = record_announces(&mut session);
+        let peer_id = add_seed(&mut session, 1);
+
+        session.announce([1u8; 20], 6881).await;
+        let home: IpAddr = "203.0.113.5".parse().unwrap();
+        assert!(session
+            .on_network_change(home, [1u8; 20], 6881)
+            .await
+            .is_empty());
+        assert!(session
+            .on_network_change(home, [1u8; 20], 6881)
+            .await
+            .is_empty());
+        assert_eq!(recorder.attempts(), 1);
+
+        //=== A new address announces right away, inside the interval ===//
+        let vpn: IpAddr = "198.51.100.7".parse().unwrap();
+        let redial = session.on_network_change(vpn, [1u8; 20], 6881).await;
+        let requests = recorder.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].key, requests[1].key);
+
+        //=== The old connection is dropped and handed back for redialing ===//
+        assert!(session.peer_manager().get_peer(&peer_id).is_none());
+        assert_eq!(redial.len(), 1);
+        assert_eq!(redial[0].port, 7001);
+    }
+
+    #[tokio::test]
+    async fn test_tracker_state_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = Config {
+            tracker_state_dir: Some(dir.path().to_path_buf()),
+            ..Config::default()
+        };
+        let data = vec![0u8; 2 * PIECE_LENGTH as usize];
+
+        let mut session = hashed_session(&data, config.clone());
+        let first = record_announces(&mut session);
+        session.announce([1u8; 20], 6881).await;
+        session.stop([1u8; 20], 6881).await;
+        let saved = session.tracker_manager().export_state();
+
+        //=== The next run picks up the same key and interval from disk ===//
+        let mut session = hashed_session(&data, config);
+        let second = record_announces(&mut session);
+        session.load_tracker_state().await;
+        assert_eq!(session.tracker_manager().export_state().key, saved.key);
+        assert_eq!(
+            session.tracker_manager().export_state().trackers[0].interval,
+            Some(1800)
+        );
+
+        session.announce([1u8; 20], 6881).await;
+        let requests = second.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].event, TrackerEvent::Started);
+        assert_eq!(requests[0].key, first.requests()[0].key);
+    }
+
+    #[tokio::test]
+    async fn test_recheck_complete_torrent_announces_as_seed() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = vec![3u8; 2 * PIECE_LENGTH as usize];
+        tokio::fs::write(dir.path().join("test"), &data)
+            .await
+            .unwrap();
+        let config = Config {
+            download_path: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let mut session = hashed_session(&data, config);
+        let recorder = record_announces(&mut session);
+
+        assert_eq!(session.recheck().await.unwrap(), 2);
+        assert!(session.is_seeding());
+        assert!(session.take_events().is_empty());
+
+        //=== The first announce already says we have everything, without `completed` ===//
+        session.announce([1u8; 20], 6881).await;
+        let requests = recorder.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].left, 0);
+        assert_eq!(requests[0].event, TrackerEvent::Started);
+    }
+
+    #[tokio::test]
+    async fn test_handle_stops_a_running_recheck() {
+        let dir = tempfile::tempdir().unwrap();
+        let data: Vec<u8> = (0..64u8)
+            .flat_map(|i| vec![i; PIECE_LENGTH as usize])
+            .collect();
+        tokio::fs::write(dir.path().join("test"), &data)
+            .await
+            .unwrap();
+        let config = Config {
+            download_path: dir.path().to_path_buf(),
+            ..Config::default()
+        };
+        let mut session = hashed_session(&data, config);
+        let handle = session.handle();
+
+        let recheck = tokio::spawn(async move {
+            let result = session.recheck().await;
+            (session, result)
+        });
+        tokio::task::yield_now().await;
+        handle.cancel_in_flight();
+
+        let (mut session, result) = tokio::time::timeout(Duration::from_secs(5), recheck)
+            .await
+            .expect("cancelled recheck should return promptly")
+            .unwrap();
+        assert!(matches!(result, Err(crate::core::TorrentError::Cancelled)));
+        assert!(!session.is_seeding());
+
+        //=== The cancel was spent on that recheck; the next one runs to the end ===//
+        assert_eq!(session.recheck().await.unwrap(), 64);
+        assert!(session.is_seeding());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_reports_no_peer_sources_when_trackers_fail() {
+        let clock = MockClock::new();
+        let mut session = test_session_with_clock(2, clock.shared());
+        let dead = Arc::new(RecordingTracker::dead());
+        let trackers = session.tracker_manager_mut();
+        trackers.register_transport("http", dead.clone());
+        trackers.add_tracker("http://a.example/announce".to_string());
+        trackers.add_tracker("http://b.example/announce".to_string());
+        assert!(!session.snapshot().no_peer_sources);
+
+        assert!(session.announce([1u8; 20], 6881).await.is_empty());
+        assert!(session.snapshot().no_peer_sources);
+        assert_eq!(dead.attempts(), 2);
+
+        //=== Retries back off rather than hammering the dead trackers ===//
+        session.announce([1u8; 20], 6881).await;
+        assert_eq!(dead.attempts(), 2);
+        clock.advance(Config::default().tracker_retry_backoff);
+        session.announce([1u8; 20], 6881).await;
+        assert_eq!(dead.attempts(), 4);
+
+        //=== A connected peer is a source even with every tracker down ===//
+        add_seed(&mut session, 1);
+        assert!(!session.snapshot().no_peer_sources);
+    }
+
+    #[test]
+    fn test_endgame_distributes_blocks_before_duplicating() {
+        let mut session = test_session(4);
+        session.config.endgame_threshold = 4;
+        let peer_a = add_seed(&mut session, 1);
+        add_seed(&mut session, 2);
+        add_seed(&mut session, 3);
+
+        //=== The first peer takes every piece, which puts us in endgame ===//
+        let initial = session.pick_requests();
+        assert_eq!(initial.len(), 8);
+        assert!(initial.iter().all(|r| r.peer_id == peer_a));
+        assert!(session.is_endgame());
+
+        let requester_counts = |session: &TorrentSession| -> Vec<usize> {
+            session
+                .outstanding_blocks()
+                .iter()
+                .map(|b| session.block_requesters(b.piece_index, b.offset).len())
+                .collect()
+        };
+
+        //=== Each round gives the other two peers one distinct block apiece ===//
+        for _ in 0..4 {
+            let round = session.pick_requests();
+            assert_eq!(round.len(), 2);
+            assert_ne!(
+                (round[0].piece_index, round[0].offset),
+                (round[1].piece_index, round[1].offset)
+            );
+            assert!(requester_counts(&session).iter().all(|&n| n <= 2));
+        }
+        assert!(requester_counts(&session).iter().all(|&n| n == 2));
+
+        //=== A received block cancels its duplicate ===//
+        let cancels = session.block_received(peer_a, 0, 0);
+        assert_eq!(cancels.len(), 1);
+        assert_ne!(cancels[0].peer_id, peer_a);
+
+        //=== With only the final few left, everyone asks for everything ===//
+        for (piece_index, offset) in [(0, BLOCK_SIZE), (1, 0), (1, BLOCK_SIZE)] {
+            session.block_received(peer_a, piece_index, offset);
+        }
+        assert_eq!(session.statistics().wire_downloaded, 4 * BLOCK_SIZE as u64);
+        assert_eq!(session.outstanding_blocks().len(), 4);
+        session.pick_requests();
+        assert!(requester_counts(&session).iter().all(|&n| n == 3));
+    }
+
+    #[test]
+    fn test_endgame_requests_a_failed_piece_again() {
+        let mut session = test_session(1);
+        session.config.endgame_threshold = 1;
+        let seed = add_seed(&mut session, 1);
+        add_seed(&mut session, 2);
+
+        session.pick_requests();
+        assert!(session.is_endgame());
+        for offset in [0, BLOCK_SIZE] {
+            session.block_received(seed, 0, offset);
+        }
+        assert!(session.outstanding_blocks().is_empty());
+
+        //=== The piece fails its hash, so every block of it is wanted again ===//
+        assert!(!session
+            .add_piece_data(0, vec![0u8; PIECE_LENGTH as usize])
+            .unwrap());
+        assert_eq!(session.outstanding_blocks().len(), 2);
+        let retried: HashSet<(PieceIndex, BlockOffset)> = session
+            .pick_requests()
+            .iter()
+            .map(|request| (request.piece_index, request.offset))
+            .collect();
+        assert_eq!(retried, HashSet::from([(0, 0), (0, BLOCK_SIZE)]));
+    }
+
+    #[test]
+    fn test_every_contributor_of_a_bad_piece_is_blamed() {
+        let mut session = test_session(1);
+        let first = add_seed(&mut session, 1);
+        let second = add_seed(&mut session, 2);
+        session.block_received(first, 0, 0);
+        session.block_received(second, 0, BLOCK_SIZE);
+
+        assert!(!session
+            .add_piece_data(0, vec![0u8; PIECE_LENGTH as usize])
+            .unwrap());
+        let ip = "127.0.0.1".parse().unwrap();
+        assert_eq!(session.reputation().get(&ip).unwrap().hash_failures, 2);
+
+        //=== What each peer sent is on record when it leaves ===//
+        let peer = session.peer_manager().get_peer(&first).unwrap();
+        assert_eq!(peer.downloaded, BLOCK_SIZE as u64);
+    }
+
+    #[test]
+    fn test_block_for_complete_piece_is_dropped() {
+        let data = vec![7u8; PIECE_LENGTH as usize];
+        let mut session = hashed_session(&data.repeat(2), Config::default());
+        let peer_id = add_seed(&mut session, 1);
+        session.pick_requests();
+
+        //=== Piece 0 completes while the peer is still sending it ===//
+        assert!(session.add_piece_data(0, data.clone()).unwrap());
+        let cancels = session.block_received(peer_id, 0, 0);
+        assert_eq!(cancels.len(), 1);
+        assert_eq!((cancels[0].piece_index, cancels[0].offset), (0, BLOCK_SIZE));
+        assert_eq!(session.statistics().wasted, BLOCK_SIZE as u64);
+        assert_eq!(session.statistics().wire_downloaded, BLOCK_SIZE as u64);
+        let peer = session.peer_manager().get_peer(&peer_id).unwrap();
+        assert!(!peer.has_request(0));
+        assert!(peer.has_request(1));
+
+        //=== Late data for it is not hashed again, so garbage can't count as corrupt ===//
+        assert!(!session.add_piece_data(0, vec![0u8; data.len()]).unwrap());
+        assert_eq!(session.statistics().corrupt, 0);
+        assert!(session.file_manager().piece_manager().has_piece(0));
+    }
+
+    #[test]
+    fn test_choke_round_reports_seeds_dropped_while_seeding() {
+        let data = vec![7u8; PIECE_LENGTH as usize];
+        let mut session = hashed_session(&data.repeat(2), Config::default());
+        assert!(session.add_piece_data(0, data.clone()).unwrap());
+        assert!(session.add_piece_data(1, data).unwrap());
+        assert!(session.is_seeding());
+
+        let seed = add_seed(&mut session, 1);
+        let (_, dropped) = session.choke_round();
+        assert_eq!(dropped, vec![seed]);
+        assert!(session.peer_manager().get_peer(&seed).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_paused_session_keeps_connections_but_requests_nothing() {
+        let mut session = test_session(4);
+        let peer_a = add_seed(&mut session, 1);
+        let peer_b = add_seed(&mut session, 2);
+        session.peer_manager_mut().rechoke();
+
+        let chokes = session.pause();
+        assert_eq!(chokes.len(), 2);
+        assert!(session.is_paused());
+        assert!(session.pick_requests().is_empty());
+        assert_eq!(session.choke_round(), (Vec::new(), Vec::new()));
+        assert!(session.announce([3u8; 20], 6881).await.is_empty());
+
+        //=== Connections survive the pause ===//
+        assert!(session.peer_manager().get_peer(&peer_a).is_some());
+        assert!(session.peer_manager().get_peer(&peer_b).is_some());
+
+        let unchokes = session.resume();
+        assert_eq!(unchokes.len(), 2);
+        assert!(!session.pick_requests().is_empty());
+
+        //=== Stop, unlike pause, drops the peers ===//
+        session.stop([3u8; 20], 6881).await;
+        assert_eq!(session.state(), SessionState::Stopped);
+        assert!(session.peer_manager().peers().is_empty());
+        assert!(session.pick_requests().is_empty());
+    }
+
+    #[test]
+    fn test_stall_when_no_peer_has_remaining_pieces() {
+        let mut session = test_session(4);
+        let peer_id = add_seed(&mut session, 1);
+        session
+            .peer_manager_mut()
+            .get_peer_mut(&peer_id)
+            .unwrap()
+            .bitfield = Bitfield::new(4);
+        session
+            .peer_manager_mut()
+            .get_peer_mut(&peer_id)
+            .unwrap()
+            .has_piece(0);
+
+        let snapshot = session.snapshot();
+        assert!(snapshot.distributed_copies < 1.0);
+        assert_eq!(
+            snapshot.stall_reason,
+            Some(StallReason::NoSourceForPieces { unavailable: 3 })
+        );
+    }
+
+    #[test]
+    fn test_stall_when_all_peers_choke_us() {
+        let mut session = test_session(4);
+        for id in 1..=3 {
+            let peer_id = add_seed(&mut session, id);
+            session
+                .peer_manager_mut()
+                .get_peer_mut(&peer_id)
+                .unwrap()
+                .peer_choking = ChokingState::Choked;
+        }
+
+        assert_eq!(
+            session.snapshot().stall_reason,
+            Some(StallReason::AllPeersChoking)
+        );
+
+        //=== One unchoke is enough to make progress possible ===//
+        session
+            .peer_manager_mut()
+            .get_peer_mut(&[2u8; 20])
+            .unwrap()
+            .peer_choking = ChokingState::Unchoked;
+        assert_eq!(session.snapshot().stall_reason, None);
+    }
+
+    #[test]
+    fn test_stall_when_requests_time_out() {
+        let clock = MockClock::new();
+        let mut session = test_session_with_clock(2, clock.shared());
+        add_seed(&mut session, 1);
+        assert_eq!(session.pick_requests().len(), 4);
+
+        //=== Freshly requested blocks are not a stall ===//
+        assert_eq!(session.snapshot().stall_reason, None);
+
+        clock.advance(Config::default().request_timeout);
+        assert_eq!(
+            session.snapshot().stall_reason,
+            Some(StallReason::RequestsTimedOut { pieces: 2 })
+        );
+    }
+
+    #[test]
+    fn test_became_seeder_fires_once() {
+        let pieces: Vec<Vec<u8>> = (0..3u8).map(|i| vec![i; PIECE_LENGTH as usize]).collect();
+        let mut session = hashed_session(&pieces.concat(), Config::default());
+
+        let mut events = Vec::new();
+        for (index, data) in pieces.iter().enumerate() {
+            assert!(!session.is_seeding());
+            assert!(session
+                .add_piece_data(index as PieceIndex, data.clone())
+                .unwrap());
+            events.extend(session.take_events());
+        }
+        assert_eq!(events, vec![SessionEvent::BecameSeeder]);
+        assert!(session.is_seeding());
+        assert!(session.peer_manager().is_complete());
+        assert_eq!(session.statistics().left, 0);
+
+        //=== Seeing a piece again does not make us a seeder twice ===//
+        assert!(!session.add_piece_data(2, pieces[2].clone()).unwrap());
+        assert!(session.take_events().is_empty());
+    }
+
+    #[test]
+    fn test_late_progress_subscriber_starts_from_snapshot() {
+        let pieces: Vec<Vec<u8>> = (0..10u8).map(|i| vec![i; PIECE_LENGTH as usize]).collect();
+        let mut session = hashed_session(&pieces.concat(), Config::default());
+        for piece_index in [0, 3, 9] {
+            let data = pieces[piece_index as usize].clone();
+            assert!(session.add_piece_data(piece_index, data).unwrap());
+        }
+
+        let mut progress = session.subscribe_progress();
+        assert!(session.add_piece_data(5, pieces[5].clone()).unwrap());
+        assert!(!session
+            .add_piece_data(6, vec![0xff; PIECE_LENGTH as usize])
+            .unwrap());
+
+        let Ok(ProgressEvent::Snapshot {
+            bitfield,
+            num_pieces,
+        }) = progress.try_recv()
+        else {
+            panic!("a subscription starts with a snapshot");
+        };
+        assert_eq!(
+            Bitfield::from_bytes(&bitfield, num_pieces),
+            Bitfield::from_indices(10, &[0, 3, 9])
+        );
+        assert_eq!(progress.try_recv(), Ok(ProgressEvent::PieceCompleted(5)));
+        assert_eq!(progress.try_recv(), Ok(ProgressEvent::PieceFailed(6)));
+        assert!(progress.try_recv().is_err());
+
+        //=== A gone subscriber is forgotten on the next event ===//
+        drop(progress);
+        assert!(session.add_piece_data(1, pieces[1].clone()).unwrap());
+        assert!(session.progress_subscribers.is_empty());
+    }
+}